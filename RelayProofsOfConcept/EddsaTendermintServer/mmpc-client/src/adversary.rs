@@ -0,0 +1,249 @@
+/// Peers that misbehave on purpose, to test that the honest peers of a session abort naming them.
+/// An adversary runs an honest peer of the protocol, and tampers with the messages it sends
+/// in the rounds it is given faults for
+use std::collections::BTreeMap;
+
+use log::info;
+
+use crate::key_store::PeerKeys;
+use crate::peer::{Peer, ProtocolAbort};
+use mmpc_server_common::{MessagePayload, PeerIdentifier};
+
+/// How an adversary tampers with its message of a round
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    // The message of the round is replaced by the given function of it
+    Mutate(fn(&MessagePayload) -> MessagePayload),
+
+    // The message of the previous round is sent again in place of the one of the round
+    Replay,
+
+    // Nothing is sent from the round on
+    Withhold,
+
+    // Another message for the previous round is sent, the given function of the one sent then
+    Equivocate(fn(&MessagePayload) -> MessagePayload),
+}
+
+pub struct Adversary<T: Peer> {
+    pub inner: T,
+    // the fault of each round the adversary misbehaves in
+    pub faults: BTreeMap<u32, Fault>,
+    // the messages the honest peer would have sent, by round
+    pub honest: BTreeMap<u32, MessagePayload>,
+    // the round the adversary is in, and the number of its messages received so far.
+    // All the messages of a round are received before the next
+    round: u32,
+    received: u32,
+}
+
+impl<T: Peer> Adversary<T> {
+    pub fn set_fault(&mut self, round: u32, fault: Fault) {
+        self.faults.insert(round, fault);
+    }
+
+    fn withholds(&self, round: u32) -> bool {
+        self.faults
+            .range(..=round)
+            .any(|(_, fault)| matches!(fault, Fault::Withhold))
+    }
+
+    // The message the adversary sends in the round in place of the honest one
+    fn tamper(&mut self, round: u32, item: Option<MessagePayload>) -> Option<MessagePayload> {
+        let item = item?;
+        self.honest.insert(round, item.clone());
+        if self.withholds(round) {
+            info!("Withholding the message of round {}", round);
+            return None;
+        }
+        let previous = round
+            .checked_sub(1)
+            .and_then(|previous| self.honest.get(&previous));
+        let tampered = match (self.faults.get(&round), previous) {
+            (Some(Fault::Mutate(mutate)), _) => mutate(&item),
+            (Some(Fault::Replay), Some(previous)) => previous.clone(),
+            (Some(Fault::Equivocate(mutate)), Some(previous)) => mutate(previous),
+            _ => return Some(item),
+        };
+        info!("Tampered with the message of round {}", round);
+        Some(tampered)
+    }
+}
+
+impl<T: Peer> Peer for Adversary<T> {
+    fn new(capacity: u32, message: Vec<u8>, keys: PeerKeys) -> Adversary<T> {
+        Adversary {
+            inner: T::new(capacity, message, keys),
+            faults: BTreeMap::new(),
+            honest: BTreeMap::new(),
+            round: 0,
+            received: 0,
+        }
+    }
+    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+        let item = self.inner.zero_step(peer_id);
+        self.tamper(0, item)
+    }
+    fn current_step(&self) -> u32 {
+        self.inner.current_step()
+    }
+    fn capacity(&self) -> u32 {
+        self.inner.capacity()
+    }
+    fn peer_id(&self) -> PeerIdentifier {
+        self.inner.peer_id()
+    }
+    fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+        self.inner.set_peer_id(peer_id);
+    }
+    fn do_step(&mut self) {
+        self.inner.do_step();
+    }
+    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        self.received += 1;
+        if self.received == self.capacity() {
+            self.round += 1;
+            self.received = 0;
+        }
+        self.inner.update_data(from, payload);
+    }
+    fn get_next_item(&mut self) -> Option<MessagePayload> {
+        let item = self.inner.get_next_item();
+        // only the item once all the messages of a round arrived is sent
+        if self.received != 0 {
+            return item;
+        }
+        self.tamper(self.round, item)
+    }
+    fn finalize(&mut self) -> Result<(), &'static str> {
+        self.inner.finalize()
+    }
+    fn is_done(&mut self) -> bool {
+        self.inner.is_done()
+    }
+    fn failure(&self) -> Option<String> {
+        self.inner.failure()
+    }
+    fn set_threshold(&mut self, threshold: u32) {
+        self.inner.set_threshold(threshold);
+    }
+    fn get_next_private_items(&mut self) -> BTreeMap<PeerIdentifier, MessagePayload> {
+        if self.withholds(self.round) {
+            return BTreeMap::new();
+        }
+        self.inner.get_next_private_items()
+    }
+    fn update_private_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        self.inner.update_private_data(from, payload);
+    }
+    fn abort(&self) -> Option<ProtocolAbort> {
+        self.inner.abort()
+    }
+    fn abort_evidence(&self) -> Vec<MessagePayload> {
+        self.inner.abort_evidence()
+    }
+    fn verify_abort(&self, abort: &ProtocolAbort, evidence: &[MessagePayload]) -> bool {
+        self.inner.verify_abort(abort, evidence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Adversary, Fault};
+    use crate::key_store::{MemoryKeyStore, PeerKeys};
+    use crate::peer::{Peer, ProtocolDataManager};
+    use mmpc_server_common::{MessagePayload, PeerIdentifier};
+
+    // Every peer sends the round it is in, for three rounds
+    struct RoundPeer {
+        peer_id: PeerIdentifier,
+        capacity: u32,
+        current_step: u32,
+        received: u32,
+    }
+
+    impl Peer for RoundPeer {
+        fn new(capacity: u32, _message: Vec<u8>, _keys: PeerKeys) -> RoundPeer {
+            RoundPeer {
+                peer_id: 0,
+                capacity,
+                current_step: 0,
+                received: 0,
+            }
+        }
+        fn zero_step(&mut self, _peer_id: PeerIdentifier) -> Option<MessagePayload> {
+            Some(String::from("0"))
+        }
+        fn current_step(&self) -> u32 {
+            self.current_step
+        }
+        fn capacity(&self) -> u32 {
+            self.capacity
+        }
+        fn peer_id(&self) -> PeerIdentifier {
+            self.peer_id
+        }
+        fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+            self.peer_id = peer_id;
+        }
+        fn do_step(&mut self) {
+            if self.received == self.capacity {
+                self.received = 0;
+                self.current_step += 1;
+            }
+        }
+        fn update_data(&mut self, _from: PeerIdentifier, _payload: MessagePayload) {
+            self.received += 1;
+        }
+        fn get_next_item(&mut self) -> Option<MessagePayload> {
+            match self.current_step {
+                step if step < 3 => Some(step.to_string()),
+                _ => None,
+            }
+        }
+        fn finalize(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+        fn is_done(&mut self) -> bool {
+            self.current_step == 3
+        }
+    }
+
+    // The messages the adversary sends until it has nothing more to send
+    fn sent(faults: Vec<(u32, Fault)>) -> Vec<MessagePayload> {
+        let capacity = 2;
+        let keys = PeerKeys::new(Box::new(MemoryKeyStore::new()), None);
+        let mut data_manager: ProtocolDataManager<Adversary<RoundPeer>> =
+            ProtocolDataManager::new(capacity, Vec::new(), keys);
+        for (round, fault) in faults {
+            data_manager.data_holder.set_fault(round, fault);
+        }
+        let mut sent = Vec::new();
+        let mut next_message = data_manager.initialize_data(1);
+        while let Some(payload) = next_message {
+            sent.push(payload.clone());
+            next_message = None;
+            for from in 1..=capacity {
+                next_message = data_manager.get_next_message(from, payload.clone(), None);
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn test_honest_without_faults() {
+        assert_eq!(sent(Vec::new()), vec!["0", "1", "2"]);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mutate = Fault::Mutate(|payload| format!("{}!", payload));
+        assert_eq!(sent(vec![(1, mutate)]), vec!["0", "1!", "2"]);
+        assert_eq!(sent(vec![(2, Fault::Replay)]), vec!["0", "1", "1"]);
+        assert_eq!(sent(vec![(1, Fault::Withhold)]), vec!["0"]);
+        let equivocate = Fault::Equivocate(|payload| format!("{}?", payload));
+        assert_eq!(sent(vec![(1, equivocate)]), vec!["0", "0?", "2"]);
+        // there is no previous round to replay in the first
+        assert_eq!(sent(vec![(0, Fault::Replay)]), vec!["0", "1", "2"]);
+    }
+}
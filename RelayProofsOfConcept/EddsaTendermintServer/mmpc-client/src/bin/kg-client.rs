@@ -1,18 +1,23 @@
+use std::env;
 use std::error::Error;
 use std::fs::OpenOptions;
 use std::io;
-use std::net::SocketAddr;
 use std::path::Path;
 use std::process;
-use std::{thread, time};
+use std::time;
 
 use clap::{App, Arg, ArgMatches};
-use log::debug;
+use log::{debug, info};
 use serde::Serialize;
 
 use mmpc_client::eddsa_peer_kg::EddsaPeer;
-use mmpc_client::peer::Peer;
-use mmpc_client::tendermint_client::SessionClient;
+use mmpc_client::eddsa_peer_threshold_kg;
+use mmpc_client::key_store::{load_or_generate_identity, FileKeyStore, PeerKeys, PASSPHRASE_VAR};
+use mmpc_client::peer::{Peer, ProtocolDataManager};
+use mmpc_client::tendermint_client::TendermintTransport;
+use mmpc_client::transport;
+use mmpc_server_common::identity::Identity;
+use mmpc_server_common::{ProtocolIdentifier, SessionIdentifier, DEFAULT_SESSION_ID};
 
 #[derive(Debug, Serialize)]
 struct Record {
@@ -20,9 +25,6 @@ struct Record {
     millis: u32,
 }
 
-const MAX_RETRY: u32 = 64;
-const RETRY_TIMEOUT: u64 = 200;
-
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
         .arg(
@@ -48,6 +50,26 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
                 .default_value("127.0.0.1:26657")
                 .long("proxy"),
         )
+        .arg(
+            Arg::with_name("key-store")
+                .long("key-store")
+                .takes_value(true)
+                .help("Directory of the key store, key-store<INDEX> by default"),
+        )
+        .arg(
+            Arg::with_name("session")
+                .short("S")
+                .long("session")
+                .takes_value(true)
+                .help("Relay session to join, the default session of the relay if not set"),
+        )
+        .arg(
+            Arg::with_name("threshold")
+                .short("T")
+                .long("threshold")
+                .takes_value(true)
+                .help("Generates a threshold key, any THRESHOLD+1 of the parties can sign with"),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -148,48 +170,55 @@ fn main() {
         .parse()
         .expect("Invalid proxy address");
 
+    let threshold: Option<u32> = matches
+        .value_of("threshold")
+        .map(|threshold| threshold.parse().expect("Invalid threshold"));
+
+    let session_id: SessionIdentifier = matches
+        .value_of("session")
+        .map(|session| session.parse().expect("Invalid session"))
+        .unwrap_or(DEFAULT_SESSION_ID);
+
     let verbosity: u64 = matches.occurrences_of("verbose");
 
     setup_logging(verbosity, client_index).expect("failed to initialize logging.");
 
+    let key_store_dir = matches
+        .value_of("key-store")
+        .map(String::from)
+        .unwrap_or_else(|| format!("key-store{}", client_index));
+    let passphrase = env::var(PASSPHRASE_VAR).unwrap_or_else(|_| {
+        println!("Set {} to the passphrase of the key store", PASSPHRASE_VAR);
+        process::exit(1);
+    });
+    let mut key_store = FileKeyStore::new(Path::new(&key_store_dir), &passphrase)
+        .expect("Unable to open key store");
+    // The identity of the peer is kept in its key store between runs,
+    // for the other peers to recognize it
+    let identity = load_or_generate_identity(&mut key_store).expect("Unable to load identity");
+    let keys = PeerKeys::new(Box::new(key_store), None);
+
     let start_time = time::SystemTime::now();
-    let port = 8080 + client_index;
     let proxy_addr = format!("tcp://{}", proxy);
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
     debug!("Capacity flag is {}", capacity);
-    let mut session: SessionClient<EddsaPeer> = SessionClient::new(
-        client_addr,
-        &proxy_addr.parse().unwrap(),
-        client_index,
-        capacity,
-        Vec::new(),
-    );
-    // Initially do not request any index, the index is determined by the server
-    let server_response = session.register(client_index, capacity, -1);
-    let next_message = session.generate_client_answer(server_response);
-    debug!("Next message: {:?}", next_message);
-    // TODO The client/server
-    let server_response = session.send_message(next_message.unwrap());
-    session.store_server_response(&server_response);
-
-    debug!("Server Response: {:?}", server_response);
-
-    for _ in { 1..MAX_RETRY } {
-        let round = session.state.data_manager.data_holder.current_step();
-        debug!("Now on round {}", round);
-        if session.state.stored_messages.get_number_messages(round) == capacity as usize {
-            for msg in session
-                .state
-                .stored_messages
-                .get_messages_vector_client_message(round)
-            {
-                session.handle_relay_message(msg.clone());
-            }
-            break;
+    match threshold {
+        Some(threshold) => {
+            let mut data_manager: ProtocolDataManager<eddsa_peer_threshold_kg::EddsaPeer> =
+                ProtocolDataManager::new(capacity, Vec::new(), keys);
+            data_manager.set_threshold(threshold);
+            run_keygen(
+                &proxy_addr,
+                identity,
+                session_id,
+                eddsa_peer_threshold_kg::PROTOCOL_ID,
+                data_manager,
+            );
+        }
+        None => {
+            let data_manager: ProtocolDataManager<EddsaPeer> =
+                ProtocolDataManager::new(capacity, Vec::new(), keys);
+            run_keygen(&proxy_addr, identity, session_id, 1, data_manager);
         }
-        let server_response = session.query();
-        session.store_server_response(&server_response);
-        thread::sleep(time::Duration::from_millis(RETRY_TIMEOUT));
     }
     let total_time = start_time.elapsed().expect("Weird time");
     println!("{:}", total_time.as_millis());
@@ -200,6 +229,25 @@ fn main() {
     }
 }
 
+fn run_keygen<T: Peer>(
+    proxy_addr: &str,
+    identity: Identity,
+    session_id: SessionIdentifier,
+    protocol_id: ProtocolIdentifier,
+    mut data_manager: ProtocolDataManager<T>,
+) {
+    // Initially do not request any index, the index is determined by the server
+    let mut transport = TendermintTransport::new(&proxy_addr.parse().unwrap(), identity, -1);
+    transport.session_id = session_id;
+    match transport::run(&mut transport, protocol_id, &mut data_manager) {
+        Ok(rounds) => info!("Key generation done in {} rounds", rounds),
+        Err(err) => {
+            println!("Relay session failed: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
 fn write_to_csv(index: u32, millis: u32, capacity: u32) -> Result<(), Box<dyn Error>> {
     let filename = format!("exp-kg-{}.csv", capacity);
     if Path::new(&filename).exists() {
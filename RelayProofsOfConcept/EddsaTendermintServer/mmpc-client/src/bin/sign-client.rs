@@ -1,19 +1,24 @@
+use std::env;
 use std::error::Error;
-use std::fs;
 use std::fs::OpenOptions;
 use std::io;
-use std::net::SocketAddr;
 use std::path::Path;
 use std::process;
-use std::{thread, time};
+use std::time;
 
 use clap::{App, Arg, ArgMatches};
-use log::debug;
+use log::info;
 use serde::Serialize;
 
 use mmpc_client::eddsa_peer_sign::EddsaPeer;
-use mmpc_client::peer::Peer;
-use mmpc_client::tendermint_client::SessionClient;
+use mmpc_client::eddsa_peer_threshold_kg::{self, KeyShare};
+use mmpc_client::eddsa_peer_threshold_sign;
+use mmpc_client::key_store::{load_or_generate_identity, FileKeyStore, PeerKeys, PASSPHRASE_VAR};
+use mmpc_client::peer::{Peer, ProtocolDataManager};
+use mmpc_client::tendermint_client::TendermintTransport;
+use mmpc_client::transport;
+use mmpc_server_common::identity::Identity;
+use mmpc_server_common::{ProtocolIdentifier, SessionIdentifier, DEFAULT_SESSION_ID};
 
 use multi_party_eddsa::protocols::aggsig::{KeyAgg, KeyPair};
 
@@ -23,9 +28,6 @@ struct Record {
     millis: u32,
 }
 
-const MAX_RETRY: u32 = 512;
-const RETRY_TIMEOUT: u64 = 200;
-
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
         .arg(
@@ -57,6 +59,32 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
                 .default_value("127.0.0.1:26657")
                 .long("proxy"),
         )
+        .arg(
+            Arg::with_name("key-store")
+                .long("key-store")
+                .takes_value(true)
+                .help("Directory of the key store, key-store<INDEX> by default"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .short("K")
+                .takes_value(true)
+                .help("Aggregated public key to sign with, if the key store has more than one"),
+        )
+        .arg(
+            Arg::with_name("session")
+                .short("S")
+                .long("session")
+                .takes_value(true)
+                .help("Relay session to join, the default session of the relay if not set"),
+        )
+        .arg(
+            Arg::with_name("threshold")
+                .short("T")
+                .long("threshold")
+                .help("Signs with a threshold key, by any t+1 of the parties"),
+        )
         .get_matches()
 }
 
@@ -150,6 +178,11 @@ fn main() {
         .parse()
         .expect("Invalid proxy address");
 
+    let session_id: SessionIdentifier = matches
+        .value_of("session")
+        .map(|session| session.parse().expect("Invalid session"))
+        .unwrap_or(DEFAULT_SESSION_ID);
+
     let verbosity: u64 = matches.occurrences_of("verbose");
     setup_logging(verbosity, client_index).expect("failed to initialize logging.");
 
@@ -158,57 +191,43 @@ fn main() {
         Err(_) => message.as_bytes().to_vec(),
     };
 
-    let data = fs::read_to_string(format!("keys{}", client_index))
-        .expect("Unable to load keys, did you run keygen first? ");
-    let (_, _, kg_index): (KeyPair, KeyAgg, i32) = serde_json::from_str(&data).unwrap();
+    let key_store_dir = matches
+        .value_of("key-store")
+        .map(String::from)
+        .unwrap_or_else(|| format!("key-store{}", client_index));
+    let passphrase = env::var(PASSPHRASE_VAR).unwrap_or_else(|_| {
+        println!("Set {} to the passphrase of the key store", PASSPHRASE_VAR);
+        process::exit(1);
+    });
+    let mut key_store = FileKeyStore::new(Path::new(&key_store_dir), &passphrase)
+        .expect("Unable to open key store");
+    // The identity of the peer is kept in its key store between runs,
+    // for the other peers to recognize it
+    let identity = load_or_generate_identity(&mut key_store).expect("Unable to load identity");
+    let keys = PeerKeys::new(
+        Box::new(key_store),
+        matches.value_of("key").map(String::from),
+    );
 
-    // Port and ip address are used as a unique indetifier to the server
-    // This should be replaced with PKi down the road
     let start_time = time::SystemTime::now();
-    let port = 8080 + client_index;
     let proxy_addr = format!("tcp://{}", proxy);
-    let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-    let mut session: SessionClient<EddsaPeer> = SessionClient::new(
-        client_addr,
-        &proxy_addr.parse().unwrap(),
-        client_index,
-        capacity,
-        message_to_sign,
-    );
-    let server_response = session.register(client_index, capacity, kg_index);
-    let mut next_message = session.generate_client_answer(server_response);
-    debug!("Next message: {:?}", next_message);
-    // TODO The client/server response could be an error
-    let mut server_response = session.send_message(next_message.clone().unwrap());
-    session.store_server_response(&server_response);
-    // Number of rounds in signing
-    let rounds = 4;
-    'outer: for _ in 0..rounds {
-        'inner: for _ in { 1..MAX_RETRY } {
-            let round = session.state.data_manager.data_holder.current_step();
-            if session.state.stored_messages.get_number_messages(round) == capacity as usize {
-                for msg in session
-                    .state
-                    .stored_messages
-                    .get_messages_vector_client_message(round)
-                {
-                    next_message = session.handle_relay_message(msg.clone());
-                }
-                // Do not send response on last round
-                if round != rounds - 1 {
-                    server_response = session.send_message(next_message.clone().unwrap());
-                    session.store_server_response(&server_response);
-                }
-                break 'inner;
-            } else {
-                let server_response = session.query();
-                // debug!("Server response {:?}", server_response);
-                // debug!("Server response len {}", server_response.keys().len());
-                session.store_server_response(&server_response);
-                thread::sleep(time::Duration::from_millis(RETRY_TIMEOUT));
-                // debug!("All stored messages {:?}", session.state.stored_messages);
-            }
-        }
+    if matches.is_present("threshold") {
+        let kg_index = KeyShare::load(&keys).index as i32;
+        let transport = connect(&proxy_addr, identity, session_id, kg_index);
+        run_signing::<eddsa_peer_threshold_sign::EddsaPeer>(
+            transport,
+            eddsa_peer_threshold_kg::PROTOCOL_ID,
+            capacity,
+            message_to_sign,
+            keys,
+        );
+    } else {
+        let data = keys
+            .load()
+            .expect("Unable to load keys, did you run keygen first? ");
+        let (_, _, kg_index): (KeyPair, KeyAgg, i32) = serde_json::from_slice(&data).unwrap();
+        let transport = connect(&proxy_addr, identity, session_id, kg_index);
+        run_signing::<EddsaPeer>(transport, 1, capacity, message_to_sign, keys);
     }
 
     let total_time = start_time.elapsed().expect("Weird time");
@@ -220,6 +239,36 @@ fn main() {
     }
 }
 
+// The transport of the peer to the relay, for the session it signs in
+fn connect(
+    proxy_addr: &str,
+    identity: Identity,
+    session_id: SessionIdentifier,
+    kg_index: i32,
+) -> TendermintTransport {
+    let mut transport = TendermintTransport::new(&proxy_addr.parse().unwrap(), identity, kg_index);
+    transport.session_id = session_id;
+    transport
+}
+
+fn run_signing<T: Peer>(
+    mut transport: TendermintTransport,
+    protocol_id: ProtocolIdentifier,
+    capacity: u32,
+    message_to_sign: Vec<u8>,
+    keys: PeerKeys,
+) {
+    let mut data_manager: ProtocolDataManager<T> =
+        ProtocolDataManager::new(capacity, message_to_sign, keys);
+    match transport::run(&mut transport, protocol_id, &mut data_manager) {
+        Ok(rounds) => info!("Signing done in {} rounds", rounds),
+        Err(err) => {
+            println!("Relay session failed: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
 fn write_to_csv(index: u32, millis: u32, capacity: u32) -> Result<(), Box<dyn Error>> {
     let filename = format!("exp-sign-{}.csv", capacity);
     if Path::new(&filename).exists() {
@@ -1,11 +1,11 @@
 use std::collections::HashMap;
-use std::fs;
 
 use curv::elliptic::curves::ed25519::*;
-use log::{debug, info};
+use log::{debug, info, warn};
 use multi_party_eddsa::protocols::aggsig::{EphemeralKey, KeyAgg, KeyPair};
 
-use crate::peer::Peer;
+use crate::key_store::{key_name, PeerKeys};
+use crate::peer::{Peer, ProtocolAbort};
 use mmpc_server_common::common::*;
 use mmpc_server_common::{MessagePayload, PeerIdentifier};
 
@@ -42,10 +42,20 @@ pub struct EddsaPeer {
     pub commitment_msg: Option<MessagePayload>,
     pub r_msg: Option<MessagePayload>,
     pub sig_msg: Option<MessagePayload>,
+
+    // the created key is saved to the key store of the peer
+    pub keys: PeerKeys,
+
+    // the public key message of each peer, a peer sending another one equivocates
+    pub received: HashMap<PeerIdentifier, MessagePayload>,
+    // the misbehavior this peer aborted on, it sends nothing more once set
+    pub abort: Option<ProtocolAbort>,
+    // the messages of the culprit that prove the abort
+    pub evidence: Vec<MessagePayload>,
 }
 
 impl Peer for EddsaPeer {
-    fn new(capacity: u32, _message: Vec<u8>, _index: u32) -> EddsaPeer {
+    fn new(capacity: u32, _message: Vec<u8>, keys: PeerKeys) -> EddsaPeer {
         debug!("Capacity is set to {}", capacity);
         EddsaPeer {
             client_key: KeyPair::create(),
@@ -69,6 +79,11 @@ impl Peer for EddsaPeer {
             commitment_msg: None,
             r_msg: None,
             sig_msg: None,
+            keys,
+
+            received: HashMap::new(),
+            abort: None,
+            evidence: Vec::new(),
         }
     }
 
@@ -82,7 +97,7 @@ impl Peer for EddsaPeer {
 
         let pk_s = serde_json::to_string(&pk).expect("Failed in serialization");
 
-        self.pk_msg = Some(generate_pk_message_payload(&pk_s));
+        self.pk_msg = Some(EddsaMessage::PublicKey(pk_s).to_payload());
         return self.pk_msg.clone();
     }
 
@@ -100,6 +115,9 @@ impl Peer for EddsaPeer {
 
     fn do_step(&mut self) {
         debug!("Current step is: {:}", self.current_step);
+        if self.abort.is_some() {
+            return;
+        }
         if self.is_step_done() {
             // do the next step
             debug!("step {:} done!", self.current_step);
@@ -117,6 +135,9 @@ impl Peer for EddsaPeer {
     }
 
     fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        if self.abort.is_some() {
+            return;
+        }
         // update data according to step
         debug!("Current step {}", self.current_step);
         match self.current_step {
@@ -136,10 +157,13 @@ impl Peer for EddsaPeer {
 
         let keygen_json = serde_json::to_string(&(key, apk, index)).unwrap();
 
-        let res = fs::write(format!("keys{}", self.peer_id), keygen_json);
+        let res = self
+            .keys
+            .store
+            .save(&key_name(&apk.apk), keygen_json.as_bytes());
         match res {
             Ok(_) => Ok(()),
-            Err(_) => Err("Failed to verify"),
+            Err(_) => Err("Failed to save key"),
         }
     }
     /// check that the protocol is done
@@ -148,10 +172,21 @@ impl Peer for EddsaPeer {
         self.is_done_step_0()
     }
 
+    fn abort(&self) -> Option<ProtocolAbort> {
+        self.abort.clone()
+    }
+
+    fn abort_evidence(&self) -> Vec<MessagePayload> {
+        self.evidence.clone()
+    }
+
     /// get the next item the peer needs to send
     /// depending on the current step and the last message
     /// of the peer that was accepted by the server
     fn get_next_item(&mut self) -> Option<MessagePayload> {
+        if self.abort.is_some() {
+            return None;
+        }
         if self.current_step == 0 || !self.pk_accepted {
             debug!("next item is pk: {:?}", self.pk_msg);
             return self.pk_msg.clone();
@@ -176,46 +211,57 @@ impl EddsaPeer {
     }
 }
 
-impl EddsaPeer {
-    pub fn resolve_payload_type(message: &MessagePayload) -> MessagePayloadType {
-        let msg_payload = message.clone();
-
-        let split_msg: Vec<&str> = msg_payload.split(RELAY_MESSAGE_DELIMITER).collect();
-        let msg_prefix = split_msg[0];
-        let msg_payload = String::from(split_msg[1].clone());
-        match msg_prefix {
-            pk_prefix if pk_prefix == String::from(PK_MESSAGE_PREFIX) => {
-                return MessagePayloadType::PublicKey(msg_payload);
-            }
-            _ => panic!("Unknown relay message prefix"),
-        }
-    }
-}
-
 impl EddsaPeer {
     /// data updaters for each step
     pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::PublicKey(pk) => {
-                let peer_id = self.peer_id;
-                if from == peer_id {
-                    self.pk_accepted = true;
-                }
+        // a resend must be the message the peer sent before
+        if let Some(received) = self.received.get(&from) {
+            if *received != payload {
+                let evidence = vec![received.clone(), payload];
+                self.blame(ProtocolAbort::Equivocation(from), evidence);
+            }
+            return;
+        }
+        match EddsaMessage::from_payload(&payload) {
+            Ok(EddsaMessage::PublicKey(pk)) => {
                 let s_slice: &str = &pk[..]; // take a full slice of the string
                 let _pk = serde_json::from_str(s_slice);
                 info!("-------Got peer # {:} pk! {:?}", from, pk);
                 match _pk {
-                    Ok(_pk) => self.add_pk(from, _pk),
-                    Err(_) => panic!("Could not serialize public key"),
+                    Ok(_pk) => {
+                        if from == self.peer_id {
+                            self.pk_accepted = true;
+                        }
+                        self.received.insert(from, payload);
+                        self.add_pk(from, _pk)
+                    }
+                    Err(_) => {
+                        warn!("Could not deserialize public key of peer {}", from);
+                        self.blame(ProtocolAbort::MalformedMessage(from), vec![payload])
+                    }
                 }
             }
+            Ok(msg) => {
+                warn!("Expected public key from peer {}, got {:?}", from, msg);
+                self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload])
+            }
+            Err(err) => {
+                warn!("Invalid message from peer {}: {}", from, err);
+                self.blame(ProtocolAbort::MalformedMessage(from), vec![payload])
+            }
         }
     }
 }
 
 impl EddsaPeer {
     /// inner calculations & data manipulations
+    fn blame(&mut self, abort: ProtocolAbort, evidence: Vec<MessagePayload>) {
+        warn!("Aborting: {}", abort);
+        if self.abort.is_none() {
+            self.abort = Some(abort);
+            self.evidence = evidence;
+        }
+    }
     fn add_pk(&mut self, peer_id: PeerIdentifier, pk: Ed25519Point) {
         self.pks.insert(peer_id, pk);
     }
@@ -235,10 +281,3 @@ impl EddsaPeer {
         return agg_key;
     }
 }
-
-#[derive(Debug)]
-pub enum MessagePayloadType {
-    /// Types of expected relay messages
-    /// for step 0 we expect PUBLIC_KEY_MESSAGE
-    PublicKey(String),
-}
@@ -6,26 +6,25 @@ use curv::elliptic::curves::ed25519::*;
 use curv::elliptic::curves::traits::ECPoint;
 use curv::elliptic::curves::traits::ECScalar;
 use curv::{BigInt, FE, GE};
-use log::{debug, info};
+use log::{debug, info, warn};
 use multi_party_eddsa::protocols::aggsig::{
     test_com, verify, EphemeralKey, KeyAgg, KeyPair, SignFirstMsg, SignSecondMsg, Signature,
 };
 
-use crate::peer::Peer;
+use crate::key_store::PeerKeys;
+use crate::peer::{Peer, ProtocolAbort};
 use mmpc_server_common::common::*;
 use mmpc_server_common::{MessagePayload, PeerIdentifier};
 
-#[derive(Debug)]
-pub enum MessagePayloadType {
-    /// Types of expected relay messages
-    /// for step 0 we expect PUBLIC_KEY_MESSAGE
-    /// for step 1 we expect Commitment
-    /// for step 2 we expect RMessage
-    /// for step 3 we expect Signature
-    PublicKey(String),
-    Commitment(String),
-    RMessage(String),
-    Signature(String),
+// The step of the protocol a message is sent in
+fn step_of(msg: &EddsaMessage) -> Option<u32> {
+    match msg {
+        EddsaMessage::PublicKey(_) => Some(0),
+        EddsaMessage::Commitment(_) => Some(1),
+        EddsaMessage::RMessage(_) => Some(2),
+        EddsaMessage::Signature(_) => Some(3),
+        _ => None,
+    }
 }
 
 #[allow(non_snake_case)]
@@ -42,6 +41,8 @@ pub struct EddsaPeer {
     // eddsa data
     pub client_key: KeyPair,
     pub pks: HashMap<PeerIdentifier, Ed25519Point>,
+    // key generation index of each signer, sent along with its public key
+    pub kg_indexes: HashMap<PeerIdentifier, u32>,
     pub commitments: HashMap<PeerIdentifier, String>,
     pub r_s: HashMap<PeerIdentifier, String>,
     pub sigs: HashMap<PeerIdentifier, String>,
@@ -64,11 +65,19 @@ pub struct EddsaPeer {
     pub commitment_msg: Option<MessagePayload>,
     pub r_msg: Option<MessagePayload>,
     pub sig_msg: Option<MessagePayload>,
+
+    // the message of each peer in each step, a peer sending another one for a step equivocates
+    pub received: HashMap<(u32, PeerIdentifier), MessagePayload>,
+    // the misbehavior this peer aborted on, it sends nothing more once set
+    pub abort: Option<ProtocolAbort>,
+    // the messages of the culprit that prove the abort
+    pub evidence: Vec<MessagePayload>,
 }
 
 impl EddsaPeer {
     /// inner calculations & data manipulations
-    fn add_pk(&mut self, peer_id: PeerIdentifier, pk: Ed25519Point) {
+    fn add_pk(&mut self, peer_id: PeerIdentifier, kg_index: u32, pk: Ed25519Point) {
+        self.kg_indexes.insert(peer_id, kg_index);
         self.pks.insert(peer_id, pk);
     }
     fn add_commitment(&mut self, peer_id: PeerIdentifier, commitment: String) {
@@ -81,7 +90,7 @@ impl EddsaPeer {
     fn add_sig(&mut self, peer_id: PeerIdentifier, sig: String) {
         self.sigs.insert(peer_id, sig);
     }
-    fn compute_r_tot(&mut self) -> GE {
+    fn compute_r_tot(&self) -> GE {
         #[allow(non_snake_case)]
         let mut Ri: Vec<GE> = Vec::new();
         for (_peer_id, r) in &self.r_s {
@@ -93,25 +102,82 @@ impl EddsaPeer {
         let r_tot = Signature::get_R_tot(Ri);
         return r_tot;
     }
-    fn aggregate_pks(&mut self) -> KeyAgg {
+    // The signers in the order of key generation, along with their public keys
+    fn signers(&self) -> (Vec<PeerIdentifier>, Vec<Ed25519Point>) {
+        let mut signers: Vec<PeerIdentifier> = self.pks.keys().cloned().collect();
+        signers.sort_by_key(|peer| self.kg_indexes[peer]);
+        let pks: Vec<Ed25519Point> = signers.iter().map(|peer| self.pks[peer].clone()).collect();
+        (signers, pks)
+    }
+    fn aggregate_pks(&self) -> KeyAgg {
         debug!("aggregating pks");
-        let _cap = self.capacity as usize;
-        let mut pks = Vec::with_capacity(self.capacity as usize);
-        for index in 0..self.capacity {
-            let peer = index + 1;
-            let pk = self.pks.get_mut(&peer).unwrap();
-            pks.push(pk.clone());
-        }
+        // the public keys are aggregated in the order of key generation,
+        // whatever order the signers registered in
+        let (signers, pks) = self.signers();
         debug!("# of public keys : {:?}", pks.len());
         let peer_id = self.peer_id;
-        let index = (peer_id - 1) as usize;
+        let index = signers
+            .iter()
+            .position(|peer| *peer == peer_id)
+            .expect("Missing public key of this peer");
         debug!("Public keys {:?}", &pks);
         debug!("KG index:{}, SIG index:{}", self.kg_index, peer_id);
-        // TODO: sort the pks according to key-gen indexes when applying
         KeyPair::key_aggregation_n(&pks, &index)
     }
 
-    fn validate_commitments(&mut self) -> bool {
+    fn blame(&mut self, abort: ProtocolAbort, evidence: Vec<MessagePayload>) {
+        warn!("Aborting: {}", abort);
+        if self.abort.is_none() {
+            self.abort = Some(abort);
+            self.evidence = evidence;
+        }
+    }
+
+    // The message the peer sent in the step, as evidence against it
+    fn sent(&self, step: u32, from: PeerIdentifier) -> Vec<MessagePayload> {
+        self.received
+            .get(&(step, from))
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+
+    // Returns true if the signature share s_i of the peer verifies,
+    // s_i * G = R_i + k * a_i * pk_i, the shares of all the peers summing up
+    // to the signature finalize verifies
+    fn verify_signature_share(&self, peer_id: PeerIdentifier, share: &Signature) -> bool {
+        let eight: FE = ECScalar::from(&BigInt::from(8));
+        let r: SignSecondMsg = match self.r_s.get(&peer_id).map(|r| serde_json::from_str(r)) {
+            Some(Ok(r)) => r,
+            _ => return false,
+        };
+        let (signers, pks) = self.signers();
+        let index = match signers.iter().position(|peer| *peer == peer_id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let r_tot = self.compute_r_tot();
+        let apk = KeyPair::key_aggregation_n(&pks, &index);
+        let k = Signature::k(&r_tot, &apk.apk, &self.message[..]);
+        let g: GE = ECPoint::generator();
+        let k_a_pk = pks[index].clone() * &(k * &apk.hash);
+        g * &(share.s.clone() * &eight) == r.R + &k_a_pk
+    }
+
+    // Returns the first peer whose signature share does not verify
+    fn invalid_signature_share(&self) -> Option<PeerIdentifier> {
+        self.sigs.iter().find_map(|(peer_id, sig)| {
+            // the share was read when received, so it deserializes
+            let share: Signature = serde_json::from_str(sig).unwrap();
+            match self.verify_signature_share(*peer_id, &share) {
+                true => None,
+                false => Some(*peer_id),
+            }
+        })
+    }
+
+    // Returns the first peer whose R does not match its commitment
+    fn validate_commitments(&mut self) -> Result<(), PeerIdentifier> {
         // iterate over all peer Rs
         debug!("----------\nvalidating commitments\n----------");
         let eight: FE = ECScalar::from(&BigInt::from(8));
@@ -120,15 +186,14 @@ impl EddsaPeer {
         for (peer_id, r) in r_s {
             debug!("peer: {:}", peer_id);
             debug!("r: {:}", r);
-            // convert the json_string to a construct
+            // both were read when received, so they deserialize
             let _r: SignSecondMsg = serde_json::from_str(r).unwrap();
 
             // get the corresponding commitment
-            let k = peer_id.clone();
-            let cmtmnt = self
-                .commitments
-                .get(&k)
-                .expect("peer didn't send commitment");
+            let cmtmnt = match self.commitments.get(peer_id) {
+                Some(cmtmnt) => cmtmnt,
+                None => return Err(*peer_id),
+            };
             debug!("commitment : {:?}", cmtmnt);
             let commitment: SignFirstMsg = serde_json::from_str(cmtmnt).unwrap();
             // if we couldn't validate the commitment - failure
@@ -137,80 +202,93 @@ impl EddsaPeer {
                 &_r.blind_factor,
                 &commitment.commitment,
             ) {
-                return false;
+                return Err(*peer_id);
             }
         }
         debug!("----------\ncommitments valid\n----------");
-        true
+        Ok(())
     }
 }
 
 impl EddsaPeer {
     /// data updaters for each step
-    pub fn update_data_step_0(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
+    pub fn update_data_step_0(&mut self, from: PeerIdentifier, pk: String) {
         let eight: FE = ECScalar::from(&BigInt::from(8));
         let eight_inv = eight.invert();
-        match payload_type {
-            MessagePayloadType::PublicKey(pk) => {
-                let peer_id = self.peer_id;
-                if from == peer_id {
-                    self.pk_accepted = true;
-                }
-                let s_slice: &str = &pk[..]; // take a full slice of the string
-                let pk: GE = serde_json::from_str(&s_slice)
-                    .unwrap_or_else(|_| panic!("Failed to deserialize R"));
-                info!("-------Got peer # {:} pk! {:?}", from, pk * &eight_inv);
-                self.add_pk(from, pk * &eight_inv);
+        let peer_id = self.peer_id;
+        if from == peer_id {
+            self.pk_accepted = true;
+        }
+        let s_slice: &str = &pk[..]; // take a full slice of the string
+        let (kg_index, pk): (u32, GE) = match serde_json::from_str(&s_slice) {
+            Ok(pk) => pk,
+            Err(_) => {
+                let evidence = self.sent(0, from);
+                return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
             }
-            _ => panic!("expected public key message"),
+        };
+        // every key generation index belongs to exactly one of the signers
+        if kg_index == 0 || kg_index > self.capacity {
+            warn!(
+                "Peer {} sent invalid key generation index {}",
+                from, kg_index
+            );
+            let evidence = self.sent(0, from);
+            return self.blame(ProtocolAbort::InvalidKeyIndex(from), evidence);
         }
+        if self
+            .kg_indexes
+            .iter()
+            .any(|(peer, index)| *peer != from && *index == kg_index)
+        {
+            warn!(
+                "Peer {} sent key generation index {} of another peer",
+                from, kg_index
+            );
+            let evidence = self.sent(0, from);
+            return self.blame(ProtocolAbort::InvalidKeyIndex(from), evidence);
+        }
+        info!("-------Got peer # {:} pk! {:?}", from, pk * &eight_inv);
+        self.add_pk(from, kg_index, pk * &eight_inv);
     }
 
-    pub fn update_data_step_1(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::Commitment(t) => {
-                info!("-------Got peer # {:} commitment! {:?}", from, t);
-                let peer_id = self.peer_id;
-                if from == peer_id {
-                    self.commitment_accepted = true;
-                }
-                self.add_commitment(from, t);
-            }
-            _ => {} //panic!("expected commitment message")
+    pub fn update_data_step_1(&mut self, from: PeerIdentifier, t: String) {
+        info!("-------Got peer # {:} commitment! {:?}", from, t);
+        if serde_json::from_str::<SignFirstMsg>(&t).is_err() {
+            let evidence = self.sent(1, from);
+            return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
+        }
+        let peer_id = self.peer_id;
+        if from == peer_id {
+            self.commitment_accepted = true;
         }
+        self.add_commitment(from, t);
     }
 
-    pub fn update_data_step_2(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::RMessage(r) => {
-                info!("-------Got peer # {:} R message!", from);
-                let peer_id = self.peer_id;
-                if from == peer_id {
-                    self.r_accepted = true;
-                }
-                self.add_r(from, r);
-            }
-            _ => {} //panic!("expected R message")
+    pub fn update_data_step_2(&mut self, from: PeerIdentifier, r: String) {
+        info!("-------Got peer # {:} R message!", from);
+        if serde_json::from_str::<SignSecondMsg>(&r).is_err() {
+            let evidence = self.sent(2, from);
+            return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
         }
+        let peer_id = self.peer_id;
+        if from == peer_id {
+            self.r_accepted = true;
+        }
+        self.add_r(from, r);
     }
 
-    pub fn update_data_step_3(&mut self, from: PeerIdentifier, payload: MessagePayload) {
-        debug!("updating data step 3");
-        let payload_type = EddsaPeer::resolve_payload_type(&payload);
-        match payload_type {
-            MessagePayloadType::Signature(s) => {
-                debug!("-------Got peer # {:} Signature", from);
-                let peer_id = self.peer_id;
-                if from == peer_id {
-                    self.sig_accepted = true;
-                }
-                self.add_sig(from, s);
-            }
-            _ => {} //panic!("expected signature message")
+    pub fn update_data_step_3(&mut self, from: PeerIdentifier, s: String) {
+        debug!("-------Got peer # {:} Signature", from);
+        if serde_json::from_str::<Signature>(&s).is_err() {
+            let evidence = self.sent(3, from);
+            return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
         }
+        let peer_id = self.peer_id;
+        if from == peer_id {
+            self.sig_accepted = true;
+        }
+        self.add_sig(from, s);
     }
 }
 
@@ -235,9 +313,21 @@ impl EddsaPeer {
     }
     pub fn is_done_step_3(&mut self) -> bool {
         debug!("Checking if last step is done");
-
+        if self.abort.is_some() {
+            return false;
+        }
         if self.sigs.len() == self.capacity as usize {
-            self.finalize().unwrap();
+            if let Err(err) = self.finalize() {
+                // the signature does not verify, the peer whose share does not is blamed
+                match self.invalid_signature_share() {
+                    Some(culprit) => {
+                        let evidence = self.sent(3, culprit);
+                        self.blame(ProtocolAbort::InvalidSignatureShare(culprit), evidence);
+                        return false;
+                    }
+                    None => panic!("{}", err),
+                }
+            }
             return true;
         }
         false
@@ -265,8 +355,8 @@ impl EddsaPeer {
             Ok(json_string) => {
                 //                self.add_commitment(peer_id, json_string.clone());
                 let r = serde_json::to_string(&sign_second_message).expect("couldn't create R");
-                self.commitment_msg = Some(generate_commitment_message_payload(&json_string));
-                self.r_msg = Some(generate_R_message_payload(&r));
+                self.commitment_msg = Some(EddsaMessage::Commitment(json_string).to_payload());
+                self.r_msg = Some(EddsaMessage::RMessage(r).to_payload());
             }
             Err(_) => panic!("Couldn't serialize commitment"),
         }
@@ -281,9 +371,11 @@ impl EddsaPeer {
     /// 2. compute R' = sum(Ri)
     /// 3. sign message
     pub fn step_3(&mut self) {
-        if !self.validate_commitments() {
+        if let Err(culprit) = self.validate_commitments() {
             // commitments sent by others are not valid. exit
-            panic!("Commitments not valid!")
+            let mut evidence = self.sent(1, culprit);
+            evidence.extend(self.sent(2, culprit));
+            return self.blame(ProtocolAbort::InvalidCommitment(culprit), evidence);
         }
         let agg_key = self.aggregate_pks();
         debug!("computed agg_key");
@@ -305,54 +397,31 @@ impl EddsaPeer {
                 // sign
                 let s = Signature::partial_sign(&eph_key.r, key, &k, &agg_key.hash, &r_tot);
                 let sig_string = serde_json::to_string(&s).expect("failed to serialize signature");
-                self.sig_msg = Some(generate_signature_message_payload(&sig_string));
+                self.sig_msg = Some(EddsaMessage::Signature(sig_string).to_payload());
             }
             None => {}
         }
     }
 }
 
-impl EddsaPeer {
-    pub fn resolve_payload_type(message: &MessagePayload) -> MessagePayloadType {
-        let msg_payload = message.clone();
-
-        let split_msg: Vec<&str> = msg_payload.split(RELAY_MESSAGE_DELIMITER).collect();
-        let msg_prefix = split_msg[0];
-        let msg_payload = String::from(split_msg[1].clone());
-        match msg_prefix {
-            pk_prefix if pk_prefix == String::from(PK_MESSAGE_PREFIX) => {
-                return MessagePayloadType::PublicKey(msg_payload);
-            }
-            cmtmnt if cmtmnt == String::from(COMMITMENT_MESSAGE_PREFIX) => {
-                return MessagePayloadType::Commitment(msg_payload);
-            }
-            r if r == String::from(R_KEY_MESSAGE_PREFIX) => {
-                return MessagePayloadType::RMessage(msg_payload);
-            }
-            sig if sig == String::from(SIGNATURE_MESSAGE_PREFIX) => {
-                return MessagePayloadType::Signature(msg_payload);
-            }
-            _ => panic!("Unknown relay message prefix"),
-        }
-    }
-}
-
 impl Peer for EddsaPeer {
-    fn new(capacity: u32, _message: Vec<u8>, index: u32) -> EddsaPeer {
-        debug!("Index is {:?}", index);
-        let data = fs::read_to_string(format!("keys{}", index))
+    fn new(capacity: u32, _message: Vec<u8>, keys: PeerKeys) -> EddsaPeer {
+        let data = keys
+            .load()
             .expect("Unable to load keys, did you run keygen first? ");
-        let (key, _apk, kg_index): (KeyPair, KeyAgg, u32) = serde_json::from_str(&data).unwrap();
+        let (key, apk, kg_index): (KeyPair, KeyAgg, u32) = serde_json::from_slice(&data).unwrap();
         EddsaPeer {
             client_key: { key },
             pks: HashMap::new(),
+            kg_indexes: HashMap::new(),
             commitments: HashMap::new(),
             r_s: HashMap::new(),
             sigs: HashMap::new(),
             capacity,
             message: _message,
             peer_id: 0,
-            agg_key: None,
+            // the aggregated key from key generation, the signature is verified against it
+            agg_key: Some(apk),
             kg_index,
             current_step: 0,
             R_tot: None,
@@ -367,6 +436,10 @@ impl Peer for EddsaPeer {
             commitment_msg: None,
             r_msg: None,
             sig_msg: None,
+
+            received: HashMap::new(),
+            abort: None,
+            evidence: Vec::new(),
         }
     }
 
@@ -378,9 +451,9 @@ impl Peer for EddsaPeer {
         self.peer_id = peer_id;
         let pk = self.client_key.public_key.clone();
 
-        let pk_s = serde_json::to_string(&pk).expect("Failed in serialization");
+        let pk_s = serde_json::to_string(&(self.kg_index, pk)).expect("Failed in serialization");
 
-        self.pk_msg = Some(generate_pk_message_payload(&pk_s));
+        self.pk_msg = Some(EddsaMessage::PublicKey(pk_s).to_payload());
         return self.pk_msg.clone();
     }
 
@@ -398,6 +471,9 @@ impl Peer for EddsaPeer {
 
     fn do_step(&mut self) {
         info!("Current step is: {:}", self.current_step);
+        if self.abort.is_some() {
+            return;
+        }
         if self.is_step_done() {
             // do the next step
             info!("step {:} done!", self.current_step);
@@ -418,13 +494,43 @@ impl Peer for EddsaPeer {
     }
 
     fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        if self.abort.is_some() {
+            return;
+        }
+        let msg = match EddsaMessage::from_payload(&payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Invalid message from peer {}: {}", from, err);
+                return self.blame(ProtocolAbort::MalformedMessage(from), vec![payload]);
+            }
+        };
+        let step = match step_of(&msg) {
+            Some(step) => step,
+            None => return self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload]),
+        };
+        // a message of a previous step is a resend, it must be the one the peer sent then
+        if let Some(received) = self.received.get(&(step, from)) {
+            if *received != payload {
+                let evidence = vec![received.clone(), payload];
+                self.blame(ProtocolAbort::Equivocation(from), evidence);
+            }
+            return;
+        }
+        if step != self.current_step {
+            warn!(
+                "Expected a message of step {} from peer {}, got one of step {}",
+                self.current_step, from, step
+            );
+            return self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload]);
+        }
+        self.received.insert((step, from), payload);
         // update data according to step
-        match self.current_step {
-            0 => self.update_data_step_0(from, payload),
-            1 => self.update_data_step_1(from, payload),
-            2 => self.update_data_step_2(from, payload),
-            3 => self.update_data_step_3(from, payload),
-            _ => panic!("Unsupported step"),
+        match msg {
+            EddsaMessage::PublicKey(pk) => self.update_data_step_0(from, pk),
+            EddsaMessage::Commitment(t) => self.update_data_step_1(from, t),
+            EddsaMessage::RMessage(r) => self.update_data_step_2(from, r),
+            EddsaMessage::Signature(s) => self.update_data_step_3(from, s),
+            _ => unreachable!(),
         }
     }
     /// Does the final calculation of the protocol
@@ -448,15 +554,10 @@ impl Peer for EddsaPeer {
         // verify message with signature
         let apk = self.aggregate_pks();
 
-        let data = fs::read_to_string(format!("keys{}", self.peer_id))
-            .expect("Unable to load keys, did you run keygen first? ");
-        let (_key, orig_apk, _kg_index): (KeyPair, KeyAgg, u32) =
-            serde_json::from_str(&data).unwrap();
-
-        let eight: FE = ECScalar::from(&BigInt::from(8));
-        let eight_inv = eight.invert();
-
-        let orig_apk = orig_apk.apk * &eight_inv;
+        let orig_apk = match self.agg_key {
+            Some(ref orig_apk) => orig_apk.apk * &eight_inv,
+            None => return Err("No aggregated key from key generation"),
+        };
 
         debug!("Aggregated pk {:?}", apk);
         debug!("Orig pk {:?}", orig_apk);
@@ -487,10 +588,91 @@ impl Peer for EddsaPeer {
         self.is_done_step_3()
     }
 
+    fn abort(&self) -> Option<ProtocolAbort> {
+        self.abort.clone()
+    }
+
+    fn abort_evidence(&self) -> Vec<MessagePayload> {
+        self.evidence.clone()
+    }
+
+    /// A message that can not be read, two different messages for a step,
+    /// an R that does not match the commitment or a signature share that does not verify
+    /// prove an abort. Whether a message was sent in the wrong step, or at all, can not be
+    /// told from the messages alone
+    fn verify_abort(&self, abort: &ProtocolAbort, evidence: &[MessagePayload]) -> bool {
+        let messages: Vec<Option<EddsaMessage>> = evidence
+            .iter()
+            .map(|payload| EddsaMessage::from_payload(payload).ok())
+            .collect();
+        match abort {
+            ProtocolAbort::MalformedMessage(_) => messages.iter().any(|msg| match msg {
+                Some(EddsaMessage::PublicKey(pk)) => serde_json::from_str::<(u32, GE)>(pk).is_err(),
+                Some(EddsaMessage::Commitment(t)) => {
+                    serde_json::from_str::<SignFirstMsg>(t).is_err()
+                }
+                Some(EddsaMessage::RMessage(r)) => {
+                    serde_json::from_str::<SignSecondMsg>(r).is_err()
+                }
+                Some(EddsaMessage::Signature(s)) => serde_json::from_str::<Signature>(s).is_err(),
+                _ => true,
+            }),
+            ProtocolAbort::Equivocation(_) => {
+                let steps: Vec<Option<u32>> = messages
+                    .iter()
+                    .map(|msg| msg.as_ref().and_then(step_of))
+                    .collect();
+                (0..evidence.len()).any(|i| {
+                    (i + 1..evidence.len()).any(|j| {
+                        steps[i].is_some() && steps[i] == steps[j] && evidence[i] != evidence[j]
+                    })
+                })
+            }
+            ProtocolAbort::InvalidCommitment(_) => {
+                let eight: FE = ECScalar::from(&BigInt::from(8));
+                let eight_inv = eight.invert();
+                let commitment = messages.iter().find_map(|msg| match msg {
+                    Some(EddsaMessage::Commitment(t)) => {
+                        serde_json::from_str::<SignFirstMsg>(t).ok()
+                    }
+                    _ => None,
+                });
+                let r = messages.iter().find_map(|msg| match msg {
+                    Some(EddsaMessage::RMessage(r)) => {
+                        serde_json::from_str::<SignSecondMsg>(r).ok()
+                    }
+                    _ => None,
+                });
+                match (commitment, r) {
+                    (Some(commitment), Some(r)) => {
+                        !test_com(&(r.R * eight_inv), &r.blind_factor, &commitment.commitment)
+                    }
+                    _ => false,
+                }
+            }
+            ProtocolAbort::InvalidSignatureShare(culprit) => {
+                // the share is checked against the Rs and public keys this peer received
+                messages.iter().any(|msg| match msg {
+                    Some(EddsaMessage::Signature(s)) => match serde_json::from_str(s) {
+                        Ok(share) => {
+                            self.is_done_step_2() && !self.verify_signature_share(*culprit, &share)
+                        }
+                        Err(_) => false,
+                    },
+                    _ => false,
+                })
+            }
+            _ => false,
+        }
+    }
+
     /// get the next item the peer needs to send
     /// depending on the current step and the last message
     /// of the peer that was accepted by the server
     fn get_next_item(&mut self) -> Option<MessagePayload> {
+        if self.abort.is_some() {
+            return None;
+        }
         if self.current_step == 0 || !self.pk_accepted {
             info!("next item is pk: {:?}", self.pk_msg);
             return self.pk_msg.clone();
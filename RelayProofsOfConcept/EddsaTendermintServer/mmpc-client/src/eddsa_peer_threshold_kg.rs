@@ -0,0 +1,685 @@
+/// Threshold (t-of-n) eddsa key generation.
+/// Every peer shares its key with a verifiable secret sharing scheme, so that any t+1
+/// of the n peers can later sign together. The shares are sent encrypted to each peer.
+use std::collections::{BTreeMap, HashMap};
+
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::traits::ECScalar;
+use curv::{BigInt, FE, GE};
+use log::{debug, info, warn};
+use multi_party_eddsa::protocols::aggsig::test_com;
+use multi_party_eddsa::protocols::thresholdsig::{
+    KeyGenBroadcastMessage1, Keys, Parameters, SharedKeys,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::key_store::{key_name, PeerKeys};
+use crate::peer::{Peer, ProtocolAbort};
+use mmpc_server_common::common::*;
+use mmpc_server_common::identity::IdentityKey;
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+
+/// Protocol identifier of threshold eddsa in protocols.json
+pub const PROTOCOL_ID: ProtocolIdentifier = 2;
+
+/// The share of the threshold key a peer keeps after key generation
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyShare {
+    pub keys: Keys,
+    pub shared_keys: SharedKeys,
+    // the schemes all peers shared their keys with, ordered by key generation index
+    pub vss_schemes: Vec<VerifiableSS>,
+    pub threshold: u32,
+    pub share_count: u32,
+    // index of the peer in key generation, starting at 1
+    pub index: u32,
+    // the identity key each peer registered to key generation with, by its index.
+    // A signer claiming an index must sign with the identity of that index
+    #[serde(default)]
+    pub identities: BTreeMap<u32, IdentityKey>,
+}
+
+impl KeyShare {
+    /// Load the key share the peer signs with from its key store
+    pub fn load(key_store: &PeerKeys) -> KeyShare {
+        let data = key_store
+            .load()
+            .expect("Unable to load threshold keys, did you run threshold keygen first? ");
+        let mut key_share: KeyShare = serde_json::from_slice(&data).unwrap();
+        key_share.keys.y_i = from_wire_point(key_share.keys.y_i);
+        key_share.shared_keys.y = from_wire_point(key_share.shared_keys.y);
+        key_share.vss_schemes = key_share
+            .vss_schemes
+            .into_iter()
+            .map(from_wire_vss)
+            .collect();
+        key_share
+    }
+
+    pub fn parameters(&self) -> Parameters {
+        Parameters {
+            threshold: self.threshold as usize,
+            share_count: self.share_count as usize,
+        }
+    }
+}
+
+/// Points are multiplied by the cofactor when deserialized
+pub fn from_wire_point(point: GE) -> GE {
+    let eight: FE = ECScalar::from(&BigInt::from(8));
+    point * &eight.invert()
+}
+
+pub fn from_wire_vss(mut vss_scheme: VerifiableSS) -> VerifiableSS {
+    vss_scheme.commitments = vss_scheme
+        .commitments
+        .into_iter()
+        .map(from_wire_point)
+        .collect();
+    vss_scheme
+}
+
+// The step a message of the protocol is sent in
+fn step_of(msg: &EddsaMessage) -> Option<u32> {
+    match msg {
+        EddsaMessage::Commitment(_) => Some(0),
+        EddsaMessage::Decommitment(_) => Some(1),
+        EddsaMessage::VssScheme(_) => Some(2),
+        _ => None,
+    }
+}
+
+pub struct EddsaPeer {
+    // this peers identifier in this session
+    pub peer_id: PeerIdentifier,
+    // # of participants
+    pub capacity: u32,
+    // any threshold + 1 of the participants can sign, set before the protocol starts
+    pub threshold: Option<u32>,
+
+    pub current_step: u32,
+    // is peer done with all calculations
+    pub is_done: bool,
+
+    // eddsa data
+    pub keys: Option<Keys>,
+    pub commitments: HashMap<PeerIdentifier, KeyGenBroadcastMessage1>,
+    pub decommitments: HashMap<PeerIdentifier, (GE, BigInt)>,
+    pub vss_schemes: HashMap<PeerIdentifier, VerifiableSS>,
+    pub secret_shares: HashMap<PeerIdentifier, FE>,
+
+    // indicators for which of this peers messages were accepted
+    pub commitment_accepted: bool,
+    pub decommitment_accepted: bool,
+    pub vss_accepted: bool,
+
+    // messages this peer generates
+    pub commitment_msg: Option<MessagePayload>,
+    pub decommitment_msg: Option<MessagePayload>,
+    pub vss_msg: Option<MessagePayload>,
+    // shares of this peers key, sent only to the peer each share belongs to
+    pub share_msgs: BTreeMap<PeerIdentifier, MessagePayload>,
+
+    // the key share is saved to the key store of the peer
+    pub key_store: PeerKeys,
+    // the identity key of each peer, pinned in the key share by its key generation index
+    pub identities: BTreeMap<PeerIdentifier, IdentityKey>,
+
+    // the message of each peer in each step, a peer sending another one equivocates
+    pub received: HashMap<(u32, PeerIdentifier), MessagePayload>,
+    // the secret share each peer sent to this peer, a peer sending another one equivocates
+    pub received_shares: HashMap<PeerIdentifier, MessagePayload>,
+    // the misbehavior this peer aborted on, it sends nothing more once set
+    pub abort: Option<ProtocolAbort>,
+    // the messages of the culprit that prove the abort
+    pub evidence: Vec<MessagePayload>,
+    // the error this peer failed on saving the key share, it sends nothing more once set
+    pub failure: Option<String>,
+}
+
+impl EddsaPeer {
+    fn threshold(&self) -> u32 {
+        self.threshold
+            .expect("The threshold is checked on the zero step")
+    }
+
+    fn parameters(&self) -> Parameters {
+        Parameters {
+            threshold: self.threshold() as usize,
+            share_count: self.capacity as usize,
+        }
+    }
+
+    // key generation indexes of all peers, which are the peer identifiers
+    fn parties(&self) -> Vec<PeerIdentifier> {
+        (1..=self.capacity).collect()
+    }
+
+    fn y_vec(&self) -> Vec<GE> {
+        self.parties()
+            .iter()
+            .map(|peer| self.decommitments[peer].0.clone())
+            .collect()
+    }
+
+    fn blame(&mut self, abort: ProtocolAbort, evidence: Vec<MessagePayload>) {
+        warn!("Aborting: {}", abort);
+        if self.abort.is_none() {
+            self.abort = Some(abort);
+            self.evidence = evidence;
+        }
+    }
+
+    // The message the peer sent in the step, as evidence against it
+    fn sent(&self, step: u32, from: PeerIdentifier) -> Vec<MessagePayload> {
+        self.received
+            .get(&(step, from))
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+
+    // Returns the first peer whose public key does not match its commitment
+    fn invalid_commitment(&self) -> Option<PeerIdentifier> {
+        self.parties().into_iter().find(|peer| {
+            let (y_i, blind) = &self.decommitments[peer];
+            !test_com(y_i, blind, &self.commitments[peer].com)
+        })
+    }
+
+    // Returns the first peer whose secret share to this peer does not match its vss scheme,
+    // or whose scheme does not share the public key it revealed
+    fn invalid_secret_share(&self) -> Option<PeerIdentifier> {
+        self.parties().into_iter().find(|peer| {
+            let vss_scheme = &self.vss_schemes[peer];
+            vss_scheme
+                .validate_share(&self.secret_shares[peer], self.peer_id as usize)
+                .is_err()
+                || vss_scheme.commitments.first() != Some(&self.decommitments[peer].0)
+        })
+    }
+}
+
+impl EddsaPeer {
+    /// data updaters for each step
+    pub fn update_data_step_0(&mut self, from: PeerIdentifier, c: String) {
+        let commitment: KeyGenBroadcastMessage1 = match serde_json::from_str(&c) {
+            Ok(commitment) => commitment,
+            Err(_) => {
+                warn!("Could not deserialize commitment of peer {}", from);
+                let evidence = self.sent(0, from);
+                return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
+            }
+        };
+        info!("-------Got peer # {:} commitment!", from);
+        if from == self.peer_id {
+            self.commitment_accepted = true;
+        }
+        self.commitments.insert(from, commitment);
+    }
+
+    pub fn update_data_step_1(&mut self, from: PeerIdentifier, d: String) {
+        let (y_i, blind): (GE, BigInt) = match serde_json::from_str(&d) {
+            Ok(decommitment) => decommitment,
+            Err(_) => {
+                warn!("Could not deserialize decommitment of peer {}", from);
+                let evidence = self.sent(1, from);
+                return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
+            }
+        };
+        info!("-------Got peer # {:} decommitment!", from);
+        if from == self.peer_id {
+            self.decommitment_accepted = true;
+        }
+        self.decommitments
+            .insert(from, (from_wire_point(y_i), blind));
+    }
+
+    pub fn update_data_step_2(&mut self, from: PeerIdentifier, v: String) {
+        let vss_scheme: VerifiableSS = match serde_json::from_str(&v) {
+            Ok(vss_scheme) => vss_scheme,
+            Err(_) => {
+                warn!("Could not deserialize vss scheme of peer {}", from);
+                let evidence = self.sent(2, from);
+                return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
+            }
+        };
+        info!("-------Got peer # {:} vss scheme!", from);
+        if from == self.peer_id {
+            self.vss_accepted = true;
+        }
+        self.vss_schemes.insert(from, from_wire_vss(vss_scheme));
+    }
+}
+
+impl EddsaPeer {
+    fn is_step_done(&mut self) -> bool {
+        match self.current_step {
+            0 => return self.is_done_step_0(),
+            1 => return self.is_done_step_1(),
+            2 => return self.is_done_step_2(),
+            _ => panic!("Unsupported step"),
+        }
+    }
+    pub fn is_done_step_0(&self) -> bool {
+        self.commitments.len() == self.capacity as usize
+    }
+    pub fn is_done_step_1(&self) -> bool {
+        self.decommitments.len() == self.capacity as usize
+    }
+    pub fn is_done_step_2(&mut self) -> bool {
+        if self.abort.is_some() {
+            return false;
+        }
+        if self.vss_schemes.len() == self.capacity as usize
+            && self.secret_shares.len() == self.capacity as usize
+        {
+            if let Some(culprit) = self.invalid_secret_share() {
+                let evidence = self.sent(2, culprit);
+                self.blame(ProtocolAbort::InvalidSecretShare(culprit), evidence);
+                return false;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+impl EddsaPeer {
+    /// steps - in each step the client does a calculation on its
+    /// data, and updates the data holder with the new data
+
+    /// step 1 - reveal the public key committed to
+    pub fn step_1(&mut self) {
+        debug!("Step 1 - no calculations required. Decommitment should be ready");
+    }
+
+    /// step 2 - after validating all commitments, share the key of this peer
+    pub fn step_2(&mut self) {
+        if let Some(culprit) = self.invalid_commitment() {
+            let mut evidence = self.sent(0, culprit);
+            evidence.extend(self.sent(1, culprit));
+            return self.blame(ProtocolAbort::InvalidCommitment(culprit), evidence);
+        }
+        let parties: Vec<usize> = self.parties().iter().map(|p| *p as usize).collect();
+        let blind_vec = self
+            .parties()
+            .iter()
+            .map(|peer| self.decommitments[peer].1.clone())
+            .collect();
+        let bc1_vec = self
+            .parties()
+            .iter()
+            .map(|peer| self.commitments[peer].clone())
+            .collect();
+        let keys = self
+            .keys
+            .as_ref()
+            .expect("Keys are created on the zero step");
+        let (vss_scheme, secret_shares, _index) = keys
+            .phase1_verify_com_phase2_distribute(
+                &self.parameters(),
+                &blind_vec,
+                &self.y_vec(),
+                &bc1_vec,
+                &parties,
+            )
+            .expect("Commitments were validated");
+        let vss_s = serde_json::to_string(&vss_scheme).expect("Failed in serialization");
+        self.vss_msg = Some(EddsaMessage::VssScheme(vss_s).to_payload());
+        for (peer, share) in self.parties().into_iter().zip(secret_shares.into_iter()) {
+            if peer == self.peer_id {
+                self.secret_shares.insert(peer, share);
+            } else {
+                let share_s = serde_json::to_string(&share).expect("Failed in serialization");
+                self.share_msgs
+                    .insert(peer, EddsaMessage::SecretShare(share_s).to_payload());
+            }
+        }
+    }
+
+    // the step of the next item the peer needs to send
+    fn next_item_step(&self) -> Option<u32> {
+        if self.current_step == 0 || !self.commitment_accepted {
+            return Some(0);
+        }
+        if self.current_step == 1 || !self.decommitment_accepted {
+            return Some(1);
+        }
+        if self.current_step == 2 || !self.vss_accepted {
+            return Some(2);
+        }
+        None
+    }
+}
+
+impl Peer for EddsaPeer {
+    fn new(capacity: u32, _message: Vec<u8>, key_store: PeerKeys) -> EddsaPeer {
+        EddsaPeer {
+            peer_id: 0,
+            capacity,
+            threshold: None,
+            current_step: 0,
+            is_done: false,
+            keys: None,
+            commitments: HashMap::new(),
+            decommitments: HashMap::new(),
+            vss_schemes: HashMap::new(),
+            secret_shares: HashMap::new(),
+            commitment_accepted: false,
+            decommitment_accepted: false,
+            vss_accepted: false,
+            commitment_msg: None,
+            decommitment_msg: None,
+            vss_msg: None,
+            share_msgs: BTreeMap::new(),
+            key_store,
+            identities: BTreeMap::new(),
+
+            received: HashMap::new(),
+            received_shares: HashMap::new(),
+            abort: None,
+            evidence: Vec::new(),
+            failure: None,
+        }
+    }
+
+    fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+        self.peer_id = peer_id;
+    }
+
+    fn set_threshold(&mut self, threshold: u32) {
+        self.threshold = Some(threshold);
+    }
+
+    /// step 0 - create the key of this peer and commit to its public key
+    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+        self.peer_id = peer_id;
+        // a t-of-n key needs more than t parties
+        match self.threshold {
+            Some(threshold) if threshold < self.capacity => {}
+            _ => {
+                let err = format!("No threshold below {} parties is set", self.capacity);
+                warn!("{}", err);
+                self.failure = Some(err);
+                return None;
+            }
+        }
+        let keys = Keys::phase1_create(peer_id as usize);
+        let (bc1, blind) = keys.phase1_broadcast();
+        let bc1_s = serde_json::to_string(&bc1).expect("Failed in serialization");
+        let decommitment_s =
+            serde_json::to_string(&(keys.y_i.clone(), blind)).expect("Failed in serialization");
+        self.keys = Some(keys);
+        self.commitment_msg = Some(EddsaMessage::Commitment(bc1_s).to_payload());
+        self.decommitment_msg = Some(EddsaMessage::Decommitment(decommitment_s).to_payload());
+        return self.commitment_msg.clone();
+    }
+
+    fn current_step(&self) -> u32 {
+        self.current_step
+    }
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn peer_id(&self) -> PeerIdentifier {
+        self.peer_id
+    }
+
+    fn do_step(&mut self) {
+        info!("Current step is: {:}", self.current_step);
+        if self.abort.is_some() || self.failure.is_some() || self.is_done {
+            return;
+        }
+        if self.is_step_done() {
+            // do the next step
+            info!("step {:} done!", self.current_step);
+            self.current_step += 1;
+            match self.current_step {
+                1 => self.step_1(),
+                2 => self.step_2(),
+                3 => {
+                    // the peer finalizes once, when all the messages of the last step verified
+                    if let Err(err) = self.finalize() {
+                        warn!("Unable to finalize: {}", err);
+                        self.failure = Some(err.to_string());
+                        return;
+                    }
+                    info!("----------\nDone.\n----------");
+                    self.is_done = true;
+                }
+                _ => panic!("Unsupported step"),
+            }
+        } else {
+            info!("step not done");
+        }
+    }
+
+    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        if self.abort.is_some() {
+            return;
+        }
+        let msg = match EddsaMessage::from_payload(&payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Invalid message from peer {}: {}", from, err);
+                return self.blame(ProtocolAbort::MalformedMessage(from), vec![payload]);
+            }
+        };
+        let step = match step_of(&msg) {
+            Some(step) => step,
+            None => return self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload]),
+        };
+        // a message of a previous step is a resend, it must be the one the peer sent then
+        if let Some(received) = self.received.get(&(step, from)) {
+            if *received != payload {
+                let evidence = vec![received.clone(), payload];
+                self.blame(ProtocolAbort::Equivocation(from), evidence);
+            }
+            return;
+        }
+        if step != self.current_step {
+            warn!(
+                "Expected a message of step {} from peer {}, got one of step {}",
+                self.current_step, from, step
+            );
+            return self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload]);
+        }
+        self.received.insert((step, from), payload);
+        // update data according to step
+        match msg {
+            EddsaMessage::Commitment(c) => self.update_data_step_0(from, c),
+            EddsaMessage::Decommitment(d) => self.update_data_step_1(from, d),
+            EddsaMessage::VssScheme(v) => self.update_data_step_2(from, v),
+            _ => unreachable!(),
+        }
+    }
+
+    fn update_private_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        if self.abort.is_some() {
+            return;
+        }
+        if self.current_step != 2 {
+            warn!("Unexpected private message from peer {}", from);
+            return;
+        }
+        // a resent share must be the one the peer sent before, the first share is kept
+        if let Some(received) = self.received_shares.get(&from) {
+            if *received != payload {
+                let evidence = vec![received.clone(), payload];
+                self.blame(ProtocolAbort::Equivocation(from), evidence);
+            }
+            return;
+        }
+        self.received_shares.insert(from, payload.clone());
+        match EddsaMessage::from_payload(&payload) {
+            Ok(EddsaMessage::SecretShare(s)) => match serde_json::from_str::<FE>(&s) {
+                Ok(share) => {
+                    info!("-------Got peer # {:} secret share!", from);
+                    self.secret_shares.insert(from, share);
+                }
+                Err(_) => {
+                    warn!("Could not deserialize secret share of peer {}", from);
+                    self.blame(ProtocolAbort::MalformedMessage(from), vec![payload])
+                }
+            },
+            Ok(msg) => {
+                warn!("Expected secret share from peer {}, got {:?}", from, msg);
+                self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload])
+            }
+            Err(err) => {
+                warn!("Invalid message from peer {}: {}", from, err);
+                self.blame(ProtocolAbort::MalformedMessage(from), vec![payload])
+            }
+        }
+    }
+
+    fn set_peer_identity(&mut self, peer_id: PeerIdentifier, identity: IdentityKey) {
+        self.identities.insert(peer_id, identity);
+    }
+
+    /// Does the final calculation of the protocol
+    /// in this case:
+    ///     verifying the shares of all peers
+    ///     and saving the key share of this peer
+    fn finalize(&mut self) -> Result<(), &'static str> {
+        let parties = self.parties();
+        let secret_shares_vec = parties
+            .iter()
+            .map(|peer| self.secret_shares[peer].clone())
+            .collect();
+        let vss_scheme_vec: Vec<VerifiableSS> = parties
+            .iter()
+            .map(|peer| self.vss_schemes[peer].clone())
+            .collect();
+        let keys = self.keys.clone().ok_or("Keys were not created")?;
+        let shared_keys = keys
+            .phase2_verify_vss_construct_keypair(
+                &self.parameters(),
+                &self.y_vec(),
+                &secret_shares_vec,
+                &vss_scheme_vec,
+                &(self.peer_id as usize),
+            )
+            .map_err(|_| "Invalid secret shares")?;
+        let key_share = KeyShare {
+            keys,
+            shared_keys,
+            vss_schemes: vss_scheme_vec,
+            threshold: self.threshold(),
+            share_count: self.capacity,
+            index: self.peer_id,
+            // the key generation index of a peer is its identifier in the session
+            identities: self.identities.clone(),
+        };
+        let key_share_json = serde_json::to_string(&key_share).unwrap();
+        self.key_store
+            .store
+            .save(
+                &key_name(&key_share.shared_keys.y),
+                key_share_json.as_bytes(),
+            )
+            .map_err(|_| "Failed to save key share")
+    }
+
+    /// check that the protocol is done
+    /// and that this peer can finalize its calculations
+    fn is_done(&mut self) -> bool {
+        self.is_done
+    }
+
+    fn abort(&self) -> Option<ProtocolAbort> {
+        self.abort.clone()
+    }
+
+    fn abort_evidence(&self) -> Vec<MessagePayload> {
+        self.evidence.clone()
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.clone()
+    }
+
+    /// get the next item the peer needs to send
+    /// depending on the current step and the last message
+    /// of the peer that was accepted by the server
+    fn get_next_item(&mut self) -> Option<MessagePayload> {
+        if self.abort.is_some() {
+            return None;
+        }
+        match self.next_item_step() {
+            Some(0) => {
+                info!("next item is commitment: {:?}", self.commitment_msg);
+                self.commitment_msg.clone()
+            }
+            Some(1) => {
+                info!("next item is decommitment: {:?}", self.decommitment_msg);
+                self.decommitment_msg.clone()
+            }
+            Some(2) => {
+                info!("next item is vss scheme: {:?}", self.vss_msg);
+                self.vss_msg.clone()
+            }
+            _ => None,
+        }
+    }
+
+    /// the secret shares are sent along with the vss scheme
+    fn get_next_private_items(&mut self) -> BTreeMap<PeerIdentifier, MessagePayload> {
+        if self.abort.is_some() {
+            return BTreeMap::new();
+        }
+        match self.next_item_step() {
+            Some(2) => self.share_msgs.clone(),
+            _ => BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EddsaPeer;
+    use crate::key_store::{MemoryKeyStore, PeerKeys};
+    use crate::peer::{Peer, ProtocolAbort};
+    use mmpc_server_common::common::EddsaMessage;
+
+    fn peer(capacity: u32) -> EddsaPeer {
+        Peer::new(
+            capacity,
+            Vec::new(),
+            PeerKeys::new(Box::new(MemoryKeyStore::new()), None),
+        )
+    }
+
+    #[test]
+    fn test_threshold_not_set() {
+        // the peer fails rather than generate a key no t+1 of the parties could sign with
+        let mut unset = peer(3);
+        assert_eq!(unset.zero_step(1), None);
+        assert!(unset.failure().is_some());
+
+        let mut too_high = peer(3);
+        too_high.set_threshold(3);
+        assert_eq!(too_high.zero_step(1), None);
+        assert!(too_high.failure().is_some());
+    }
+
+    #[test]
+    fn test_secret_share_equivocation() {
+        let mut peer = peer(3);
+        peer.current_step = 2;
+        let share = |value: &str| EddsaMessage::SecretShare(value.to_string()).to_payload();
+
+        // a resend of the share the peer sent is no equivocation
+        peer.received_shares.insert(2, share("first"));
+        peer.update_private_data(2, share("first"));
+        assert_eq!(peer.abort, None);
+
+        // another share of the same peer is, with both shares as evidence
+        peer.update_private_data(2, share("second"));
+        assert_eq!(peer.abort, Some(ProtocolAbort::Equivocation(2)));
+        assert_eq!(peer.evidence, vec![share("first"), share("second")]);
+    }
+}
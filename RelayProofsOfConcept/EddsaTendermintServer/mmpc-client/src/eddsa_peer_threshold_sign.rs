@@ -0,0 +1,744 @@
+/// Threshold (t-of-n) eddsa signing, by any t+1 of the peers that generated the key.
+/// The signers share an ephemeral key just like the key was shared in key generation,
+/// and the local signatures of all signers are combined to a single signature.
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use curv::arithmetic::traits::Converter;
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::traits::ECPoint;
+use curv::elliptic::curves::traits::ECScalar;
+use curv::{BigInt, FE, GE};
+use log::{debug, info, warn};
+use multi_party_eddsa::protocols::aggsig::test_com;
+use multi_party_eddsa::protocols::thresholdsig::{
+    EphemeralKey, EphemeralSharedKeys, KeyGenBroadcastMessage1, LocalSig, Parameters, Signature,
+};
+
+use crate::eddsa_peer_threshold_kg::{from_wire_point, from_wire_vss, KeyShare};
+use crate::key_store::PeerKeys;
+use crate::peer::{Peer, ProtocolAbort};
+use mmpc_server_common::common::*;
+use mmpc_server_common::identity::IdentityKey;
+use mmpc_server_common::{MessagePayload, PeerIdentifier};
+
+// The step a message of the protocol is sent in
+fn step_of(msg: &EddsaMessage) -> Option<u32> {
+    match msg {
+        EddsaMessage::Commitment(_) => Some(0),
+        EddsaMessage::Decommitment(_) => Some(1),
+        EddsaMessage::VssScheme(_) => Some(2),
+        EddsaMessage::LocalSig(_) => Some(3),
+        _ => None,
+    }
+}
+
+pub struct EddsaPeer {
+    // this peers identifier in this session
+    pub peer_id: PeerIdentifier,
+    // # of signers
+    pub capacity: u32,
+
+    pub current_step: u32,
+    // is peer done with all calculations
+    pub is_done: bool,
+
+    // eddsa data
+    pub key_share: KeyShare,
+    // message to sign
+    pub message: Vec<u8>,
+    pub ephemeral_key: Option<EphemeralKey>,
+    pub ephemeral_shared_keys: Option<EphemeralSharedKeys>,
+    // key generation index of each signer, sent along with its commitment
+    pub kg_indexes: HashMap<PeerIdentifier, u32>,
+    pub commitments: HashMap<PeerIdentifier, KeyGenBroadcastMessage1>,
+    pub decommitments: HashMap<PeerIdentifier, (GE, BigInt)>,
+    pub vss_schemes: HashMap<PeerIdentifier, VerifiableSS>,
+    pub secret_shares: HashMap<PeerIdentifier, FE>,
+    pub local_sigs: HashMap<PeerIdentifier, LocalSig>,
+
+    // indicators for which of this peers messages were accepted
+    pub commitment_accepted: bool,
+    pub decommitment_accepted: bool,
+    pub vss_accepted: bool,
+    pub sig_accepted: bool,
+
+    // messages this peer generates
+    pub commitment_msg: Option<MessagePayload>,
+    pub decommitment_msg: Option<MessagePayload>,
+    pub vss_msg: Option<MessagePayload>,
+    pub sig_msg: Option<MessagePayload>,
+    // shares of the ephemeral key, sent only to the peer each share belongs to
+    pub share_msgs: BTreeMap<PeerIdentifier, MessagePayload>,
+    // the identity key each signer registered to the session with
+    pub identities: BTreeMap<PeerIdentifier, IdentityKey>,
+
+    // the message of each peer in each step, a peer sending another one equivocates
+    pub received: HashMap<(u32, PeerIdentifier), MessagePayload>,
+    // the secret share each peer sent to this peer, a peer sending another one equivocates
+    pub received_shares: HashMap<PeerIdentifier, MessagePayload>,
+    // the misbehavior this peer aborted on, it sends nothing more once set
+    pub abort: Option<ProtocolAbort>,
+    // the messages of the culprit that prove the abort
+    pub evidence: Vec<MessagePayload>,
+    // the error this peer failed on saving the signature, it sends nothing more once set
+    pub failure: Option<String>,
+}
+
+impl EddsaPeer {
+    /// inner calculations & data manipulations
+    fn parameters(&self) -> Parameters {
+        Parameters {
+            threshold: self.key_share.threshold as usize,
+            share_count: self.capacity as usize,
+        }
+    }
+
+    // the signers ordered by their key generation index
+    fn signers(&self) -> Vec<PeerIdentifier> {
+        let mut signers: Vec<PeerIdentifier> = self.kg_indexes.keys().cloned().collect();
+        signers.sort_by_key(|peer| self.kg_indexes[peer]);
+        signers
+    }
+
+    // key generation indexes of the signers, in the order of the signers
+    fn signer_indexes(&self) -> Vec<usize> {
+        self.signers()
+            .iter()
+            .map(|peer| self.kg_indexes[peer] as usize)
+            .collect()
+    }
+
+    #[allow(non_snake_case)]
+    fn R_vec(&self) -> Vec<GE> {
+        self.signers()
+            .iter()
+            .map(|peer| self.decommitments[peer].0.clone())
+            .collect()
+    }
+
+    fn blame(&mut self, abort: ProtocolAbort, evidence: Vec<MessagePayload>) {
+        warn!("Aborting: {}", abort);
+        if self.abort.is_none() {
+            self.abort = Some(abort);
+            self.evidence = evidence;
+        }
+    }
+
+    // The message the peer sent in the step, as evidence against it
+    fn sent(&self, step: u32, from: PeerIdentifier) -> Vec<MessagePayload> {
+        self.received
+            .get(&(step, from))
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+
+    // Returns true if the key generation index belongs to the peer. An index is pinned
+    // to the identity that generated the key with it, when the transport knows identities
+    fn owns_kg_index(&self, from: PeerIdentifier, kg_index: u32) -> bool {
+        if kg_index == 0 || kg_index > self.key_share.share_count {
+            return false;
+        }
+        match (
+            self.key_share.identities.get(&kg_index),
+            self.identities.get(&from),
+        ) {
+            (Some(pinned), Some(identity)) => pinned == identity,
+            _ => true,
+        }
+    }
+
+    // Returns the first signer whose ephemeral public key does not match its commitment
+    fn invalid_commitment(&self) -> Option<PeerIdentifier> {
+        self.signers().into_iter().find(|peer| {
+            #[allow(non_snake_case)]
+            let (R_i, blind) = &self.decommitments[peer];
+            !test_com(R_i, blind, &self.commitments[peer].com)
+        })
+    }
+
+    // Returns the first signer whose share of its ephemeral key to this peer does not match
+    // its vss scheme, or whose scheme does not share the ephemeral public key it revealed
+    fn invalid_secret_share(&self) -> Option<PeerIdentifier> {
+        let index = self.key_share.index as usize;
+        self.signers().into_iter().find(|peer| {
+            let vss_scheme = &self.vss_schemes[peer];
+            vss_scheme
+                .validate_share(&self.secret_shares[peer], index)
+                .is_err()
+                || vss_scheme.commitments.first() != Some(&self.decommitments[peer].0)
+        })
+    }
+
+    // Returns the first signer whose local signature does not verify.
+    // Local signatures are verified against schemes combined with the k of the first
+    // threshold + 1 of them, so the signature of this peer fills those places
+    // and the local signature of each signer is verified on its own after them
+    fn invalid_local_sig(&self) -> Option<PeerIdentifier> {
+        let own_sig = self.local_sigs.get(&self.peer_id)?;
+        let own_index = (self.key_share.index - 1) as usize;
+        let padding = self.key_share.threshold as usize + 1;
+        let signers = self.signers();
+        let vss_ephemeral_keys: Vec<VerifiableSS> = signers
+            .iter()
+            .map(|peer| self.vss_schemes[peer].clone())
+            .collect();
+        signers
+            .into_iter()
+            .zip(self.signer_indexes())
+            .find(|(peer, kg_index)| {
+                let mut local_sig_vec = vec![own_sig.clone(); padding];
+                local_sig_vec.push(self.local_sigs[peer].clone());
+                let mut parties_index_vec = vec![own_index; padding];
+                parties_index_vec.push(kg_index - 1);
+                LocalSig::verify_local_sigs(
+                    &local_sig_vec,
+                    &parties_index_vec,
+                    &self.key_share.vss_schemes,
+                    &vss_ephemeral_keys,
+                )
+                .is_err()
+            })
+            .map(|(peer, _)| peer)
+    }
+}
+
+impl EddsaPeer {
+    /// data updaters for each step
+    pub fn update_data_step_0(&mut self, from: PeerIdentifier, c: String) {
+        let (kg_index, commitment): (u32, KeyGenBroadcastMessage1) = match serde_json::from_str(&c)
+        {
+            Ok(commitment) => commitment,
+            Err(_) => {
+                warn!("Could not deserialize commitment of peer {}", from);
+                let evidence = self.sent(0, from);
+                return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
+            }
+        };
+        // every key generation index belongs to exactly one of the signers
+        if !self.owns_kg_index(from, kg_index) {
+            warn!(
+                "Peer {} sent key generation index {} it does not have",
+                from, kg_index
+            );
+            let evidence = self.sent(0, from);
+            return self.blame(ProtocolAbort::InvalidKeyIndex(from), evidence);
+        }
+        if self
+            .kg_indexes
+            .iter()
+            .any(|(peer, index)| *peer != from && *index == kg_index)
+        {
+            warn!(
+                "Peer {} sent key generation index {} of another peer",
+                from, kg_index
+            );
+            let evidence = self.sent(0, from);
+            return self.blame(ProtocolAbort::InvalidKeyIndex(from), evidence);
+        }
+        info!("-------Got peer # {:} commitment!", from);
+        if from == self.peer_id {
+            self.commitment_accepted = true;
+        }
+        self.kg_indexes.insert(from, kg_index);
+        self.commitments.insert(from, commitment);
+    }
+
+    pub fn update_data_step_1(&mut self, from: PeerIdentifier, d: String) {
+        #[allow(non_snake_case)]
+        let (R_i, blind): (GE, BigInt) = match serde_json::from_str(&d) {
+            Ok(decommitment) => decommitment,
+            Err(_) => {
+                warn!("Could not deserialize decommitment of peer {}", from);
+                let evidence = self.sent(1, from);
+                return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
+            }
+        };
+        info!("-------Got peer # {:} decommitment!", from);
+        if from == self.peer_id {
+            self.decommitment_accepted = true;
+        }
+        self.decommitments
+            .insert(from, (from_wire_point(R_i), blind));
+    }
+
+    pub fn update_data_step_2(&mut self, from: PeerIdentifier, v: String) {
+        let vss_scheme: VerifiableSS = match serde_json::from_str(&v) {
+            Ok(vss_scheme) => vss_scheme,
+            Err(_) => {
+                warn!("Could not deserialize vss scheme of peer {}", from);
+                let evidence = self.sent(2, from);
+                return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
+            }
+        };
+        info!("-------Got peer # {:} vss scheme!", from);
+        if from == self.peer_id {
+            self.vss_accepted = true;
+        }
+        self.vss_schemes.insert(from, from_wire_vss(vss_scheme));
+    }
+
+    pub fn update_data_step_3(&mut self, from: PeerIdentifier, s: String) {
+        let local_sig: LocalSig = match serde_json::from_str(&s) {
+            Ok(local_sig) => local_sig,
+            Err(_) => {
+                warn!("Could not deserialize local signature of peer {}", from);
+                let evidence = self.sent(3, from);
+                return self.blame(ProtocolAbort::MalformedMessage(from), evidence);
+            }
+        };
+        debug!("-------Got peer # {:} local signature", from);
+        if from == self.peer_id {
+            self.sig_accepted = true;
+        }
+        self.local_sigs.insert(from, local_sig);
+    }
+}
+
+impl EddsaPeer {
+    fn is_step_done(&mut self) -> bool {
+        match self.current_step {
+            0 => return self.is_done_step_0(),
+            1 => return self.is_done_step_1(),
+            2 => return self.is_done_step_2(),
+            3 => return self.is_done_step_3(),
+            _ => panic!("Unsupported step"),
+        }
+    }
+    pub fn is_done_step_0(&self) -> bool {
+        self.commitments.len() == self.capacity as usize
+    }
+    pub fn is_done_step_1(&self) -> bool {
+        self.decommitments.len() == self.capacity as usize
+    }
+    pub fn is_done_step_2(&self) -> bool {
+        self.vss_schemes.len() == self.capacity as usize
+            && self.secret_shares.len() == self.capacity as usize
+    }
+    pub fn is_done_step_3(&mut self) -> bool {
+        debug!("Checking if last step is done");
+        if self.abort.is_some() {
+            return false;
+        }
+        if self.local_sigs.len() == self.capacity as usize {
+            if let Some(culprit) = self.invalid_local_sig() {
+                let evidence = self.sent(3, culprit);
+                self.blame(ProtocolAbort::InvalidSignatureShare(culprit), evidence);
+                return false;
+            }
+            return true;
+        }
+        false
+    }
+}
+
+impl EddsaPeer {
+    /// steps - in each step the client does a calculation on its
+    /// data, and updates the data holder with the new data
+
+    /// step 1 - reveal the ephemeral public key committed to
+    pub fn step_1(&mut self) {
+        debug!("Step 1 - no calculations required. Decommitment should be ready");
+    }
+
+    /// step 2 - after validating all commitments, share the ephemeral key of this peer
+    pub fn step_2(&mut self) {
+        if let Some(culprit) = self.invalid_commitment() {
+            let mut evidence = self.sent(0, culprit);
+            evidence.extend(self.sent(1, culprit));
+            return self.blame(ProtocolAbort::InvalidCommitment(culprit), evidence);
+        }
+        let signers = self.signers();
+        let blind_vec = signers
+            .iter()
+            .map(|peer| self.decommitments[peer].1.clone())
+            .collect();
+        let bc1_vec = signers
+            .iter()
+            .map(|peer| self.commitments[peer].clone())
+            .collect();
+        let ephemeral_key = self
+            .ephemeral_key
+            .as_ref()
+            .expect("Ephemeral key is created on the zero step");
+        let (vss_scheme, secret_shares, _index) = ephemeral_key
+            .phase1_verify_com_phase2_distribute(
+                &self.parameters(),
+                &blind_vec,
+                &self.R_vec(),
+                &bc1_vec,
+                &self.signer_indexes(),
+            )
+            .expect("Commitments were validated");
+        let vss_s = serde_json::to_string(&vss_scheme).expect("Failed in serialization");
+        self.vss_msg = Some(EddsaMessage::VssScheme(vss_s).to_payload());
+        for (peer, share) in signers.into_iter().zip(secret_shares.into_iter()) {
+            if peer == self.peer_id {
+                self.secret_shares.insert(peer, share);
+            } else {
+                let share_s = serde_json::to_string(&share).expect("Failed in serialization");
+                self.share_msgs
+                    .insert(peer, EddsaMessage::SecretShare(share_s).to_payload());
+            }
+        }
+    }
+
+    /// step 3 - after validating the shares of the ephemeral key, sign the message locally
+    pub fn step_3(&mut self) {
+        if let Some(culprit) = self.invalid_secret_share() {
+            let evidence = self.sent(2, culprit);
+            return self.blame(ProtocolAbort::InvalidSecretShare(culprit), evidence);
+        }
+        let signers = self.signers();
+        let secret_shares_vec = signers
+            .iter()
+            .map(|peer| self.secret_shares[peer].clone())
+            .collect();
+        let vss_scheme_vec = signers
+            .iter()
+            .map(|peer| self.vss_schemes[peer].clone())
+            .collect();
+        let ephemeral_key = self
+            .ephemeral_key
+            .as_ref()
+            .expect("Ephemeral key is created on the zero step");
+        let ephemeral_shared_keys = ephemeral_key
+            .phase2_verify_vss_construct_keypair(
+                &self.parameters(),
+                &self.R_vec(),
+                &secret_shares_vec,
+                &vss_scheme_vec,
+                &(self.key_share.index as usize),
+            )
+            .expect("Ephemeral key shares were validated");
+        let local_sig = LocalSig::compute(
+            &self.message[..],
+            &ephemeral_shared_keys,
+            &self.key_share.shared_keys,
+        );
+        let sig_s = serde_json::to_string(&local_sig).expect("failed to serialize signature");
+        self.sig_msg = Some(EddsaMessage::LocalSig(sig_s).to_payload());
+        self.ephemeral_shared_keys = Some(ephemeral_shared_keys);
+    }
+
+    // the step of the next item the peer needs to send
+    fn next_item_step(&self) -> Option<u32> {
+        if self.current_step == 0 || !self.commitment_accepted {
+            return Some(0);
+        }
+        if self.current_step == 1 || !self.decommitment_accepted {
+            return Some(1);
+        }
+        if self.current_step == 2 || !self.vss_accepted {
+            return Some(2);
+        }
+        if self.current_step == 3 || !self.sig_accepted {
+            return Some(3);
+        }
+        None
+    }
+}
+
+impl Peer for EddsaPeer {
+    fn new(capacity: u32, message: Vec<u8>, keys: PeerKeys) -> EddsaPeer {
+        let key_share = KeyShare::load(&keys);
+        assert!(
+            capacity > key_share.threshold,
+            "At least {} peers are required to sign",
+            key_share.threshold + 1
+        );
+        EddsaPeer {
+            peer_id: 0,
+            capacity,
+            current_step: 0,
+            is_done: false,
+            key_share,
+            message,
+            ephemeral_key: None,
+            ephemeral_shared_keys: None,
+            kg_indexes: HashMap::new(),
+            commitments: HashMap::new(),
+            decommitments: HashMap::new(),
+            vss_schemes: HashMap::new(),
+            secret_shares: HashMap::new(),
+            local_sigs: HashMap::new(),
+            commitment_accepted: false,
+            decommitment_accepted: false,
+            vss_accepted: false,
+            sig_accepted: false,
+            commitment_msg: None,
+            decommitment_msg: None,
+            vss_msg: None,
+            sig_msg: None,
+            share_msgs: BTreeMap::new(),
+            identities: BTreeMap::new(),
+
+            received: HashMap::new(),
+            received_shares: HashMap::new(),
+            abort: None,
+            evidence: Vec::new(),
+            failure: None,
+        }
+    }
+
+    fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+        self.peer_id = peer_id;
+    }
+
+    /// step 0 - create the ephemeral key of this peer and commit to its public key
+    fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+        self.peer_id = peer_id;
+        let kg_index = self.key_share.index;
+        let ephemeral_key = EphemeralKey::ephermeral_key_create_from_deterministic_secret(
+            &self.key_share.keys,
+            &self.message[..],
+            kg_index as usize,
+        );
+        let (bc1, blind) = ephemeral_key.phase1_broadcast();
+        let bc1_s = serde_json::to_string(&(kg_index, bc1)).expect("Failed in serialization");
+        let decommitment_s = serde_json::to_string(&(ephemeral_key.R_i.clone(), blind))
+            .expect("Failed in serialization");
+        self.ephemeral_key = Some(ephemeral_key);
+        self.commitment_msg = Some(EddsaMessage::Commitment(bc1_s).to_payload());
+        self.decommitment_msg = Some(EddsaMessage::Decommitment(decommitment_s).to_payload());
+        return self.commitment_msg.clone();
+    }
+
+    fn current_step(&self) -> u32 {
+        self.current_step
+    }
+
+    fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    fn peer_id(&self) -> PeerIdentifier {
+        self.peer_id
+    }
+
+    fn do_step(&mut self) {
+        info!("Current step is: {:}", self.current_step);
+        if self.abort.is_some() || self.failure.is_some() || self.is_done {
+            return;
+        }
+        if self.is_step_done() {
+            // do the next step
+            info!("step {:} done!", self.current_step);
+            self.current_step += 1;
+            match self.current_step {
+                1 => self.step_1(),
+                2 => self.step_2(),
+                3 => self.step_3(),
+                4 => {
+                    // the peer finalizes once, when all the messages of the last step verified
+                    if let Err(err) = self.finalize() {
+                        warn!("Unable to finalize: {}", err);
+                        self.failure = Some(err.to_string());
+                        return;
+                    }
+                    info!("----------\nDone.\n----------");
+                    self.is_done = true;
+                }
+                _ => panic!("Unsupported step"),
+            }
+        } else {
+            info!("step not done");
+        }
+    }
+
+    fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        if self.abort.is_some() {
+            return;
+        }
+        let msg = match EddsaMessage::from_payload(&payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                warn!("Invalid message from peer {}: {}", from, err);
+                return self.blame(ProtocolAbort::MalformedMessage(from), vec![payload]);
+            }
+        };
+        let step = match step_of(&msg) {
+            Some(step) => step,
+            None => return self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload]),
+        };
+        // a message of a previous step is a resend, it must be the one the peer sent then
+        if let Some(received) = self.received.get(&(step, from)) {
+            if *received != payload {
+                let evidence = vec![received.clone(), payload];
+                self.blame(ProtocolAbort::Equivocation(from), evidence);
+            }
+            return;
+        }
+        if step != self.current_step {
+            warn!(
+                "Expected a message of step {} from peer {}, got one of step {}",
+                self.current_step, from, step
+            );
+            return self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload]);
+        }
+        self.received.insert((step, from), payload);
+        // update data according to step
+        match msg {
+            EddsaMessage::Commitment(c) => self.update_data_step_0(from, c),
+            EddsaMessage::Decommitment(d) => self.update_data_step_1(from, d),
+            EddsaMessage::VssScheme(v) => self.update_data_step_2(from, v),
+            EddsaMessage::LocalSig(s) => self.update_data_step_3(from, s),
+            _ => unreachable!(),
+        }
+    }
+
+    fn update_private_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+        if self.abort.is_some() {
+            return;
+        }
+        if self.current_step != 2 {
+            warn!("Unexpected private message from peer {}", from);
+            return;
+        }
+        // a resent share must be the one the peer sent before, the first share is kept
+        if let Some(received) = self.received_shares.get(&from) {
+            if *received != payload {
+                let evidence = vec![received.clone(), payload];
+                self.blame(ProtocolAbort::Equivocation(from), evidence);
+            }
+            return;
+        }
+        self.received_shares.insert(from, payload.clone());
+        match EddsaMessage::from_payload(&payload) {
+            Ok(EddsaMessage::SecretShare(s)) => match serde_json::from_str::<FE>(&s) {
+                Ok(share) => {
+                    info!("-------Got peer # {:} secret share!", from);
+                    self.secret_shares.insert(from, share);
+                }
+                Err(_) => {
+                    warn!("Could not deserialize secret share of peer {}", from);
+                    self.blame(ProtocolAbort::MalformedMessage(from), vec![payload])
+                }
+            },
+            Ok(msg) => {
+                warn!("Expected secret share from peer {}, got {:?}", from, msg);
+                self.blame(ProtocolAbort::UnexpectedMessage(from), vec![payload])
+            }
+            Err(err) => {
+                warn!("Invalid message from peer {}: {}", from, err);
+                self.blame(ProtocolAbort::MalformedMessage(from), vec![payload])
+            }
+        }
+    }
+
+    fn set_peer_identity(&mut self, peer_id: PeerIdentifier, identity: IdentityKey) {
+        self.identities.insert(peer_id, identity);
+    }
+
+    /// Does the final calculation of the protocol
+    /// in this case:
+    ///     verifying the local signatures of all signers,
+    ///     combining them and verifying the signature
+    #[allow(non_snake_case)]
+    fn finalize(&mut self) -> Result<(), &'static str> {
+        let signers = self.signers();
+        let local_sig_vec: Vec<LocalSig> = signers
+            .iter()
+            .map(|peer| self.local_sigs[peer].clone())
+            .collect();
+        let vss_ephemeral_keys: Vec<VerifiableSS> = signers
+            .iter()
+            .map(|peer| self.vss_schemes[peer].clone())
+            .collect();
+        // local signatures are verified against the key generation schemes by 0 based indexes
+        let parties_index_vec: Vec<usize> = self
+            .signer_indexes()
+            .iter()
+            .map(|index| index - 1)
+            .collect();
+        let vss_sum_local_sigs = LocalSig::verify_local_sigs(
+            &local_sig_vec,
+            &parties_index_vec,
+            &self.key_share.vss_schemes,
+            &vss_ephemeral_keys,
+        )
+        .map_err(|_| "Invalid local signatures")?;
+        let R = self
+            .ephemeral_shared_keys
+            .as_ref()
+            .ok_or("Ephemeral key was not shared")?
+            .R
+            .clone();
+        let signature =
+            Signature::generate(&vss_sum_local_sigs, &local_sig_vec, &parties_index_vec, R);
+        // Verify signature against the key from key generation
+        match signature.verify(&self.message[..], &self.key_share.shared_keys.y) {
+            Ok(_) => {
+                let mut R_vec = signature.R.pk_to_key_slice().to_vec();
+                let mut s_vec = BigInt::to_vec(&signature.sigma.to_big_int());
+                s_vec.reverse();
+                R_vec.extend_from_slice(&s_vec[..]);
+
+                fs::write(
+                    format!("signature{}", self.peer_id),
+                    BigInt::from(&R_vec[..]).to_str_radix(16),
+                )
+                .map_err(|_| "Unable to save the signature")
+            }
+            Err(_) => Err("Failed to verify"),
+        }
+    }
+
+    /// check that the protocol is done
+    /// and that this peer can finalize its calculations
+    fn is_done(&mut self) -> bool {
+        self.is_done
+    }
+
+    fn abort(&self) -> Option<ProtocolAbort> {
+        self.abort.clone()
+    }
+
+    fn abort_evidence(&self) -> Vec<MessagePayload> {
+        self.evidence.clone()
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.failure.clone()
+    }
+
+    /// get the next item the peer needs to send
+    /// depending on the current step and the last message
+    /// of the peer that was accepted by the server
+    fn get_next_item(&mut self) -> Option<MessagePayload> {
+        if self.abort.is_some() {
+            return None;
+        }
+        match self.next_item_step() {
+            Some(0) => {
+                info!("next item is commitment: {:?}", self.commitment_msg);
+                self.commitment_msg.clone()
+            }
+            Some(1) => {
+                info!("next item is decommitment: {:?}", self.decommitment_msg);
+                self.decommitment_msg.clone()
+            }
+            Some(2) => {
+                info!("next item is vss scheme: {:?}", self.vss_msg);
+                self.vss_msg.clone()
+            }
+            Some(3) => {
+                info!("next item is local signature: {:?}", self.sig_msg);
+                self.sig_msg.clone()
+            }
+            _ => None,
+        }
+    }
+
+    /// the shares of the ephemeral key are sent along with its vss scheme
+    fn get_next_private_items(&mut self) -> BTreeMap<PeerIdentifier, MessagePayload> {
+        if self.abort.is_some() {
+            return BTreeMap::new();
+        }
+        match self.next_item_step() {
+            Some(2) => self.share_msgs.clone(),
+            _ => BTreeMap::new(),
+        }
+    }
+}
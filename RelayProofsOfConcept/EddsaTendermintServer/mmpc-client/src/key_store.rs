@@ -0,0 +1,357 @@
+/// Storage of the keys peers create in key generation and sign with.
+/// Keys are named by the aggregated public key they belong to.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curv::elliptic::curves::traits::ECPoint;
+use curv::GE;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use mmpc_server_common::identity::Identity;
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const KEY_FILE_EXTENSION: &str = "key";
+
+/// Environment variable the clients read the passphrase of their key store from
+pub const PASSPHRASE_VAR: &str = "MMPC_KEY_PASSPHRASE";
+
+/// The name the identity of the peer is saved under, apart from its keys
+pub const IDENTITY_NAME: &str = "identity";
+
+/// Reasons a key could not be saved to or loaded from a key store
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyStoreError {
+    // There is no key by this name in the store
+    NotFound(String),
+
+    // A key name must be the hex encoding of a public key
+    InvalidName(String),
+
+    // The store holds more than one key, and the key to use was not given
+    AmbiguousKey,
+
+    // The passphrase does not decrypt the key, or the key was tampered with
+    WrongPassphrase,
+
+    Io(String),
+
+    Malformed(String),
+}
+
+impl fmt::Display for KeyStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyStoreError::NotFound(name) => write!(f, "No key named {}", name),
+            KeyStoreError::InvalidName(name) => write!(f, "Invalid key name {}", name),
+            KeyStoreError::AmbiguousKey => write!(f, "More than one key, choose which to use"),
+            KeyStoreError::WrongPassphrase => write!(f, "Wrong passphrase"),
+            KeyStoreError::Io(err) => write!(f, "Key store error: {}", err),
+            KeyStoreError::Malformed(err) => write!(f, "Malformed key: {}", err),
+        }
+    }
+}
+
+/// A store of keys, each saved under the name of the aggregated public key it belongs to
+pub trait KeyStore {
+    fn save(&mut self, name: &str, key: &[u8]) -> Result<(), KeyStoreError>;
+    fn load(&self, name: &str) -> Result<Vec<u8>, KeyStoreError>;
+    fn names(&self) -> Result<Vec<String>, KeyStoreError>;
+}
+
+/// The name a key is saved under, the hex encoding of its aggregated public key
+pub fn key_name(public_key: &GE) -> String {
+    hex::encode(public_key.pk_to_key_slice())
+}
+
+/// The name of the only key in the store, used when the key to use was not given
+pub fn single_key_name(store: &dyn KeyStore) -> Result<String, KeyStoreError> {
+    let mut names = store.names()?;
+    names.retain(|name| name != IDENTITY_NAME);
+    match names.len() {
+        0 => Err(KeyStoreError::NotFound(String::from("any"))),
+        1 => Ok(names.remove(0)),
+        _ => Err(KeyStoreError::AmbiguousKey),
+    }
+}
+
+/// Loads the identity of the peer from the store,
+/// or generates a new identity and saves it there if the store has none
+pub fn load_or_generate_identity(store: &mut dyn KeyStore) -> Result<Identity, KeyStoreError> {
+    match store.load(IDENTITY_NAME) {
+        Ok(bytes) => Identity::from_bytes(&bytes)
+            .ok_or_else(|| KeyStoreError::Malformed(String::from("Invalid identity"))),
+        Err(KeyStoreError::NotFound(_)) => {
+            let identity = Identity::generate();
+            store.save(IDENTITY_NAME, &identity.to_bytes())?;
+            Ok(identity)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// names are used as file names, so only hex encoded public keys are accepted,
+// besides the name of the identity
+fn validate_name(name: &str) -> Result<(), KeyStoreError> {
+    if name == IDENTITY_NAME {
+        return Ok(());
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(KeyStoreError::InvalidName(String::from(name)));
+    }
+    Ok(())
+}
+
+/// The key store of a peer, along with the name of the key it signs with.
+/// Key generation peers save the key they create to the store
+pub struct PeerKeys {
+    pub store: Box<dyn KeyStore>,
+    pub name: Option<String>,
+}
+
+impl PeerKeys {
+    pub fn new(store: Box<dyn KeyStore>, name: Option<String>) -> PeerKeys {
+        PeerKeys { store, name }
+    }
+
+    /// Load the key the peer signs with
+    pub fn load(&self) -> Result<Vec<u8>, KeyStoreError> {
+        match self.name {
+            Some(ref name) => self.store.load(name),
+            None => self.store.load(&single_key_name(self.store.as_ref())?),
+        }
+    }
+}
+
+/// Keeps the keys in memory only, for tests
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    keys: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> MemoryKeyStore {
+        MemoryKeyStore {
+            keys: BTreeMap::new(),
+        }
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn save(&mut self, name: &str, key: &[u8]) -> Result<(), KeyStoreError> {
+        validate_name(name)?;
+        self.keys.insert(String::from(name), key.to_vec());
+        Ok(())
+    }
+
+    fn load(&self, name: &str) -> Result<Vec<u8>, KeyStoreError> {
+        self.keys
+            .get(name)
+            .cloned()
+            .ok_or_else(|| KeyStoreError::NotFound(String::from(name)))
+    }
+
+    fn names(&self) -> Result<Vec<String>, KeyStoreError> {
+        Ok(self.keys.keys().cloned().collect())
+    }
+}
+
+// A key as it is written to its file, encrypted with a key derived from the passphrase
+#[derive(Serialize, Deserialize)]
+struct EncryptedKey {
+    salt: [u8; SALT_SIZE],
+    nonce: [u8; NONCE_SIZE],
+    ciphertext: Vec<u8>,
+}
+
+/// Keeps every key in its own file in a directory, encrypted by a passphrase.
+/// The encryption key is derived from the passphrase with Argon2
+pub struct FileKeyStore {
+    dir: PathBuf,
+    passphrase: String,
+}
+
+impl FileKeyStore {
+    /// Opens the store in the given directory, creating it if needed
+    pub fn new(dir: &Path, passphrase: &str) -> Result<FileKeyStore, KeyStoreError> {
+        fs::create_dir_all(dir).map_err(|err| KeyStoreError::Io(err.to_string()))?;
+        Ok(FileKeyStore {
+            dir: dir.to_path_buf(),
+            passphrase: String::from(passphrase),
+        })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name).with_extension(KEY_FILE_EXTENSION)
+    }
+
+    fn cipher(&self, salt: &[u8]) -> Result<ChaCha20Poly1305, KeyStoreError> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+            .map_err(|err| KeyStoreError::Malformed(err.to_string()))?;
+        Ok(ChaCha20Poly1305::new(&Key::from(key)))
+    }
+}
+
+impl KeyStore for FileKeyStore {
+    fn save(&mut self, name: &str, key: &[u8]) -> Result<(), KeyStoreError> {
+        validate_name(name)?;
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        // the name is authenticated, so a key can not be passed off as another
+        let ciphertext = self
+            .cipher(&salt)?
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: key,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| KeyStoreError::Malformed(String::from("Failed to encrypt key")))?;
+        let encrypted = EncryptedKey {
+            salt,
+            nonce,
+            ciphertext,
+        };
+        let data = serde_json::to_string(&encrypted)
+            .map_err(|err| KeyStoreError::Malformed(err.to_string()))?;
+        fs::write(self.path(name), data).map_err(|err| KeyStoreError::Io(err.to_string()))
+    }
+
+    fn load(&self, name: &str) -> Result<Vec<u8>, KeyStoreError> {
+        validate_name(name)?;
+        let path = self.path(name);
+        if !path.exists() {
+            return Err(KeyStoreError::NotFound(String::from(name)));
+        }
+        let data = fs::read_to_string(path).map_err(|err| KeyStoreError::Io(err.to_string()))?;
+        let encrypted: EncryptedKey =
+            serde_json::from_str(&data).map_err(|err| KeyStoreError::Malformed(err.to_string()))?;
+        self.cipher(&encrypted.salt)?
+            .decrypt(
+                &Nonce::from(encrypted.nonce),
+                Payload {
+                    msg: &encrypted.ciphertext,
+                    aad: name.as_bytes(),
+                },
+            )
+            .map_err(|_| KeyStoreError::WrongPassphrase)
+    }
+
+    fn names(&self) -> Result<Vec<String>, KeyStoreError> {
+        let entries = fs::read_dir(&self.dir).map_err(|err| KeyStoreError::Io(err.to_string()))?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|err| KeyStoreError::Io(err.to_string()))?
+                .path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(KEY_FILE_EXTENSION) {
+                continue;
+            }
+            if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                names.push(String::from(name));
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        load_or_generate_identity, single_key_name, FileKeyStore, KeyStore, KeyStoreError,
+        MemoryKeyStore, PeerKeys,
+    };
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mmpc-key-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_memory_key_store() {
+        let mut store = MemoryKeyStore::new();
+        assert_eq!(
+            single_key_name(&store),
+            Err(KeyStoreError::NotFound(String::from("any")))
+        );
+        store.save("ab01", b"key share").unwrap();
+        assert_eq!(store.load("ab01"), Ok(b"key share".to_vec()));
+        assert_eq!(single_key_name(&store), Ok(String::from("ab01")));
+        store.save("cd02", b"other share").unwrap();
+        assert_eq!(single_key_name(&store), Err(KeyStoreError::AmbiguousKey));
+        assert_eq!(
+            store.save("../keys", b"key share"),
+            Err(KeyStoreError::InvalidName(String::from("../keys")))
+        );
+
+        let keys = PeerKeys::new(Box::new(store), Some(String::from("cd02")));
+        assert_eq!(keys.load(), Ok(b"other share".to_vec()));
+    }
+
+    #[test]
+    fn test_file_key_store() {
+        let dir = temp_dir("file");
+        let mut store = FileKeyStore::new(&dir, "passphrase").unwrap();
+        store.save("ab01", b"key share").unwrap();
+        assert_eq!(store.names(), Ok(vec![String::from("ab01")]));
+        assert_eq!(store.load("ab01"), Ok(b"key share".to_vec()));
+
+        // the key is encrypted at rest
+        let data = fs::read_to_string(dir.join("ab01.key")).unwrap();
+        assert!(!data.contains("key share"));
+
+        // a reopened store reads the key with the same passphrase only
+        let reopened = FileKeyStore::new(&dir, "passphrase").unwrap();
+        assert_eq!(reopened.load("ab01"), Ok(b"key share".to_vec()));
+        let wrong = FileKeyStore::new(&dir, "wrong passphrase").unwrap();
+        assert_eq!(wrong.load("ab01"), Err(KeyStoreError::WrongPassphrase));
+
+        // a key file renamed to another key is rejected
+        fs::copy(dir.join("ab01.key"), dir.join("cd02.key")).unwrap();
+        assert_eq!(store.load("cd02"), Err(KeyStoreError::WrongPassphrase));
+        assert_eq!(
+            store.load("ef03"),
+            Err(KeyStoreError::NotFound(String::from("ef03")))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_identity() {
+        let dir = temp_dir("identity");
+        let mut store = FileKeyStore::new(&dir, "passphrase").unwrap();
+        let identity = load_or_generate_identity(&mut store).unwrap();
+        store.save("ab01", b"key share").unwrap();
+
+        // the identity is kept encrypted, and is not taken for a key
+        let mut reopened = FileKeyStore::new(&dir, "passphrase").unwrap();
+        assert_eq!(single_key_name(&reopened), Ok(String::from("ab01")));
+        let restored = load_or_generate_identity(&mut reopened).unwrap();
+        assert_eq!(restored.key(), identity.key());
+        let mut wrong = FileKeyStore::new(&dir, "wrong passphrase").unwrap();
+        assert_eq!(
+            load_or_generate_identity(&mut wrong).err(),
+            Some(KeyStoreError::WrongPassphrase)
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
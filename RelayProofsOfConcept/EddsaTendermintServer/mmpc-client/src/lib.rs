@@ -1,4 +1,17 @@
+pub mod adversary;
 pub mod eddsa_peer_kg;
 pub mod eddsa_peer_sign;
+pub mod eddsa_peer_threshold_kg;
+pub mod eddsa_peer_threshold_sign;
+pub mod key_store;
 pub mod peer;
+#[cfg(feature = "rocket-transport")]
+pub mod rocket_client;
+pub mod simulation;
+#[cfg(feature = "tendermint-transport")]
+pub mod subscription;
+#[cfg(feature = "tendermint-transport")]
 pub mod tendermint_client;
+#[cfg(feature = "tokio-transport")]
+pub mod tokio_client;
+pub mod transport;
@@ -1,9 +1,124 @@
-use mmpc_server_common::{MessagePayload, PeerIdentifier};
+use std::collections::BTreeMap;
+use std::fmt;
 
-pub const MAX_CLIENTS: usize = 12;
+use crate::key_store::PeerKeys;
+use mmpc_server_common::identity::IdentityKey;
+use mmpc_server_common::{AbortReason, MessagePayload, PeerIdentifier};
+
+/// Misbehavior of another peer that made a peer abort the protocol, naming the culprit
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolAbort {
+    // The peer sent a message that can not be read
+    MalformedMessage(PeerIdentifier),
+
+    // The peer sent a message of another step of the protocol
+    UnexpectedMessage(PeerIdentifier),
+
+    // The peer sent two different messages for the same step
+    Equivocation(PeerIdentifier),
+
+    // The R the peer revealed does not match the commitment it sent
+    InvalidCommitment(PeerIdentifier),
+
+    // The peer sent a key generation index that is not its own
+    InvalidKeyIndex(PeerIdentifier),
+
+    // The signature share of the peer does not verify
+    InvalidSignatureShare(PeerIdentifier),
+
+    // The secret share the peer sent does not match the scheme it shared its key with
+    InvalidSecretShare(PeerIdentifier),
+
+    // The peer did not send its message of a round
+    Unresponsive(PeerIdentifier),
+}
+
+impl ProtocolAbort {
+    /// The peer that misbehaved
+    pub fn culprit(&self) -> PeerIdentifier {
+        match *self {
+            ProtocolAbort::MalformedMessage(peer_id)
+            | ProtocolAbort::UnexpectedMessage(peer_id)
+            | ProtocolAbort::Equivocation(peer_id)
+            | ProtocolAbort::InvalidCommitment(peer_id)
+            | ProtocolAbort::InvalidKeyIndex(peer_id)
+            | ProtocolAbort::InvalidSignatureShare(peer_id)
+            | ProtocolAbort::InvalidSecretShare(peer_id)
+            | ProtocolAbort::Unresponsive(peer_id) => peer_id,
+        }
+    }
+
+    /// The reason code the abort is reported to the relay with
+    pub fn reason(&self) -> AbortReason {
+        match self {
+            ProtocolAbort::MalformedMessage(_) => AbortReason::MalformedMessage,
+            ProtocolAbort::UnexpectedMessage(_) => AbortReason::UnexpectedMessage,
+            ProtocolAbort::Equivocation(_) => AbortReason::Equivocation,
+            ProtocolAbort::InvalidCommitment(_) => AbortReason::InvalidCommitment,
+            ProtocolAbort::InvalidKeyIndex(_) => AbortReason::InvalidKeyIndex,
+            ProtocolAbort::InvalidSignatureShare(_) => AbortReason::InvalidSignatureShare,
+            ProtocolAbort::InvalidSecretShare(_) => AbortReason::InvalidSecretShare,
+            ProtocolAbort::Unresponsive(_) => AbortReason::Unresponsive,
+        }
+    }
+
+    /// The abort an abort message of the relay reports, if it blames a culprit
+    pub fn from_reason(reason: AbortReason, culprit: PeerIdentifier) -> Option<ProtocolAbort> {
+        match reason {
+            AbortReason::Unspecified => None,
+            AbortReason::MalformedMessage => Some(ProtocolAbort::MalformedMessage(culprit)),
+            AbortReason::UnexpectedMessage => Some(ProtocolAbort::UnexpectedMessage(culprit)),
+            AbortReason::Equivocation => Some(ProtocolAbort::Equivocation(culprit)),
+            AbortReason::InvalidCommitment => Some(ProtocolAbort::InvalidCommitment(culprit)),
+            AbortReason::InvalidKeyIndex => Some(ProtocolAbort::InvalidKeyIndex(culprit)),
+            AbortReason::InvalidSignatureShare => {
+                Some(ProtocolAbort::InvalidSignatureShare(culprit))
+            }
+            AbortReason::InvalidSecretShare => Some(ProtocolAbort::InvalidSecretShare(culprit)),
+            AbortReason::Unresponsive => Some(ProtocolAbort::Unresponsive(culprit)),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolAbort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolAbort::MalformedMessage(peer_id) => {
+                write!(f, "Peer {} sent a malformed message", peer_id)
+            }
+            ProtocolAbort::UnexpectedMessage(peer_id) => {
+                write!(f, "Peer {} sent a message of another step", peer_id)
+            }
+            ProtocolAbort::Equivocation(peer_id) => {
+                write!(
+                    f,
+                    "Peer {} sent different messages for the same step",
+                    peer_id
+                )
+            }
+            ProtocolAbort::InvalidCommitment(peer_id) => {
+                write!(f, "R of peer {} does not match its commitment", peer_id)
+            }
+            ProtocolAbort::InvalidKeyIndex(peer_id) => {
+                write!(f, "Peer {} sent an invalid key generation index", peer_id)
+            }
+            ProtocolAbort::InvalidSignatureShare(peer_id) => {
+                write!(f, "Signature share of peer {} does not verify", peer_id)
+            }
+            ProtocolAbort::InvalidSecretShare(peer_id) => {
+                write!(
+                    f,
+                    "Secret share of peer {} does not match its scheme",
+                    peer_id
+                )
+            }
+            ProtocolAbort::Unresponsive(peer_id) => write!(f, "Peer {} did not respond", peer_id),
+        }
+    }
+}
 
 pub trait Peer {
-    fn new(capacity: u32, message: Vec<u8>, index: u32) -> Self;
+    fn new(capacity: u32, message: Vec<u8>, keys: PeerKeys) -> Self;
     fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload>;
     fn current_step(&self) -> u32;
     fn capacity(&self) -> u32;
@@ -14,6 +129,36 @@ pub trait Peer {
     fn get_next_item(&mut self) -> Option<MessagePayload>;
     fn finalize(&mut self) -> Result<(), &'static str>;
     fn is_done(&mut self) -> bool;
+
+    /// Payloads the peer sends along with its next item, each only readable by its recipient
+    fn get_next_private_items(&mut self) -> BTreeMap<PeerIdentifier, MessagePayload> {
+        BTreeMap::new()
+    }
+    /// Update the peer with a payload another peer sent only to it
+    fn update_private_data(&mut self, _from: PeerIdentifier, _payload: MessagePayload) {}
+    /// Set the threshold t of a t-of-n protocol, any t+1 of the peers can complete it.
+    /// A peer of a protocol without a threshold ignores it
+    fn set_threshold(&mut self, _threshold: u32) {}
+    /// Tell the peer the identity key another peer registered to the session with
+    fn set_peer_identity(&mut self, _peer_id: PeerIdentifier, _identity: IdentityKey) {}
+    /// The misbehavior of another peer the peer aborted on, it sends nothing more once it did
+    fn abort(&self) -> Option<ProtocolAbort> {
+        None
+    }
+    /// Messages the culprit of the abort sent that prove it misbehaved
+    fn abort_evidence(&self) -> Vec<MessagePayload> {
+        Vec::new()
+    }
+    /// The error the peer failed on without another peer misbehaving, such as saving the
+    /// result of the protocol. It sends nothing more once it failed
+    fn failure(&self) -> Option<String> {
+        None
+    }
+    /// Returns true if the messages of the culprit prove the abort of another peer.
+    /// A peer that can not tell from the messages alone returns false
+    fn verify_abort(&self, _abort: &ProtocolAbort, _evidence: &[MessagePayload]) -> bool {
+        false
+    }
 }
 
 pub struct ProtocolDataManager<T: Peer> {
@@ -23,17 +168,22 @@ pub struct ProtocolDataManager<T: Peer> {
 }
 
 impl<T: Peer> ProtocolDataManager<T> {
-    pub fn new(capacity: u32, message: Vec<u8>, index: u32) -> ProtocolDataManager<T>
+    pub fn new(capacity: u32, message: Vec<u8>, keys: PeerKeys) -> ProtocolDataManager<T>
     where
         T: Peer,
     {
         ProtocolDataManager {
-            data_holder: Peer::new(capacity, message, index),
+            data_holder: Peer::new(capacity, message, keys),
             client_data: None,
             new_client_data: false,
         }
     }
 
+    /// Sets the threshold of the protocol the peer runs, before the session starts
+    pub fn set_threshold(&mut self, threshold: u32) {
+        self.data_holder.set_threshold(threshold);
+    }
+
     /// set manager with the initial values that a local peer holds at the beginning of
     /// the protocol session
     /// return: first message
@@ -44,13 +194,18 @@ impl<T: Peer> ProtocolDataManager<T> {
         return self.client_data.clone();
     }
 
-    /// Get the next message this client needs to send
+    /// Get the next message this client needs to send.
+    /// A private payload is the part of the message only this client could decrypt
     pub fn get_next_message(
         &mut self,
         from: PeerIdentifier,
         payload: MessagePayload,
+        private_payload: Option<MessagePayload>,
     ) -> Option<MessagePayload> {
         self.data_holder.update_data(from, payload);
+        if let Some(private_payload) = private_payload {
+            self.data_holder.update_private_data(from, private_payload);
+        }
         self.data_holder.do_step();
         self.data_holder.get_next_item()
     }
@@ -0,0 +1,275 @@
+/// Transport over the Rocket key-value server. Peers sign up to a session, set their message
+/// of every round under a key of their own, and poll for the keys of the other peers.
+/// The server authenticates nobody, so the private items are encrypted to their recipients
+/// with the encryption keys the peers set when signing up
+use std::collections::BTreeMap;
+
+use futures::future;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::transport::{poll_interval, RoundMessage, Transport, TransportError, TransportFuture};
+use mmpc_server_common::encryption::{EncryptedPayload, EncryptionKey, EncryptionKeyPair};
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+
+// The keys of the other peers are polled every POLL_TIMEOUT milliseconds, up to MAX_POLLS times
+const MAX_POLLS: u32 = 512;
+const POLL_TIMEOUT: u64 = 10;
+
+const ENCRYPTION_KEY_ROUND: &str = "encryption-key";
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct TupleKey {
+    pub first: String,
+    pub second: String,
+    pub third: String,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PartySignup {
+    pub number: u32,
+    pub uuid: String,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Index {
+    pub key: TupleKey,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: TupleKey,
+    pub value: String,
+}
+
+pub struct RocketTransport {
+    client: reqwest::Client,
+    // address of the server, e.g. http://127.0.0.1:8001
+    server: String,
+    encryption: EncryptionKeyPair,
+    encryption_keys: BTreeMap<PeerIdentifier, EncryptionKey>,
+    signup: Option<PartySignup>,
+    capacity: u32,
+    rounds: BTreeMap<u32, BTreeMap<PeerIdentifier, RoundMessage>>,
+}
+
+impl RocketTransport {
+    pub fn new(server: &str) -> RocketTransport {
+        RocketTransport {
+            client: reqwest::Client::new(),
+            server: String::from(server),
+            encryption: EncryptionKeyPair::generate(),
+            encryption_keys: BTreeMap::new(),
+            signup: None,
+            capacity: 0,
+            rounds: BTreeMap::new(),
+        }
+    }
+
+    fn postb<T>(&self, path: &str, body: T) -> Result<String, TransportError>
+    where
+        T: serde::ser::Serialize,
+    {
+        self.client
+            .post(&format!("{}/{}", self.server, path))
+            .json(&body)
+            .send()
+            .and_then(|mut res| res.text())
+            .map_err(|err| TransportError::Unreachable(err.to_string()))
+    }
+
+    // The key of a value the given party sets in the session
+    fn key(&self, party: PeerIdentifier, round: &str) -> TupleKey {
+        let uuid = match self.signup {
+            Some(ref signup) => signup.uuid.clone(),
+            None => String::new(),
+        };
+        TupleKey {
+            first: party.to_string(),
+            second: String::from(round),
+            third: uuid,
+        }
+    }
+
+    fn set(&self, key: TupleKey, value: String) -> Result<(), TransportError> {
+        let res_body = self.postb("set", Entry { key, value })?;
+        let answer: Result<(), ()> = serde_json::from_str(&res_body)
+            .map_err(|err| TransportError::Rejected(err.to_string()))?;
+        answer.map_err(|_| TransportError::Rejected(String::from("Value not set")))
+    }
+
+    fn get(&self, key: TupleKey) -> Result<Option<String>, TransportError> {
+        let res_body = self.postb("get", Index { key })?;
+        let answer: Result<Entry, ()> = serde_json::from_str(&res_body)
+            .map_err(|err| TransportError::Rejected(err.to_string()))?;
+        Ok(answer.ok().map(|entry| entry.value))
+    }
+
+    fn encryption_key(
+        &mut self,
+        peer_id: PeerIdentifier,
+    ) -> Result<Option<EncryptionKey>, TransportError> {
+        if let Some(key) = self.encryption_keys.get(&peer_id) {
+            return Ok(Some(*key));
+        }
+        let value = match self.get(self.key(peer_id, ENCRYPTION_KEY_ROUND))? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let key: EncryptionKey = serde_json::from_str(&value)
+            .map_err(|err| TransportError::Rejected(err.to_string()))?;
+        self.encryption_keys.insert(peer_id, key);
+        Ok(Some(key))
+    }
+
+    fn peer_id(&self) -> PeerIdentifier {
+        self.signup
+            .as_ref()
+            .map(|signup| signup.number)
+            .unwrap_or(0)
+    }
+
+    // Reads the message of the peer in the round, if it was set
+    fn read_message(
+        &mut self,
+        round: u32,
+        from: PeerIdentifier,
+    ) -> Result<Option<RoundMessage>, TransportError> {
+        let payload = match self.get(self.key(from, &format!("round{}", round)))? {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+        // the private items are set before the message, so they are there once the message is
+        let peer_id = self.peer_id();
+        let private_key = self.key(from, &format!("round{}-{}", round, peer_id));
+        let private_payload = match self.get(private_key)? {
+            Some(value) => {
+                let ciphertext: EncryptedPayload = serde_json::from_str(&value)
+                    .map_err(|err| TransportError::Rejected(err.to_string()))?;
+                match self.encryption_key(from)? {
+                    Some(sender_key) => {
+                        match self
+                            .encryption
+                            .decrypt(&sender_key, from, peer_id, &ciphertext)
+                        {
+                            Ok(private_payload) => Some(private_payload),
+                            Err(err) => {
+                                warn!("Private payload of peer {}: {}", from, err);
+                                None
+                            }
+                        }
+                    }
+                    None => {
+                        warn!(
+                            "No encryption key of peer {}, ignoring private payload",
+                            from
+                        );
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        Ok(Some(RoundMessage {
+            from,
+            payload,
+            private_payload,
+        }))
+    }
+}
+
+impl Transport for RocketTransport {
+    /// The server signs up parties to a session until it is full, the capacity of the sessions
+    /// is fixed by the server and must be the capacity of the protocol
+    fn register(
+        &mut self,
+        _protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> TransportFuture<'_, PeerIdentifier> {
+        self.capacity = capacity;
+        let key = TupleKey {
+            first: String::from("signup"),
+            second: String::new(),
+            third: String::new(),
+        };
+        let signup = self.postb("signup", key).and_then(|res_body| {
+            let answer: Result<PartySignup, ()> = serde_json::from_str(&res_body)
+                .map_err(|err| TransportError::Rejected(err.to_string()))?;
+            answer.map_err(|_| TransportError::Rejected(String::from("Signup failed")))
+        });
+        let signup = match signup {
+            Ok(signup) => signup,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        info!(
+            "Signed up to session {} as party {}",
+            signup.uuid, signup.number
+        );
+        let peer_id = signup.number;
+        self.signup = Some(signup);
+        let public_key = serde_json::to_string(&self.encryption.public_key()).unwrap();
+        let result = self
+            .set(self.key(peer_id, ENCRYPTION_KEY_ROUND), public_key)
+            .map(|_| peer_id);
+        Box::new(future::result(result))
+    }
+
+    fn send(
+        &mut self,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> TransportFuture<'_, ()> {
+        let mut private_items = private_items;
+        poll_interval(POLL_TIMEOUT, MAX_POLLS, move || {
+            let peer_id = self.peer_id();
+            // the items of peers whose encryption keys are not set yet are sent on a later poll
+            let recipients: Vec<PeerIdentifier> = private_items.keys().cloned().collect();
+            for recipient in recipients {
+                let recipient_key = match self.encryption_key(recipient)? {
+                    Some(recipient_key) => recipient_key,
+                    None => continue,
+                };
+                let private_payload = private_items.remove(&recipient).unwrap();
+                let ciphertext =
+                    self.encryption
+                        .encrypt(&recipient_key, peer_id, recipient, &private_payload);
+                self.set(
+                    self.key(peer_id, &format!("round{}-{}", round, recipient)),
+                    serde_json::to_string(&ciphertext).unwrap(),
+                )?;
+            }
+            if !private_items.is_empty() {
+                return Ok(None);
+            }
+            self.set(
+                self.key(peer_id, &format!("round{}", round)),
+                payload.clone(),
+            )?;
+            Ok(Some(()))
+        })
+    }
+
+    fn receive_round(&mut self, round: u32) -> TransportFuture<'_, Vec<RoundMessage>> {
+        poll_interval(POLL_TIMEOUT, MAX_POLLS, move || {
+            for from in 1..=self.capacity {
+                let received = self
+                    .rounds
+                    .get(&round)
+                    .and_then(|messages| messages.get(&from));
+                if received.is_some() {
+                    continue;
+                }
+                if let Some(msg) = self.read_message(round, from)? {
+                    debug!("party {:?} round{:?} read success", from, round);
+                    self.rounds.entry(round).or_default().insert(from, msg);
+                }
+            }
+            if self.rounds.get(&round).map(BTreeMap::len) != Some(self.capacity as usize) {
+                return Ok(None);
+            }
+            let messages = self.rounds.remove(&round);
+            Ok(messages.map(|messages| messages.values().cloned().collect()))
+        })
+    }
+}
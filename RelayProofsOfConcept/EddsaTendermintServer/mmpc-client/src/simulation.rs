@@ -0,0 +1,636 @@
+/// Runs all the peers of a session in one process, relaying their messages through a relay
+/// that is called directly rather than over sockets. The network between the relay and the peers
+/// is simulated from a seed, it reorders, delays and drops the messages the relay delivers,
+/// so a run is replayed exactly by running it again with the same seed.
+/// A peer that aborts on the misbehavior of another peer stops, and once no message moves
+/// any more the peers still waiting blame the first peer whose message they are missing
+use std::collections::BTreeMap;
+
+use log::{debug, info};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::peer::{Peer, ProtocolAbort, ProtocolDataManager};
+use crate::transport::{RoundMessage, TransportError};
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+
+// A simulation that takes more steps than this, or in which no message moves for
+// MAX_IDLE_STEPS steps, is taken to be stuck
+const MAX_STEPS: u64 = 100_000;
+const MAX_IDLE_STEPS: u64 = 1_000;
+
+/// How the simulated network treats the messages the relay delivers
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkConditions {
+    // deliver messages in any order, rather than in the order the relay sent them
+    pub reorder: bool,
+    // the most steps of the simulation a message can be delayed by
+    pub max_delay: u64,
+    // the probability a message is lost
+    pub drop_rate: f64,
+}
+
+impl NetworkConditions {
+    /// A network delivering every message, in order and without delay
+    pub fn reliable() -> NetworkConditions {
+        NetworkConditions {
+            reorder: false,
+            max_delay: 0,
+            drop_rate: 0.0,
+        }
+    }
+}
+
+/// A message of a round the relay delivers to a peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub to: PeerIdentifier,
+    pub round: u32,
+    pub message: RoundMessage,
+}
+
+/// A relay the peers of a simulation send their messages through
+pub trait SimulatedRelay {
+    /// Registers the peer with the given index in the simulation,
+    /// returns the identifier of the peer in the session
+    fn register(
+        &mut self,
+        index: usize,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> Result<PeerIdentifier, TransportError>;
+
+    /// Offers the message of a peer in a round to the relay, along with the private items.
+    /// Returns the messages the relay delivers on taking it,
+    /// or None if the relay can not take it yet, to be offered again later
+    fn submit(
+        &mut self,
+        from: PeerIdentifier,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> Result<Option<Vec<Delivery>>, TransportError>;
+
+    /// Queries the relay for the messages of the round the peer is missing, for relays the peers poll.
+    /// A relay pushing every message to its recipients has nothing more to deliver
+    fn query(
+        &mut self,
+        _to: PeerIdentifier,
+        _round: u32,
+        _missing: Vec<PeerIdentifier>,
+    ) -> Result<Vec<Delivery>, TransportError> {
+        Ok(Vec::new())
+    }
+}
+
+/// What a simulation went through until all of its peers were done or stuck
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationReport {
+    pub rounds: u32,
+    pub steps: u64,
+    pub delivered: u32,
+    pub dropped: u32,
+    // the abort of each peer that did not finish the protocol, by its identifier
+    pub aborts: BTreeMap<PeerIdentifier, ProtocolAbort>,
+}
+
+struct SimulatedPeer<T: Peer> {
+    data_manager: ProtocolDataManager<T>,
+    peer_id: PeerIdentifier,
+    round: u32,
+    // the message of the round the relay did not take yet
+    outgoing: Option<(MessagePayload, BTreeMap<PeerIdentifier, MessagePayload>)>,
+    // messages of the round and of later ones, in the order they arrived
+    received: BTreeMap<u32, Vec<RoundMessage>>,
+    done: bool,
+}
+
+// A message on its way, delivered once the simulation reaches the due step
+struct InFlight {
+    due: u64,
+    delivery: Delivery,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Submit(usize),
+    Deliver(usize),
+    Query(usize),
+}
+
+pub struct Simulation<T: Peer, R: SimulatedRelay> {
+    relay: R,
+    conditions: NetworkConditions,
+    rng: StdRng,
+    peers: Vec<SimulatedPeer<T>>,
+    // index of each peer in the simulation, by its identifier in the session
+    indexes: BTreeMap<PeerIdentifier, usize>,
+    in_flight: Vec<InFlight>,
+    step: u64,
+    // the last step the relay took a message or a message was delivered in
+    last_progress: u64,
+    delivered: u32,
+    dropped: u32,
+    aborts: BTreeMap<PeerIdentifier, ProtocolAbort>,
+}
+
+impl<T: Peer, R: SimulatedRelay> Simulation<T, R> {
+    pub fn new(
+        relay: R,
+        conditions: NetworkConditions,
+        seed: u64,
+        data_managers: Vec<ProtocolDataManager<T>>,
+    ) -> Simulation<T, R> {
+        Simulation {
+            relay,
+            conditions,
+            rng: StdRng::seed_from_u64(seed),
+            peers: data_managers
+                .into_iter()
+                .map(|data_manager| SimulatedPeer {
+                    data_manager,
+                    peer_id: 0,
+                    round: 0,
+                    outgoing: None,
+                    received: BTreeMap::new(),
+                    done: false,
+                })
+                .collect(),
+            indexes: BTreeMap::new(),
+            in_flight: Vec::new(),
+            step: 0,
+            last_progress: 0,
+            delivered: 0,
+            dropped: 0,
+            aborts: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the peers in a random order, and runs them until none has anything more to send.
+    /// Every step either a peer offers its message to the relay, queries the relay,
+    /// or a message is delivered to a peer. Fails with a timeout if the simulation got stuck
+    /// with no peer missing the message of another
+    pub fn run(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+    ) -> Result<SimulationReport, TransportError> {
+        self.register(protocol_id)?;
+        while !self.peers.iter().all(|peer| peer.done) {
+            if self.step >= MAX_STEPS || self.step - self.last_progress >= MAX_IDLE_STEPS {
+                self.blame_unresponsive()?;
+                break;
+            }
+            self.step += 1;
+            let actions = self.actions();
+            let action = match actions.choose(&mut self.rng) {
+                Some(action) => *action,
+                // waiting for delayed messages
+                None => continue,
+            };
+            match action {
+                Action::Submit(index) => self.submit(index)?,
+                Action::Deliver(position) => self.deliver(position),
+                Action::Query(index) => self.query(index)?,
+            }
+        }
+        Ok(SimulationReport {
+            rounds: self.peers.iter().map(|peer| peer.round).max().unwrap_or(0),
+            steps: self.step,
+            delivered: self.delivered,
+            dropped: self.dropped,
+            aborts: self.aborts.clone(),
+        })
+    }
+
+    /// The peers, in the order they were given to the simulation
+    pub fn into_peers(self) -> Vec<T> {
+        self.peers
+            .into_iter()
+            .map(|peer| peer.data_manager.data_holder)
+            .collect()
+    }
+
+    // Every peer still waiting blames the first other peer whose message of the round it misses
+    fn blame_unresponsive(&mut self) -> Result<(), TransportError> {
+        for peer in self.peers.iter_mut().filter(|peer| !peer.done) {
+            let capacity = peer.data_manager.data_holder.capacity();
+            let received = peer.received.get(&peer.round);
+            let peer_id = peer.peer_id;
+            let missing = (1..=capacity).find(|from| {
+                *from != peer_id && !received.into_iter().flatten().any(|msg| msg.from == *from)
+            });
+            if let Some(missing) = missing {
+                info!("Peer {} stuck waiting for peer {}", peer_id, missing);
+                self.aborts
+                    .insert(peer_id, ProtocolAbort::Unresponsive(missing));
+            }
+        }
+        if self.aborts.is_empty() {
+            return Err(TransportError::Timeout);
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, protocol_id: ProtocolIdentifier) -> Result<(), TransportError> {
+        let mut order: Vec<usize> = (0..self.peers.len()).collect();
+        order.shuffle(&mut self.rng);
+        for index in order {
+            let capacity = self.peers[index].data_manager.data_holder.capacity();
+            let peer_id = self.relay.register(index, protocol_id, capacity)?;
+            info!("Peer {} registered as peer {}", index, peer_id);
+            self.indexes.insert(peer_id, index);
+            self.peers[index].peer_id = peer_id;
+        }
+        for peer in self.peers.iter_mut() {
+            match peer.data_manager.initialize_data(peer.peer_id) {
+                Some(payload) => {
+                    let private_items = peer.data_manager.data_holder.get_next_private_items();
+                    peer.outgoing = Some((payload, private_items));
+                }
+                None => peer.done = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn actions(&self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for (index, peer) in self.peers.iter().enumerate() {
+            if peer.outgoing.is_some() {
+                actions.push(Action::Submit(index));
+            } else if !peer.done {
+                actions.push(Action::Query(index));
+            }
+        }
+        let due = self
+            .in_flight
+            .iter()
+            .enumerate()
+            .filter(|(_, msg)| msg.due <= self.step)
+            .map(|(position, _)| Action::Deliver(position));
+        if self.conditions.reorder {
+            actions.extend(due);
+        } else if let Some(msg) = self.in_flight.first() {
+            // messages are delivered in the order they were sent, a delayed message holds back the rest
+            if msg.due <= self.step {
+                actions.push(Action::Deliver(0));
+            }
+        }
+        actions
+    }
+
+    fn submit(&mut self, index: usize) -> Result<(), TransportError> {
+        let peer = &self.peers[index];
+        let (payload, private_items) = peer.outgoing.clone().unwrap();
+        let (peer_id, round) = (peer.peer_id, peer.round);
+        let own_message = RoundMessage {
+            from: peer_id,
+            payload: payload.clone(),
+            private_payload: private_items.get(&peer_id).cloned(),
+        };
+        let deliveries = match self.relay.submit(peer_id, round, payload, private_items)? {
+            Some(deliveries) => deliveries,
+            None => {
+                debug!("Relay did not take the message of peer {} yet", peer_id);
+                return Ok(());
+            }
+        };
+        debug!(
+            "Relay took the message of peer {} in round {}",
+            peer_id, round
+        );
+        self.peers[index].outgoing = None;
+        self.last_progress = self.step;
+        self.receive(index, round, own_message);
+        self.send(deliveries);
+        Ok(())
+    }
+
+    fn query(&mut self, index: usize) -> Result<(), TransportError> {
+        let peer = &self.peers[index];
+        let capacity = peer.data_manager.data_holder.capacity();
+        let received = peer.received.get(&peer.round);
+        let missing = (1..=capacity)
+            .filter(|from| !received.into_iter().flatten().any(|msg| msg.from == *from))
+            .collect();
+        let deliveries = self.relay.query(peer.peer_id, peer.round, missing)?;
+        self.send(deliveries);
+        Ok(())
+    }
+
+    // Puts the deliveries on their way, each might be delayed or lost
+    fn send(&mut self, deliveries: Vec<Delivery>) {
+        for delivery in deliveries {
+            if self.rng.gen_bool(self.conditions.drop_rate) {
+                debug!(
+                    "Dropped message of peer {} to peer {}",
+                    delivery.message.from, delivery.to
+                );
+                self.dropped += 1;
+                continue;
+            }
+            let delay = self.rng.gen_range(0, self.conditions.max_delay + 1);
+            self.in_flight.push(InFlight {
+                due: self.step + delay,
+                delivery,
+            });
+        }
+    }
+
+    fn deliver(&mut self, position: usize) {
+        let Delivery { to, round, message } = self.in_flight.remove(position).delivery;
+        match self.indexes.get(&to) {
+            Some(index) => {
+                self.delivered += 1;
+                self.last_progress = self.step;
+                self.receive(*index, round, message);
+            }
+            None => debug!("Dropped message to unknown peer {}", to),
+        }
+    }
+
+    // Keeps the message until all the messages of its round arrived,
+    // then moves the peer to the next round
+    fn receive(&mut self, index: usize, round: u32, message: RoundMessage) {
+        let peer = &mut self.peers[index];
+        // a message can arrive more than once, from a query and from the relay delivering it
+        if round < peer.round {
+            return;
+        }
+        let messages = peer.received.entry(round).or_default();
+        if messages.iter().any(|msg| msg.from == message.from) {
+            return;
+        }
+        messages.push(message);
+
+        let capacity = peer.data_manager.data_holder.capacity() as usize;
+        if peer.outgoing.is_some() || peer.received.get(&peer.round).map(Vec::len) != Some(capacity)
+        {
+            return;
+        }
+        let mut next_message = None;
+        for msg in peer.received.remove(&peer.round).unwrap() {
+            next_message =
+                peer.data_manager
+                    .get_next_message(msg.from, msg.payload, msg.private_payload);
+        }
+        peer.round += 1;
+        match next_message {
+            Some(payload) => {
+                let private_items = peer.data_manager.data_holder.get_next_private_items();
+                peer.outgoing = Some((payload, private_items));
+            }
+            None => {
+                match peer.data_manager.data_holder.abort() {
+                    Some(abort) => {
+                        info!("Peer {} aborted: {}", peer.peer_id, abort);
+                        self.aborts.insert(peer.peer_id, abort);
+                    }
+                    None => info!("Peer {} done after {} rounds", peer.peer_id, peer.round),
+                }
+                peer.done = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Delivery, NetworkConditions, SimulatedRelay, Simulation};
+    use crate::key_store::{MemoryKeyStore, PeerKeys};
+    use crate::peer::{Peer, ProtocolAbort, ProtocolDataManager};
+    use crate::transport::{RoundMessage, TransportError};
+    use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+    use std::collections::BTreeMap;
+
+    // Every peer sends its identifier, then the largest identifier it received
+    struct MaxPeer {
+        peer_id: PeerIdentifier,
+        capacity: u32,
+        current_step: u32,
+        ids: BTreeMap<PeerIdentifier, u32>,
+        maxes: BTreeMap<PeerIdentifier, u32>,
+    }
+
+    impl Peer for MaxPeer {
+        fn new(capacity: u32, _message: Vec<u8>, _keys: PeerKeys) -> MaxPeer {
+            MaxPeer {
+                peer_id: 0,
+                capacity,
+                current_step: 0,
+                ids: BTreeMap::new(),
+                maxes: BTreeMap::new(),
+            }
+        }
+        fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+            Some(peer_id.to_string())
+        }
+        fn current_step(&self) -> u32 {
+            self.current_step
+        }
+        fn capacity(&self) -> u32 {
+            self.capacity
+        }
+        fn peer_id(&self) -> PeerIdentifier {
+            self.peer_id
+        }
+        fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+            self.peer_id = peer_id;
+        }
+        fn do_step(&mut self) {
+            let received = match self.current_step {
+                0 => self.ids.len(),
+                _ => self.maxes.len(),
+            };
+            if received == self.capacity as usize {
+                self.current_step += 1;
+            }
+        }
+        fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+            let value = payload.parse().unwrap();
+            match self.current_step {
+                0 => self.ids.insert(from, value),
+                _ => self.maxes.insert(from, value),
+            };
+        }
+        fn get_next_item(&mut self) -> Option<MessagePayload> {
+            match self.current_step {
+                0 => Some(self.peer_id.to_string()),
+                1 => self.ids.values().max().map(|max| max.to_string()),
+                _ => None,
+            }
+        }
+        fn finalize(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+        fn is_done(&mut self) -> bool {
+            self.current_step == 2
+        }
+    }
+
+    // Takes every message and delivers it to all the peers, or keeps it for queries
+    struct BroadcastRelay {
+        capacity: u32,
+        registered: u32,
+        // keep the messages rather than deliver them, the peers query for them
+        polled: bool,
+        messages: BTreeMap<u32, BTreeMap<PeerIdentifier, MessagePayload>>,
+    }
+
+    impl BroadcastRelay {
+        fn new(capacity: u32, polled: bool) -> BroadcastRelay {
+            BroadcastRelay {
+                capacity,
+                registered: 0,
+                polled,
+                messages: BTreeMap::new(),
+            }
+        }
+    }
+
+    impl SimulatedRelay for BroadcastRelay {
+        fn register(
+            &mut self,
+            _index: usize,
+            _protocol_id: ProtocolIdentifier,
+            _capacity: u32,
+        ) -> Result<PeerIdentifier, TransportError> {
+            self.registered += 1;
+            Ok(self.registered)
+        }
+
+        fn submit(
+            &mut self,
+            from: PeerIdentifier,
+            round: u32,
+            payload: MessagePayload,
+            _private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+        ) -> Result<Option<Vec<Delivery>>, TransportError> {
+            self.messages
+                .entry(round)
+                .or_default()
+                .insert(from, payload.clone());
+            if self.polled {
+                return Ok(Some(Vec::new()));
+            }
+            let deliveries = (1..=self.capacity)
+                .map(|to| Delivery {
+                    to,
+                    round,
+                    message: RoundMessage {
+                        from,
+                        payload: payload.clone(),
+                        private_payload: None,
+                    },
+                })
+                .collect();
+            Ok(Some(deliveries))
+        }
+
+        fn query(
+            &mut self,
+            to: PeerIdentifier,
+            round: u32,
+            missing: Vec<PeerIdentifier>,
+        ) -> Result<Vec<Delivery>, TransportError> {
+            if !self.polled {
+                return Ok(Vec::new());
+            }
+            let messages = self.messages.get(&round).cloned().unwrap_or_default();
+            Ok(missing
+                .into_iter()
+                .filter_map(|from| {
+                    messages.get(&from).map(|payload| Delivery {
+                        to,
+                        round,
+                        message: RoundMessage {
+                            from,
+                            payload: payload.clone(),
+                            private_payload: None,
+                        },
+                    })
+                })
+                .collect())
+        }
+    }
+
+    fn simulation(
+        capacity: u32,
+        polled: bool,
+        conditions: NetworkConditions,
+        seed: u64,
+    ) -> Simulation<MaxPeer, BroadcastRelay> {
+        let data_managers = (0..capacity)
+            .map(|_| {
+                let keys = PeerKeys::new(Box::new(MemoryKeyStore::new()), None);
+                ProtocolDataManager::new(capacity, Vec::new(), keys)
+            })
+            .collect();
+        Simulation::new(
+            BroadcastRelay::new(capacity, polled),
+            conditions,
+            seed,
+            data_managers,
+        )
+    }
+
+    fn unreliable() -> NetworkConditions {
+        NetworkConditions {
+            reorder: true,
+            max_delay: 5,
+            drop_rate: 0.2,
+        }
+    }
+
+    #[test]
+    fn test_run_reliable() {
+        let capacity = 4;
+        let mut sim = simulation(capacity, false, NetworkConditions::reliable(), 1);
+        let report = sim.run(0).unwrap();
+        assert_eq!(report.rounds, 2);
+        assert_eq!(report.dropped, 0);
+        // every peer received the messages of the others in both rounds
+        assert_eq!(report.delivered, 2 * capacity * capacity);
+        for peer in sim.into_peers() {
+            assert_eq!(peer.maxes.len(), capacity as usize);
+            assert!(peer.maxes.values().all(|max| *max == capacity));
+        }
+    }
+
+    #[test]
+    fn test_run_is_deterministic() {
+        let conditions = NetworkConditions {
+            reorder: true,
+            max_delay: 3,
+            drop_rate: 0.0,
+        };
+        let first = simulation(5, false, conditions.clone(), 7).run(0).unwrap();
+        let second = simulation(5, false, conditions, 7).run(0).unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_queries_recover_dropped_messages() {
+        let capacity = 3;
+        let mut sim = simulation(capacity, true, unreliable(), 3);
+        let report = sim.run(0).unwrap();
+        assert!(report.dropped > 0);
+        for peer in sim.into_peers() {
+            assert!(peer.maxes.values().all(|max| *max == capacity));
+        }
+    }
+
+    #[test]
+    fn test_dropped_messages_stall_pushed_relay() {
+        let mut sim = simulation(3, false, unreliable(), 3);
+        let report = sim.run(0).unwrap();
+        assert!(!report.aborts.is_empty());
+        for (peer_id, abort) in report.aborts {
+            match abort {
+                ProtocolAbort::Unresponsive(culprit) => assert_ne!(culprit, peer_id),
+                other => panic!("Expected unresponsive peer, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! Subscription to the transactions of a session over the websocket endpoint of a Tendermint node.
+//! The relay application tags every message and abort it delivers with an event,
+//! so a peer subscribed to its session receives them as the blocks holding them are committed
+use std::collections::BTreeMap;
+use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::json;
+use subtle_encoding::base64;
+use tungstenite::{Message, WebSocket};
+use url::Url;
+
+use crate::transport::TransportError;
+use mmpc_server_common::{
+    PeerIdentifier, SessionIdentifier, PEER_ATTRIBUTE, RELAY_EVENT, ROUND_ATTRIBUTE,
+    SESSION_ATTRIBUTE,
+};
+
+// identifier of the subscribe request, the node sends the events of the subscription with it
+const SUBSCRIBE_ID: &str = "relay";
+
+/// A transaction of the session, committed in a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxEvent {
+    /// the attributes of the relay event of the transaction, by their name
+    pub attributes: BTreeMap<String, String>,
+    pub tx: Vec<u8>,
+    /// the log of the application on delivering the transaction
+    pub log: String,
+}
+
+impl TxEvent {
+    fn attribute<T: std::str::FromStr>(&self, name: &str) -> Option<T> {
+        self.attributes.get(name)?.parse().ok()
+    }
+
+    /// The round of the message the transaction holds, None for an abort
+    pub fn round(&self) -> Option<u32> {
+        self.attribute(ROUND_ATTRIBUTE)
+    }
+
+    /// The peer that sent the message the transaction holds, None for an abort
+    pub fn peer(&self) -> Option<PeerIdentifier> {
+        self.attribute(PEER_ATTRIBUTE)
+    }
+}
+
+// The JSON-RPC messages the node sends over the websocket, the response to the subscribe
+// request and the events of the subscription.
+// The events of a transaction are listed by `<type>.<attribute>`
+#[derive(Deserialize)]
+struct RpcMessage {
+    result: Option<RpcResult>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct RpcResult {
+    data: Option<EventData>,
+    #[serde(default)]
+    events: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct EventData {
+    value: EventValue,
+}
+
+#[derive(Deserialize)]
+struct EventValue {
+    #[serde(rename = "TxResult")]
+    tx_result: TxResult,
+}
+
+#[derive(Deserialize)]
+struct TxResult {
+    tx: String,
+    result: DeliverTxResult,
+}
+
+#[derive(Deserialize)]
+struct DeliverTxResult {
+    #[serde(default)]
+    log: String,
+}
+
+/// Returns the websocket endpoint of the node with the given RPC address,
+/// such as `ws://127.0.0.1:26657/websocket` for `tcp://127.0.0.1:26657`
+pub fn websocket_url(rpc_addr: &tendermint::net::Address) -> Option<String> {
+    match rpc_addr {
+        tendermint::net::Address::Tcp { host, port, .. } => {
+            Some(format!("ws://{}:{}/websocket", host, port))
+        }
+        _ => None,
+    }
+}
+
+// The query of the events of the transactions of the session
+fn session_query(session_id: SessionIdentifier) -> String {
+    format!(
+        "tm.event = 'Tx' AND {}.{} = '{}'",
+        RELAY_EVENT, SESSION_ATTRIBUTE, session_id
+    )
+}
+
+fn unreachable<E: ToString>(err: E) -> TransportError {
+    TransportError::Unreachable(err.to_string())
+}
+
+pub struct Subscription {
+    socket: WebSocket<TcpStream>,
+}
+
+impl Subscription {
+    /// Subscribes to the transactions of the session at the websocket endpoint of a node.
+    /// Reading the next event waits up to `timeout` for it to be committed
+    pub fn connect(
+        url: &str,
+        session_id: SessionIdentifier,
+        timeout: Duration,
+    ) -> Result<Subscription, TransportError> {
+        let url = Url::parse(url).map_err(unreachable)?;
+        let (mut socket, _) = tungstenite::connect(url).map_err(unreachable)?;
+        socket
+            .get_ref()
+            .set_read_timeout(Some(timeout))
+            .map_err(unreachable)?;
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": SUBSCRIBE_ID,
+            "method": "subscribe",
+            "params": { "query": session_query(session_id) },
+        });
+        socket
+            .write_message(Message::Text(request.to_string()))
+            .map_err(unreachable)?;
+        debug!("Subscribed to the transactions of session {}", session_id);
+        Ok(Subscription { socket })
+    }
+
+    /// Returns the next transaction of the session, or None if none was committed in time.
+    /// Fails if the node rejected the subscription or closed the connection
+    pub fn next_event(&mut self) -> Result<Option<TxEvent>, TransportError> {
+        loop {
+            let text = match self.socket.read_message() {
+                Ok(Message::Text(text)) => text,
+                Ok(Message::Close(_)) => {
+                    return Err(unreachable("Subscription closed by the node"))
+                }
+                // pings are answered by the socket itself
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(ref err))
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(err) => return Err(unreachable(err)),
+            };
+            let msg: RpcMessage = match serde_json::from_str(&text) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    warn!("Ignoring malformed message of the node: {}", err);
+                    continue;
+                }
+            };
+            if let Some(err) = msg.error {
+                return Err(TransportError::Rejected(err.to_string()));
+            }
+            // the response to the subscribe request carries no event
+            let (data, events) = match msg.result {
+                Some(RpcResult {
+                    data: Some(data),
+                    events,
+                }) => (data, events),
+                _ => continue,
+            };
+            let tx = match base64::decode(&data.value.tx_result.tx) {
+                Ok(tx) => tx,
+                Err(err) => {
+                    warn!("Ignoring transaction that does not decode: {}", err);
+                    continue;
+                }
+            };
+            let prefix = format!("{}.", RELAY_EVENT);
+            let attributes = events
+                .into_iter()
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .filter_map(|(key, values)| {
+                    Some((key[prefix.len()..].to_string(), values.into_iter().next()?))
+                })
+                .collect();
+            return Ok(Some(TxEvent {
+                attributes,
+                tx,
+                log: data.value.tx_result.result.log,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Subscription, TxEvent};
+    use crate::transport::TransportError;
+
+    use serde_json::json;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use subtle_encoding::base64;
+    use tungstenite::Message;
+
+    // A mock of the websocket endpoint of a node, that confirms the subscription
+    // and sends the given messages. Returns the endpoint along with the query subscribed to
+    fn mock_node(messages: Vec<String>) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/websocket", listener.local_addr().unwrap());
+        let node = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let request: serde_json::Value = match socket.read_message().unwrap() {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                other => panic!("Unexpected request {:?}", other),
+            };
+            let confirmation = json!({"jsonrpc": "2.0", "id": request["id"], "result": {}});
+            socket
+                .write_message(Message::Text(confirmation.to_string()))
+                .unwrap();
+            for msg in messages {
+                socket.write_message(Message::Text(msg)).unwrap();
+            }
+            // wait for the peer to close the subscription
+            while socket.read_message().is_ok() {}
+            request["params"]["query"].as_str().unwrap().to_string()
+        });
+        (url, node)
+    }
+
+    fn tx_event(tx: &[u8], log: &str, events: serde_json::Value) -> String {
+        let tx = String::from_utf8(base64::encode(tx)).unwrap();
+        json!({
+            "jsonrpc": "2.0",
+            "id": "relay#event",
+            "result": {
+                "query": "",
+                "data": {
+                    "type": "tendermint/event/Tx",
+                    "value": {
+                        "TxResult": {"height": "3", "index": 0, "tx": tx, "result": {"log": log}}
+                    }
+                },
+                "events": events
+            }
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_subscription() {
+        let (url, node) = mock_node(vec![
+            tx_event(
+                b"message",
+                "{}",
+                json!({
+                    "relay.session": ["0"],
+                    "relay.round": ["1"],
+                    "relay.peer": ["2"],
+                    "tm.event": ["Tx"]
+                }),
+            ),
+            tx_event(
+                b"abort",
+                "aborted",
+                json!({"relay.session": ["0"], "relay.abort": ["1"]}),
+            ),
+        ]);
+        let mut subscription = Subscription::connect(&url, 0, Duration::from_millis(100)).unwrap();
+
+        let event: TxEvent = subscription.next_event().unwrap().unwrap();
+        assert_eq!(event.tx, b"message".to_vec());
+        assert_eq!((event.round(), event.peer()), (Some(1), Some(2)));
+        assert_eq!(event.attributes.len(), 3);
+        let event = subscription.next_event().unwrap().unwrap();
+        assert_eq!(event.log, "aborted");
+        assert_eq!((event.round(), event.peer()), (None, None));
+        // nothing more was committed
+        assert_eq!(subscription.next_event(), Ok(None));
+
+        drop(subscription);
+        assert_eq!(
+            node.join().unwrap(),
+            "tm.event = 'Tx' AND relay.session = '0'"
+        );
+    }
+
+    #[test]
+    fn test_subscription_rejected() {
+        let error = json!({
+            "jsonrpc": "2.0",
+            "id": "relay",
+            "error": {"code": -32603, "message": "Internal error"}
+        });
+        let (url, _node) = mock_node(vec![error.to_string()]);
+        let mut subscription = Subscription::connect(&url, 0, Duration::from_millis(100)).unwrap();
+        match subscription.next_event() {
+            Err(TransportError::Rejected(_)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
@@ -1,331 +1,579 @@
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::time::Duration;
 
-use crate::peer::{Peer, ProtocolDataManager, MAX_CLIENTS};
-use log::{debug, error, info, warn};
+use crate::peer::ProtocolAbort;
+use crate::subscription::{websocket_url, Subscription};
+use crate::transport::{poll_interval, RoundMessage, Transport, TransportError, TransportFuture};
+use futures::future;
+use log::{debug, info, warn};
 
-use mmpc_server_common::common::*;
+use mmpc_server_common::encryption::EncryptionKeyPair;
+use mmpc_server_common::identity::{Identity, IdentityKey, PeerIdentities, SignedClientMessage};
+use mmpc_server_common::query::QueryPath;
 use mmpc_server_common::{
-    ClientMessage, MessagePayload, MissingMessagesRequest, PeerIdentifier, ProtocolIdentifier,
-    RelayMessage, ServerMessage, ServerMessageType, ServerResponse, StoredMessages,
+    AbortMessage, ClientMessage, MessagePayload, MissingMessagesReply, MissingMessagesRequest,
+    PeerIdentifier, PeersReply, ProtocolIdentifier, RelayMessage, ServerMessage, ServerResponse,
+    SessionIdentifier, StoredMessages, DEFAULT_SESSION_ID, MAX_PAGE_SIZE,
 };
 
-pub struct SessionClient<T>
-where
-    T: Peer,
-{
-    pub state: State<T>,
+// The messages of a round are queried every RETRY_TIMEOUT milliseconds, up to MAX_RETRY times.
+// A peer subscribed to the session waits up to RETRY_TIMEOUT for each message instead,
+// and queries the session only every QUERY_INTERVAL times for what the subscription missed
+const MAX_RETRY: u32 = 512;
+const RETRY_TIMEOUT: u64 = 200;
+const QUERY_INTERVAL: u32 = 25;
+
+/// Transport over the Tendermint relay. Messages are sent as transactions,
+/// and the messages of a round are queried from the application until all arrived
+pub struct TendermintTransport {
     pub client: tendermint::rpc::Client,
+    // identity every transaction of this peer is signed with
+    pub identity: Identity,
+    // identities of the peers, their messages must be signed by them
+    pub identities: PeerIdentities,
+    // key pair the other peers encrypt payloads to this peer with
+    pub encryption: EncryptionKeyPair,
+    // index of the peer in key generation, -1 for key generation itself
+    pub kg_index: i32,
+    // the session every transaction of this peer is for, the default session unless set
+    pub session_id: SessionIdentifier,
+    pub protocol_id: ProtocolIdentifier,
+    pub capacity: u32,
+    pub peer_id: PeerIdentifier,
+    pub stored_messages: StoredMessages<ClientMessage>,
+    // the signed envelope of every message stored, by its sender and payload,
+    // the evidence an abort blaming its sender is reported with
+    envelopes: BTreeMap<(PeerIdentifier, MessagePayload), SignedClientMessage>,
+    // websocket endpoint of the node, the transactions of the session are subscribed to there
+    websocket_url: Option<String>,
+    subscription: Option<Subscription>,
 }
 
-impl<T: Peer> SessionClient<T> {
+impl TendermintTransport {
     pub fn new(
-        client_addr: SocketAddr,
         server_addr: &tendermint::net::Address,
-        client_index: u32,
-        capacity: u32,
-        message: Vec<u8>,
-    ) -> SessionClient<T> {
-        let protocol_id = 1;
-        SessionClient {
-            state: State::new(protocol_id, capacity, client_addr, client_index, message),
+        identity: Identity,
+        kg_index: i32,
+    ) -> TendermintTransport {
+        TendermintTransport {
             client: tendermint::rpc::Client::new(server_addr).unwrap(),
+            identity,
+            identities: PeerIdentities::new(),
+            encryption: EncryptionKeyPair::generate(),
+            kg_index,
+            session_id: DEFAULT_SESSION_ID,
+            protocol_id: 0,
+            capacity: 0,
+            peer_id: 0,
+            stored_messages: StoredMessages::new(),
+            envelopes: BTreeMap::new(),
+            websocket_url: websocket_url(server_addr),
+            subscription: None,
         }
     }
 }
 
-impl<T: Peer> SessionClient<T> {
-    pub fn query(&self) -> BTreeMap<u32, ClientMessage> {
-        let current_step = self.state.data_manager.data_holder.current_step();
-        debug!("Current step {}", current_step);
-        let capacity = self.state.data_manager.data_holder.capacity();
-        debug!("Capacity {}", capacity);
-        let mut missing_clients = self
-            .state
+impl TendermintTransport {
+    // The error of a response of the application, an aborted session returns its abort.
+    // The messages of a round the application replies with are no error
+    fn server_error(&mut self, log: &str) -> Option<TransportError> {
+        let server_msg: ServerMessage = serde_json::from_str(log).ok()?;
+        if let Some(abort) = server_msg.abort {
+            return Some(self.handle_abort(abort));
+        }
+        match server_msg.response {
+            Some(ServerResponse::ErrorResponse(err)) => {
+                Some(TransportError::Rejected(err.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    // An abort blaming a culprit is returned along with the messages of the culprit
+    // in its evidence, only the messages the culprit signed are taken.
+    // An abort of the relay on a missed deadline names the peers that did not respond
+    fn handle_abort(&mut self, abort: AbortMessage) -> TransportError {
+        warn!("Session aborted by peer {}", abort.peer_number);
+        if !abort.unresponsive.is_empty() {
+            return TransportError::Unresponsive(abort.unresponsive);
+        }
+        let (culprit, abort_of_peer) = match abort.culprit.and_then(|culprit| {
+            ProtocolAbort::from_reason(abort.reason, culprit).map(|a| (culprit, a))
+        }) {
+            Some(blame) => blame,
+            None => return TransportError::Aborted,
+        };
+        let evidence = abort
+            .evidence
+            .iter()
+            .filter_map(|envelope| self.identities.verify(envelope, self.session_id).ok())
+            .filter(|relay_msg| relay_msg.peer_number == culprit)
+            .map(|relay_msg| relay_msg.message)
+            .collect();
+        TransportError::AbortedBlaming(abort.peer_number, abort_of_peer, evidence)
+    }
+
+    // The missing messages of the round are read a page at a time,
+    // a round of n peers takes at most n / MAX_PAGE_SIZE + 1 queries
+    pub fn query(
+        &mut self,
+        round: u32,
+    ) -> Result<BTreeMap<u32, SignedClientMessage>, TransportError> {
+        let missing_clients = self
             .stored_messages
-            .get_missing_clients_vector(current_step, capacity);
+            .get_missing_clients_vector(round, self.capacity);
 
         debug!("Missing: {:?}", missing_clients);
 
         // No need to query if nothing is missing
         if missing_clients.is_empty() {
-            return BTreeMap::new();
+            return Ok(BTreeMap::new());
         }
 
-        if missing_clients.len() > MAX_CLIENTS {
-            missing_clients.truncate(MAX_CLIENTS);
+        let mut request = MissingMessagesRequest {
+            round,
+            missing_clients,
+            cursor: None,
+            page_size: Some(MAX_PAGE_SIZE),
+            session_id: Some(self.session_id),
+        };
+        let mut messages = BTreeMap::new();
+        loop {
+            let tx = serde_json::to_string(&request).unwrap();
+            let reply: MissingMessagesReply = match self.client.abci_query(None, tx, None, false) {
+                Ok(response) => {
+                    if let Some(err) = self.server_error(&response.log.to_string()) {
+                        return Err(err);
+                    }
+                    response
+                        .value
+                        .and_then(|value| serde_json::from_slice(&value).ok())
+                        .unwrap_or_default()
+                }
+                Err(_) => {
+                    warn!("Query not successful, returning the messages read so far");
+                    break;
+                }
+            };
+            debug!(
+                "Read {} of {} missing messages",
+                messages.len() + reply.missing_messages.len(),
+                reply.total
+            );
+            messages.extend(reply.missing_messages);
+            match reply.next_cursor {
+                Some(cursor) => request.cursor = Some(cursor),
+                None => break,
+            }
         }
-        debug!("Missing requested: {:?}", missing_clients);
+        Ok(messages)
+    }
 
-        let request = MissingMessagesRequest {
-            round: current_step,
-            missing_clients: missing_clients,
-        };
-        let tx = serde_json::to_string(&request).unwrap();
-        match self.client.abci_query(None, tx, None, false) {
-            Ok(response) => {
-                let response_log = response.log;
-                let server_response: BTreeMap<u32, ClientMessage> =
-                    match serde_json::from_str(&response_log.to_string()) {
-                        Ok(server_response) => server_response,
-                        Err(_) => BTreeMap::new(),
-                    };
-                server_response
+    fn register_peer(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> Result<PeerIdentifier, TransportError> {
+        self.protocol_id = protocol_id;
+        self.capacity = capacity;
+        self.subscribe();
+        let mut msg = ClientMessage::new();
+        msg.set_register(
+            protocol_id,
+            capacity,
+            self.kg_index,
+            Some(self.encryption.public_key()),
+        );
+
+        debug!("Register message {:?}", msg);
+        let server_response = self.commit_message(msg)?;
+        match server_response.response {
+            Some(ServerResponse::Register(peer_id)) => {
+                info!("Registered OK");
+                self.peer_id = peer_id;
+                Ok(peer_id)
             }
-            Err(_) => {
-                warn!("Query not successful, returning empty message");
-                BTreeMap::new()
+            Some(ServerResponse::ErrorResponse(err)) => {
+                Err(TransportError::Rejected(err.to_string()))
             }
+            _ => Err(TransportError::Rejected(String::from(
+                "No response to register",
+            ))),
         }
     }
 
-    pub fn register(&mut self, index: u32, capacity: u32, kg_index: i32) -> ServerMessage {
+    /// Creates a session of the protocol for the number of peers, which only the participants
+    /// can register to if any are listed, and sets it as the session of this peer.
+    /// Returns the identifier the relay numbered the session with
+    pub fn create_session(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        participants: Vec<IdentityKey>,
+    ) -> Result<SessionIdentifier, TransportError> {
         let mut msg = ClientMessage::new();
-        let port = 8080 + index;
-        let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
-        // No index to begin with
-        msg.set_register(client_addr, self.state.protocol_id, capacity, kg_index);
+        msg.set_create_session(protocol_id, capacity, participants);
+        let server_response = self.commit_message(msg)?;
+        match server_response.response {
+            Some(ServerResponse::Session(session_id)) => {
+                info!("Created session {}", session_id);
+                self.session_id = session_id;
+                Ok(session_id)
+            }
+            Some(ServerResponse::ErrorResponse(err)) => {
+                Err(TransportError::Rejected(err.to_string()))
+            }
+            _ => Err(TransportError::Rejected(String::from(
+                "No response to create session",
+            ))),
+        }
+    }
 
-        debug!("Register message {:?}", msg);
-        let tx =
-            tendermint::abci::transaction::Transaction::new(serde_json::to_string(&msg).unwrap());
-        let response = self.client.broadcast_tx_commit(tx).unwrap();
-        let server_response = response.clone().deliver_tx.log.unwrap();
-        info!("Registered OK");
-        debug!("ServerResponse {:?}", server_response);
+    // Signs the message for the session of this peer
+    fn sign(&self, mut msg: ClientMessage) -> SignedClientMessage {
+        msg.set_session(self.session_id);
+        self.identity.sign(&msg)
+    }
+
+    // Sends the message in a transaction and waits for it to be committed,
+    // returns the response of the application to it
+    fn commit_message(&mut self, msg: ClientMessage) -> Result<ServerMessage, TransportError> {
+        let envelope = self.sign(msg);
+        let tx = tendermint::abci::transaction::Transaction::new(
+            serde_json::to_string(&envelope).unwrap(),
+        );
+        let response = self
+            .client
+            .broadcast_tx_commit(tx)
+            .map_err(|err| TransportError::Unreachable(err.to_string()))?;
+        // A message rejected on check carries the error response in the check log
+        let tx_result = if response.check_tx.code.is_err() {
+            response.check_tx
+        } else {
+            response.deliver_tx
+        };
         let server_response: ServerMessage =
-            serde_json::from_str(&response.deliver_tx.log.unwrap().to_string()).unwrap();
+            serde_json::from_str(&tx_result.log.unwrap().to_string())
+                .map_err(|err| TransportError::Rejected(err.to_string()))?;
         debug!("ServerResponse {:?}", server_response);
-        // TODO Add Error checks etc
-        self.state.registered = true;
-        return server_response;
+        Ok(server_response)
+    }
+
+    /// Queries the signed register messages of the peers registered so far,
+    /// and pins the identities and encryption keys they registered with.
+    /// Returns the number of peers whose encryption keys are known
+    pub fn query_peers(&mut self) -> usize {
+        let path = QueryPath::Peers(self.session_id).to_string();
+        let response = match self
+            .client
+            .abci_query(path.parse().ok(), String::new(), None, false)
+        {
+            Ok(response) => response,
+            Err(_) => {
+                warn!("Peers query not successful");
+                return 0;
+            }
+        };
+        let reply: PeersReply = response
+            .value
+            .and_then(|value| serde_json::from_slice(&value).ok())
+            .unwrap_or_default();
+        let mut known = 0;
+        for (peer_id, registration) in reply.registrations.iter() {
+            match self.identities.add_peer(*peer_id, registration) {
+                Ok(()) => {
+                    if self.identities.encryption_key(peer_id).is_some() {
+                        known += 1;
+                    }
+                }
+                Err(err) => warn!("Invalid registration of peer {}: {}", peer_id, err),
+            }
+        }
+        known
     }
 
-    pub fn send_message(&self, msg: ClientMessage) -> BTreeMap<u32, ClientMessage> {
+    pub fn send_message(
+        &mut self,
+        msg: ClientMessage,
+    ) -> Result<BTreeMap<u32, SignedClientMessage>, TransportError> {
         debug!("Sending message {:?}", msg);
-        let tx =
-            tendermint::abci::transaction::Transaction::new(serde_json::to_string(&msg).unwrap());
-        let server_response = match self.client.broadcast_tx_commit(tx) {
+        let envelope = self.sign(msg);
+        let tx = tendermint::abci::transaction::Transaction::new(
+            serde_json::to_string(&envelope).unwrap(),
+        );
+        match self.client.broadcast_tx_commit(tx) {
             Ok(response) => {
-                let server_response = response.clone().deliver_tx.log.unwrap();
+                // A message rejected on check carries the error response in the check log
+                let tx_result = if response.check_tx.code.is_err() {
+                    response.check_tx
+                } else {
+                    response.deliver_tx
+                };
+                let server_response = tx_result.log.map(|log| log.to_string()).unwrap_or_default();
                 debug!("ServerResponse {:?}", server_response);
-                let server_response: BTreeMap<u32, ClientMessage> =
-                    serde_json::from_str(&response.deliver_tx.log.unwrap().to_string()).unwrap();
-                server_response
+                if let Some(err) = self.server_error(&server_response) {
+                    return Err(err);
+                }
+                Ok(serde_json::from_str(&server_response).unwrap_or_default())
             }
             Err(_) => {
                 warn!("Unable to include message in block, returning empty response");
-                BTreeMap::new()
+                Ok(BTreeMap::new())
             }
-        };
-        return server_response;
+        }
     }
 
-    // Stores the server response to the stored messages
-    pub fn store_server_response(&mut self, messages: &BTreeMap<u32, ClientMessage>) {
-        let round = self.state.data_manager.data_holder.current_step();
-        for (client_idx, msg) in messages {
-            self.state
-                .stored_messages
-                .update(round, *client_idx, msg.clone());
+    // Subscribes to the transactions of the session, before any is sent.
+    // Without a subscription the peer polls for the messages of each round
+    fn subscribe(&mut self) {
+        let url = match &self.websocket_url {
+            Some(url) => url,
+            None => return,
+        };
+        let timeout = Duration::from_millis(RETRY_TIMEOUT);
+        match Subscription::connect(url, self.session_id, timeout) {
+            Ok(subscription) => self.subscription = Some(subscription),
+            Err(err) => warn!("Unable to subscribe to the session, polling: {:?}", err),
         }
     }
 
-    pub fn handle_relay_message(&mut self, client_msg: ClientMessage) -> Option<ClientMessage> {
-        let msg = client_msg.relay_message.unwrap();
-        let new_message;
-        let next = self.state.handle_relay_message(msg.clone());
-        match next {
-            Some(next_msg) => {
-                new_message = Some(self.state.generate_relay_message(next_msg.clone()));
+    // Stores the messages the subscription receives, until the messages of the round
+    // are all stored or none arrives in time. A subscription that fails is dropped for polling
+    fn receive_events(&mut self, round: u32) -> Result<(), TransportError> {
+        while self.stored_messages.get_number_messages(round) != self.capacity as usize {
+            let event = match self.subscription.as_mut().map(Subscription::next_event) {
+                Some(Ok(Some(event))) => event,
+                Some(Ok(None)) | None => return Ok(()),
+                Some(Err(err)) => {
+                    warn!("Subscription failed, polling: {:?}", err);
+                    self.subscription = None;
+                    return Ok(());
+                }
+            };
+            if let Some(err) = self.server_error(&event.log) {
+                return Err(err);
             }
-            None => {
-                new_message = Some(ClientMessage::new());
+            // only relay messages are tagged with a round and a peer
+            let (event_round, peer_id) = match (event.round(), event.peer()) {
+                (Some(event_round), Some(peer_id)) => (event_round, peer_id),
+                _ => continue,
+            };
+            match serde_json::from_slice(&event.tx) {
+                Ok(envelope) => {
+                    let mut messages = BTreeMap::new();
+                    messages.insert(peer_id, envelope);
+                    self.store_server_response(event_round, &messages);
+                }
+                Err(err) => warn!("Ignoring transaction of peer {}: {}", peer_id, err),
             }
         }
-        new_message
+        Ok(())
     }
 
-    pub fn generate_client_answer(&mut self, msg: ServerMessage) -> Option<ClientMessage> {
-        // let last_message = self.state.last_message.clone();
-        let mut new_message = None;
-        let msg_type = msg.msg_type();
-        match msg_type {
-            ServerMessageType::Response => {
-                let next = self.state.handle_server_response(&msg);
-                match next {
-                    Ok(next_msg) => {
-                        new_message = Some(next_msg.clone());
-                    }
-                    Err(_) => {
-                        error!("Error in handle_server_response");
-                    }
+    // Stores the server response to the stored messages of the round.
+    // Only messages signed for the round by the identity the peer registered with are stored
+    pub fn store_server_response(
+        &mut self,
+        round: u32,
+        messages: &BTreeMap<u32, SignedClientMessage>,
+    ) {
+        if messages
+            .keys()
+            .any(|client_idx| self.identities.get(client_idx).is_none())
+        {
+            self.query_peers();
+        }
+        for (client_idx, envelope) in messages {
+            let relay_message = match self
+                .identities
+                .verify_round(envelope, self.session_id, round)
+            {
+                Ok(relay_message) => relay_message,
+                Err(err) => {
+                    warn!("Dropping message of client {}: {}", client_idx, err);
+                    continue;
                 }
+            };
+            if relay_message.peer_number != *client_idx {
+                warn!(
+                    "Dropping message of client {} sent as another client",
+                    client_idx
+                );
+                continue;
             }
-            // TODO: better cases separation, this is a placeholder
-            ServerMessageType::RelayMessage => {
-                new_message = Some(ClientMessage::new());
-            }
-            ServerMessageType::Abort => {
-                info!("Got abort message");
-                //Ok(MessageProcessResult::NoMessage)
-                new_message = Some(ClientMessage::new());
-            }
-            ServerMessageType::Undefined => {
-                new_message = Some(ClientMessage::new());
-                //panic!("Got undefined message: {:?}",msg);
-            }
-        };
-        new_message
-    }
-}
-
-/// Inner client state, responsible for parsing server responses and producing the next message
-pub struct State<T>
-where
-    T: Peer,
-{
-    pub registered: bool,
-    pub protocol_id: ProtocolIdentifier,
-    pub client_addr: SocketAddr,
-    pub data_manager: ProtocolDataManager<T>,
-    pub last_message: ClientMessage,
-    pub bc_dests: Vec<ProtocolIdentifier>,
-    pub stored_messages: StoredMessages,
-}
-
-impl<T: Peer> State<T> {
-    pub fn new(
-        protocol_id: ProtocolIdentifier,
-        capacity: u32,
-        client_addr: SocketAddr,
-        client_index: u32,
-        message: Vec<u8>,
-    ) -> State<T>
-    where
-        T: Peer,
-    {
-        let data_m: ProtocolDataManager<T> =
-            ProtocolDataManager::new(capacity, message, client_index);
-        State {
-            registered: false,
-            protocol_id,
-            client_addr,
-            last_message: ClientMessage::new(),
-            bc_dests: vec![0],
-            data_manager: data_m,
-            stored_messages: StoredMessages::new(),
+            self.envelopes.insert(
+                (*client_idx, relay_message.message.clone()),
+                envelope.clone(),
+            );
+            let mut msg = ClientMessage::new();
+            msg.relay_message = Some(relay_message);
+            self.stored_messages.update(round, *client_idx, msg);
         }
     }
-}
 
-impl<T: Peer> State<T> {
-    fn handle_relay_message(&mut self, relay_msg: RelayMessage) -> Option<MessagePayload> {
-        // parse relay message
-        let from = relay_msg.peer_number;
-        if from == self.data_manager.data_holder.peer_id() {
-            debug!("-------self message accepted ------\n ");
+    // The messages of the round once all are stored, with the private payloads to this peer decrypted
+    fn round_messages(&mut self, round: u32) -> Option<Vec<RoundMessage>> {
+        if self.stored_messages.get_number_messages(round) != self.capacity as usize {
+            return None;
+        }
+        let relay_messages: Vec<RelayMessage> = self
+            .stored_messages
+            .get_messages_vector_client_message(round)
+            .into_iter()
+            .filter_map(|msg| msg.relay_message)
+            .collect();
+        // The encryption keys of the peers are only needed once they send private payloads
+        if relay_messages.iter().any(|msg| {
+            msg.is_encrypted() && self.identities.encryption_key(&msg.peer_number).is_none()
+        }) {
+            self.query_peers();
         }
-        let payload = relay_msg.message;
-        self.data_manager.get_next_message(from, payload)
+        let messages = relay_messages
+            .into_iter()
+            .map(|relay_msg| {
+                let from = relay_msg.peer_number;
+                let private_payload = match (
+                    relay_msg.ciphertext_for(self.peer_id),
+                    self.identities.encryption_key(&from),
+                ) {
+                    (Some(ciphertext), Some(sender_key)) => {
+                        match self
+                            .encryption
+                            .decrypt(sender_key, from, self.peer_id, ciphertext)
+                        {
+                            Ok(private_payload) => Some(private_payload),
+                            Err(err) => {
+                                warn!("Private payload of peer {}: {}", from, err);
+                                None
+                            }
+                        }
+                    }
+                    (Some(_), None) => {
+                        warn!(
+                            "No encryption key of peer {}, ignoring private payload",
+                            from
+                        );
+                        None
+                    }
+                    (None, _) => None,
+                };
+                RoundMessage {
+                    from,
+                    payload: relay_msg.message,
+                    private_payload,
+                }
+            })
+            .collect();
+        Some(messages)
     }
 
-    fn generate_relay_message(&self, payload: MessagePayload) -> ClientMessage {
-        let _msg = ClientMessage::new();
-        // create relay message
-        let mut relay_message = RelayMessage::new(
-            self.data_manager.data_holder.peer_id(),
-            self.protocol_id,
-            self.client_addr,
-        );
-        let to: Vec<u32> = self.bc_dests.clone();
-
+    // The message is broadcast, with the private items encrypted to each of their recipients
+    fn generate_relay_message(
+        &mut self,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> ClientMessage {
+        if private_items
+            .keys()
+            .any(|peer_id| self.identities.encryption_key(peer_id).is_none())
+        {
+            self.query_peers();
+        }
+        let mut relay_message = RelayMessage::new(self.peer_id, self.protocol_id);
         let mut client_message = ClientMessage::new();
 
-        relay_message.set_message_params(to, String::from(payload));
+        relay_message.set_message_params(vec![0], payload);
+        relay_message.set_round(round);
+        for (recipient, private_payload) in private_items.iter() {
+            match self.identities.encryption_key(recipient) {
+                Some(recipient_key) => {
+                    let ciphertext = self.encryption.encrypt(
+                        recipient_key,
+                        self.peer_id,
+                        *recipient,
+                        private_payload,
+                    );
+                    relay_message.ciphertexts.insert(*recipient, ciphertext);
+                }
+                None => warn!("No encryption key of peer {}", recipient),
+            }
+        }
         client_message.relay_message = Some(relay_message);
         client_message
     }
+}
 
-    fn handle_register_response(&mut self, peer_id: PeerIdentifier) -> Result<ClientMessage, ()> {
-        info!("Peer identifier: {}", peer_id);
-        // Set the session parameters
-        let message = self
-            .data_manager
-            .initialize_data(peer_id)
-            .unwrap_or_else(|| panic!("failed to initialize"));
-        Ok(self.generate_relay_message(message.clone()))
+impl Transport for TendermintTransport {
+    fn register(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> TransportFuture<'_, PeerIdentifier> {
+        Box::new(future::result(self.register_peer(protocol_id, capacity)))
     }
 
-    fn get_last_message(&self) -> Option<ClientMessage> {
-        let last_msg = self.last_message.clone();
-        return Some(last_msg.clone());
+    fn peer_identity(&self, peer_id: PeerIdentifier) -> Option<IdentityKey> {
+        self.identities.get(&peer_id).cloned()
     }
 
-    fn handle_error_response(&mut self, err_msg: &str) -> Result<ClientMessage, &'static str> {
-        match err_msg {
-            resp if resp == String::from(NOT_YOUR_TURN) => {
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(msg) => {
-                        return Ok(msg.clone());
-                    }
-                    None => {
-                        panic!("No message to resend");
-                    }
-                }
-            }
-            not_initialized_resp if not_initialized_resp == String::from(STATE_NOT_INITIALIZED) => {
-                debug!("Not initialized, sending again");
-                let last_msg = self.get_last_message();
-                match last_msg {
-                    Some(_) => {
-                        // If protocol is not initialized, wait for a message from the server
-                        return Ok(ClientMessage::new());
-                    }
-                    None => {
-                        panic!("No message to resend");
-                    }
-                }
+    fn send(
+        &mut self,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> TransportFuture<'_, ()> {
+        let msg = self.generate_relay_message(round, payload, private_items);
+        let result = self
+            .send_message(msg)
+            .map(|server_response| self.store_server_response(round, &server_response));
+        Box::new(future::result(result))
+    }
+
+    fn receive_round(&mut self, round: u32) -> TransportFuture<'_, Vec<RoundMessage>> {
+        let mut polls = 0;
+        poll_interval(RETRY_TIMEOUT, MAX_RETRY, move || {
+            if let Some(messages) = self.round_messages(round) {
+                return Ok(Some(messages));
             }
-            _ => {
-                warn!("didn't handle error correctly");
-                return Err("error response handling failed");
+            // the first query returns the messages committed before the peer waited for the round,
+            // later ones what the subscription can not deliver, such as an abort on a deadline
+            if self.subscription.is_none() || polls % QUERY_INTERVAL == 0 {
+                let server_response = self.query(round)?;
+                self.store_server_response(round, &server_response);
             }
-        }
+            polls += 1;
+            self.receive_events(round)?;
+            Ok(self.round_messages(round))
+        })
     }
 
-    fn handle_server_response(
+    /// The abort is recorded on the chain, the application checks the evidence
+    /// is signed by the culprit before it blames it
+    fn abort(
         &mut self,
-        msg: &ServerMessage,
-    ) -> Result<ClientMessage, &'static str> {
-        let server_response = msg.response.clone().unwrap();
-        match server_response {
-            ServerResponse::Register(peer_id) => {
-                let client_message = self.handle_register_response(peer_id);
-                match client_message {
-                    Ok(_msg) => {
-                        debug!("sending peers first message: {:#?}", _msg);
-                        return Ok(_msg.clone());
-                    }
-                    Err(_) => {
-                        error!("error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
-            }
-            ServerResponse::ErrorResponse(err_msg) => {
-                let err_msg_slice: &str = &err_msg[..];
-                let msg = self.handle_error_response(err_msg_slice);
-                match msg {
-                    Ok(_msg) => return Ok(_msg),
-                    Err(_) => {
-                        error!("error occured");
-                        return Ok(ClientMessage::new());
-                    }
-                }
-            }
-            ServerResponse::NoResponse => unimplemented!(),
-        }
+        abort: &ProtocolAbort,
+        evidence: Vec<MessagePayload>,
+    ) -> TransportFuture<'_, ()> {
+        let culprit = abort.culprit();
+        let evidence = evidence
+            .into_iter()
+            .filter_map(|payload| self.envelopes.get(&(culprit, payload)).cloned())
+            .collect();
+        let mut msg = ClientMessage::new();
+        msg.abort = Some(AbortMessage::blame(
+            self.peer_id,
+            self.protocol_id,
+            abort.reason(),
+            culprit,
+            evidence,
+        ));
+        // the session is aborted once the abort is included, with the abort of this peer
+        // or of a peer that aborted before it
+        let result = match self.send_message(msg) {
+            Err(TransportError::Unreachable(err)) => Err(TransportError::Unreachable(err)),
+            _ => Ok(()),
+        };
+        Box::new(future::result(result))
     }
 }
@@ -0,0 +1,826 @@
+/// Client of the Tokio relay server, which relays the messages of the peers in turns
+/// over a TCP connection. The protocol itself is run by a `Peer`, as with the Tendermint client
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use futures::sync::mpsc;
+use futures::{future, Async, Future, Poll, Sink, Stream};
+use log::{debug, info, warn};
+use tokio::codec::Framed;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Core;
+
+use crate::key_store::PeerKeys;
+use crate::peer::{Peer, ProtocolAbort, ProtocolDataManager};
+use crate::transport::{RoundMessage, Transport, TransportError, TransportFuture};
+use relay_server_common::common::RelayError;
+use relay_server_common::encryption::EncryptionKeyPair;
+use relay_server_common::identity::{Identity, IdentityKey, PeerIdentities, SignedClientMessage};
+use relay_server_common::{
+    AbortMessage, AbortReason, Challenge, ClientMessage, ClientToServerCodec, MessagePayload,
+    PeerIdentifier, ProtocolIdentifier, RelayMessage, ResumptionToken, ServerMessage,
+    ServerMessageType, ServerResponse, SessionIdentifier,
+};
+
+// Number of attempts to resume the relay session after the connection is lost
+const MAX_RECONNECTS: u32 = 5;
+const RECONNECT_TIMEOUT: u64 = 1000;
+
+pub struct Client<T>
+where
+    T: Peer,
+{
+    pub registered: bool,
+    pub protocol_id: ProtocolIdentifier,
+    pub session_id: Option<SessionIdentifier>,
+    // token to resume the relay session with if the connection is lost
+    pub token: Option<ResumptionToken>,
+    // set once the peer has nothing more to send
+    pub finished: bool,
+    pub data_manager: ProtocolDataManager<T>,
+    pub last_message: ClientMessage,
+    pub bc_dests: Vec<ProtocolIdentifier>,
+    // identity every message to the server is signed with
+    pub identity: Identity,
+    // identities of the other peers, relay messages must be signed by their sender
+    pub identities: PeerIdentities,
+    // key pair the other peers encrypt payloads to this peer with
+    pub encryption: EncryptionKeyPair,
+    // the round each payload the peer relays is signed for. The peer generates a payload
+    // again until the other peers accept it, and it is sent again for the same round
+    pub rounds: BTreeMap<MessagePayload, u32>,
+    // every peer sends a single message in a round, so the round of a message
+    // is the number of messages relayed from its sender before it
+    pub relayed: BTreeMap<PeerIdentifier, u32>,
+}
+
+impl<T: Peer> Client<T> {
+    pub fn new(
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        session_id: Option<SessionIdentifier>,
+        identity: Identity,
+        message: Vec<u8>,
+        keys: PeerKeys,
+    ) -> Client<T>
+    where
+        T: Peer,
+    {
+        let data_m: ProtocolDataManager<T> = ProtocolDataManager::new(capacity, message, keys);
+        Client {
+            registered: false,
+            protocol_id,
+            session_id,
+            token: None,
+            finished: false,
+            last_message: ClientMessage::new(),
+            bc_dests: (1..(capacity + 1)).collect(),
+            identity,
+            identities: PeerIdentities::new(),
+            encryption: EncryptionKeyPair::generate(),
+            rounds: BTreeMap::new(),
+            relayed: BTreeMap::new(),
+            data_manager: data_m,
+        }
+    }
+
+    // Determines the response that should be sent to the server and returns a future
+    // for sending a response, or an ok() future if no action is needed
+    pub fn respond_to_server<E: 'static>(
+        &mut self,
+        msg: ServerMessage,
+        // A sender to pass messages to be written back to the server
+        tx: mpsc::Sender<SignedClientMessage>,
+    ) -> Box<dyn Future<Item = (), Error = E>> {
+        // register once the server sends the challenge of the connection,
+        // a peer that resumes its session sent the resume message already
+        if let Some(ServerResponse::Challenge(challenge)) = msg.response {
+            if self.generate_resume_message().is_some() {
+                return Box::new(futures::future::ok(()));
+            }
+            let register = self.generate_register_message(challenge);
+            let envelope = self
+                .identity
+                .sign(&register, self.session_id.unwrap_or(0), 0);
+            return Box::new(tx.clone().send(envelope).then(|_| Ok(())));
+        }
+        let response = self.generate_client_answer(msg).unwrap();
+        debug!("Returning {:?}", response);
+        if response.is_empty() {
+            Box::new(futures::future::ok(()))
+        } else {
+            let round = match response.relay_message {
+                Some(ref relay_message) => self.round_of(&relay_message.message),
+                None => 0,
+            };
+            let envelope = self
+                .identity
+                .sign(&response, self.session_id.unwrap_or(0), round);
+            Box::new(tx.clone().send(envelope).then(|_| Ok(())))
+        }
+    }
+
+    pub fn generate_client_answer(&mut self, msg: ServerMessage) -> Option<ClientMessage> {
+        let mut new_message = None;
+        let msg_type = msg.msg_type();
+        match msg_type {
+            ServerMessageType::Response => {
+                let next = self.handle_server_response(&msg);
+                match next {
+                    Ok(next_msg) => {
+                        new_message = Some(next_msg.clone());
+                    }
+                    Err(_) => {
+                        warn!("Error in handle_server_response");
+                    }
+                }
+            }
+            ServerMessageType::RelayMessage => match self.verify_relay_message(&msg) {
+                Ok(relay_msg) => {
+                    let next = self.handle_relay_message(relay_msg);
+                    match next {
+                        Some(next_msg) => {
+                            let private_items =
+                                self.data_manager.data_holder.get_next_private_items();
+                            new_message =
+                                Some(self.generate_relay_message(next_msg.clone(), private_items));
+                        }
+                        None => {
+                            let peer = &self.data_manager.data_holder;
+                            match (peer.abort(), peer.failure()) {
+                                (Some(abort), _) => warn!("Aborting the protocol: {}", abort),
+                                (None, Some(err)) => warn!("Peer failed: {}", err),
+                                (None, None) => info!("next item is None. Client is finished."),
+                            }
+                            self.finished = true;
+                            new_message = Some(ClientMessage::new());
+                        }
+                    }
+                }
+                Err(err) => {
+                    // a message its sender did not sign could be forged by the relay
+                    warn!("Dropping relay message: {}", err);
+                    new_message = Some(ClientMessage::new());
+                }
+            },
+            ServerMessageType::Abort => {
+                info!("Got abort message");
+                // an aborted session can not be resumed
+                self.token = None;
+                new_message = Some(ClientMessage::new());
+            }
+            ServerMessageType::Undefined => {
+                new_message = Some(ClientMessage::new());
+            }
+        };
+        if self.last_message.is_empty() {
+            if let Some(ref msg) = new_message {
+                self.last_message = msg.clone();
+            }
+            new_message
+        } else {
+            let new_message = new_message.unwrap_or_else(ClientMessage::new);
+            if !self.last_message.are_equal_payloads(&new_message) {
+                debug!("last message changed");
+                self.last_message = new_message;
+            }
+            Some(self.last_message.clone())
+        }
+    }
+
+    pub fn generate_register_message(&mut self, challenge: Challenge) -> ClientMessage {
+        let mut msg = ClientMessage::new();
+        msg.register(
+            self.protocol_id,
+            self.data_manager.data_holder.capacity(),
+            self.session_id,
+            challenge,
+            Some(self.encryption.public_key()),
+        );
+        msg
+    }
+
+    /// Returns a message to resume the relay session with, if the peer registered to one
+    pub fn generate_resume_message(&self) -> Option<ClientMessage> {
+        match (self.session_id, self.token) {
+            (Some(session_id), Some(token)) => {
+                let mut msg = ClientMessage::new();
+                msg.resume(session_id, self.data_manager.data_holder.peer_id(), token);
+                Some(msg)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<T: Peer> Client<T> {
+    // The round the payload is signed for, a payload is first relayed in the round
+    // after the payloads generated before it
+    fn round_of(&mut self, payload: &MessagePayload) -> u32 {
+        let next = self.rounds.len() as u32;
+        *self.rounds.entry(payload.clone()).or_insert(next)
+    }
+
+    // Verifies the relay message is signed by the identity of the peer that sent it,
+    // for the session and the next round of the sender, and returns the signed message
+    fn verify_relay_message(&mut self, msg: &ServerMessage) -> Result<RelayMessage, RelayError> {
+        let envelope = msg
+            .signed_message
+            .as_ref()
+            .ok_or(RelayError::InvalidSignature)?;
+        let relay_msg = self
+            .identities
+            .verify(envelope, self.session_id.unwrap_or(0))?;
+        let round = self.relayed.entry(relay_msg.peer_number).or_insert(0);
+        if envelope.round != *round {
+            return Err(RelayError::WrongRound);
+        }
+        *round += 1;
+        Ok(relay_msg)
+    }
+
+    fn handle_relay_message(&mut self, relay_msg: RelayMessage) -> Option<MessagePayload> {
+        let from = relay_msg.peer_number;
+        let peer_id = self.data_manager.data_holder.peer_id();
+        if from == peer_id {
+            debug!("-------self message accepted ------\n ");
+        }
+        // the server sends the encryption keys of all peers once the session is full,
+        // before any private payload can be relayed
+        let private_payload = match (
+            relay_msg.ciphertext_for(peer_id),
+            self.identities.encryption_key(&from),
+        ) {
+            (Some(ciphertext), Some(sender_key)) => {
+                match self
+                    .encryption
+                    .decrypt(sender_key, from, peer_id, ciphertext)
+                {
+                    Ok(private_payload) => Some(private_payload),
+                    Err(err) => {
+                        warn!("Private payload of peer {}: {}", from, err);
+                        None
+                    }
+                }
+            }
+            (Some(_), None) => {
+                warn!(
+                    "No encryption key of peer {}, ignoring private payload",
+                    from
+                );
+                None
+            }
+            (None, _) => None,
+        };
+        let payload = relay_msg.message;
+        self.data_manager
+            .get_next_message(from, payload, private_payload)
+    }
+
+    // The message is broadcast, with the private items encrypted to each of their recipients
+    fn generate_relay_message(
+        &self,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> ClientMessage {
+        let peer_id = self.data_manager.data_holder.peer_id();
+        let mut relay_message = RelayMessage::new(peer_id, self.protocol_id);
+        let to: Vec<u32> = self.bc_dests.clone();
+
+        let mut client_message = ClientMessage::new();
+
+        relay_message.set_message_params(to, payload);
+        for (recipient, private_payload) in private_items.iter() {
+            match self.identities.encryption_key(recipient) {
+                Some(recipient_key) => {
+                    let ciphertext = self.encryption.encrypt(
+                        recipient_key,
+                        peer_id,
+                        *recipient,
+                        private_payload,
+                    );
+                    relay_message.ciphertexts.insert(*recipient, ciphertext);
+                }
+                None => warn!(
+                    "No encryption key of peer {}, can not send its private payload",
+                    recipient
+                ),
+            }
+        }
+        client_message.relay_message = Some(relay_message);
+        client_message
+    }
+
+    fn handle_register_response(&mut self, peer_id: PeerIdentifier) -> Result<ClientMessage, ()> {
+        info!("Peer identifier: {}", peer_id);
+        // Set the session parameters
+        let message = self
+            .data_manager
+            .initialize_data(peer_id)
+            .unwrap_or_else(|| panic!("failed to initialize"));
+        let private_items = self.data_manager.data_holder.get_next_private_items();
+        Ok(self.generate_relay_message(message, private_items))
+    }
+
+    fn handle_error_response(&mut self, err: RelayError) -> Result<ClientMessage, RelayError> {
+        match err {
+            RelayError::NotYourTurn => Ok(self.last_message.clone()),
+            RelayError::StateNotInitialized => {
+                // If protocol is not initialized, wait for a message from the server
+                debug!("Not initialized, waiting for the session to fill");
+                Ok(ClientMessage::new())
+            }
+            _ => {
+                warn!("Can't continue the relay session: {}", err);
+                Err(err)
+            }
+        }
+    }
+
+    fn handle_server_response(
+        &mut self,
+        msg: &ServerMessage,
+    ) -> Result<ClientMessage, &'static str> {
+        let server_response = msg.response.clone().unwrap();
+        match server_response {
+            // the register message is sent on the challenge, in respond_to_server
+            ServerResponse::Challenge(_) => Ok(ClientMessage::new()),
+            ServerResponse::Register(peer_id, token) => {
+                self.registered = true;
+                self.token = Some(token);
+                match self.handle_register_response(peer_id) {
+                    Ok(msg) => {
+                        debug!("sending peers first message: {:#?}", msg);
+                        Ok(msg)
+                    }
+                    Err(_) => {
+                        warn!("error occured");
+                        Ok(ClientMessage::new())
+                    }
+                }
+            }
+            ServerResponse::ErrorResponse(err) => match self.handle_error_response(err) {
+                Ok(msg) => Ok(msg),
+                // waiting would stall the client, as the session can not proceed
+                Err(err) => panic!("Relay session failed: {}", err),
+            },
+            ServerResponse::Resumed(peer_id) => {
+                info!("Resumed relay session as peer {}", peer_id);
+                // the last message might have been lost with the connection, send it again
+                Ok(self.last_message.clone())
+            }
+            ServerResponse::Session(session_id) => {
+                info!("Registered to session {}", session_id);
+                self.session_id = Some(session_id);
+                Ok(ClientMessage::new())
+            }
+            ServerResponse::Peers(registrations) => {
+                // pin the identities and encryption keys the peers registered with
+                for (peer_id, registration) in registrations.iter() {
+                    if let Err(err) = self.identities.add_peer(*peer_id, registration) {
+                        warn!("Invalid registration of peer {}: {}", peer_id, err);
+                    }
+                }
+                Ok(ClientMessage::new())
+            }
+            // nothing to answer, wait for the next message of the server
+            ServerResponse::NoResponse => Ok(ClientMessage::new()),
+        }
+    }
+}
+
+/// Runs the protocol of the client with the relay server at the given address until the peer
+/// is done. A lost connection is resumed, as long as the server still holds the session
+pub fn run<T: Peer>(addr: &SocketAddr, client: Client<T>) -> Client<T> {
+    // Create the event loop and initiate the connection to the remote server
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let session = Arc::new(Mutex::new(client));
+
+    let mut reconnects = 0;
+    loop {
+        let tcp = TcpStream::connect(addr, &handle);
+
+        let handshake = tcp.and_then(|stream| {
+            let handshake_io = Framed::new(stream, ClientToServerCodec::new(false));
+            let client = session.lock().unwrap();
+            // resume the relay session after a lost connection,
+            // otherwise the peer registers once the server sends the challenge of the connection
+            match client.generate_resume_message() {
+                Some(msg) => {
+                    let session_id = client.session_id.unwrap_or(0);
+                    future::Either::A(handshake_io.send(client.identity.sign(&msg, session_id, 0)))
+                }
+                None => future::Either::B(future::ok(handshake_io)),
+            }
+            .map(|handshake_io| handshake_io.into_inner())
+        });
+
+        let client = handshake.and_then(|socket| {
+            let mut client = session.lock().unwrap();
+
+            let (to_server, from_server) =
+                Framed::new(socket, ClientToServerCodec::new(false)).split();
+            let (tx, rx) = mpsc::channel(0);
+            let reader = from_server.for_each(move |msg| {
+                debug!("Received {:?}", msg);
+                client.respond_to_server(msg, tx.clone())
+            });
+
+            let writer = rx
+                .map_err(|()| unreachable!("rx can't fail"))
+                .fold(to_server, |to_server, msg| to_server.send(msg))
+                .map(|_| ());
+
+            reader
+                .select(writer)
+                .map(|_| info!("Closing connection"))
+                .map_err(|(err, _)| err)
+        });
+
+        if let Err(err) = core.run(client) {
+            warn!("Connection error: {}", err);
+        }
+
+        // the connection is closed, resume the session unless this peer is done
+        let can_resume = {
+            let client = session.lock().unwrap();
+            !client.finished && client.token.is_some()
+        };
+        if !can_resume || reconnects == MAX_RECONNECTS {
+            break;
+        }
+        reconnects += 1;
+        info!(
+            "Connection lost, resuming relay session (attempt {})",
+            reconnects
+        );
+        thread::sleep(time::Duration::from_millis(RECONNECT_TIMEOUT));
+    }
+
+    match Arc::try_unwrap(session) {
+        Ok(session) => session.into_inner().unwrap(),
+        Err(_) => panic!("Relay session is still in use"),
+    }
+}
+
+/// Transport over a connection to the Tokio relay server. The connection is run by a thread
+/// of its own, the transport sends and receives over channels to it.
+/// The relay accepts the messages of the peers in turns, a message sent out of turn is sent
+/// again whenever a message of another peer is relayed.
+/// For a protocol in broadcast mode the relay takes the messages of a round in any order,
+/// and drops a message that is sent again
+pub struct TokioTransport {
+    identity: Identity,
+    identities: PeerIdentities,
+    encryption: EncryptionKeyPair,
+    session_id: Option<SessionIdentifier>,
+    protocol_id: ProtocolIdentifier,
+    capacity: u32,
+    peer_id: Option<PeerIdentifier>,
+    // challenge the server sent to the connection, the peer registers with it
+    challenge: Option<Challenge>,
+    outgoing: mpsc::UnboundedSender<SignedClientMessage>,
+    incoming: mpsc::UnboundedReceiver<ServerMessage>,
+    // message of the peer the relay did not accept yet, with the round it is signed for
+    pending: Option<(ClientMessage, u32)>,
+    // every peer sends a single message in a round, so the round of a message
+    // is the number of messages relayed from its sender before it
+    relayed: BTreeMap<PeerIdentifier, u32>,
+    rounds: BTreeMap<u32, Vec<RoundMessage>>,
+    // the signed envelope of every message relayed, by its sender and payload,
+    // the evidence an abort blaming its sender is reported with
+    envelopes: BTreeMap<(PeerIdentifier, MessagePayload), SignedClientMessage>,
+}
+
+// The reason code of the relay an abort is reported with
+fn abort_reason(abort: &ProtocolAbort) -> AbortReason {
+    match abort {
+        ProtocolAbort::MalformedMessage(_) => AbortReason::MalformedMessage,
+        ProtocolAbort::UnexpectedMessage(_) => AbortReason::UnexpectedMessage,
+        ProtocolAbort::Equivocation(_) => AbortReason::Equivocation,
+        ProtocolAbort::InvalidCommitment(_) => AbortReason::InvalidCommitment,
+        ProtocolAbort::InvalidKeyIndex(_) => AbortReason::InvalidKeyIndex,
+        ProtocolAbort::InvalidSignatureShare(_) => AbortReason::InvalidSignatureShare,
+        ProtocolAbort::InvalidSecretShare(_) => AbortReason::InvalidSecretShare,
+        ProtocolAbort::Unresponsive(_) => AbortReason::Unresponsive,
+    }
+}
+
+// The abort a reason code of the relay blames the culprit with
+fn protocol_abort(reason: AbortReason, culprit: PeerIdentifier) -> Option<ProtocolAbort> {
+    match reason {
+        AbortReason::Unspecified => None,
+        AbortReason::MalformedMessage => Some(ProtocolAbort::MalformedMessage(culprit)),
+        AbortReason::UnexpectedMessage => Some(ProtocolAbort::UnexpectedMessage(culprit)),
+        AbortReason::Equivocation => Some(ProtocolAbort::Equivocation(culprit)),
+        AbortReason::InvalidCommitment => Some(ProtocolAbort::InvalidCommitment(culprit)),
+        AbortReason::InvalidKeyIndex => Some(ProtocolAbort::InvalidKeyIndex(culprit)),
+        AbortReason::InvalidSignatureShare => Some(ProtocolAbort::InvalidSignatureShare(culprit)),
+        AbortReason::InvalidSecretShare => Some(ProtocolAbort::InvalidSecretShare(culprit)),
+        AbortReason::Unresponsive => Some(ProtocolAbort::Unresponsive(culprit)),
+    }
+}
+
+impl TokioTransport {
+    /// Connects to the relay server, to join the given session or any open session
+    pub fn connect(
+        addr: &SocketAddr,
+        identity: Identity,
+        session_id: Option<SessionIdentifier>,
+    ) -> TokioTransport {
+        let (outgoing, to_send) = mpsc::unbounded();
+        let (received, incoming) = mpsc::unbounded();
+        let addr = *addr;
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let connection = TcpStream::connect(&addr, &handle).and_then(|stream| {
+                let (to_server, from_server) =
+                    Framed::new(stream, ClientToServerCodec::new(false)).split();
+                // the transport is gone once the receiver is dropped
+                let reader = from_server.for_each(move |msg| {
+                    received
+                        .unbounded_send(msg)
+                        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Transport closed"))
+                });
+                let writer = to_send
+                    .map_err(|()| unreachable!("rx can't fail"))
+                    .fold(to_server, |to_server, msg| to_server.send(msg))
+                    .map(|_| ());
+                reader.select(writer).map(|_| ()).map_err(|(err, _)| err)
+            });
+            if let Err(err) = core.run(connection) {
+                warn!("Connection error: {}", err);
+            }
+        });
+        TokioTransport {
+            identity,
+            identities: PeerIdentities::new(),
+            encryption: EncryptionKeyPair::generate(),
+            session_id,
+            protocol_id: 0,
+            capacity: 0,
+            peer_id: None,
+            challenge: None,
+            outgoing,
+            incoming,
+            pending: None,
+            relayed: BTreeMap::new(),
+            rounds: BTreeMap::new(),
+            envelopes: BTreeMap::new(),
+        }
+    }
+
+    // Signs the message for the session of this peer and the given round
+    fn send_message(&self, msg: &ClientMessage, round: u32) -> Result<(), TransportError> {
+        let session_id = self.session_id.unwrap_or(0);
+        self.outgoing
+            .unbounded_send(self.identity.sign(msg, session_id, round))
+            .map_err(|_| TransportError::Unreachable(String::from("Connection closed")))
+    }
+
+    // Handles the next message from the server, if there is one
+    fn poll_server(&mut self) -> Poll<(), TransportError> {
+        let msg = match self.incoming.poll() {
+            Ok(Async::Ready(Some(msg))) => msg,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(None)) | Err(()) => {
+                return Err(TransportError::Unreachable(String::from(
+                    "Connection closed",
+                )))
+            }
+        };
+        match msg.msg_type() {
+            ServerMessageType::Response => self.handle_server_response(&msg)?,
+            ServerMessageType::RelayMessage => self.handle_relay_message(&msg)?,
+            ServerMessageType::Abort => return Err(self.handle_abort(msg.abort.unwrap())),
+            ServerMessageType::Undefined => {}
+        }
+        Ok(Async::Ready(()))
+    }
+
+    // An abort blaming a culprit is returned along with the messages of the culprit
+    // in its evidence, only the messages the culprit signed are taken.
+    // An abort of the relay on a missed deadline names the peers that did not respond
+    fn handle_abort(&mut self, abort: AbortMessage) -> TransportError {
+        if !abort.unresponsive.is_empty() {
+            return TransportError::Unresponsive(abort.unresponsive);
+        }
+        let (culprit, abort_of_peer) = match abort.culprit {
+            Some(culprit) => (culprit, protocol_abort(abort.reason, culprit)),
+            None => return TransportError::Aborted,
+        };
+        let abort_of_peer = match abort_of_peer {
+            Some(abort_of_peer) => abort_of_peer,
+            None => return TransportError::Aborted,
+        };
+        let evidence = abort
+            .evidence
+            .iter()
+            .filter_map(|envelope| {
+                self.identities
+                    .verify(envelope, self.session_id.unwrap_or(0))
+                    .ok()
+            })
+            .filter(|relay_msg| relay_msg.peer_number == culprit)
+            .map(|relay_msg| relay_msg.message)
+            .collect();
+        TransportError::AbortedBlaming(abort.peer_number, abort_of_peer, evidence)
+    }
+
+    fn handle_server_response(&mut self, msg: &ServerMessage) -> Result<(), TransportError> {
+        match msg.response.clone() {
+            Some(ServerResponse::Challenge(challenge)) => self.challenge = Some(challenge),
+            Some(ServerResponse::Register(peer_id, _)) => self.peer_id = Some(peer_id),
+            Some(ServerResponse::Session(session_id)) => {
+                info!("Registered to session {}", session_id);
+                self.session_id = Some(session_id);
+            }
+            Some(ServerResponse::Peers(registrations)) => {
+                // pin the identities and encryption keys the peers registered with
+                for (peer_id, registration) in registrations.iter() {
+                    if let Err(err) = self.identities.add_peer(*peer_id, registration) {
+                        warn!("Invalid registration of peer {}: {}", peer_id, err);
+                    }
+                }
+            }
+            // the pending message is sent again once another peer is done with its turn
+            Some(ServerResponse::ErrorResponse(RelayError::NotYourTurn))
+            | Some(ServerResponse::ErrorResponse(RelayError::StateNotInitialized)) => {}
+            Some(ServerResponse::ErrorResponse(err)) => {
+                return Err(TransportError::Rejected(err.to_string()))
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_relay_message(&mut self, msg: &ServerMessage) -> Result<(), TransportError> {
+        // a message its sender did not sign for the session and round could be forged
+        // or replayed by the relay
+        let envelope = match msg.signed_message {
+            Some(ref envelope) => envelope,
+            None => return Ok(()),
+        };
+        let relay_msg = match self
+            .identities
+            .verify(envelope, self.session_id.unwrap_or(0))
+        {
+            Ok(relay_msg) => relay_msg,
+            Err(err) => {
+                warn!("Dropping relay message: {}", err);
+                return Ok(());
+            }
+        };
+        let from = relay_msg.peer_number;
+        let round = self.relayed.get(&from).cloned().unwrap_or(0);
+        if envelope.round != round {
+            warn!(
+                "Dropping relay message of peer {}: {}",
+                from,
+                RelayError::WrongRound
+            );
+            return Ok(());
+        }
+        self.envelopes
+            .insert((from, relay_msg.message.clone()), envelope.clone());
+        let peer_id = self.peer_id.unwrap_or(0);
+        if from == peer_id {
+            self.pending = None;
+        } else if let Some((ref pending, pending_round)) = self.pending {
+            self.send_message(pending, pending_round)?;
+        }
+        let private_payload = match (
+            relay_msg.ciphertext_for(peer_id),
+            self.identities.encryption_key(&from),
+        ) {
+            (Some(ciphertext), Some(sender_key)) => {
+                match self
+                    .encryption
+                    .decrypt(sender_key, from, peer_id, ciphertext)
+                {
+                    Ok(private_payload) => Some(private_payload),
+                    Err(err) => {
+                        warn!("Private payload of peer {}: {}", from, err);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        self.rounds.entry(round).or_default().push(RoundMessage {
+            from,
+            payload: relay_msg.message,
+            private_payload,
+        });
+        self.relayed.insert(from, round + 1);
+        Ok(())
+    }
+}
+
+impl Transport for TokioTransport {
+    fn register(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> TransportFuture<'_, PeerIdentifier> {
+        self.protocol_id = protocol_id;
+        self.capacity = capacity;
+        Box::new(future::poll_fn(move || loop {
+            if let Some(peer_id) = self.peer_id {
+                return Ok(Async::Ready(peer_id));
+            }
+            // register once the server sends the challenge of the connection
+            if let Some(challenge) = self.challenge.take() {
+                let mut msg = ClientMessage::new();
+                msg.register(
+                    protocol_id,
+                    capacity,
+                    self.session_id,
+                    challenge,
+                    Some(self.encryption.public_key()),
+                );
+                self.send_message(&msg, 0)?;
+            }
+            if let Async::NotReady = self.poll_server()? {
+                return Ok(Async::NotReady);
+            }
+        }))
+    }
+
+    fn peer_identity(&self, peer_id: PeerIdentifier) -> Option<IdentityKey> {
+        self.identities.get(&peer_id).cloned()
+    }
+
+    fn send(
+        &mut self,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> TransportFuture<'_, ()> {
+        Box::new(future::poll_fn(move || {
+            // the server sends the encryption keys of the peers right after registering them
+            while private_items
+                .keys()
+                .any(|peer_id| self.identities.encryption_key(peer_id).is_none())
+            {
+                if let Async::NotReady = self.poll_server()? {
+                    return Ok(Async::NotReady);
+                }
+            }
+            let peer_id = self.peer_id.unwrap_or(0);
+            let mut relay_message = RelayMessage::new(peer_id, self.protocol_id);
+            relay_message.set_message_params((1..=self.capacity).collect(), payload.clone());
+            for (recipient, private_payload) in private_items.iter() {
+                let recipient_key = self.identities.encryption_key(recipient).unwrap();
+                let ciphertext =
+                    self.encryption
+                        .encrypt(recipient_key, peer_id, *recipient, private_payload);
+                relay_message.ciphertexts.insert(*recipient, ciphertext);
+            }
+            let mut msg = ClientMessage::new();
+            msg.relay_message = Some(relay_message);
+            self.send_message(&msg, round)?;
+            self.pending = Some((msg, round));
+            Ok(Async::Ready(()))
+        }))
+    }
+
+    fn receive_round(&mut self, round: u32) -> TransportFuture<'_, Vec<RoundMessage>> {
+        Box::new(future::poll_fn(move || loop {
+            if self.rounds.get(&round).map(Vec::len) == Some(self.capacity as usize) {
+                return Ok(Async::Ready(self.rounds.remove(&round).unwrap()));
+            }
+            if let Async::NotReady = self.poll_server()? {
+                return Ok(Async::NotReady);
+            }
+        }))
+    }
+
+    /// The relay checks the evidence is signed by the culprit before it blames it
+    fn abort(
+        &mut self,
+        abort: &ProtocolAbort,
+        evidence: Vec<MessagePayload>,
+    ) -> TransportFuture<'_, ()> {
+        let culprit = abort.culprit();
+        let evidence = evidence
+            .into_iter()
+            .filter_map(|payload| self.envelopes.get(&(culprit, payload)).cloned())
+            .collect();
+        let mut msg = ClientMessage::new();
+        msg.abort = Some(AbortMessage::blame(
+            self.peer_id.unwrap_or(0),
+            self.protocol_id,
+            abort_reason(abort),
+            culprit,
+            evidence,
+        ));
+        Box::new(future::result(self.send_message(&msg, 0)))
+    }
+}
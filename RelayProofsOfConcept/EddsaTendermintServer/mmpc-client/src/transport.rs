@@ -0,0 +1,459 @@
+/// Transports relay the messages of the peers of a session to each other.
+/// A peer is run the same over any of them, the Tokio relay, the Tendermint relay,
+/// the Rocket key-value server, or channels between peers of the same process
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time;
+
+use futures::sync::mpsc;
+use futures::{future, Async, Future, Stream};
+use log::{debug, info, warn};
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Interval;
+
+use crate::peer::{Peer, ProtocolAbort, ProtocolDataManager};
+use mmpc_server_common::identity::IdentityKey;
+use mmpc_server_common::{MessagePayload, PeerIdentifier, ProtocolIdentifier};
+
+/// Reasons a transport could not relay the messages of a peer
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportError {
+    // The relay rejected a message of the peer
+    Rejected(String),
+
+    // The relay can not be reached
+    Unreachable(String),
+
+    // The relay aborted the session
+    Aborted,
+
+    // The messages of a round did not all arrive in time
+    Timeout,
+
+    // The relay aborted the session as the peers did not send their messages in time
+    Unresponsive(Vec<PeerIdentifier>),
+
+    // The peer aborted the protocol on the misbehavior of another peer
+    Blamed(ProtocolAbort),
+
+    // Another peer aborted the session blaming a culprit, with the messages of the culprit
+    // it reported as evidence
+    AbortedBlaming(PeerIdentifier, ProtocolAbort, Vec<MessagePayload>),
+
+    // The peer failed to complete the protocol by itself, such as saving its result
+    Failed(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Rejected(err) => write!(f, "Message rejected: {}", err),
+            TransportError::Unreachable(err) => write!(f, "Relay unreachable: {}", err),
+            TransportError::Aborted => write!(f, "Session aborted"),
+            TransportError::Timeout => write!(f, "Timed out waiting for the messages of a round"),
+            TransportError::Unresponsive(peers) => {
+                write!(
+                    f,
+                    "Session aborted, peers {:?} did not respond in time",
+                    peers
+                )
+            }
+            TransportError::Blamed(abort) => write!(f, "Protocol aborted: {}", abort),
+            TransportError::AbortedBlaming(accuser, abort, _) => {
+                write!(f, "Session aborted by peer {}: {}", accuser, abort)
+            }
+            TransportError::Failed(err) => write!(f, "Peer failed: {}", err),
+        }
+    }
+}
+
+pub type TransportFuture<'a, T> = Box<dyn Future<Item = T, Error = TransportError> + 'a>;
+
+/// A message a peer sent in a round of the protocol
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundMessage {
+    pub from: PeerIdentifier,
+    pub payload: MessagePayload,
+    // the part of the message only the receiving peer could read
+    pub private_payload: Option<MessagePayload>,
+}
+
+pub trait Transport {
+    /// Registers the peer to a session of the protocol, resolves to the identifier of the peer in it
+    fn register(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> TransportFuture<'_, PeerIdentifier>;
+
+    /// Sends the message of the peer in a round, along with the private items,
+    /// each only readable by its recipient
+    fn send(
+        &mut self,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> TransportFuture<'_, ()>;
+
+    /// Resolves to the messages of all the peers in a round, the peer's own message included
+    fn receive_round(&mut self, round: u32) -> TransportFuture<'_, Vec<RoundMessage>>;
+
+    /// The identity key the peer registered to the session with, if the transport knows it
+    fn peer_identity(&self, _peer_id: PeerIdentifier) -> Option<IdentityKey> {
+        None
+    }
+
+    /// Aborts the session blaming the culprit, with the messages it sent as evidence.
+    /// A transport that can not abort a session does nothing
+    fn abort(
+        &mut self,
+        _abort: &ProtocolAbort,
+        _evidence: Vec<MessagePayload>,
+    ) -> TransportFuture<'_, ()> {
+        Box::new(future::ok(()))
+    }
+}
+
+// The abort of another peer is the peer's own abort if the evidence proves it to the peer
+fn confirm_abort<T: Peer>(peer: &T, err: TransportError) -> TransportError {
+    match err {
+        TransportError::AbortedBlaming(accuser, abort, evidence) => {
+            if peer.verify_abort(&abort, &evidence) {
+                TransportError::Blamed(abort)
+            } else {
+                warn!("Peer {} aborted without proving: {}", accuser, abort);
+                TransportError::Aborted
+            }
+        }
+        err => err,
+    }
+}
+
+/// Runs the peer over the transport until it has nothing more to send, blocking the thread
+/// on a runtime the futures of the transport are driven on.
+/// Returns the number of rounds it took, or the abort of the peer if another peer misbehaved
+pub fn run<T: Peer, R: Transport>(
+    transport: &mut R,
+    protocol_id: ProtocolIdentifier,
+    data_manager: &mut ProtocolDataManager<T>,
+) -> Result<u32, TransportError> {
+    let mut runtime = Runtime::new().map_err(|err| TransportError::Unreachable(err.to_string()))?;
+    let capacity = data_manager.data_holder.capacity();
+    let peer_id = runtime.block_on(transport.register(protocol_id, capacity))?;
+    info!("Registered as peer {}", peer_id);
+    let mut next_message = data_manager.initialize_data(peer_id);
+    if let Some(err) = data_manager.data_holder.failure() {
+        return Err(TransportError::Failed(err));
+    }
+    let mut round = 0;
+    while let Some(payload) = next_message {
+        let private_items = data_manager.data_holder.get_next_private_items();
+        runtime
+            .block_on(transport.send(round, payload, private_items))
+            .map_err(|err| confirm_abort(&data_manager.data_holder, err))?;
+        debug!("Waiting for the messages of round {}", round);
+        next_message = None;
+        let messages = runtime
+            .block_on(transport.receive_round(round))
+            .map_err(|err| confirm_abort(&data_manager.data_holder, err))?;
+        for msg in messages.iter() {
+            if let Some(identity) = transport.peer_identity(msg.from) {
+                data_manager
+                    .data_holder
+                    .set_peer_identity(msg.from, identity);
+            }
+        }
+        for msg in messages {
+            next_message =
+                data_manager.get_next_message(msg.from, msg.payload, msg.private_payload);
+        }
+        if let Some(abort) = data_manager.data_holder.abort() {
+            let evidence = data_manager.data_holder.abort_evidence();
+            if let Err(err) = runtime.block_on(transport.abort(&abort, evidence)) {
+                warn!("Could not report the abort to the relay: {}", err);
+            }
+            return Err(TransportError::Blamed(abort));
+        }
+        if let Some(err) = data_manager.data_holder.failure() {
+            return Err(TransportError::Failed(err));
+        }
+        round += 1;
+    }
+    Ok(round)
+}
+
+/// A future calling `poll` every `interval` milliseconds until it yields a value, for transports
+/// the peer queries the relay with. Gives up with a timeout after `retries` calls.
+/// The calls are timed with the timer of the runtime the future runs on, such as the one of `run`
+pub fn poll_interval<'a, T, F>(interval: u64, retries: u32, mut poll: F) -> TransportFuture<'a, T>
+where
+    T: 'a,
+    F: FnMut() -> Result<Option<T>, TransportError> + 'a,
+{
+    Box::new(
+        Interval::new(time::Instant::now(), time::Duration::from_millis(interval))
+            .take(u64::from(retries))
+            .map_err(|err| TransportError::Unreachable(err.to_string()))
+            .and_then(move |_| poll())
+            .filter_map(|value| value)
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(|(value, _)| value.ok_or(TransportError::Timeout)),
+    )
+}
+
+/// Transport between peers of the same process, each message is sent over a channel
+/// to every peer of the session
+pub struct ChannelTransport {
+    peer_id: PeerIdentifier,
+    // the channel to each of the peers, by the identifier of the peer
+    peers: BTreeMap<PeerIdentifier, mpsc::UnboundedSender<(u32, RoundMessage)>>,
+    incoming: mpsc::UnboundedReceiver<(u32, RoundMessage)>,
+    // messages received ahead of the round the peer waits for
+    rounds: BTreeMap<u32, Vec<RoundMessage>>,
+}
+
+/// Creates the transports of all the peers of a session, the peer of the i-th transport is peer i+1
+pub fn channel_transports(capacity: u32) -> Vec<ChannelTransport> {
+    let (senders, receivers): (Vec<_>, Vec<_>) = (0..capacity).map(|_| mpsc::unbounded()).unzip();
+    let peers: BTreeMap<PeerIdentifier, _> = (1..=capacity).zip(senders).collect();
+    receivers
+        .into_iter()
+        .zip(1..=capacity)
+        .map(|(incoming, peer_id)| ChannelTransport {
+            peer_id,
+            peers: peers.clone(),
+            incoming,
+            rounds: BTreeMap::new(),
+        })
+        .collect()
+}
+
+impl Transport for ChannelTransport {
+    fn register(
+        &mut self,
+        _protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> TransportFuture<'_, PeerIdentifier> {
+        if capacity as usize != self.peers.len() {
+            return Box::new(future::err(TransportError::Rejected(format!(
+                "Session of {} peers, not {}",
+                self.peers.len(),
+                capacity
+            ))));
+        }
+        Box::new(future::ok(self.peer_id))
+    }
+
+    fn send(
+        &mut self,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> TransportFuture<'_, ()> {
+        for (peer_id, peer) in self.peers.iter() {
+            let msg = RoundMessage {
+                from: self.peer_id,
+                payload: payload.clone(),
+                private_payload: private_items.get(peer_id).cloned(),
+            };
+            if peer.unbounded_send((round, msg)).is_err() {
+                return Box::new(future::err(TransportError::Unreachable(format!(
+                    "Peer {} is gone",
+                    peer_id
+                ))));
+            }
+        }
+        Box::new(future::ok(()))
+    }
+
+    fn receive_round(&mut self, round: u32) -> TransportFuture<'_, Vec<RoundMessage>> {
+        let capacity = self.peers.len();
+        Box::new(future::poll_fn(move || loop {
+            if self.rounds.get(&round).map(Vec::len) == Some(capacity) {
+                return Ok(Async::Ready(self.rounds.remove(&round).unwrap()));
+            }
+            match self.incoming.poll() {
+                Ok(Async::Ready(Some((msg_round, msg)))) => {
+                    self.rounds.entry(msg_round).or_default().push(msg)
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) | Err(()) => {
+                    return Err(TransportError::Unreachable(String::from("Channel closed")))
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel_transports, poll_interval, run, TransportError};
+    use crate::key_store::{MemoryKeyStore, PeerKeys};
+    use crate::peer::{Peer, ProtocolDataManager};
+    use mmpc_server_common::{MessagePayload, PeerIdentifier};
+    use std::collections::BTreeMap;
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+
+    // Every peer sends its identifier, then the sum of all identifiers along with
+    // a private item to every other peer
+    struct SumPeer {
+        peer_id: PeerIdentifier,
+        capacity: u32,
+        current_step: u32,
+        ids: BTreeMap<PeerIdentifier, u32>,
+        sums: BTreeMap<PeerIdentifier, u32>,
+        private: BTreeMap<PeerIdentifier, MessagePayload>,
+        // the peer fails once it received the sums, as if it could not save them
+        failing: bool,
+        failure: Option<String>,
+    }
+
+    impl Peer for SumPeer {
+        fn new(capacity: u32, _message: Vec<u8>, _keys: PeerKeys) -> SumPeer {
+            SumPeer {
+                peer_id: 0,
+                capacity,
+                current_step: 0,
+                ids: BTreeMap::new(),
+                sums: BTreeMap::new(),
+                private: BTreeMap::new(),
+                failing: false,
+                failure: None,
+            }
+        }
+        fn zero_step(&mut self, peer_id: PeerIdentifier) -> Option<MessagePayload> {
+            Some(peer_id.to_string())
+        }
+        fn current_step(&self) -> u32 {
+            self.current_step
+        }
+        fn capacity(&self) -> u32 {
+            self.capacity
+        }
+        fn peer_id(&self) -> PeerIdentifier {
+            self.peer_id
+        }
+        fn set_peer_id(&mut self, peer_id: PeerIdentifier) {
+            self.peer_id = peer_id;
+        }
+        fn do_step(&mut self) {
+            let received = match self.current_step {
+                0 => self.ids.len(),
+                _ => self.sums.len(),
+            };
+            if received == self.capacity as usize {
+                self.current_step += 1;
+                if self.current_step == 2 && self.failing {
+                    self.failure = Some(String::from("Unable to save the sums"));
+                }
+            }
+        }
+        fn update_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+            let value = payload.parse().unwrap();
+            match self.current_step {
+                0 => self.ids.insert(from, value),
+                _ => self.sums.insert(from, value),
+            };
+        }
+        fn update_private_data(&mut self, from: PeerIdentifier, payload: MessagePayload) {
+            self.private.insert(from, payload);
+        }
+        fn get_next_item(&mut self) -> Option<MessagePayload> {
+            match self.current_step {
+                0 => Some(self.peer_id.to_string()),
+                1 => Some(self.ids.values().sum::<u32>().to_string()),
+                _ => None,
+            }
+        }
+        fn get_next_private_items(&mut self) -> BTreeMap<PeerIdentifier, MessagePayload> {
+            match self.current_step {
+                1 => (1..=self.capacity)
+                    .filter(|peer_id| *peer_id != self.peer_id)
+                    .map(|peer_id| (peer_id, format!("{}->{}", self.peer_id, peer_id)))
+                    .collect(),
+                _ => BTreeMap::new(),
+            }
+        }
+        fn finalize(&mut self) -> Result<(), &'static str> {
+            Ok(())
+        }
+        fn is_done(&mut self) -> bool {
+            self.current_step == 2
+        }
+        fn failure(&self) -> Option<String> {
+            self.failure.clone()
+        }
+    }
+
+    #[test]
+    fn test_run_over_channels() {
+        let capacity = 3;
+        let handles: Vec<_> = channel_transports(capacity)
+            .into_iter()
+            .map(|mut transport| {
+                thread::spawn(move || {
+                    let keys = PeerKeys::new(Box::new(MemoryKeyStore::new()), None);
+                    let mut data_manager: ProtocolDataManager<SumPeer> =
+                        ProtocolDataManager::new(capacity, Vec::new(), keys);
+                    let rounds = run(&mut transport, 0, &mut data_manager).unwrap();
+                    let peer = data_manager.data_holder;
+                    (rounds, peer.peer_id, peer.sums, peer.private)
+                })
+            })
+            .collect();
+        for handle in handles {
+            let (rounds, peer_id, sums, private) = handle.join().unwrap();
+            assert_eq!(rounds, 2);
+            assert_eq!(sums.len(), capacity as usize);
+            assert!(sums.values().all(|sum| *sum == 6));
+            // only the items sent to this peer are received
+            assert_eq!(private.len(), capacity as usize - 1);
+            for (from, item) in private {
+                assert_eq!(item, format!("{}->{}", from, peer_id));
+            }
+        }
+    }
+
+    #[test]
+    fn test_register_to_other_capacity() {
+        let mut transports = channel_transports(2);
+        let keys = PeerKeys::new(Box::new(MemoryKeyStore::new()), None);
+        let mut data_manager: ProtocolDataManager<SumPeer> =
+            ProtocolDataManager::new(3, Vec::new(), keys);
+        match run(&mut transports[0], 0, &mut data_manager) {
+            Err(TransportError::Rejected(_)) => {}
+            other => panic!("Expected rejected registration, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_run_failing_peer() {
+        let mut transports = channel_transports(1);
+        let keys = PeerKeys::new(Box::new(MemoryKeyStore::new()), None);
+        let mut data_manager: ProtocolDataManager<SumPeer> =
+            ProtocolDataManager::new(1, Vec::new(), keys);
+        data_manager.data_holder.failing = true;
+        assert_eq!(
+            run(&mut transports[0], 0, &mut data_manager),
+            Err(TransportError::Failed(String::from(
+                "Unable to save the sums"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_poll_interval() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut polls = 0;
+        let value = runtime.block_on(poll_interval(1, 5, || {
+            polls += 1;
+            Ok(if polls == 3 { Some(polls) } else { None })
+        }));
+        assert_eq!(value, Ok(3));
+
+        let never = runtime.block_on(poll_interval(1, 5, || Ok(None::<u32>)));
+        assert_eq!(never, Err(TransportError::Timeout));
+    }
+}
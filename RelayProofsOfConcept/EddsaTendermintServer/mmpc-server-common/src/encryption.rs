@@ -0,0 +1,148 @@
+/// Encryption of relay message payloads between two peers, so the relay can not read them
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::common::MessageDecodeError;
+use crate::{MessagePayload, PeerIdentifier};
+
+const NONCE_SIZE: usize = 12;
+
+/// Public key a peer registers with, used by the other peers to encrypt payloads to it
+pub type EncryptionKey = [u8; 32];
+
+/// A payload encrypted by the sender of a relay message to one of its recipients
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptedPayload {
+    pub nonce: [u8; NONCE_SIZE],
+
+    pub ciphertext: Vec<u8>,
+}
+
+/// The encryption key pair of a peer, generated for every session it registers to
+pub struct EncryptionKeyPair {
+    secret: StaticSecret,
+}
+
+impl EncryptionKeyPair {
+    pub fn generate() -> EncryptionKeyPair {
+        EncryptionKeyPair {
+            secret: StaticSecret::new(OsRng),
+        }
+    }
+
+    pub fn public_key(&self) -> EncryptionKey {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    // Both peers derive the same cipher from their own secret and the public key of the other
+    fn cipher(&self, peer_key: &EncryptionKey) -> ChaCha20Poly1305 {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer_key));
+        let key = Sha256::digest(shared.as_bytes());
+        ChaCha20Poly1305::new(&key)
+    }
+
+    // The sender and recipient are authenticated along with the payload,
+    // so the relay can not pass a ciphertext off as sent by or to another peer
+    fn associated_data(from: PeerIdentifier, to: PeerIdentifier) -> Vec<u8> {
+        format!("{}:{}", from, to).into_bytes()
+    }
+
+    /// Encrypts the payload peer `from` sends to peer `to`, whose encryption key is given
+    pub fn encrypt(
+        &self,
+        recipient_key: &EncryptionKey,
+        from: PeerIdentifier,
+        to: PeerIdentifier,
+        payload: &str,
+    ) -> EncryptedPayload {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let aad = EncryptionKeyPair::associated_data(from, to);
+        let ciphertext = self
+            .cipher(recipient_key)
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: payload.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .expect("Failed to encrypt payload");
+        EncryptedPayload { nonce, ciphertext }
+    }
+
+    /// Decrypts the payload peer `from`, whose encryption key is given, sent to peer `to`
+    pub fn decrypt(
+        &self,
+        sender_key: &EncryptionKey,
+        from: PeerIdentifier,
+        to: PeerIdentifier,
+        payload: &EncryptedPayload,
+    ) -> Result<MessagePayload, MessageDecodeError> {
+        let aad = EncryptionKeyPair::associated_data(from, to);
+        let plaintext = self
+            .cipher(sender_key)
+            .decrypt(
+                &Nonce::from(payload.nonce),
+                Payload {
+                    msg: &payload.ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| MessageDecodeError::DecryptionFailed)?;
+        String::from_utf8(plaintext).map_err(|err| MessageDecodeError::Malformed(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EncryptionKeyPair;
+    use crate::common::MessageDecodeError;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let sender = EncryptionKeyPair::generate();
+        let recipient = EncryptionKeyPair::generate();
+        let payload = sender.encrypt(&recipient.public_key(), 1, 2, "secret share");
+        assert_ne!(payload.ciphertext, b"secret share".to_vec());
+        assert_eq!(
+            recipient.decrypt(&sender.public_key(), 1, 2, &payload),
+            Ok(String::from("secret share"))
+        );
+    }
+
+    #[test]
+    fn test_decrypt_failures() {
+        let sender = EncryptionKeyPair::generate();
+        let recipient = EncryptionKeyPair::generate();
+        let other = EncryptionKeyPair::generate();
+        let payload = sender.encrypt(&recipient.public_key(), 1, 2, "secret share");
+
+        // only the recipient can decrypt the payload
+        assert_eq!(
+            other.decrypt(&sender.public_key(), 1, 2, &payload),
+            Err(MessageDecodeError::DecryptionFailed)
+        );
+        // the payload can not be passed off as sent by or to another peer
+        assert_eq!(
+            recipient.decrypt(&sender.public_key(), 3, 2, &payload),
+            Err(MessageDecodeError::DecryptionFailed)
+        );
+        assert_eq!(
+            recipient.decrypt(&sender.public_key(), 1, 3, &payload),
+            Err(MessageDecodeError::DecryptionFailed)
+        );
+
+        let mut tampered = payload.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(
+            recipient.decrypt(&sender.public_key(), 1, 2, &tampered),
+            Err(MessageDecodeError::DecryptionFailed)
+        );
+    }
+}
@@ -1,47 +1,108 @@
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, BTreeSet};
 use std::vec::Vec;
 use tokio_jsoncodec::Codec as JsonCodec;
 
 pub mod common;
+pub mod encryption;
+pub mod identity;
 pub mod protocol;
+pub mod query;
+
+use crate::common::RelayError;
+use crate::encryption::{EncryptedPayload, EncryptionKey};
+use crate::identity::IdentityKey;
+use crate::identity::SignedClientMessage;
 
 pub type ProtocolIdentifier = u32;
 pub type PeerIdentifier = u32;
 pub type MessagePayload = String;
-
-const MAX_CLIENTS: u32 = 12;
+pub type SessionIdentifier = u64;
+
+/// Number of messages a query of missing messages returns when the request sets no page size,
+/// and the largest page a request can ask for
+pub const DEFAULT_PAGE_SIZE: u32 = 64;
+pub const MAX_PAGE_SIZE: u32 = 256;
+
+/// The session the application starts with, transactions that name no session are for it.
+/// Other sessions are created by transactions, and numbered in the order they are created
+pub const DEFAULT_SESSION_ID: SessionIdentifier = 0;
+
+/// Type of the event the application emits for every message and abort of a session it delivers.
+/// Peers subscribe to the events of their session to receive its messages as blocks are committed
+pub const RELAY_EVENT: &str = "relay";
+pub const SESSION_ATTRIBUTE: &str = "session";
+pub const ROUND_ATTRIBUTE: &str = "round";
+pub const PEER_ATTRIBUTE: &str = "peer";
+// set instead of the round and peer on an abort, to the peer that aborted
+pub const ABORT_ATTRIBUTE: &str = "abort";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelayMessage {
     pub peer_number: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
-    pub from: SocketAddr,
     pub to: Vec<PeerIdentifier>,
     pub message: MessagePayload,
+
+    // Payloads encrypted by the sender to each of the recipients,
+    // only the recipient of a payload can decrypt it
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ciphertexts: BTreeMap<PeerIdentifier, EncryptedPayload>,
+
+    // The round the message is sent in, the relay rejects it in any other round.
+    // A message without a round is malformed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub round: Option<u32>,
 }
 
 impl RelayMessage {
-    pub fn new(
-        peer_number: PeerIdentifier,
-        protocol_id: ProtocolIdentifier,
-        from: SocketAddr,
-    ) -> RelayMessage {
+    pub fn new(peer_number: PeerIdentifier, protocol_id: ProtocolIdentifier) -> RelayMessage {
         RelayMessage {
             peer_number,
             protocol_id,
-            from,
             to: Vec::new(),
             message: String::from(""),
+            ciphertexts: BTreeMap::new(),
+            round: None,
         }
     }
 
+    pub fn set_round(&mut self, round: u32) {
+        self.round = Some(round);
+    }
+
     pub fn set_message_params<S: Into<String>>(&mut self, to: Vec<PeerIdentifier>, message: S) {
         //self.round = round_number;
         self.to = to;
         self.message = message.into();
     }
+
+    /// Sets a payload encrypted to each of the recipients instead of a plaintext message
+    pub fn set_encrypted_message_params(
+        &mut self,
+        ciphertexts: BTreeMap<PeerIdentifier, EncryptedPayload>,
+    ) {
+        self.to = ciphertexts.keys().cloned().collect();
+        self.message = String::from("");
+        self.ciphertexts = ciphertexts;
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        !self.ciphertexts.is_empty()
+    }
+
+    /// The payload encrypted to the given recipient
+    pub fn ciphertext_for(&self, peer_id: PeerIdentifier) -> Option<&EncryptedPayload> {
+        self.ciphertexts.get(&peer_id)
+    }
+
+    /// Size in bytes of the largest payload a recipient of the message gets
+    pub fn payload_size(&self) -> usize {
+        self.ciphertexts
+            .values()
+            .map(|payload| payload.ciphertext.len())
+            .fold(self.message.len(), usize::max)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -49,17 +110,73 @@ pub enum ServerResponse {
     // Register response containing peer number
     Register(PeerIdentifier),
 
-    // Error message
-    ErrorResponse(String),
+    // Identifier of the relay session that was created
+    Session(SessionIdentifier),
+
+    // Error response
+    ErrorResponse(RelayError),
 
     // No response
     NoResponse,
 }
 
+/// Why a peer aborted the protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AbortReason {
+    // The peer aborted without blaming another peer
+    Unspecified,
+
+    // The culprit sent a message that could not be parsed
+    MalformedMessage,
+
+    // The culprit sent a message that does not belong to the round it was sent in
+    UnexpectedMessage,
+
+    // The culprit sent two different messages for the same round
+    Equivocation,
+
+    // The R the culprit revealed does not match the commitment it sent
+    InvalidCommitment,
+
+    // The culprit claimed a key generation index it does not have
+    InvalidKeyIndex,
+
+    // The signature share of the culprit does not verify
+    InvalidSignatureShare,
+
+    // The secret share the culprit sent does not match the scheme it shared its key with
+    InvalidSecretShare,
+
+    // The culprit did not send its message of the round
+    Unresponsive,
+}
+
+impl Default for AbortReason {
+    fn default() -> AbortReason {
+        AbortReason::Unspecified
+    }
+}
+
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
 pub struct AbortMessage {
     pub peer_number: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
+
+    #[serde(default)]
+    pub reason: AbortReason,
+
+    // The peer the abort blames for misbehaving
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub culprit: Option<PeerIdentifier>,
+
+    // Messages the culprit signed that prove it misbehaved as the reason says,
+    // such as the commitment and R that do not match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evidence: Vec<SignedClientMessage>,
+
+    // The peers that missed a deadline of the session, set when the relay aborts it on a timeout
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unresponsive: Vec<PeerIdentifier>,
 }
 
 impl AbortMessage {
@@ -67,19 +184,69 @@ impl AbortMessage {
         AbortMessage {
             peer_number,
             protocol_id,
+            ..Default::default()
+        }
+    }
+
+    /// An abort blaming the culprit for the reason, along with the messages it signed as evidence
+    pub fn blame(
+        peer_number: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+        reason: AbortReason,
+        culprit: PeerIdentifier,
+        evidence: Vec<SignedClientMessage>,
+    ) -> AbortMessage {
+        AbortMessage {
+            peer_number,
+            protocol_id,
+            reason,
+            culprit: Some(culprit),
+            evidence,
+            ..Default::default()
+        }
+    }
+
+    /// An abort of the relay itself, naming the peers that missed a deadline of the session.
+    /// The relay is not a peer of the session, so the abort is sent as peer number 0
+    pub fn timeout(
+        protocol_id: ProtocolIdentifier,
+        unresponsive: Vec<PeerIdentifier>,
+    ) -> AbortMessage {
+        AbortMessage {
+            peer_number: 0,
+            protocol_id,
+            reason: AbortReason::Unresponsive,
+            unresponsive,
+            ..Default::default()
         }
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RegisterMessage {
-    pub addr: SocketAddr,
-
     pub protocol_id: ProtocolIdentifier,
 
     pub capacity: u32,
 
     pub index: i32,
+
+    // Key the other peers encrypt payloads to this peer with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+/// Creates a relay session running the protocol with the given number of participants.
+/// A session listing its participants accepts only their identities,
+/// and numbers each peer by the position of its identity in the list
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CreateSessionMessage {
+    pub protocol_id: ProtocolIdentifier,
+
+    pub capacity: u32,
+
+    // Identities allowed to register, any identity can if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub participants: Vec<IdentityKey>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -106,11 +273,43 @@ pub struct ServerMessage {
 pub struct MissingMessagesRequest {
     pub round: u32,
     pub missing_clients: Vec<u32>,
+
+    // The session of the messages, the default session if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<SessionIdentifier>,
+
+    // Only the messages of the clients after the cursor are returned,
+    // the next cursor of the previous page. None for the first page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<PeerIdentifier>,
+
+    // The most messages to return, DEFAULT_PAGE_SIZE if not set and at most MAX_PAGE_SIZE
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_size: Option<u32>,
+}
+
+impl MissingMessagesRequest {
+    pub fn page_size(&self) -> usize {
+        self.page_size
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE) as usize
+    }
 }
 
+/// A page of the stored messages of a round a peer is missing
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
-pub struct MissingMessagesReply {
-    pub missing_messages: BTreeMap<u32, ClientMessage>,
+pub struct MissingMessagesReply<M = SignedClientMessage> {
+    pub missing_messages: BTreeMap<u32, M>,
+    /// number of the requested messages stored, on all the pages
+    pub total: u32,
+    /// cursor of the next page, None on the last page
+    pub next_cursor: Option<PeerIdentifier>,
+}
+
+/// The signed register messages of the peers registered so far, by peer number
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct PeersReply {
+    pub registrations: BTreeMap<PeerIdentifier, SignedClientMessage>,
 }
 
 impl ServerMessage {
@@ -138,20 +337,23 @@ impl ServerMessage {
     }
 }
 
+/// Messages of the peers, stored by round and party.
+/// The relay stores the signed envelopes the messages were sent in,
+/// so peers can verify them, and peers store the messages they verified
 #[derive(Default, Debug, Deserialize, Serialize, Clone)]
-pub struct StoredMessages {
-    pub messages: BTreeMap<u32, BTreeMap<u32, ClientMessage>>,
+pub struct StoredMessages<M> {
+    pub messages: BTreeMap<u32, BTreeMap<u32, M>>,
 }
 
-impl StoredMessages {
-    pub fn new() -> StoredMessages {
+impl<M: Clone> StoredMessages<M> {
+    pub fn new() -> StoredMessages<M> {
         StoredMessages {
             messages: BTreeMap::new(),
         }
     }
 
-    // Insert a new ClientMessage for a given round, and a given party
-    pub fn update(&mut self, round: u32, party: u32, msg: ClientMessage) {
+    // Insert a new message for a given round, and a given party
+    pub fn update(&mut self, round: u32, party: u32, msg: M) {
         self.messages.entry(round).or_insert(BTreeMap::new());
         match self.messages.get_mut(&round) {
             Some(messages) => {
@@ -171,7 +373,7 @@ impl StoredMessages {
 
     // Returns the messages of the current round as client messages format,
     // or an empty vector if no messages are stored for the round
-    pub fn get_messages_vector_client_message(&self, round: u32) -> Vec<ClientMessage> {
+    pub fn get_messages_vector_client_message(&self, round: u32) -> Vec<M> {
         match self.messages.get(&round) {
             Some(round_messages) => {
                 let mut response_vec = Vec::new();
@@ -186,33 +388,57 @@ impl StoredMessages {
 
     // Returns the messages of the current round as client messages format,
     // or an empty hashmap if no messages are stored for the round
-    pub fn get_messages_map_client_message(&self, round: u32) -> BTreeMap<u32, ClientMessage> {
+    pub fn get_messages_map_client_message(&self, round: u32) -> BTreeMap<u32, M> {
         match self.messages.get(&round) {
-            Some(round_messages) => {
-                let mut response = BTreeMap::new();
-                // Only return a response on the first MAX clients
-                let mut max_counter = 0;
-                for (client_idx, msg) in round_messages.iter() {
-                    let idx = *client_idx as u32;
-                    response.insert(idx, msg.clone());
-                    max_counter += 1;
-                    if max_counter > MAX_CLIENTS {
-                        break;
-                    }
-                }
-                return response;
-            }
+            Some(round_messages) => round_messages.clone(),
             None => return BTreeMap::new(),
         }
     }
 
+    // Returns the page of the messages of the round from the given clients that follows the cursor,
+    // along with the number of messages stored from those clients on all the pages
+    pub fn get_messages_page(
+        &self,
+        round: u32,
+        clients: &[u32],
+        cursor: Option<u32>,
+        page_size: usize,
+    ) -> MissingMessagesReply<M> {
+        let mut reply = MissingMessagesReply {
+            missing_messages: BTreeMap::new(),
+            total: 0,
+            next_cursor: None,
+        };
+        let round_messages = match self.messages.get(&round) {
+            Some(round_messages) => round_messages,
+            None => return reply,
+        };
+        let clients: BTreeSet<&u32> = clients.iter().collect();
+        for (client_idx, msg) in round_messages {
+            if !clients.contains(client_idx) {
+                continue;
+            }
+            reply.total += 1;
+            if cursor.map_or(false, |cursor| *client_idx <= cursor) {
+                continue;
+            }
+            if reply.missing_messages.len() < page_size {
+                reply.missing_messages.insert(*client_idx, msg.clone());
+            } else if reply.next_cursor.is_none() {
+                // more messages follow the full page
+                reply.next_cursor = reply.missing_messages.keys().next_back().cloned();
+            }
+        }
+        reply
+    }
+
     // Returns the messages of the current round as client messages format,
     // or an empty hashmap if no messages are stored for the round
     pub fn get_messages_map_from_vector(
         &self,
         round: u32,
         missing_clients: &[u32],
-    ) -> BTreeMap<u32, ClientMessage> {
+    ) -> BTreeMap<u32, M> {
         match self.messages.get(&round) {
             Some(round_messages) => {
                 // TODO: Rewrite with filter and iterator
@@ -256,6 +482,14 @@ pub struct ClientMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_message: Option<RelayMessage>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub create_session: Option<CreateSessionMessage>,
+
+    // The session the message is for, the default session if not set.
+    // It is signed along with the message, so the message can not be replayed in another session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<SessionIdentifier>,
 }
 
 impl ClientMessage {
@@ -266,35 +500,65 @@ impl ClientMessage {
             abort: None,
 
             relay_message: None,
+
+            create_session: None,
+
+            session_id: None,
         }
     }
 
+    pub fn set_create_session(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        participants: Vec<IdentityKey>,
+    ) {
+        self.create_session = Some(CreateSessionMessage {
+            protocol_id,
+            capacity,
+            participants,
+        });
+    }
+
+    pub fn set_session(&mut self, session_id: SessionIdentifier) {
+        self.session_id = Some(session_id);
+    }
+
+    /// The session the message is for
+    pub fn session_id(&self) -> SessionIdentifier {
+        self.session_id.unwrap_or(DEFAULT_SESSION_ID)
+    }
+
     pub fn set_register(
         &mut self,
-        addr: SocketAddr,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
         index: i32,
+        encryption_key: Option<EncryptionKey>,
     ) {
         self.register = Some(RegisterMessage {
-            addr,
             protocol_id,
             capacity,
             index,
+            encryption_key,
         });
     }
 
     pub fn is_empty(&self) -> bool {
-        self.relay_message.is_none() && self.abort.is_none() && self.register.is_none()
+        self.relay_message.is_none()
+            && self.abort.is_none()
+            && self.register.is_none()
+            && self.create_session.is_none()
     }
 
     pub fn are_equal_payloads(&self, msg: &ClientMessage) -> bool {
         if self.register.is_some() && msg.register.is_some() {
             return true;
         } else if self.relay_message.is_some() && msg.relay_message.is_some() {
-            let self_message = self.relay_message.clone().unwrap().message;
-            let message = msg.relay_message.clone().unwrap().message;
-            return self_message == message;
+            let self_message = self.relay_message.clone().unwrap();
+            let message = msg.relay_message.clone().unwrap();
+            return self_message.message == message.message
+                && self_message.ciphertexts == message.ciphertexts;
         } else if self.abort.is_some() && msg.abort.is_some() {
             return true;
         }
@@ -311,12 +575,16 @@ impl ClientMessage {
         if self.abort.is_some() {
             return ClientMessageType::Abort;
         }
+        if self.create_session.is_some() {
+            return ClientMessageType::CreateSession;
+        }
         return ClientMessageType::Undefined;
     }
 }
 
 #[derive(Debug)]
 pub enum ClientMessageType {
+    CreateSession,
     Register,
     Abort,
     RelayMessage,
@@ -335,8 +603,10 @@ pub type ClientToServerCodec = JsonCodec<ServerMessage, ClientMessage>;
 
 #[cfg(test)]
 mod tests {
-    use super::ClientMessage;
     use super::StoredMessages;
+    use super::{AbortMessage, AbortReason};
+    use super::{ClientMessage, ClientMessageType, DEFAULT_SESSION_ID};
+    use super::{MissingMessagesRequest, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
 
     #[test]
     fn test_stored_messages() {
@@ -395,31 +665,67 @@ mod tests {
             assert_eq!(i, idx);
             i += 2;
         }
-        // Test for more that MAX clients
+        // Test for more than a page of clients, all are returned
         let mut stored_messages = StoredMessages::new();
-        stored_messages.update(round, 1, ClientMessage::new());
-        stored_messages.update(round, 2, ClientMessage::new());
-        stored_messages.update(round, 3, ClientMessage::new());
-        stored_messages.update(round, 4, ClientMessage::new());
-        stored_messages.update(round, 5, ClientMessage::new());
-        stored_messages.update(round, 6, ClientMessage::new());
-        stored_messages.update(round, 7, ClientMessage::new());
-        stored_messages.update(round, 8, ClientMessage::new());
-        stored_messages.update(round, 9, ClientMessage::new());
-        stored_messages.update(round, 10, ClientMessage::new());
-        stored_messages.update(round, 11, ClientMessage::new());
-        stored_messages.update(round, 12, ClientMessage::new());
-        stored_messages.update(round, 13, ClientMessage::new());
-        stored_messages.update(round, 14, ClientMessage::new());
+        for idx in 1..=300 {
+            stored_messages.update(round, idx, ClientMessage::new());
+        }
         assert_eq!(
             stored_messages
                 .get_messages_map_client_message(round)
                 .into_iter()
                 .len(),
-            13
+            300
         );
     }
 
+    #[test]
+    fn test_get_messages_page() {
+        let mut stored_messages = StoredMessages::new();
+        let round = 1;
+        for idx in 1..=10 {
+            stored_messages.update(round, idx, ClientMessage::new());
+        }
+        // all clients but 4 are missing, 11 is not stored yet
+        let missing: Vec<u32> = (1..=11).filter(|idx| *idx != 4).collect();
+        let mut cursor = None;
+        let mut pages = Vec::new();
+        loop {
+            let reply = stored_messages.get_messages_page(round, &missing, cursor, 4);
+            assert_eq!(reply.total, 9);
+            pages.push(reply.missing_messages.keys().cloned().collect::<Vec<u32>>());
+            cursor = match reply.next_cursor {
+                Some(next_cursor) => Some(next_cursor),
+                None => break,
+            };
+        }
+        assert_eq!(pages, vec![vec![1, 2, 3, 5], vec![6, 7, 8, 9], vec![10]]);
+
+        // a full last page has no next cursor
+        let reply = stored_messages.get_messages_page(round, &missing, Some(5), 5);
+        assert_eq!(reply.missing_messages.len(), 5);
+        assert_eq!(reply.next_cursor, None);
+        // an empty round
+        let reply = stored_messages.get_messages_page(round + 1, &missing, None, 4);
+        assert_eq!((reply.missing_messages.len(), reply.total), (0, 0));
+    }
+
+    #[test]
+    fn test_page_size() {
+        let mut request = MissingMessagesRequest::default();
+        assert_eq!(request.page_size(), DEFAULT_PAGE_SIZE as usize);
+        request.page_size = Some(10);
+        assert_eq!(request.page_size(), 10);
+        request.page_size = Some(MAX_PAGE_SIZE + 1);
+        assert_eq!(request.page_size(), MAX_PAGE_SIZE as usize);
+        request.page_size = Some(0);
+        assert_eq!(request.page_size(), 1);
+        // requests of clients that do not page
+        let request: MissingMessagesRequest =
+            serde_json::from_str(r#"{"round":1,"missing_clients":[2]}"#).unwrap();
+        assert_eq!((request.cursor, request.page_size), (None, None));
+    }
+
     #[test]
     fn test_get_messages_from_vector() {
         let mut stored_messages = StoredMessages::new();
@@ -432,4 +738,51 @@ mod tests {
             stored_messages.get_messages_map_from_vector(round, &[2])
         );
     }
+
+    #[test]
+    fn test_client_message_session() {
+        // a message sent before sessions were created is for the default session
+        let msg: ClientMessage = serde_json::from_str("{}").unwrap();
+        assert_eq!(msg.session_id(), DEFAULT_SESSION_ID);
+        assert!(msg.is_empty());
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(!json.contains("session"));
+
+        let mut msg = ClientMessage::new();
+        msg.set_create_session(1, 3, Vec::new());
+        assert!(!msg.is_empty());
+        match msg.msg_type() {
+            ClientMessageType::CreateSession => {}
+            msg_type => panic!("Expected create session, got {:?}", msg_type),
+        }
+        let mut msg = ClientMessage::new();
+        msg.set_session(4);
+        let json = serde_json::to_string(&msg).unwrap();
+        let msg: ClientMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(msg.session_id(), 4);
+    }
+
+    #[test]
+    fn test_abort_message_blame() {
+        // an abort of a peer that does not blame, as sent before aborts carried a reason
+        let abort: AbortMessage =
+            serde_json::from_str(r#"{"peer_number":2,"protocol_id":1}"#).unwrap();
+        assert_eq!(abort.reason, AbortReason::Unspecified);
+        assert_eq!(abort.culprit, None);
+        assert!(abort.evidence.is_empty());
+
+        let abort = AbortMessage::blame(2, 1, AbortReason::InvalidCommitment, 3, Vec::new());
+        let json = serde_json::to_string(&abort).unwrap();
+        let abort: AbortMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(abort.reason, AbortReason::InvalidCommitment);
+        assert_eq!(abort.culprit, Some(3));
+        assert!(abort.unresponsive.is_empty());
+
+        let abort = AbortMessage::timeout(1, vec![2, 4]);
+        let json = serde_json::to_string(&abort).unwrap();
+        let abort: AbortMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(abort.reason, AbortReason::Unresponsive);
+        assert_eq!(abort.culprit, None);
+        assert_eq!(abort.unresponsive, vec![2, 4]);
+    }
 }
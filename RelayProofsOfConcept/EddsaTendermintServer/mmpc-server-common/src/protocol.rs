@@ -1,20 +1,111 @@
 /// Structures for supported protocols for relay-server
-use log::debug;
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 
+use crate::common::RelayError;
 use crate::ProtocolIdentifier;
 
-static PROTOCOLS_F: &str = r#"./protocols.json"#;
+/// Path the protocols are loaded from when no other path is given
+pub const DEFAULT_PROTOCOLS_PATH: &str = r#"./protocols.json"#;
+
+/// Which peers each message of a round is sent to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Delivery {
+    // Every message is sent to all the other peers of the session
+    Broadcast,
+
+    // A message may be sent to any of the other peers
+    PointToPoint,
+}
+
+impl Default for Delivery {
+    fn default() -> Delivery {
+        Delivery::PointToPoint
+    }
+}
+
+/// The numbers of peers a protocol can run with,
+/// either listed one by one or as an inclusive range
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Capacities {
+    List(Vec<u32>),
+    Range { min: u32, max: u32 },
+}
+
+impl Capacities {
+    pub fn contains(&self, capacity: u32) -> bool {
+        match self {
+            Capacities::List(capacities) => capacities.contains(&capacity),
+            Capacities::Range { min, max } => *min <= capacity && capacity <= *max,
+        }
+    }
+}
+
+/// Deadlines of a protocol in seconds, overriding the deadlines the relay was started with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ProtocolTimeouts {
+    #[serde(default)]
+    pub registration: Option<u64>,
+
+    #[serde(default)]
+    pub round: Option<u64>,
+}
+
+impl ProtocolTimeouts {
+    pub fn registration(&self) -> Option<Duration> {
+        self.registration.map(Duration::from_secs)
+    }
+
+    pub fn round(&self) -> Option<Duration> {
+        self.round.map(Duration::from_secs)
+    }
+}
+
+/// A protocol as declared in the protocols file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProtocolSpec {
+    pub id: ProtocolIdentifier,
+    pub names: Vec<String>,
+    pub capacities: Capacities,
+
+    // number of rounds the protocol runs, unlimited if not set
+    #[serde(default)]
+    pub rounds: Option<u32>,
+
+    #[serde(default)]
+    pub delivery: Delivery,
+
+    // largest payload in bytes a peer can send in a message, unlimited if not set
+    #[serde(default)]
+    pub max_payload: Option<usize>,
+
+    #[serde(default)]
+    pub timeouts: ProtocolTimeouts,
+
+    // any threshold + 1 of the parties can complete the protocol
+    #[serde(default)]
+    pub threshold: Option<u32>,
+}
 
 #[derive(Debug, Clone)]
 pub struct ProtocolDescriptor {
     pub id: ProtocolIdentifier,
     pub capacity: u32,
     pub turn: Arc<RwLock<u32>>,
+    pub delivery: Delivery,
+    pub rounds: Option<u32>,
+    pub max_payload: Option<usize>,
+    pub timeouts: ProtocolTimeouts,
 }
 
 impl ProtocolDescriptor {
@@ -23,6 +114,21 @@ impl ProtocolDescriptor {
             id,
             capacity,
             turn: Arc::new(RwLock::new(1)),
+            delivery: Delivery::default(),
+            rounds: None,
+            max_payload: None,
+            timeouts: ProtocolTimeouts::default(),
+        }
+    }
+
+    /// Describes a session of the given protocol with the limits it declares
+    pub fn from_spec(spec: &ProtocolSpec, capacity: u32) -> ProtocolDescriptor {
+        ProtocolDescriptor {
+            delivery: spec.delivery,
+            rounds: spec.rounds,
+            max_payload: spec.max_payload,
+            timeouts: spec.timeouts,
+            ..ProtocolDescriptor::new(spec.id, capacity)
         }
     }
 
@@ -45,52 +151,256 @@ impl ProtocolDescriptor {
     }
 }
 
-/// Returns true if the protocol is a valid protocol as determined by the
-/// protocols.json file
-pub fn is_valid_protocol(p: &ProtocolDescriptor) -> bool {
-    let all_protocols = get_protocols();
-    match all_protocols {
-        Ok(_protocols) => {
-            for prot in _protocols.protocols {
-                debug!("Checking if fits protocol: {:?}", prot);
-                if prot.id == p.id {
-                    if prot.capacities.contains(&(p.capacity)) {
-                        return true;
-                    } else {
-                        return false;
-                    }
-                }
+/// Reasons the protocols file could not be loaded
+#[derive(Debug)]
+pub enum ProtocolError {
+    // The file could not be read
+    Io(io::Error),
+
+    // The file is not a valid protocols file
+    Parse(serde_json::Error),
+
+    // Two protocols were declared with the same identifier
+    DuplicateProtocol(ProtocolIdentifier),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::Io(err) => write!(f, "Unable to read protocols file: {}", err),
+            ProtocolError::Parse(err) => write!(f, "Corrupt protocols file: {}", err),
+            ProtocolError::DuplicateProtocol(id) => {
+                write!(f, "Protocol {} is declared more than once", id)
             }
-            return false;
         }
-        Err(_) => panic!("Corrupt protocols file"),
     }
 }
 
+impl Error for ProtocolError {}
+
 #[derive(Debug, Deserialize, Serialize)]
-struct Protocolss {
-    pub protocols: Vec<Protocol>,
+struct Protocols {
+    pub protocols: Vec<ProtocolSpec>,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
-struct Protocol {
-    pub id: u32,
-    pub capacities: Vec<u32>,
-    pub names: Vec<String>,
+/// The protocols the relay supports, loaded once from the protocols file.
+/// The file can be reloaded while the relay runs, sessions that already
+/// started keep the limits they started with
+#[derive(Debug)]
+pub struct ProtocolRegistry {
+    path: PathBuf,
+
+    protocols: RwLock<BTreeMap<ProtocolIdentifier, ProtocolSpec>>,
+
+    // modification time of the file when it was last loaded
+    modified: RwLock<Option<SystemTime>>,
 }
 
-// Reutrn all avaliable protocols
-fn get_protocols() -> Result<Protocolss, Box<dyn Error>> {
-    debug!("Getting protocols");
+impl ProtocolRegistry {
+    /// Loads the protocols declared in the given file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ProtocolRegistry, ProtocolError> {
+        let registry = ProtocolRegistry::empty(path);
+        registry.reload()?;
+        Ok(registry)
+    }
+
+    /// A registry of the given file that supports no protocol until it is reloaded
+    pub fn empty<P: AsRef<Path>>(path: P) -> ProtocolRegistry {
+        ProtocolRegistry {
+            path: path.as_ref().to_path_buf(),
+            protocols: RwLock::new(BTreeMap::new()),
+            modified: RwLock::new(None),
+        }
+    }
+
+    /// Loads the protocols file again.
+    /// If the file can not be loaded the protocols loaded before are kept
+    pub fn reload(&self) -> Result<(), ProtocolError> {
+        debug!("Loading protocols from {}", self.path.display());
+        let modified = self.modification_time();
+        let file = File::open(&self.path).map_err(ProtocolError::Io)?;
+        let p: Protocols =
+            serde_json::from_reader(BufReader::new(file)).map_err(ProtocolError::Parse)?;
+
+        let mut protocols = BTreeMap::new();
+        for spec in p.protocols {
+            let id = spec.id;
+            if protocols.insert(id, spec).is_some() {
+                return Err(ProtocolError::DuplicateProtocol(id));
+            }
+        }
+        info!(
+            "Loaded {} protocols from {}",
+            protocols.len(),
+            self.path.display()
+        );
+        *self.protocols.write().unwrap() = protocols;
+        *self.modified.write().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Reloads the protocols file if it was modified since it was last loaded.
+    /// Returns true if the protocols were reloaded
+    pub fn reload_if_modified(&self) -> Result<bool, ProtocolError> {
+        match self.modification_time() {
+            Some(modified) if Some(modified) != *self.modified.read().unwrap() => {
+                self.reload().map(|()| true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn modification_time(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the declared protocol with the given identifier
+    pub fn get(&self, id: ProtocolIdentifier) -> Option<ProtocolSpec> {
+        self.protocols.read().unwrap().get(&id).cloned()
+    }
+
+    /// Describes a session of the given protocol and capacity,
+    /// if the protocol is supported with that many peers
+    pub fn descriptor(
+        &self,
+        id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> Result<ProtocolDescriptor, RelayError> {
+        let spec = self.get(id).ok_or(RelayError::UnknownProtocol)?;
+        debug!("Checking if fits protocol: {:?}", spec);
+        if !spec.capacities.contains(capacity) {
+            return Err(RelayError::InvalidProtocol);
+        }
+        // a threshold protocol needs more than t parties
+        if let Some(threshold) = spec.threshold {
+            if capacity <= threshold {
+                return Err(RelayError::InvalidProtocol);
+            }
+        }
+        Ok(ProtocolDescriptor::from_spec(&spec, capacity))
+    }
+}
+
+impl Default for ProtocolRegistry {
+    /// Loads the protocols from the default path,
+    /// a registry that can not be loaded supports no protocol
+    fn default() -> ProtocolRegistry {
+        ProtocolRegistry::load(DEFAULT_PROTOCOLS_PATH).unwrap_or_else(|err| {
+            warn!("{}, no protocol is supported", err);
+            ProtocolRegistry::empty(DEFAULT_PROTOCOLS_PATH)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capacities, Delivery, ProtocolRegistry};
+    use crate::common::RelayError;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn protocols_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_registry_descriptor() {
+        let path = protocols_file(
+            "protocols-descriptor",
+            r#"{"protocols":[
+                {"id":0,"names":["listed"],"capacities":[2,4]},
+                {"id":1,"names":["ranged"],"capacities":{"min":2,"max":10},"rounds":3,
+                 "delivery":"broadcast","max_payload":128,"timeouts":{"round":5}},
+                {"id":2,"names":["threshold"],"capacities":[2,3],"threshold":2}
+            ]}"#,
+        );
+        let registry = ProtocolRegistry::load(&path).unwrap();
+        assert_eq!(
+            registry.get(0).unwrap().capacities,
+            Capacities::List(vec![2, 4])
+        );
+        assert!(registry.descriptor(0, 4).is_ok());
+        assert_eq!(
+            registry.descriptor(0, 3).unwrap_err(),
+            RelayError::InvalidProtocol
+        );
+
+        let descriptor = registry.descriptor(1, 7).unwrap();
+        assert_eq!(descriptor.capacity, 7);
+        assert_eq!(descriptor.rounds, Some(3));
+        assert_eq!(descriptor.delivery, Delivery::Broadcast);
+        assert_eq!(descriptor.max_payload, Some(128));
+        assert_eq!(descriptor.timeouts.registration, None);
+        assert_eq!(descriptor.timeouts.round, Some(5));
+        assert_eq!(
+            registry.descriptor(1, 11).unwrap_err(),
+            RelayError::InvalidProtocol
+        );
+
+        // a threshold protocol needs more than t parties
+        assert_eq!(registry.get(2).unwrap().threshold, Some(2));
+        assert!(registry.descriptor(2, 3).is_ok());
+        assert_eq!(
+            registry.descriptor(2, 2).unwrap_err(),
+            RelayError::InvalidProtocol
+        );
+
+        assert_eq!(
+            registry.descriptor(3, 2).unwrap_err(),
+            RelayError::UnknownProtocol
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_registry_reload() {
+        let path = protocols_file(
+            "protocols-reload",
+            r#"{"protocols":[{"id":0,"names":["test"],"capacities":[2]}]}"#,
+        );
+        let registry = ProtocolRegistry::load(&path).unwrap();
+        assert!(registry.get(1).is_none());
 
-    // Open the file in read-only mode with buffer.
-    let path = PROTOCOLS_F;
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+        fs::write(
+            &path,
+            r#"{"protocols":[{"id":0,"names":["test"],"capacities":[2]},
+                             {"id":1,"names":["new"],"capacities":[3]}]}"#,
+        )
+        .unwrap();
+        registry.reload().unwrap();
+        assert!(registry.get(1).is_some());
 
-    // Read the JSON contents of the file as an instance of `Protocols`.
-    let p = serde_json::from_reader(reader)?;
+        // a corrupt file keeps the protocols loaded before
+        fs::write(&path, "{").unwrap();
+        assert!(registry.reload().is_err());
+        assert!(registry.get(1).is_some());
 
-    //debug!("Got protocols: {:?}", p);
-    Ok(p)
+        fs::write(
+            &path,
+            r#"{"protocols":[{"id":0,"names":["a"],"capacities":[2]},
+                             {"id":0,"names":["b"],"capacities":[3]}]}"#,
+        )
+        .unwrap();
+        assert!(registry.reload().is_err());
+        assert!(registry.get(1).is_some());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_registry_missing_file() {
+        assert!(ProtocolRegistry::load("./no-such-protocols.json").is_err());
+        let registry = ProtocolRegistry::empty("./no-such-protocols.json");
+        assert_eq!(
+            registry.descriptor(0, 2).unwrap_err(),
+            RelayError::UnknownProtocol
+        );
+    }
 }
@@ -0,0 +1,141 @@
+//! Paths of the versioned queries of the relay application, and the replies to them.
+//! A reply is returned serialized in the value of the query response, read from the state
+//! committed in the last block, along with proofs of the records it was read from if requested
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::common::RelayError;
+use crate::identity::{IdentityKey, SignedClientMessage};
+use crate::{AbortMessage, PeerIdentifier, ProtocolIdentifier, SessionIdentifier};
+
+/// Version of the query paths, their first segment
+pub const QUERY_VERSION: &str = "v1";
+
+/// Type of the proof operations of a query response, one for each record the reply was read from.
+/// The key of an operation is the key of the record, and its data the serialized Merkle proof
+pub const MERKLE_PROOF_OP: &str = "relay:merkle";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueryPath {
+    /// `/v1/session/{id}/round/{round}`, the messages of a round
+    Round(SessionIdentifier, u32),
+
+    /// `/v1/session/{id}/peers`, the signed register messages of the peers
+    Peers(SessionIdentifier),
+
+    /// `/v1/session/{id}/status`
+    Status(SessionIdentifier),
+}
+
+impl QueryPath {
+    pub fn session_id(&self) -> SessionIdentifier {
+        match *self {
+            QueryPath::Round(session_id, _)
+            | QueryPath::Peers(session_id)
+            | QueryPath::Status(session_id) => session_id,
+        }
+    }
+}
+
+impl fmt::Display for QueryPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "/{}/session/{}", QUERY_VERSION, self.session_id())?;
+        match self {
+            QueryPath::Round(_, round) => write!(f, "/round/{}", round),
+            QueryPath::Peers(_) => write!(f, "/peers"),
+            QueryPath::Status(_) => write!(f, "/status"),
+        }
+    }
+}
+
+impl FromStr for QueryPath {
+    type Err = RelayError;
+
+    fn from_str(path: &str) -> Result<QueryPath, RelayError> {
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        if segments.len() < 3 || segments[0] != QUERY_VERSION || segments[1] != "session" {
+            return Err(RelayError::InvalidQuery);
+        }
+        let session_id = segments[2].parse().map_err(|_| RelayError::InvalidQuery)?;
+        match &segments[3..] {
+            ["round", round] => round
+                .parse()
+                .map(|round| QueryPath::Round(session_id, round))
+                .map_err(|_| RelayError::InvalidQuery),
+            ["peers"] => Ok(QueryPath::Peers(session_id)),
+            ["status"] => Ok(QueryPath::Status(session_id)),
+            _ => Err(RelayError::InvalidQuery),
+        }
+    }
+}
+
+/// The messages of a round stored so far, by the peer that sent them,
+/// along with their number as stored in the record the proof of the reply starts with
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct RoundReply {
+    pub round: u32,
+    pub messages: BTreeMap<PeerIdentifier, SignedClientMessage>,
+    #[serde(default)]
+    pub total: u32,
+}
+
+/// The stage a relay session is in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RelaySessionState {
+    // no peer registered to the session yet
+    Empty,
+
+    // the session waits for its peers to register
+    Uninitialized,
+
+    // all the peers registered, the session relays their messages
+    Initialized,
+
+    // the session was aborted, by one of its peers or on a missed deadline
+    Aborted,
+}
+
+/// The protocol a session runs, the stage it is in, its current round,
+/// the participants it was created for and the abort of the session if it was aborted
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StatusReply {
+    pub protocol_id: ProtocolIdentifier,
+    pub capacity: u32,
+    pub state: RelaySessionState,
+    pub round: u32,
+    #[serde(default)]
+    pub participants: Vec<IdentityKey>,
+    pub abort: Option<AbortMessage>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryPath;
+    use crate::common::RelayError;
+
+    #[test]
+    fn test_query_path() {
+        let paths = vec![
+            (QueryPath::Round(3, 2), "/v1/session/3/round/2"),
+            (QueryPath::Peers(0), "/v1/session/0/peers"),
+            (QueryPath::Status(12), "/v1/session/12/status"),
+        ];
+        for (path, text) in paths {
+            assert_eq!(path.to_string(), text);
+            assert_eq!(text.parse::<QueryPath>(), Ok(path));
+        }
+        for invalid in &[
+            "",
+            "peers",
+            "/v2/session/0/peers",
+            "/v1/session/first/peers",
+            "/v1/session/0/round/last",
+            "/v1/session/0/round",
+            "/v1/session/0/status/1",
+        ] {
+            assert_eq!(invalid.parse::<QueryPath>(), Err(RelayError::InvalidQuery));
+        }
+    }
+}
@@ -1,4 +1,6 @@
 echo "$0: MP-EDDSA"
+# Passphrase the key stores of the clients are encrypted with
+export MMPC_KEY_PASSPHRASE=${MMPC_KEY_PASSPHRASE:-demo}
 #clean
 
 rm signature?
@@ -10,9 +10,11 @@
 //! this will run a client that utilizes the server in some way
 //!
 use clap::{App, Arg, ArgMatches};
-use mmpc_server::RelayApp;
+use mmpc_server::{RelayApp, SessionLimits, Storage, Timeouts};
+use mmpc_server_common::protocol::ProtocolRegistry;
 use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
@@ -30,6 +32,47 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
                 .short("P")
                 .long("participants"),
         )
+        .arg(
+            Arg::with_name("registration-timeout")
+                .long("registration-timeout")
+                .default_value("300")
+                .value_name("SECONDS")
+                .help("Time the peers have to register, 0 to wait forever"),
+        )
+        .arg(
+            Arg::with_name("round-timeout")
+                .long("round-timeout")
+                .default_value("60")
+                .value_name("SECONDS")
+                .help("Time the peers have to send their messages of a round, 0 to wait forever"),
+        )
+        .arg(
+            Arg::with_name("session-retention")
+                .long("session-retention")
+                .default_value("600")
+                .value_name("SECONDS")
+                .help("Time a created session is kept after it ended, 0 to keep it forever"),
+        )
+        .arg(
+            Arg::with_name("open-sessions")
+                .long("open-sessions")
+                .default_value("4")
+                .value_name("NUMBER")
+                .help("Number of open sessions an identity can create, 0 for any number"),
+        )
+        .arg(
+            Arg::with_name("protocols")
+                .long("protocols")
+                .default_value("./protocols.json")
+                .value_name("FILE")
+                .help("File declaring the supported protocols"),
+        )
+        .arg(
+            Arg::with_name("db")
+                .long("db")
+                .value_name("DIR")
+                .help("Directory the state is stored in, kept only in memory if not given"),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -114,11 +157,51 @@ fn main() {
         .parse()
         .expect("Invalid number of participants");
 
+    let timeout = |name| -> Option<Duration> {
+        let seconds: u64 = matches
+            .value_of(name)
+            .unwrap()
+            .parse()
+            .expect("Unable to parse timeout");
+        match seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    };
+    let timeouts = Timeouts {
+        registration: timeout("registration-timeout"),
+        round: timeout("round-timeout"),
+    };
+
+    let open_sessions: usize = matches
+        .value_of("open-sessions")
+        .unwrap()
+        .parse()
+        .expect("Invalid number of open sessions");
+    let limits = SessionLimits {
+        retention: timeout("session-retention"),
+        open_sessions: match open_sessions {
+            0 => None,
+            open_sessions => Some(open_sessions),
+        },
+    };
+
     let port = addr.port().to_string();
 
     let verbosity: u64 = matches.occurrences_of("verbose");
 
     setup_logging(verbosity, port).expect("failed to initialize logging.");
 
-    abci::run(addr, RelayApp::new(capacity));
+    let protocols = ProtocolRegistry::load(matches.value_of("protocols").unwrap())
+        .unwrap_or_else(|err| panic!("{}", err));
+
+    let mut app =
+        RelayApp::with_protocols(capacity, timeouts, protocols).with_session_limits(limits);
+    if let Some(db) = matches.value_of("db") {
+        app = Storage::open(db)
+            .and_then(|storage| app.with_storage(storage))
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    abci::run(addr, app);
 }
@@ -1,4 +1,12 @@
+mod merkle;
+mod query;
 mod relay_app;
 mod relay_session;
+mod session_manager;
+mod storage;
 
+pub use crate::merkle::MerkleProof;
 pub use crate::relay_app::RelayApp;
+pub use crate::relay_session::Timeouts;
+pub use crate::session_manager::SessionLimits;
+pub use crate::storage::{Storage, StorageError};
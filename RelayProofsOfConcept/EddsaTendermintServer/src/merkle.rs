@@ -0,0 +1,273 @@
+//! Merkle tree over the records of the application state.
+//! Every record is a leaf, in the order of its key, so the root commits to the whole state
+//! and validators holding a different state compute a different app hash.
+//! A proof of a record lets a client check it is part of the state of a block
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::storage::Record;
+
+pub type Hash = Vec<u8>;
+
+// prefixes that keep the hash of a leaf apart from the hash of an inner node
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Returns the hash of a leaf holding the given record
+pub fn leaf_hash(key: &[u8], value: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update((key.len() as u64).to_be_bytes());
+    hasher.update(key);
+    hasher.update((value.len() as u64).to_be_bytes());
+    hasher.update(value);
+    hasher.finalize().to_vec()
+}
+
+/// Returns the hash of an inner node with the given children
+pub fn node_hash(left: &[u8], right: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+/// Proof that a record is a leaf of a tree, sent along with the value of the record.
+/// The hashes of the siblings of the leaf and of each of its ancestors are listed
+/// from the leaves up, but for the nodes without a sibling
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub value: Vec<u8>,
+    // position of the leaf, among the number of leaves of the tree
+    pub index: usize,
+    pub leaves: usize,
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    /// Returns the root of the tree the record with the given key is a leaf of, by the proof.
+    /// The record is part of the state if the root is the app hash of the block
+    pub fn root(&self, key: &[u8]) -> Hash {
+        let mut hash = leaf_hash(key, &self.value);
+        let mut siblings = self.siblings.iter();
+        let (mut index, mut width) = (self.index, self.leaves);
+        while width > 1 {
+            // a node without a sibling is carried up as it is
+            if index % 2 == 1 || index + 1 < width {
+                // a proof missing a sibling yields another root rather than failing
+                let sibling = siblings.next().map(Vec::as_slice).unwrap_or_default();
+                hash = if index % 2 == 1 {
+                    node_hash(sibling, &hash)
+                } else {
+                    node_hash(&hash, sibling)
+                };
+            }
+            index /= 2;
+            width = (width + 1) / 2;
+        }
+        hash
+    }
+}
+
+pub struct MerkleTree {
+    // the hashes of each level of the tree, from the leaves up to the root
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    /// Builds the tree over the given records, which must be sorted by key.
+    /// A node without a sibling is carried up to the next level as it is
+    pub fn new(records: &[Record]) -> MerkleTree {
+        MerkleTree::from_leaves(
+            records
+                .iter()
+                .map(|(key, value)| leaf_hash(key, value))
+                .collect(),
+        )
+    }
+
+    // Builds the levels of the tree over the hashes of its leaves
+    fn from_leaves(leaves: Vec<Hash>) -> MerkleTree {
+        let mut levels = vec![leaves];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => single.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    /// Updates the tree built over the previous records to the given records, both sorted by key.
+    /// Only the leaves of the records that changed are hashed again. While no record is added
+    /// or removed, only the nodes above those leaves are, otherwise the levels are built again
+    pub fn update(&mut self, previous: &[Record], records: &[Record]) {
+        let same_keys = previous.len() == records.len()
+            && previous
+                .iter()
+                .zip(records)
+                .all(|((previous_key, _), (key, _))| previous_key == key);
+        if same_keys {
+            for (index, ((_, previous_value), (key, value))) in
+                previous.iter().zip(records).enumerate()
+            {
+                if previous_value != value {
+                    self.set_leaf(index, leaf_hash(key, value));
+                }
+            }
+            return;
+        }
+        let mut previous_leaves = previous.iter().zip(self.levels[0].iter()).peekable();
+        let leaves = records
+            .iter()
+            .map(|(key, value)| {
+                while let Some(((previous_key, _), _)) = previous_leaves.peek() {
+                    if previous_key >= key {
+                        break;
+                    }
+                    previous_leaves.next();
+                }
+                match previous_leaves.peek() {
+                    Some(((previous_key, previous_value), hash))
+                        if previous_key == key && previous_value == value =>
+                    {
+                        (*hash).clone()
+                    }
+                    _ => leaf_hash(key, value),
+                }
+            })
+            .collect();
+        *self = MerkleTree::from_leaves(leaves);
+    }
+
+    // Replaces the hash of a leaf, and the hashes of the nodes above it
+    fn set_leaf(&mut self, index: usize, hash: Hash) {
+        self.levels[0][index] = hash;
+        let mut position = index;
+        for level in 1..self.levels.len() {
+            let left = position & !1;
+            let below = &self.levels[level - 1];
+            let node = match below.get(left + 1) {
+                Some(right) => node_hash(&below[left], right),
+                None => below[left].clone(),
+            };
+            position /= 2;
+            self.levels[level][position] = node;
+        }
+    }
+
+    /// Returns the proof of the leaf at the given position, holding the given value
+    pub fn proof(&self, index: usize, value: &[u8]) -> MerkleProof {
+        let mut siblings = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(sibling.clone());
+            }
+            position /= 2;
+        }
+        MerkleProof {
+            value: value.to_vec(),
+            index,
+            leaves: self.levels[0].len(),
+            siblings,
+        }
+    }
+
+    /// Returns the root of the tree, the hash of no data if the tree is empty
+    pub fn root(&self) -> Hash {
+        match self.levels[self.levels.len() - 1].first() {
+            Some(root) => root.clone(),
+            None => Sha256::digest(&[]).to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leaf_hash, node_hash, MerkleProof, MerkleTree};
+    use crate::storage::Record;
+
+    fn records(count: usize) -> Vec<Record> {
+        (0..count)
+            .map(|i| (format!("key/{}", i).into_bytes(), vec![i as u8]))
+            .collect()
+    }
+
+    #[test]
+    fn test_root() {
+        let records = records(3);
+        let leaves: Vec<_> = records
+            .iter()
+            .map(|(key, value)| leaf_hash(key, value))
+            .collect();
+        assert_eq!(MerkleTree::new(&records[..1]).root(), leaves[0]);
+        assert_eq!(
+            MerkleTree::new(&records).root(),
+            node_hash(&node_hash(&leaves[0], &leaves[1]), &leaves[2])
+        );
+        assert_eq!(MerkleTree::new(&[]).root(), MerkleTree::new(&[]).root());
+    }
+
+    #[test]
+    fn test_root_commits_to_state() {
+        let root = MerkleTree::new(&records(5)).root();
+        let mut changed = records(5);
+        changed[3].1 = vec![9];
+        assert_ne!(MerkleTree::new(&changed).root(), root);
+        assert_ne!(MerkleTree::new(&records(4)).root(), root);
+        // the hash depends on where each key ends and its value starts
+        assert_ne!(leaf_hash(b"ab", b"c"), leaf_hash(b"a", b"bc"));
+    }
+
+    #[test]
+    fn test_update() {
+        let mut tree = MerkleTree::new(&records(5));
+        // a record changes
+        let mut changed = records(5);
+        changed[3].1 = vec![9];
+        tree.update(&records(5), &changed);
+        assert_eq!(tree.root(), MerkleTree::new(&changed).root());
+        // records are added and removed
+        let mut next = changed.clone();
+        next.remove(1);
+        next.push((b"key/7".to_vec(), vec![7]));
+        next.push((b"key/8".to_vec(), vec![8]));
+        tree.update(&changed, &next);
+        assert_eq!(tree.root(), MerkleTree::new(&next).root());
+        tree.update(&next, &[]);
+        assert_eq!(tree.root(), MerkleTree::new(&[]).root());
+        tree.update(&[], &next);
+        assert_eq!(tree.root(), MerkleTree::new(&next).root());
+        let proof = tree.proof(4, &next[4].1);
+        assert_eq!(proof.root(&next[4].0), tree.root());
+    }
+
+    #[test]
+    fn test_proof() {
+        for count in 1..8 {
+            let records = records(count);
+            let tree = MerkleTree::new(&records);
+            for (index, (key, value)) in records.iter().enumerate() {
+                let proof = tree.proof(index, value);
+                assert_eq!(proof.root(key), tree.root());
+                // the proof holds only for the record at its position
+                let other = (index + 1) % count;
+                if other != index {
+                    assert_ne!(proof.root(&records[other].0), tree.root());
+                }
+                let forged = MerkleProof {
+                    value: vec![9],
+                    ..proof
+                };
+                assert_ne!(forged.root(key), tree.root());
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Replies to the versioned queries, read from the state committed in the last block.
+//! The app hash of the block commits to that state, so each record a reply is read from
+//! can be proven to a client that trusts the header of the block
+use abci::{Proof, ProofOp};
+use mmpc_server_common::common::RelayError;
+use mmpc_server_common::identity::SignedClientMessage;
+use mmpc_server_common::query::{QueryPath, RoundReply, MERKLE_PROOF_OP};
+use mmpc_server_common::{
+    MissingMessagesRequest, PeerIdentifier, PeersReply, SessionIdentifier, StoredMessages,
+    DEFAULT_SESSION_ID,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::merkle::MerkleTree;
+use crate::relay_session::{self, ABORT_KEY, REGISTRATION_PREFIX, SESSION_KEY};
+use crate::session_manager::session_prefix;
+use crate::storage::{self, Change, Record};
+
+fn to_value<T: Serialize>(reply: &T) -> Vec<u8> {
+    serde_json::to_vec(reply).expect("Unable to serialize the reply")
+}
+
+/// The records of the state as committed in a block, sorted by key
+pub struct CommittedState {
+    height: i64,
+    records: Vec<Record>,
+    tree: MerkleTree,
+}
+
+impl CommittedState {
+    pub fn new(height: i64, records: Vec<Record>) -> CommittedState {
+        let tree = MerkleTree::new(&records);
+        CommittedState {
+            height,
+            records,
+            tree,
+        }
+    }
+
+    /// Moves to the records committed in the block of the given height.
+    /// The tree is updated for the records that changed, which are returned to be stored
+    pub fn update(&mut self, height: i64, records: Vec<Record>) -> Vec<Change> {
+        let changes = storage::changes(&self.records, &records);
+        if !changes.is_empty() {
+            self.tree.update(&self.records, &records);
+            self.records = records;
+        }
+        self.height = height;
+        changes
+    }
+
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+
+    /// Returns the hash of the committed state, the root of the Merkle tree over its records
+    pub fn app_hash(&self) -> Vec<u8> {
+        self.tree.root()
+    }
+
+    /// Answers the query with the serialized reply,
+    /// along with the positions of the records the reply was read from
+    pub fn query(&self, path: &QueryPath) -> Result<(Vec<u8>, Vec<usize>), RelayError> {
+        let session_prefix = session_prefix(path.session_id());
+        let session = self.session_position(path.session_id())?;
+        Ok(match *path {
+            QueryPath::Round(_, round) => {
+                let prefix = format!("{}{}", session_prefix, relay_session::round_prefix(round));
+                let positions = self.range(&prefix);
+                let (total, count) = self.round_count(path.session_id(), round);
+                let reply = RoundReply {
+                    round,
+                    messages: self.envelopes(positions.clone(), &prefix),
+                    total,
+                };
+                (
+                    to_value(&reply),
+                    count.into_iter().chain(positions).collect(),
+                )
+            }
+            QueryPath::Peers(_) => {
+                let prefix = format!("{}{}", session_prefix, REGISTRATION_PREFIX);
+                let positions = self.range(&prefix);
+                let reply = PeersReply {
+                    registrations: self.envelopes(positions.clone(), &prefix),
+                };
+                (to_value(&reply), positions.collect())
+            }
+            QueryPath::Status(_) => {
+                let mut status = relay_session::session_status(&self.records[session].1)
+                    .expect("Corrupt committed session");
+                let mut positions = vec![session];
+                if let Some(abort) = self.position(&format!("{}{}", session_prefix, ABORT_KEY)) {
+                    status.abort = Some(
+                        serde_json::from_slice(&self.records[abort].1)
+                            .expect("Corrupt committed abort"),
+                    );
+                    positions.push(abort);
+                }
+                (to_value(&status), positions)
+            }
+        })
+    }
+
+    /// Answers the request for the messages of a round a peer is missing, a page at a time.
+    /// The records read are the number of messages of the round and the messages of the page.
+    /// A session that was aborted answers with the error of its abort
+    pub fn missing_messages(
+        &self,
+        request: &MissingMessagesRequest,
+    ) -> Result<(Vec<u8>, Vec<usize>), RelayError> {
+        let session_id = request.session_id.unwrap_or(DEFAULT_SESSION_ID);
+        let session_prefix = session_prefix(session_id);
+        self.session_position(session_id)?;
+        if self
+            .position(&format!("{}{}", session_prefix, ABORT_KEY))
+            .is_some()
+        {
+            return Err(RelayError::SessionAborted);
+        }
+        let prefix = format!(
+            "{}{}",
+            session_prefix,
+            relay_session::round_prefix(request.round)
+        );
+        let mut stored_messages = StoredMessages::new();
+        for (peer_id, envelope) in self.envelopes(self.range(&prefix), &prefix) {
+            stored_messages.update(request.round, peer_id, envelope);
+        }
+        let reply = stored_messages.get_messages_page(
+            request.round,
+            &request.missing_clients,
+            request.cursor,
+            request.page_size(),
+        );
+        let (_, count) = self.round_count(session_id, request.round);
+        let positions = count
+            .into_iter()
+            .chain(
+                reply
+                    .missing_messages
+                    .keys()
+                    .filter_map(|peer_id| self.position(&format!("{}{:010}", prefix, peer_id))),
+            )
+            .collect();
+        Ok((to_value(&reply), positions))
+    }
+
+    /// The proofs of the records at the given positions, one operation for each record
+    pub fn proof(&self, positions: &[usize]) -> Proof {
+        let ops: Vec<ProofOp> = positions
+            .iter()
+            .map(|&position| {
+                let (key, value) = &self.records[position];
+                let mut op = ProofOp::new();
+                op.set_field_type(String::from(MERKLE_PROOF_OP));
+                op.set_key(key.clone());
+                op.set_data(to_value(&self.tree.proof(position, value)));
+                op
+            })
+            .collect();
+        let mut proof = Proof::new();
+        proof.set_ops(ops.into());
+        proof
+    }
+
+    // The position of the record of the session,
+    // an error if the session was not created or nothing of it was committed yet
+    fn session_position(&self, session_id: SessionIdentifier) -> Result<usize, RelayError> {
+        self.position(&format!("{}{}", session_prefix(session_id), SESSION_KEY))
+            .ok_or(RelayError::UnknownSession)
+    }
+
+    // The number of messages stored for the round along with the position of its record,
+    // no messages if none were stored for the round
+    fn round_count(&self, session_id: SessionIdentifier, round: u32) -> (u32, Option<usize>) {
+        let key = format!(
+            "{}{}",
+            session_prefix(session_id),
+            relay_session::count_key(round)
+        );
+        match self.position(&key) {
+            Some(position) => (
+                serde_json::from_slice(&self.records[position].1).expect("Corrupt committed count"),
+                Some(position),
+            ),
+            None => (0, None),
+        }
+    }
+
+    // The position of the record with the key
+    fn position(&self, key: &str) -> Option<usize> {
+        self.records
+            .binary_search_by(|(record_key, _)| record_key.as_slice().cmp(key.as_bytes()))
+            .ok()
+    }
+
+    // The positions of the records whose keys start with the prefix
+    fn range(&self, prefix: &str) -> Range<usize> {
+        let start = match self
+            .records
+            .binary_search_by(|(key, _)| key.as_slice().cmp(prefix.as_bytes()))
+        {
+            Ok(start) | Err(start) => start,
+        };
+        let count = self.records[start..]
+            .iter()
+            .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+            .count();
+        start..start + count
+    }
+
+    // The signed messages stored in the records, by the peer number their keys end with
+    fn envelopes(
+        &self,
+        positions: Range<usize>,
+        prefix: &str,
+    ) -> BTreeMap<PeerIdentifier, SignedClientMessage> {
+        self.records[positions]
+            .iter()
+            .map(|(key, value)| {
+                let peer_id = String::from_utf8_lossy(&key[prefix.len()..])
+                    .parse()
+                    .expect("Corrupt committed key");
+                let envelope = serde_json::from_slice(value).expect("Corrupt committed message");
+                (peer_id, envelope)
+            })
+            .collect()
+    }
+}
@@ -1,50 +1,213 @@
-use crate::relay_session::RelaySession;
+use crate::query::CommittedState;
+use crate::relay_session::{RelaySession, Timeouts};
+use crate::session_manager::{SessionLimits, SessionManager};
+use crate::storage::{CommitInfo, Storage, StorageError};
 use abci::{
-    RequestCheckTx, RequestDeliverTx, RequestQuery, ResponseCheckTx, ResponseDeliverTx,
-    ResponseQuery,
+    Event, KVPair, RequestBeginBlock, RequestCheckTx, RequestCommit, RequestDeliverTx, RequestInfo,
+    RequestQuery, ResponseBeginBlock, ResponseCheckTx, ResponseCommit, ResponseDeliverTx,
+    ResponseInfo, ResponseQuery,
 };
-use log::{debug, info, warn};
-use mmpc_server_common::protocol::ProtocolDescriptor;
+use log::{debug, error, info, warn};
+use mmpc_server_common::common::RelayError;
+use mmpc_server_common::identity::{IdentityKey, SignedClientMessage};
+use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
+use mmpc_server_common::query::QueryPath;
 use mmpc_server_common::{
-    ClientMessage, ClientMessageType, MissingMessagesRequest, ServerMessage, ServerResponse,
+    ClientMessage, ClientMessageType, MissingMessagesRequest, PeerIdentifier, ServerMessage,
+    ServerResponse, SessionIdentifier, ABORT_ATTRIBUTE, DEFAULT_PAGE_SIZE, DEFAULT_SESSION_ID,
+    PEER_ATTRIBUTE, RELAY_EVENT, ROUND_ATTRIBUTE, SESSION_ATTRIBUTE,
 };
-
-const MAX_CLIENTS: usize = 12;
+use std::sync::Arc;
+use std::time::Duration;
 
 pub struct RelayApp {
-    relay_session: RelaySession,
+    sessions: SessionManager,
+
+    // where the state is stored once a block is committed, the state is only kept in memory without it
+    storage: Option<Storage>,
+
+    // height of the block being executed
+    height: i64,
+
+    // the last block committed
+    last_commit: CommitInfo,
+
+    // the state of the last block committed, the versioned queries are answered from
+    committed: CommittedState,
 }
 
 impl RelayApp {
+    /// Creates the application with the default session for the given number of peers
     pub fn new(capacity: u32) -> RelayApp {
+        RelayApp::with_timeouts(capacity, Timeouts::default())
+    }
+
+    /// Creates the application with sessions that are aborted if they miss one of the deadlines
+    pub fn with_timeouts(capacity: u32, timeouts: Timeouts) -> RelayApp {
+        RelayApp::with_protocols(capacity, timeouts, ProtocolRegistry::default())
+    }
+
+    /// Creates the application with sessions running the protocols of the registry.
+    /// All the nodes must load the same protocols, as they decide which transactions are valid
+    pub fn with_protocols(
+        capacity: u32,
+        timeouts: Timeouts,
+        protocols: ProtocolRegistry,
+    ) -> RelayApp {
         RelayApp {
-            relay_session: RelaySession::new(capacity),
+            sessions: SessionManager::new(capacity, timeouts, Arc::new(protocols)),
+            storage: None,
+            height: 0,
+            last_commit: CommitInfo::default(),
+            committed: CommittedState::new(0, Vec::new()),
         }
     }
+
+    /// Sets how long the sessions clients create are kept after they ended,
+    /// and how many open sessions an identity can create
+    pub fn with_session_limits(mut self, limits: SessionLimits) -> RelayApp {
+        self.sessions.set_limits(limits);
+        self
+    }
+
+    /// Stores the state of the application once each block is committed.
+    /// The application resumes from the state of the last block committed to the storage
+    pub fn with_storage(mut self, storage: Storage) -> Result<RelayApp, StorageError> {
+        let records = storage.records()?;
+        self.sessions.restore(&records)?;
+        self.last_commit = storage.last_commit()?;
+        self.height = self.last_commit.height;
+        self.committed = CommittedState::new(self.height, records);
+        info!(
+            "Restored the state of block {} from the storage, holding {} sessions",
+            self.last_commit.height,
+            self.sessions.number_of_sessions()
+        );
+        self.storage = Some(storage);
+        Ok(self)
+    }
 }
 
-// Convert incoming tx data to the proper BigEndian size. txs.len() > 8 will return 0
+// Convert incoming tx data to a string for logging, invalid UTF-8 is replaced
 fn convert_tx(bytes: &[u8]) -> String {
-    String::from_utf8(bytes.to_vec()).expect("Found invalid UTF-8")
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+// Parse incoming tx data as a signed client message,
+// returns the envelope along with the client message after verifying its signature
+fn parse_tx(bytes: &[u8]) -> Result<(SignedClientMessage, ClientMessage), RelayError> {
+    let envelope: SignedClientMessage = serde_json::from_slice(bytes).map_err(|err| {
+        warn!("Malformed transaction: {}", err);
+        RelayError::MalformedMessage
+    })?;
+    let client_message = envelope.open().map_err(|err| {
+        warn!("Rejected transaction: {}", err);
+        err
+    })?;
+    Ok((envelope, client_message))
+}
+
+// Sets the response to a delivered transaction in its data, which the results of the block
+// commit to, and in its log for the clients that read it there
+fn set_response(resp: &mut ResponseDeliverTx, response: String) {
+    resp.set_data(response.clone().into_bytes());
+    resp.set_log(response);
+}
+
+// The event tagging a transaction of the session, subscribers query the events by their attributes
+fn relay_event(session_id: SessionIdentifier, attributes: &[(&str, String)]) -> Event {
+    let attributes: Vec<KVPair> = [(SESSION_ATTRIBUTE, session_id.to_string())]
+        .iter()
+        .chain(attributes)
+        .map(|(key, value)| {
+            let mut pair = KVPair::new();
+            pair.set_key(key.as_bytes().to_vec());
+            pair.set_value(value.as_bytes().to_vec());
+            pair
+        })
+        .collect();
+    let mut event = Event::new();
+    event.set_field_type(String::from(RELAY_EVENT));
+    event.set_attributes(attributes.into());
+    event
 }
 
 impl RelayApp {
-    fn can_relay(&self, client_message: &ClientMessage) -> u32 {
+    // Serialize an error response, to be returned in the log of a rejected transaction.
+    // A session that was aborted returns its abort along with the error
+    fn error_log(&self, session_id: SessionIdentifier, err: RelayError) -> String {
+        let mut server_msg = ServerMessage::new();
+        server_msg.response = Some(ServerResponse::ErrorResponse(err));
+        if err == RelayError::SessionAborted {
+            server_msg.abort = self
+                .sessions
+                .get_session(session_id)
+                .ok()
+                .and_then(|session| session.abort_message());
+        }
+        serde_json::to_string(&server_msg).unwrap()
+    }
+
+    // Answers a query from the state of the last block with the serialized reply in the value
+    // of the response, along with the proofs of the records it was read from if requested
+    fn query_committed(
+        &self,
+        session_id: SessionIdentifier,
+        reply: Result<(Vec<u8>, Vec<usize>), RelayError>,
+        prove: bool,
+    ) -> ResponseQuery {
+        let mut resp = ResponseQuery::new();
+        match reply {
+            Ok((value, positions)) => {
+                debug!(
+                    "Query of session {}: {} records",
+                    session_id,
+                    positions.len()
+                );
+                if prove {
+                    resp.set_proof(self.committed.proof(&positions));
+                }
+                resp.set_value(value);
+                resp.set_code(0);
+                resp.set_index(-1);
+                resp.set_height(self.committed.height());
+            }
+            Err(err) => {
+                resp.set_code(err.code());
+                resp.set_log(self.error_log(session_id, err));
+            }
+        }
+        resp
+    }
+
+    fn can_relay(
+        session: &RelaySession,
+        identity: &IdentityKey,
+        client_message: &ClientMessage,
+    ) -> Result<(), RelayError> {
         match client_message.msg_type() {
             ClientMessageType::RelayMessage => {
                 let msg = client_message.clone().relay_message.unwrap();
-                let can_relay = self.relay_session.can_relay(&msg.from, &msg);
+                let can_relay = session.can_relay(identity, &msg);
                 match can_relay {
                     Ok(()) => debug!("Can relay this message"),
                     _ => (),
                 }
+                can_relay
             }
-            _ => (),
+            _ => Ok(()),
         }
-        0
     }
 
-    fn is_valid(&self, client_message: &ClientMessage) -> u32 {
+    fn is_valid(
+        &self,
+        identity: &IdentityKey,
+        client_message: &ClientMessage,
+    ) -> Result<(), RelayError> {
+        if let Some(create) = &client_message.create_session {
+            return self.sessions.can_create(create, identity).map(|_| ());
+        }
+        let session = self.sessions.get_session(client_message.session_id())?;
         match client_message.msg_type() {
             ClientMessageType::Register => {
                 let register = client_message.clone().register.unwrap();
@@ -54,32 +217,64 @@ impl RelayApp {
                 );
                 let protocol_descriptor =
                     ProtocolDescriptor::new(register.protocol_id, register.capacity);
-                if self
-                    .relay_session
-                    .can_register(&register.addr, protocol_descriptor)
-                {
-                    0
-                } else {
-                    1
-                }
+                session.can_register(protocol_descriptor, identity)
             }
             ClientMessageType::RelayMessage => {
-                // TODO: Check validity of relay message here
-                0
+                RelayApp::can_relay(&session, identity, client_message)
+            }
+            ClientMessageType::Abort => {
+                let abort = client_message.clone().abort.unwrap();
+                session.can_abort(identity, &abort).map(|_| ())
+            }
+            _ => {
+                warn!("Unsupported message type {:?}", client_message.msg_type());
+                Err(RelayError::UnsupportedMessage)
             }
-            _ => unimplemented!("This is not yet implemented"),
         }
     }
 }
 
 impl abci::Application for RelayApp {
+    fn info(&mut self, _req: &RequestInfo) -> ResponseInfo {
+        // tendermint replays the blocks after the last one committed
+        let mut resp = ResponseInfo::new();
+        resp.set_last_block_height(self.last_commit.height);
+        resp.set_last_block_app_hash(self.last_commit.app_hash.clone());
+        resp
+    }
+
+    fn begin_block(&mut self, req: &RequestBeginBlock) -> ResponseBeginBlock {
+        self.height = req.get_header().get_height();
+        // deadlines are checked against the time of the block,
+        // which is the same for all the nodes executing it
+        let time = req.get_header().get_time();
+        let block_time = Duration::new(time.get_seconds() as u64, time.get_nanos() as u32);
+        for (session_id, abort) in self.sessions.set_block_time(block_time) {
+            warn!(
+                "Session {} aborted, peers {:?} did not respond in time",
+                session_id, abort.unresponsive
+            );
+        }
+        ResponseBeginBlock::new()
+    }
+
     fn check_tx(&mut self, req: &RequestCheckTx) -> ResponseCheckTx {
         let mut resp = ResponseCheckTx::new();
         let c = convert_tx(req.get_tx());
         debug!("CheckTX: Received {:?}", c);
-        let client_message: ClientMessage = serde_json::from_slice(req.get_tx()).unwrap();
-        debug!("Value is {:?}", client_message);
-        resp.set_code(self.is_valid(&client_message));
+        let mut session_id = DEFAULT_SESSION_ID;
+        let validity = parse_tx(req.get_tx()).and_then(|(envelope, client_message)| {
+            debug!("Value is {:?}", client_message);
+            session_id = client_message.session_id();
+            self.is_valid(&envelope.identity, &client_message)
+        });
+        match validity {
+            Ok(()) => resp.set_code(0),
+            Err(err) => {
+                resp.set_code(err.code());
+                resp.set_log(self.error_log(session_id, err));
+            }
+        }
         resp
     }
 
@@ -87,63 +282,143 @@ impl abci::Application for RelayApp {
         let mut resp = ResponseDeliverTx::new();
         let c = convert_tx(req.get_tx());
         info!("DeliverTX: Received {:?}", c);
-        let client_message: ClientMessage = serde_json::from_slice(req.get_tx()).unwrap();
+        let (envelope, client_message) = match parse_tx(req.get_tx()) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                resp.set_code(err.code());
+                resp.set_log(self.error_log(DEFAULT_SESSION_ID, err));
+                return resp;
+            }
+        };
         info!("Value is {:?} In DeliverTx", client_message);
 
         debug!("Message type is {:?}", client_message.msg_type());
 
+        if let Some(create) = &client_message.create_session {
+            match self.sessions.create_session(create, envelope.identity) {
+                Ok(session_id) => {
+                    resp.set_events(vec![relay_event(session_id, &[])].into());
+                    let mut server_msg = ServerMessage::new();
+                    server_msg.response = Some(ServerResponse::Session(session_id));
+                    set_response(&mut resp, serde_json::to_string(&server_msg).unwrap());
+                }
+                Err(err) => {
+                    resp.set_code(err.code());
+                    resp.set_log(self.error_log(DEFAULT_SESSION_ID, err));
+                }
+            }
+            return resp;
+        }
+        let session_id = client_message.session_id();
+        let mut session = match self.sessions.get_session(session_id) {
+            Ok(session) => session,
+            Err(err) => {
+                resp.set_code(err.code());
+                resp.set_log(self.error_log(session_id, err));
+                return resp;
+            }
+        };
+
         match client_message.msg_type() {
             ClientMessageType::Register => {
-                if self.is_valid(&client_message) != 0 {
-                    resp.set_code(1);
-                    return resp;
-                }
                 let register = client_message.register.unwrap();
                 warn!(
                     "Got register message. protocol id requested: {}",
                     register.protocol_id
                 );
-                let client_index = self
-                    .relay_session
-                    .register_new_peer(
-                        register.addr,
-                        register.protocol_id,
-                        register.capacity,
-                        register.index,
-                    )
-                    .unwrap();
+                let client_index = match session.register_new_peer(
+                    register.protocol_id,
+                    register.capacity,
+                    register.index,
+                    envelope.identity,
+                ) {
+                    Ok(client_index) => client_index,
+                    Err(err) => {
+                        resp.set_code(err.code());
+                        resp.set_log(self.error_log(session_id, err));
+                        return resp;
+                    }
+                };
+                // the signed register message is kept for the other peers,
+                // to learn the identity and encryption key of this peer
+                session.add_registration(client_index, envelope);
                 resp.set_code(0);
-                info!("Setting data to {:?}", resp.data);
                 let mut server_msg = ServerMessage::new();
                 server_msg.response = Some(ServerResponse::Register(client_index));
-                // TODO: Currently using log and not data, data is expecting a different encoding,
-                // sigh
-                resp.set_log(serde_json::to_string(&server_msg).unwrap().to_owned());
+                set_response(&mut resp, serde_json::to_string(&server_msg).unwrap());
             }
             ClientMessageType::RelayMessage => {
                 let relay_msg = client_message.clone().relay_message.unwrap();
                 let peer_id = relay_msg.peer_number;
                 info!("Got relay message from {}", peer_id);
-                if self.can_relay(&client_message) == 0 {
-                    debug!("I can relay this")
+                if let Err(err) = RelayApp::can_relay(&session, &envelope.identity, &client_message)
+                {
+                    resp.set_code(err.code());
+                    resp.set_log(self.error_log(session_id, err));
+                    return resp;
                 }
-                let round = self.relay_session.round();
-                self.relay_session
-                    .update_stored_messages(round, peer_id, client_message);
+                let round = session.round();
+                // the envelope is stored, so the other peers can verify the message
+                session.update_stored_messages(round, peer_id, envelope);
                 info!("Stored message of client {}", peer_id);
+                // peers subscribed to the session receive the message once the block is committed
+                resp.set_events(
+                    vec![relay_event(
+                        session_id,
+                        &[
+                            (ROUND_ATTRIBUTE, round.to_string()),
+                            (PEER_ATTRIBUTE, peer_id.to_string()),
+                        ],
+                    )]
+                    .into(),
+                );
 
-                let response = self
-                    .relay_session
-                    .stored_messages()
-                    .get_messages_map_client_message(round);
-                resp.set_log(serde_json::to_string(&response).unwrap().to_owned());
+                // the first page of the messages of the round, peers query the ones that follow
+                let capacity = session.protocol().capacity;
+                let peers: Vec<PeerIdentifier> = (1..=capacity).collect();
+                let response = session.stored_messages().get_messages_page(
+                    round,
+                    &peers,
+                    None,
+                    DEFAULT_PAGE_SIZE as usize,
+                );
+                set_response(
+                    &mut resp,
+                    serde_json::to_string(&response.missing_messages).unwrap(),
+                );
                 debug!("Response log {:?}", resp.log);
-                self.relay_session
-                    .try_increase_round(self.relay_session.protocol().capacity);
+                session.try_increase_round(session.protocol().capacity);
                 // If received a message from each party, increase round
                 debug!("Response log {:?}", resp.log);
             }
-            _ => unimplemented!("This is not yet implemented"),
+            ClientMessageType::Abort => {
+                let abort = client_message.abort.unwrap();
+                warn!("Got abort message from {}", abort.peer_number);
+                // the abort is recorded in the state, for the peers that poll for messages
+                match session.abort(&envelope.identity, &abort) {
+                    Ok(recorded) => {
+                        resp.set_events(
+                            vec![relay_event(
+                                session_id,
+                                &[(ABORT_ATTRIBUTE, recorded.peer_number.to_string())],
+                            )]
+                            .into(),
+                        );
+                        let mut server_msg = ServerMessage::new();
+                        server_msg.abort = Some(recorded);
+                        set_response(&mut resp, serde_json::to_string(&server_msg).unwrap());
+                    }
+                    Err(err) => {
+                        resp.set_code(err.code());
+                        resp.set_log(self.error_log(session_id, err));
+                    }
+                }
+            }
+            _ => {
+                let err = RelayError::UnsupportedMessage;
+                resp.set_code(err.code());
+                resp.set_log(self.error_log(session_id, err));
+            }
         }
 
         resp
@@ -152,31 +427,542 @@ impl abci::Application for RelayApp {
     fn query(&mut self, req: &RequestQuery) -> ResponseQuery {
         let mut resp = ResponseQuery::new();
 
-        let missing_messages: MissingMessagesRequest = serde_json::from_slice(&req.data).unwrap();
+        // the versioned queries are answered from the state of the last block
+        if !req.path.is_empty() {
+            match req.path.parse::<QueryPath>() {
+                Ok(path) => {
+                    let reply = self.committed.query(&path);
+                    return self.query_committed(path.session_id(), reply, req.get_prove());
+                }
+                Err(err) => {
+                    warn!("Unknown query path {}", req.path);
+                    resp.set_code(err.code());
+                    resp.set_log(self.error_log(DEFAULT_SESSION_ID, err));
+                    return resp;
+                }
+            }
+        }
+
+        // the empty path answers the messages a peer is missing, from the state of the last block
+        // as well. Peers polling an aborted session learn of the abort instead of waiting for messages
+        let missing_messages: MissingMessagesRequest = match serde_json::from_slice(&req.data) {
+            Ok(missing_messages) => missing_messages,
+            Err(err) => {
+                warn!("Malformed query: {}", err);
+                let err = RelayError::MalformedMessage;
+                resp.set_code(err.code());
+                resp.set_log(self.error_log(DEFAULT_SESSION_ID, err));
+                return resp;
+            }
+        };
         debug!("Query: Received {:?}", missing_messages);
+        let session_id = missing_messages.session_id.unwrap_or(DEFAULT_SESSION_ID);
+        let reply = self.committed.missing_messages(&missing_messages);
+        self.query_committed(session_id, reply, req.get_prove())
+    }
+
+    fn commit(&mut self, _req: &RequestCommit) -> ResponseCommit {
+        // only the records that changed in the block are hashed again and written
+        let changes = self.committed.update(self.height, self.sessions.records());
+        let app_hash = self.committed.app_hash();
+        self.last_commit = match &self.storage {
+            Some(storage) => storage
+                .commit(self.height, &app_hash, &changes)
+                .unwrap_or_else(|err| {
+                    // the block can't be acknowledged without its state
+                    error!("Unable to commit block {}: {}", self.height, err);
+                    panic!("{}", err)
+                }),
+            None => CommitInfo {
+                height: self.height,
+                app_hash,
+            },
+        };
+        debug!(
+            "Committed block {} with app hash {}",
+            self.height,
+            hex::encode(&self.last_commit.app_hash)
+        );
+        let mut resp = ResponseCommit::new();
+        resp.set_data(self.last_commit.app_hash.clone());
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RelayApp;
+    use crate::merkle::MerkleProof;
+    use crate::session_manager::SessionLimits;
+    use abci::{
+        Application, RequestCheckTx, RequestCommit, RequestDeliverTx, RequestQuery,
+        ResponseDeliverTx,
+    };
+    use mmpc_server_common::common::RelayError;
+    use mmpc_server_common::identity::{Identity, SignedClientMessage};
+    use mmpc_server_common::query::{QueryPath, RelaySessionState, RoundReply, StatusReply};
+    use mmpc_server_common::{
+        AbortMessage, AbortReason, ClientMessage, MissingMessagesReply, MissingMessagesRequest,
+        PeersReply, RelayMessage, ServerMessage, ServerResponse, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE,
+    };
+    use std::collections::BTreeMap;
+
+    fn register_tx(identity: &Identity, capacity: u32) -> Vec<u8> {
+        let mut msg = ClientMessage::new();
+        msg.set_register(1, capacity, -1, None);
+        serde_json::to_vec(&identity.sign(&msg)).unwrap()
+    }
+
+    // A message of the first round of the session
+    fn first_round_message(peer_id: u32) -> RelayMessage {
+        let mut relay_message = RelayMessage::new(peer_id, 1);
+        relay_message.set_round(0);
+        relay_message
+    }
+
+    fn deliver(app: &mut RelayApp, tx: &[u8]) {
+        let mut req = RequestDeliverTx::new();
+        req.set_tx(tx.to_vec());
+        assert_eq!(app.deliver_tx(&req).get_code(), 0);
+    }
+
+    fn check(app: &mut RelayApp, tx: &[u8]) -> u32 {
+        let mut req = RequestCheckTx::new();
+        req.set_tx(tx.to_vec());
+        app.check_tx(&req).get_code()
+    }
+
+    #[test]
+    fn test_check_tx() {
+        let capacity = 2;
+        let mut app = RelayApp::new(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in &identities {
+            deliver(&mut app, &register_tx(identity, capacity));
+        }
+
+        // transactions that do not decode are rejected, not panicked on
+        let malformed = RelayError::MalformedMessage.code();
+        assert_eq!(check(&mut app, &[0xff, 0xfe]), malformed);
+        assert_eq!(check(&mut app, b"{}"), malformed);
+
+        let tx = serde_json::to_vec(&identities[0].sign(&ClientMessage::new())).unwrap();
+        assert_eq!(check(&mut app, &tx), RelayError::UnsupportedMessage.code());
+        // an abort is sent by the peer it names
+        let mut msg = ClientMessage::new();
+        msg.abort = Some(AbortMessage::new(1, 1));
+        let tx = serde_json::to_vec(&identities[1].sign(&msg)).unwrap();
+        assert_eq!(check(&mut app, &tx), RelayError::InvalidSignature.code());
+
+        // a relay message without a round is malformed
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(RelayMessage::new(1, 1));
+        let tx = serde_json::to_vec(&identities[0].sign(&msg)).unwrap();
+        assert_eq!(check(&mut app, &tx), malformed);
 
-        // TODO: Error handle
-        let requested_round = missing_messages.round;
-        let mut missing_clients = missing_messages.missing_clients;
-        debug!("Requested round {}", requested_round);
+        msg.relay_message = Some(first_round_message(1));
+        let tx = serde_json::to_vec(&identities[0].sign(&msg)).unwrap();
+        assert_eq!(check(&mut app, &tx), 0);
+        deliver(&mut app, &tx);
+        assert_eq!(check(&mut app, &tx), RelayError::DuplicateMessage.code());
+        // a message signed by another peer than the one it names is rejected
+        let tx = serde_json::to_vec(&identities[1].sign(&msg)).unwrap();
+        assert_eq!(check(&mut app, &tx), RelayError::InvalidSignature.code());
+    }
+
+    #[test]
+    fn test_deliver_tx_events() {
+        let capacity = 2;
+        let mut app = RelayApp::new(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in &identities {
+            deliver(&mut app, &register_tx(identity, capacity));
+        }
+        let attributes = |resp: &ResponseDeliverTx| -> Vec<(String, String)> {
+            let events = resp.get_events();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].get_field_type(), "relay");
+            events[0]
+                .get_attributes()
+                .iter()
+                .map(|pair| {
+                    (
+                        String::from_utf8(pair.get_key().to_vec()).unwrap(),
+                        String::from_utf8(pair.get_value().to_vec()).unwrap(),
+                    )
+                })
+                .collect()
+        };
 
-        let stored_messages = self.relay_session.stored_messages();
-        // debug!("Query: All Stored Messages: {:?}", stored_messages);
+        // a relayed message is tagged with its session, round and sender
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(first_round_message(2));
+        let mut req = RequestDeliverTx::new();
+        req.set_tx(serde_json::to_vec(&identities[1].sign(&msg)).unwrap());
+        let resp = app.deliver_tx(&req);
+        assert_eq!(resp.get_code(), 0);
+        let tags = |tags: &[(&str, &str)]| -> Vec<(String, String)> {
+            tags.iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            attributes(&resp),
+            tags(&[("session", "0"), ("round", "0"), ("peer", "2")])
+        );
+        // a rejected message is not
+        let resp = app.deliver_tx(&req);
+        assert_eq!(resp.get_code(), RelayError::DuplicateMessage.code());
+        assert!(resp.get_events().is_empty());
 
-        if missing_clients.len() > MAX_CLIENTS {
-            missing_clients.truncate(MAX_CLIENTS);
+        // an abort is tagged with the peer that aborted
+        let mut msg = ClientMessage::new();
+        msg.abort = Some(AbortMessage::new(1, 1));
+        req.set_tx(serde_json::to_vec(&identities[0].sign(&msg)).unwrap());
+        let resp = app.deliver_tx(&req);
+        assert_eq!(resp.get_code(), 0);
+        assert_eq!(attributes(&resp), tags(&[("session", "0"), ("abort", "1")]));
+    }
+
+    #[test]
+    fn test_abort() {
+        let capacity = 2;
+        let mut app = RelayApp::new(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in &identities {
+            deliver(&mut app, &register_tx(identity, capacity));
         }
-        let response =
-            stored_messages.get_messages_map_from_vector(requested_round, &missing_clients);
 
-        debug!("Server response {:?}", response);
+        // the blame of the second peer holds, it was not sent its message of the round yet
+        let mut msg = ClientMessage::new();
+        msg.abort = Some(AbortMessage::blame(
+            1,
+            1,
+            AbortReason::Unresponsive,
+            2,
+            vec![],
+        ));
+        let tx = serde_json::to_vec(&identities[0].sign(&msg)).unwrap();
+        assert_eq!(check(&mut app, &tx), 0);
+        deliver(&mut app, &tx);
+        assert_eq!(check(&mut app, &tx), RelayError::SessionAborted.code());
 
-        resp.set_log(serde_json::to_string(&response).unwrap().to_owned());
-        debug!("Response log {:?}", resp.log);
+        // the session relays no more messages, and returns the abort
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(first_round_message(2));
+        let tx = serde_json::to_vec(&identities[1].sign(&msg)).unwrap();
+        let mut req = RequestDeliverTx::new();
+        req.set_tx(tx);
+        let resp = app.deliver_tx(&req);
+        assert_eq!(resp.get_code(), RelayError::SessionAborted.code());
+        let server_msg: ServerMessage = serde_json::from_str(resp.get_log()).unwrap();
+        assert_eq!(server_msg.abort.unwrap().culprit, Some(2));
 
-        resp.set_code(0);
-        resp.set_index(-1);
-        resp.set_height(1_i64);
-        resp
+        // peers polling for the messages of the round learn of the abort once it is committed
+        app.commit(&RequestCommit::new());
+        let mut req = RequestQuery::new();
+        let request = MissingMessagesRequest {
+            round: 0,
+            missing_clients: vec![2],
+            ..Default::default()
+        };
+        req.set_data(serde_json::to_vec(&request).unwrap());
+        let resp = app.query(&req);
+        assert_eq!(resp.get_code(), RelayError::SessionAborted.code());
+        let server_msg: ServerMessage = serde_json::from_str(resp.get_log()).unwrap();
+        let abort = server_msg.abort.unwrap();
+        assert_eq!(abort.peer_number, 1);
+        assert_eq!(abort.reason, AbortReason::Unresponsive);
+    }
+
+    #[test]
+    fn test_query_pages() {
+        let capacity = 100;
+        let mut app = RelayApp::new(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in &identities {
+            deliver(&mut app, &register_tx(identity, capacity));
+        }
+        // all the peers but the last send their message of the round
+        let mut delivered: BTreeMap<u32, SignedClientMessage> = BTreeMap::new();
+        for (i, identity) in identities.iter().enumerate().skip(1) {
+            let peer_id = i as u32 + 1;
+            let mut msg = ClientMessage::new();
+            msg.relay_message = Some(first_round_message(peer_id));
+            let tx = serde_json::to_vec(&identity.sign(&msg)).unwrap();
+            let mut req = RequestDeliverTx::new();
+            req.set_tx(tx);
+            let resp = app.deliver_tx(&req);
+            delivered = serde_json::from_str(resp.get_log()).unwrap();
+        }
+        // the response to a message holds the first page of the round
+        assert_eq!(delivered.len(), DEFAULT_PAGE_SIZE as usize);
+        let app_hash = app.commit(&RequestCommit::new()).get_data().to_vec();
+
+        // the first peer misses all the messages, and reads them in pages
+        let mut request = MissingMessagesRequest {
+            round: 0,
+            missing_clients: (2..=capacity).collect(),
+            ..Default::default()
+        };
+        let mut messages = BTreeMap::new();
+        let mut queries = 0;
+        loop {
+            let mut req = RequestQuery::new();
+            req.set_data(serde_json::to_vec(&request).unwrap());
+            req.set_prove(true);
+            let resp = app.query(&req);
+            assert_eq!(resp.get_code(), 0);
+            let reply: MissingMessagesReply = serde_json::from_slice(resp.get_value()).unwrap();
+            assert_eq!(reply.total, capacity - 1);
+            // the page is proven along with the number of messages of the round
+            let ops = resp.get_proof().get_ops();
+            assert_eq!(ops.len(), reply.missing_messages.len() + 1);
+            for op in ops {
+                let proof: MerkleProof = serde_json::from_slice(op.get_data()).unwrap();
+                assert_eq!(proof.root(op.get_key()), app_hash);
+            }
+            let count: MerkleProof = serde_json::from_slice(ops[0].get_data()).unwrap();
+            assert_eq!(count.value, (capacity - 1).to_string().into_bytes());
+            queries += 1;
+            messages.extend(reply.missing_messages);
+            match reply.next_cursor {
+                Some(cursor) => request.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(queries, 2);
+        assert_eq!(messages.len(), capacity as usize - 1);
+
+        // a page as large as allowed holds them all
+        request.cursor = None;
+        request.page_size = Some(MAX_PAGE_SIZE);
+        let mut req = RequestQuery::new();
+        req.set_data(serde_json::to_vec(&request).unwrap());
+        let reply: MissingMessagesReply =
+            serde_json::from_slice(app.query(&req).get_value()).unwrap();
+        assert_eq!(reply.missing_messages.len(), capacity as usize - 1);
+        assert_eq!(reply.next_cursor, None);
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut app = RelayApp::new(2);
+        let capacity = 3;
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let deliver_signed = |app: &mut RelayApp, identity: &Identity, msg: &ClientMessage| {
+            let mut req = RequestDeliverTx::new();
+            req.set_tx(serde_json::to_vec(&identity.sign(msg)).unwrap());
+            app.deliver_tx(&req)
+        };
+
+        // any identity creates a session, for the participants it lists
+        let mut msg = ClientMessage::new();
+        msg.set_create_session(
+            1,
+            capacity,
+            identities.iter().map(|identity| identity.key()).collect(),
+        );
+        let resp = deliver_signed(&mut app, &Identity::generate(), &msg);
+        assert_eq!(resp.get_code(), 0);
+        let server_msg: ServerMessage = serde_json::from_str(resp.get_log()).unwrap();
+        match server_msg.response {
+            Some(ServerResponse::Session(session_id)) => assert_eq!(session_id, 1),
+            response => panic!("Expected the created session, got {:?}", response),
+        }
+        assert_eq!(resp.get_events().len(), 1);
+
+        // only the participants register, numbered by their position in the list
+        let register = |session_id: u64| {
+            let mut msg = ClientMessage::new();
+            msg.set_register(1, capacity, -1, None);
+            msg.set_session(session_id);
+            msg
+        };
+        let resp = deliver_signed(&mut app, &Identity::generate(), &register(1));
+        assert_eq!(resp.get_code(), RelayError::NotAParticipant.code());
+        let resp = deliver_signed(&mut app, &identities[0], &register(2));
+        assert_eq!(resp.get_code(), RelayError::UnknownSession.code());
+        for (i, identity) in identities.iter().enumerate().rev() {
+            let resp = deliver_signed(&mut app, identity, &register(1));
+            assert_eq!(resp.get_code(), 0);
+            let server_msg: ServerMessage = serde_json::from_str(resp.get_log()).unwrap();
+            match server_msg.response {
+                Some(ServerResponse::Register(peer_id)) => assert_eq!(peer_id, i as u32 + 1),
+                response => panic!("Expected the peer number, got {:?}", response),
+            }
+        }
+
+        // messages are relayed and queried within their session
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(first_round_message(1));
+        msg.set_session(1);
+        let resp = deliver_signed(&mut app, &identities[0], &msg);
+        assert_eq!(resp.get_code(), 0);
+        let attributes = resp.get_events()[0].get_attributes();
+        assert_eq!(attributes[0].get_value(), b"1");
+        // the default session has no peers to relay it
+        msg.session_id = None;
+        let resp = deliver_signed(&mut app, &identities[0], &msg);
+        assert_eq!(resp.get_code(), RelayError::NotAPeer.code());
+
+        let mut request = MissingMessagesRequest {
+            round: 0,
+            missing_clients: vec![1],
+            ..Default::default()
+        };
+        let query = |app: &mut RelayApp, request: &MissingMessagesRequest| {
+            let mut req = RequestQuery::new();
+            req.set_data(serde_json::to_vec(request).unwrap());
+            app.query(&req)
+        };
+        app.commit(&RequestCommit::new());
+        let reply: MissingMessagesReply =
+            serde_json::from_slice(query(&mut app, &request).get_value()).unwrap();
+        assert!(reply.missing_messages.is_empty());
+        request.session_id = Some(1);
+        let reply: MissingMessagesReply =
+            serde_json::from_slice(query(&mut app, &request).get_value()).unwrap();
+        assert_eq!(reply.missing_messages.len(), 1);
+        request.session_id = Some(2);
+        assert_eq!(
+            query(&mut app, &request).get_code(),
+            RelayError::UnknownSession.code()
+        );
+
+        // the committed state of the session is answered on its versioned paths
+        let mut req = RequestQuery::new();
+        req.set_path(QueryPath::Status(1).to_string());
+        let status: StatusReply = serde_json::from_slice(app.query(&req).get_value()).unwrap();
+        assert_eq!((status.capacity, status.participants.len()), (capacity, 3));
+        req.set_path(QueryPath::Round(1, 0).to_string());
+        let reply: RoundReply = serde_json::from_slice(app.query(&req).get_value()).unwrap();
+        assert_eq!(reply.messages.keys().collect::<Vec<_>>(), vec![&1]);
+        req.set_path(QueryPath::Status(2).to_string());
+        assert_eq!(
+            app.query(&req).get_code(),
+            RelayError::UnknownSession.code()
+        );
+
+        // an identity creates up to the limit of open sessions, the ones after are rejected
+        let mut app = RelayApp::new(2).with_session_limits(SessionLimits {
+            retention: None,
+            open_sessions: Some(1),
+        });
+        let mut msg = ClientMessage::new();
+        msg.set_create_session(1, capacity, Vec::new());
+        let tx = serde_json::to_vec(&identities[0].sign(&msg)).unwrap();
+        assert_eq!(check(&mut app, &tx), 0);
+        deliver(&mut app, &tx);
+        assert_eq!(check(&mut app, &tx), RelayError::TooManySessions.code());
+        let tx = serde_json::to_vec(&identities[1].sign(&msg)).unwrap();
+        assert_eq!(check(&mut app, &tx), 0);
+    }
+
+    #[test]
+    fn test_app_hash_of_replicas() {
+        // validators executing the same transactions reach the same app hash
+        let capacity = 3;
+        let txs: Vec<Vec<u8>> = (0..capacity)
+            .map(|_| register_tx(&Identity::generate(), capacity))
+            .collect();
+        let mut first = RelayApp::new(capacity);
+        let mut second = RelayApp::new(capacity);
+        let empty = first.commit(&RequestCommit::new()).get_data().to_vec();
+
+        deliver(&mut first, &txs[0]);
+        deliver(&mut second, &txs[0]);
+        let registered = first.commit(&RequestCommit::new()).get_data().to_vec();
+        assert_ne!(registered, empty);
+        assert_eq!(
+            second.commit(&RequestCommit::new()).get_data(),
+            &registered[..]
+        );
+        // a block without transactions leaves the state as it was
+        assert_eq!(
+            first.commit(&RequestCommit::new()).get_data(),
+            &registered[..]
+        );
+
+        // and diverge once they execute different transactions
+        deliver(&mut first, &txs[1]);
+        deliver(&mut second, &txs[2]);
+        assert_ne!(
+            first.commit(&RequestCommit::new()).get_data(),
+            second.commit(&RequestCommit::new()).get_data()
+        );
+    }
+
+    #[test]
+    fn test_query_committed() {
+        let capacity = 2;
+        let mut app = RelayApp::new(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in &identities {
+            deliver(&mut app, &register_tx(identity, capacity));
+        }
+        let query = |app: &mut RelayApp, path: QueryPath| {
+            let mut req = RequestQuery::new();
+            req.set_path(path.to_string());
+            req.set_prove(true);
+            app.query(&req)
+        };
+        // nothing is committed before the first block
+        let resp = query(&mut app, QueryPath::Status(0));
+        assert_eq!(resp.get_code(), RelayError::UnknownSession.code());
+        let app_hash = app.commit(&RequestCommit::new()).get_data().to_vec();
+
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(first_round_message(2));
+        let tx = serde_json::to_vec(&identities[1].sign(&msg)).unwrap();
+        deliver(&mut app, &tx);
+        // the replies are read from the state of the last block, which holds no message yet
+        let resp = query(&mut app, QueryPath::Round(0, 0));
+        let reply: RoundReply = serde_json::from_slice(resp.get_value()).unwrap();
+        assert!(reply.messages.is_empty());
+        assert!(resp.get_proof().get_ops().is_empty());
+        // the proof of each record holds against the app hash of the block
+        let resp = query(&mut app, QueryPath::Peers(0));
+        let reply: PeersReply = serde_json::from_slice(resp.get_value()).unwrap();
+        assert_eq!(reply.registrations.len(), capacity as usize);
+        for op in resp.get_proof().get_ops() {
+            let proof: MerkleProof = serde_json::from_slice(op.get_data()).unwrap();
+            assert_eq!(proof.root(op.get_key()), app_hash);
+        }
+
+        let app_hash = app.commit(&RequestCommit::new()).get_data().to_vec();
+        let resp = query(&mut app, QueryPath::Round(0, 0));
+        assert_eq!(resp.get_code(), 0);
+        let reply: RoundReply = serde_json::from_slice(resp.get_value()).unwrap();
+        assert_eq!(reply.messages.keys().collect::<Vec<_>>(), vec![&2]);
+        let ops = resp.get_proof().get_ops();
+        assert_eq!(ops.len(), 2);
+        let proofs: Vec<MerkleProof> = ops
+            .iter()
+            .map(|op| serde_json::from_slice(op.get_data()).unwrap())
+            .collect();
+        for (op, proof) in ops.iter().zip(proofs.iter()) {
+            assert_eq!(proof.root(op.get_key()), app_hash);
+        }
+        // the proven records are the number of messages of the round, which the reply
+        // must hold all of, and the message of the reply
+        assert_eq!(reply.total, 1);
+        assert_eq!(proofs[0].value, b"1");
+        assert_eq!(
+            proofs[1].value,
+            serde_json::to_vec(&reply.messages[&2]).unwrap()
+        );
+
+        let resp = query(&mut app, QueryPath::Status(0));
+        let status: StatusReply = serde_json::from_slice(resp.get_value()).unwrap();
+        assert_eq!((status.capacity, status.round), (capacity, 0));
+        assert_eq!(status.state, RelaySessionState::Initialized);
+        assert!(status.abort.is_none());
+
+        let resp = query(&mut app, QueryPath::Status(1));
+        assert_eq!(resp.get_code(), RelayError::UnknownSession.code());
+        let mut req = RequestQuery::new();
+        req.set_path(String::from("/v1/session/0/messages"));
+        assert_eq!(app.query(&req).get_code(), RelayError::InvalidQuery.code());
     }
 }
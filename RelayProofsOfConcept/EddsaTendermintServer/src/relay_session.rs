@@ -1,42 +1,127 @@
 use log::{debug, info, warn};
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use mmpc_server_common::{ClientMessage, StoredMessages};
-use mmpc_server_common::{PeerIdentifier, ProtocolIdentifier, RelayMessage};
+use mmpc_server_common::StoredMessages;
+use mmpc_server_common::{
+    AbortMessage, AbortReason, PeerIdentifier, ProtocolIdentifier, RelayMessage,
+};
 
-use mmpc_server_common::protocol::ProtocolDescriptor;
+use mmpc_server_common::common::RelayError;
+use mmpc_server_common::identity::{IdentityKey, SignedClientMessage};
+use mmpc_server_common::protocol::{
+    Delivery, ProtocolDescriptor, ProtocolRegistry, ProtocolTimeouts,
+};
+pub use mmpc_server_common::query::RelaySessionState;
+use mmpc_server_common::query::StatusReply;
 
-#[derive(Clone, Debug)]
+use crate::storage::{Record, StorageError};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Peer {
     pub peer_id: PeerIdentifier,
-    pub addr: SocketAddr,
     pub registered: bool,
+    // identity key the peer registered with, every message it sends must be signed by it
+    pub identity: IdentityKey,
+    // index the peer had in key generation, or -1 if it did not announce one
+    pub kg_index: i32,
 }
 
 impl Peer {
-    pub fn new(addr: SocketAddr) -> Peer {
+    pub fn new(identity: IdentityKey) -> Peer {
         Peer {
             peer_id: 0,
-            addr: addr,
             registered: false,
+            identity,
+            kg_index: -1,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum RelaySessionState {
-    Empty,
+/// Deadlines of a relay session, a session that misses one is aborted.
+/// Time is measured by the time of the blocks, so all the nodes abort at the same block.
+/// A deadline that is not set is never missed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    // time the peers have to register, from the registration of the first peer
+    pub registration: Option<Duration>,
+
+    // time the peers have to send their messages of a round, from the start of the round
+    pub round: Option<Duration>,
+}
+
+impl Timeouts {
+    // The deadlines of a session of the protocol,
+    // those the protocol declares take the place of these
+    fn of_protocol(&self, protocol: &ProtocolDescriptor) -> Timeouts {
+        Timeouts {
+            registration: protocol.timeouts.registration().or(self.registration),
+            round: protocol.timeouts.round().or(self.round),
+        }
+    }
+}
 
-    Uninitialized,
+// Keys of the records the state of a session is stored in
+pub(crate) const SESSION_KEY: &str = "session";
+pub(crate) const ABORT_KEY: &str = "abort";
+const PEER_PREFIX: &str = "peer/";
+pub(crate) const REGISTRATION_PREFIX: &str = "registration/";
+const MESSAGE_PREFIX: &str = "message/";
+const COUNT_PREFIX: &str = "count/";
 
-    Initialized,
+/// Prefix of the keys of the records of the messages of a round
+pub(crate) fn round_prefix(round: u32) -> String {
+    format!("{}{:010}/", MESSAGE_PREFIX, round)
+}
+
+/// Key of the record of the number of messages stored for a round,
+/// which proves a reply holds all the messages of the round
+pub(crate) fn count_key(round: u32) -> String {
+    format!("{}{:010}", COUNT_PREFIX, round)
+}
+
+/// Reads the status of a session from its stored record
+pub(crate) fn session_status(value: &[u8]) -> Result<StatusReply, StorageError> {
+    let session: SessionRecord = from_value(value)?;
+    Ok(StatusReply {
+        protocol_id: session.protocol_id,
+        capacity: session.capacity,
+        state: session.state,
+        round: session.round,
+        participants: session.participants,
+        abort: None,
+    })
+}
+
+// The protocol, stage and deadlines of a session, as stored.
+// The time of the block is left out, it is set again at the beginning of every block,
+// so the record changes only when the session does
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionRecord {
+    protocol_id: ProtocolIdentifier,
+    capacity: u32,
+    delivery: Delivery,
+    rounds: Option<u32>,
+    max_payload: Option<usize>,
+    timeouts: ProtocolTimeouts,
+    state: RelaySessionState,
+    round: u32,
+    deadline: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    participants: Vec<IdentityKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    creator: Option<IdentityKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ended: Option<Duration>,
 }
 
 #[derive(Debug, Clone)]
 pub struct RelaySession {
-    peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    // the peers by the number each was registered as, kept in order
+    // so that every node iterates and stores them the same way
+    peers: Arc<RwLock<BTreeMap<PeerIdentifier, Peer>>>,
 
     active_peers: Arc<RwLock<u32>>,
 
@@ -46,7 +131,39 @@ pub struct RelaySession {
 
     round: Arc<RwLock<u32>>,
 
-    stored_messages: Arc<RwLock<StoredMessages>>,
+    stored_messages: Arc<RwLock<StoredMessages<SignedClientMessage>>>,
+
+    // the signed register messages of the peers, by the peer number each was registered as
+    registrations: Arc<RwLock<BTreeMap<PeerIdentifier, SignedClientMessage>>>,
+
+    timeouts: Timeouts,
+
+    // the time of the current block, since the unix epoch
+    block_time: Arc<RwLock<Duration>>,
+
+    // the block time the current stage of the session must be done by,
+    // either the registration of all peers or a round
+    deadline: Arc<RwLock<Option<Duration>>>,
+
+    // the protocols a session can run, the session takes the limits of its protocol
+    // from the registry once the first peer registers
+    protocols: Arc<ProtocolRegistry>,
+
+    // the abort the session was aborted with, by one of its peers or on a missed deadline
+    abort: Arc<RwLock<Option<AbortMessage>>>,
+
+    // the identities allowed to register, any identity can if empty.
+    // Each is registered as the peer numbered by its position in the list
+    participants: Arc<RwLock<Vec<IdentityKey>>>,
+
+    // the identity that created the session, none for the default session
+    creator: Arc<RwLock<Option<IdentityKey>>>,
+
+    // the block time the session was aborted or ran the last round of its protocol by
+    ended: Arc<RwLock<Option<Duration>>>,
+
+    // held while a peer registers, so peers registering at once are given distinct numbers
+    registering: Arc<Mutex<()>>,
 }
 
 impl RelaySession {
@@ -62,66 +179,91 @@ impl RelaySession {
     }
 
     /// Register a new peer to this relay session
-    /// after adding this address as a peer,
+    /// after adding this identity as a peer,
     /// the state might change to either Uninitialized (if this is the first peer registering)
-    /// or Initialized (meaning session has reached the required # of participants)
+    /// or Initialized (meaning session has reached the required # of participants).
+    /// Peers are numbered in the order they register, the key generation index a peer
+    /// announces is kept but does not determine its number
     pub fn register_new_peer(
         &self,
-        addr: SocketAddr,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
         index: i32,
-    ) -> Option<u32> {
-        let _addr = &addr;
+        identity: IdentityKey,
+    ) -> Result<PeerIdentifier, RelayError> {
+        let _registering = self.registering.lock().unwrap();
         let number_of_active_peers = self.get_number_of_active_peers();
 
-        let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
+        // the first peer sets the protocol of the session along with its limits,
+        // the peers that follow register to the protocol the session runs
+        let protocol_descriptor = match self.state() {
+            RelaySessionState::Empty => self.protocols.descriptor(protocol_id, capacity),
+            _ => Ok(ProtocolDescriptor::new(protocol_id, capacity)),
+        };
         debug!("-----------------PEERS: {:?}---------------", self.peers);
-        if self.can_register(_addr, protocol_descriptor) {
-            let mut peer = Peer::new(addr);
-            peer.registered = true;
-            peer.peer_id = number_of_active_peers + 1;
-
-            self.peers.write().unwrap().insert(addr, peer);
-
-            // activate this connection as a peer
-            // if needed, set the ProtocolDescriptor for this sessuib
-            // and change the state
-            let state = self.state();
-            if let RelaySessionState::Empty = state {
-                self.set_protocol(ProtocolDescriptor::new(protocol_id, capacity));
-                info!("Relay session state is now Uninitialized");
-                self.set_state(RelaySessionState::Uninitialized);
+        match protocol_descriptor.and_then(|protocol_descriptor| {
+            self.can_register(protocol_descriptor.clone(), &identity)
+                .map(|()| protocol_descriptor)
+        }) {
+            Ok(protocol_descriptor) => {
+                // a participant of the session is numbered by its position in the participants,
+                // other peers by the order they register in
+                let peer_id = match self.participant_number(&identity) {
+                    Some(peer_id) => peer_id,
+                    None => number_of_active_peers + 1,
+                };
+                let mut peer = Peer::new(identity);
+                peer.registered = true;
+                peer.peer_id = peer_id;
+                peer.kg_index = index;
+
+                self.peers.write().unwrap().insert(peer.peer_id, peer);
+
+                // activate this connection as a peer
+                // if needed, set the ProtocolDescriptor for this sessuib
+                // and change the state
+                let state = self.state();
+                if let RelaySessionState::Empty = state {
+                    self.set_protocol(protocol_descriptor);
+                    info!("Relay session state is now Uninitialized");
+                    self.set_state(RelaySessionState::Uninitialized);
+                    self.set_deadline(self.timeouts().registration);
+                }
+                //if self.protocol.clone().into_inner().capacity == number_of_active_peers + 1 {
+                if self.protocol().capacity == number_of_active_peers + 1 {
+                    info!("Relay session state is now Initialized");
+                    self.set_state(RelaySessionState::Initialized);
+                    self.set_deadline(self.timeouts().round);
+                }
+                info!(
+                    "Registered peer {} with key generation index {}",
+                    peer_id, index
+                );
+                Ok(peer_id)
             }
-            //if self.protocol.clone().into_inner().capacity == number_of_active_peers + 1 {
-            if self.protocol().capacity == number_of_active_peers + 1 {
-                info!("Relay session state is now Initialized");
-                self.set_state(RelaySessionState::Initialized);
+            Err(err) => {
+                warn!("Unable to register: {}", err); // error
+                Err(err)
             }
-            info!("Registered peer {}", number_of_active_peers + 1);
-            if index == -1 {
-                Some(number_of_active_peers + 1) //peer_id
-            } else {
-                Some(index as u32) //peer_id
-            }
-        } else {
-            warn!("Unable to register {:}", addr); // error
-            None
         }
     }
 
-    /// Checks if it is possible for this address
+    /// Checks if it is possible for the given identity
     /// to register as a peer in this session
-    pub fn can_register(&self, _addr: &SocketAddr, protocol: ProtocolDescriptor) -> bool {
+    pub fn can_register(
+        &self,
+        protocol: ProtocolDescriptor,
+        identity: &IdentityKey,
+    ) -> Result<(), RelayError> {
         match self.state() {
             // if this is the first peer to register
             // check that the protocol is valid
             RelaySessionState::Empty => {
                 debug!("Checking if protocol description is valid");
-                if !mmpc_server_common::protocol::is_valid_protocol(&protocol) {
+                if let Err(err) = self.protocols.descriptor(protocol.id, protocol.capacity) {
                     warn!("Protocol is invalid");
 
-                    return false;
+                    return Err(err);
                 }
             }
             // if there is already a set protocol,
@@ -129,26 +271,67 @@ impl RelaySession {
             RelaySessionState::Uninitialized => {
                 debug!("Checking if protocol description is same as at protocol description");
                 let prot = self.protocol();
-                if !(prot.id == protocol.id && prot.capacity == protocol.capacity) {
+                if prot.id != protocol.id {
                     warn!("Protocol description does not fit current configuration");
-                    return false;
+                    return Err(RelayError::InvalidProtocol);
                 }
+                if prot.capacity != protocol.capacity {
+                    warn!("Protocol capacity does not fit current configuration");
+                    return Err(RelayError::CapacityMismatch);
+                }
+            }
+            RelaySessionState::Initialized => {
+                debug!("Relay session is already initialized");
+                return Err(RelayError::SessionFull);
             }
-            _ => {
-                debug!("Relay session state is neither empty nor uninitialized ");
-                return false;
+            RelaySessionState::Aborted => {
+                debug!("Relay session was aborted");
+                return Err(RelayError::SessionAborted);
             }
         }
-        true
+        // an identity can register only once to the session
+        if self
+            .peers
+            .read()
+            .unwrap()
+            .values()
+            .any(|peer| peer.identity == *identity)
+        {
+            debug!("Identity is already registered to the session");
+            return Err(RelayError::CantRegister);
+        }
+        if !self.participants.read().unwrap().is_empty()
+            && self.participant_number(identity).is_none()
+        {
+            debug!("Identity is not a participant of the session");
+            return Err(RelayError::NotAParticipant);
+        }
+        Ok(())
     }
 }
 
 impl RelaySession {
     /// Creates a new Relay Session with default (empty) fields
     /// and an Empty state
+    #[cfg(test)]
     pub fn new(capacity: u32) -> RelaySession {
+        RelaySession::with_timeouts(capacity, Timeouts::default())
+    }
+
+    /// Creates a new empty Relay Session that is aborted if it misses one of the deadlines
+    #[cfg(test)]
+    pub fn with_timeouts(capacity: u32, timeouts: Timeouts) -> RelaySession {
+        RelaySession::with_protocols(capacity, timeouts, Arc::new(ProtocolRegistry::default()))
+    }
+
+    /// Creates a new empty Relay Session running one of the protocols of the registry
+    pub fn with_protocols(
+        capacity: u32,
+        timeouts: Timeouts,
+        protocols: Arc<ProtocolRegistry>,
+    ) -> RelaySession {
         RelaySession {
-            peers: Arc::new(RwLock::new(HashMap::new())),
+            peers: Arc::new(RwLock::new(BTreeMap::new())),
 
             active_peers: Arc::new(RwLock::new(0)),
 
@@ -161,19 +344,133 @@ impl RelaySession {
             round: Arc::new(RwLock::new(0)),
 
             stored_messages: Arc::new(RwLock::new(StoredMessages::new())),
+
+            registrations: Arc::new(RwLock::new(BTreeMap::new())),
+
+            timeouts,
+
+            block_time: Arc::new(RwLock::new(Duration::from_secs(0))),
+
+            deadline: Arc::new(RwLock::new(None)),
+
+            protocols,
+
+            abort: Arc::new(RwLock::new(None)),
+
+            participants: Arc::new(RwLock::new(Vec::new())),
+
+            creator: Arc::new(RwLock::new(None)),
+
+            ended: Arc::new(RwLock::new(None)),
+
+            registering: Arc::new(Mutex::new(())),
         }
     }
 
-    /// Check if this relay message sent from the given SocketAddr
-    /// and is valid to send to rest of the peers
-    pub fn can_relay(&self, _from: &SocketAddr, msg: &RelayMessage) -> Result<(), &'static str> {
+    /// Opens the session the creator created for the peers to register, running the protocol
+    /// among the participants.
+    /// The peers have until the registration deadline from the given block time to register
+    pub fn open(
+        &self,
+        protocol: ProtocolDescriptor,
+        participants: Vec<IdentityKey>,
+        creator: IdentityKey,
+        block_time: Duration,
+    ) {
+        *self.block_time.write().unwrap() = block_time;
+        *self.participants.write().unwrap() = participants;
+        *self.creator.write().unwrap() = Some(creator);
+        self.set_protocol(protocol);
+        info!("Relay session state is now Uninitialized");
+        self.set_state(RelaySessionState::Uninitialized);
+        self.set_deadline(self.timeouts().registration);
+    }
+
+    // The number the participant with the given identity registers as
+    fn participant_number(&self, identity: &IdentityKey) -> Option<PeerIdentifier> {
+        self.participants
+            .read()
+            .unwrap()
+            .iter()
+            .position(|participant| participant == identity)
+            .map(|position| position as PeerIdentifier + 1)
+    }
+
+    /// Check if this relay message, signed by the given identity,
+    /// is valid to send to rest of the peers
+    pub fn can_relay(&self, identity: &IdentityKey, msg: &RelayMessage) -> Result<(), RelayError> {
         debug!("Checking if {:} can relay", msg.peer_number);
         debug!("Server state: {:?}", self.state());
         debug!("Turn of peer #: {:}", self.protocol().next());
 
-        // TODO: Add some checks of what messages can be stored
+        if self.state() == RelaySessionState::Aborted {
+            return Err(RelayError::SessionAborted);
+        }
+
+        // the sender is the peer that registered with the identity that signed the message
+        let sender = match self.peer_of(identity) {
+            Some(peer) if peer.registered => peer.peer_id,
+            _ => return Err(RelayError::NotAPeer),
+        };
+        // a peer sends its messages under its own number only
+        if msg.peer_number != sender {
+            return Err(RelayError::InvalidSignature);
+        }
+        if msg.protocol_id != self.protocol().id {
+            return Err(RelayError::InvalidProtocol);
+        }
+        // the signature of a message is bound to the round it names, so a message without one
+        // could be replayed in any round
+        let round = self.round();
+        match msg.round {
+            None => return Err(RelayError::MalformedMessage),
+            Some(msg_round) if msg_round != round => return Err(RelayError::WrongRound),
+            Some(_) => {}
+        }
+        if self
+            .stored_messages
+            .read()
+            .unwrap()
+            .messages
+            .get(&round)
+            .map_or(false, |messages| messages.contains_key(&sender))
+        {
+            return Err(RelayError::DuplicateMessage);
+        }
+
+        self.within_limits(sender, msg)
+    }
+
+    // Returns the peer registered with the given identity
+    fn peer_of(&self, identity: &IdentityKey) -> Option<Peer> {
+        self.peers
+            .read()
+            .unwrap()
+            .values()
+            .find(|peer| peer.identity == *identity)
+            .cloned()
+    }
 
-        return Ok(());
+    // Checks the message keeps to the limits the protocol of the session declares
+    fn within_limits(&self, sender: PeerIdentifier, msg: &RelayMessage) -> Result<(), RelayError> {
+        let protocol = self.protocol();
+        if let Some(rounds) = protocol.rounds {
+            if self.round() >= rounds {
+                return Err(RelayError::TooManyRounds);
+            }
+        }
+        if let Some(max_payload) = protocol.max_payload {
+            if msg.payload_size() > max_payload {
+                return Err(RelayError::PayloadTooLarge);
+            }
+        }
+        if protocol.delivery == Delivery::Broadcast
+            && (1..=protocol.capacity)
+                .any(|peer_id| peer_id != sender && !msg.to.contains(&peer_id))
+        {
+            return Err(RelayError::InvalidRecipients);
+        }
+        Ok(())
     }
 
     // Return the current state of the relay session
@@ -198,17 +495,24 @@ impl RelaySession {
         self.round.read().unwrap().clone()
     }
 
-    pub fn update_stored_messages(&mut self, round: u32, party: u32, msg: ClientMessage) {
+    pub fn update_stored_messages(&mut self, round: u32, party: u32, msg: SignedClientMessage) {
         self.stored_messages
             .write()
             .unwrap()
             .update(round, party, msg);
     }
 
-    pub fn stored_messages(&self) -> StoredMessages {
+    pub fn stored_messages(&self) -> StoredMessages<SignedClientMessage> {
         self.stored_messages.read().unwrap().clone()
     }
 
+    pub fn add_registration(&self, peer_id: PeerIdentifier, registration: SignedClientMessage) {
+        self.registrations
+            .write()
+            .unwrap()
+            .insert(peer_id, registration);
+    }
+
     pub fn try_increase_round(&self, capacity: u32) {
         if self
             .stored_messages
@@ -218,31 +522,337 @@ impl RelaySession {
             == capacity as usize
         {
             *self.round.write().unwrap() += 1;
+            // a session that ran all the rounds of its protocol has nothing left to wait for
+            match self.protocol().rounds {
+                Some(rounds) if self.round() >= rounds => {
+                    self.set_deadline(None);
+                    self.set_ended();
+                }
+                _ => self.set_deadline(self.timeouts().round),
+            }
+        }
+    }
+
+    /// Sets the time of the block being executed, and aborts the session
+    /// if it missed its deadline by then.
+    /// The abort names the peers that did not send their message of the current round,
+    /// while a session that is still registering names no peer,
+    /// as the peers it waits for never registered.
+    /// Returns the abort if the session was aborted
+    pub fn set_block_time(&self, time: Duration) -> Option<AbortMessage> {
+        *self.block_time.write().unwrap() = time;
+        match *self.deadline.read().unwrap() {
+            Some(deadline) if deadline <= time => {}
+            _ => return None,
+        }
+        let unresponsive = match self.state() {
+            RelaySessionState::Uninitialized => vec![],
+            RelaySessionState::Initialized => self.awaited_peers(),
+            _ => return None,
+        };
+        warn!(
+            "Session missed its deadline, aborting. Unresponsive peers: {:?}",
+            unresponsive
+        );
+        let abort = AbortMessage::timeout(self.protocol().id, unresponsive);
+        self.set_abort(abort.clone());
+        Some(abort)
+    }
+
+    // The peers that did not send their message of the current round yet
+    fn awaited_peers(&self) -> Vec<PeerIdentifier> {
+        let round = self.round();
+        let stored_messages = self.stored_messages.read().unwrap();
+        let sent = stored_messages.messages.get(&round);
+        (1..=self.protocol().capacity)
+            .filter(|peer_id| sent.map_or(true, |sent| !sent.contains_key(peer_id)))
+            .collect()
+    }
+
+    /// Returns the identity that created the session
+    pub fn creator(&self) -> Option<IdentityKey> {
+        *self.creator.read().unwrap()
+    }
+
+    /// Returns the block time the session ended by, if it was aborted
+    /// or ran all the rounds of its protocol
+    pub fn ended_at(&self) -> Option<Duration> {
+        *self.ended.read().unwrap()
+    }
+
+    // Sets the session as ended at the time of the current block
+    fn set_ended(&self) {
+        *self.ended.write().unwrap() = Some(*self.block_time.read().unwrap());
+    }
+
+    /// Returns the abort the session was aborted with
+    pub fn abort_message(&self) -> Option<AbortMessage> {
+        self.abort.read().unwrap().clone()
+    }
+
+    // Sets the session as aborted with the given abort, it waits for no one from then on
+    fn set_abort(&self, abort: AbortMessage) {
+        self.set_state(RelaySessionState::Aborted);
+        *self.abort.write().unwrap() = Some(abort);
+        *self.deadline.write().unwrap() = None;
+        self.set_ended();
+    }
+
+    /// Checks the abort can be sent by the peer signed by the given identity.
+    /// Returns the number of the peer
+    pub fn can_abort(
+        &self,
+        identity: &IdentityKey,
+        abort: &AbortMessage,
+    ) -> Result<PeerIdentifier, RelayError> {
+        if self.state() == RelaySessionState::Aborted {
+            return Err(RelayError::SessionAborted);
+        }
+        match self.peers.read().unwrap().get(&abort.peer_number) {
+            Some(peer) if peer.registered => {
+                if peer.identity != *identity {
+                    return Err(RelayError::InvalidSignature);
+                }
+            }
+            _ => return Err(RelayError::NotAPeer),
         }
+        if abort.protocol_id != self.protocol().id {
+            return Err(RelayError::InvalidProtocol);
+        }
+        Ok(abort.peer_number)
+    }
+
+    /// Aborts the session on the abort of the peer signed by the given identity.
+    /// An abort blaming another peer is recorded along with its evidence if the blame holds,
+    /// otherwise the session is aborted without blaming any peer.
+    /// Returns the abort that was recorded
+    pub fn abort(
+        &self,
+        identity: &IdentityKey,
+        abort: &AbortMessage,
+    ) -> Result<AbortMessage, RelayError> {
+        let accuser = self.can_abort(identity, abort)?;
+        let recorded = match self.verify_blame(accuser, abort) {
+            Ok(culprit) => {
+                warn!(
+                    "Peer {} blamed peer {}: {:?}",
+                    accuser, culprit, abort.reason
+                );
+                AbortMessage::blame(
+                    accuser,
+                    abort.protocol_id,
+                    abort.reason,
+                    culprit,
+                    abort.evidence.clone(),
+                )
+            }
+            Err(reason) => {
+                warn!("Ignoring blame of peer {:?}: {}", abort.culprit, reason);
+                AbortMessage::new(accuser, abort.protocol_id)
+            }
+        };
+        self.set_abort(recorded.clone());
+        Ok(recorded)
     }
+
+    // A blame holds if the culprit is another peer of the session,
+    // and all the evidence are relay messages the culprit signed.
+    // An unresponsive peer has no messages to prove it, so the blame holds if the session
+    // waits for its message. Returns the culprit
+    fn verify_blame(
+        &self,
+        accuser: PeerIdentifier,
+        abort: &AbortMessage,
+    ) -> Result<PeerIdentifier, &'static str> {
+        let culprit = abort.culprit.ok_or("No culprit")?;
+        let identity = match self.peers.read().unwrap().get(&culprit) {
+            Some(peer) if peer.registered && culprit != accuser => peer.identity,
+            _ => return Err("The culprit is not another peer of the session"),
+        };
+        let mut payloads = Vec::new();
+        for envelope in &abort.evidence {
+            if envelope.identity != identity {
+                return Err("Evidence not signed by the culprit");
+            }
+            let relay_msg = envelope
+                .open()
+                .ok()
+                .and_then(|msg| msg.relay_message)
+                .filter(|relay_msg| relay_msg.peer_number == culprit)
+                .ok_or("Evidence is not a relay message of the culprit")?;
+            payloads.push(relay_msg.message);
+        }
+        payloads.sort();
+        payloads.dedup();
+        match abort.reason {
+            AbortReason::Unspecified => return Err("No reason"),
+            AbortReason::Unresponsive if !self.awaited_peers().contains(&culprit) => {
+                return Err("The session does not wait for the culprit")
+            }
+            AbortReason::Unresponsive => {}
+            AbortReason::Equivocation if payloads.len() < 2 => {
+                return Err("Equivocation needs two different messages")
+            }
+            _ if payloads.is_empty() => return Err("No evidence"),
+            _ => {}
+        }
+        Ok(culprit)
+    }
+
+    // The deadlines of the session, as set for the relay or declared by its protocol
+    fn timeouts(&self) -> Timeouts {
+        self.timeouts.of_protocol(&self.protocol())
+    }
+
+    // Sets the deadline of the current stage of the session, from the time of the current block
+    fn set_deadline(&self, timeout: Option<Duration>) {
+        let block_time = *self.block_time.read().unwrap();
+        *self.deadline.write().unwrap() = timeout.map(|timeout| block_time + timeout);
+    }
+
+    /// Returns the state of the session as records sorted by key, to be stored once a block is committed.
+    /// Numbers in the keys are zero padded, so the records of peers and rounds sort by number
+    pub fn records(&self) -> Vec<Record> {
+        let protocol = self.protocol();
+        let session = SessionRecord {
+            protocol_id: protocol.id,
+            capacity: protocol.capacity,
+            delivery: protocol.delivery,
+            rounds: protocol.rounds,
+            max_payload: protocol.max_payload,
+            timeouts: protocol.timeouts,
+            state: self.state(),
+            round: self.round(),
+            deadline: *self.deadline.read().unwrap(),
+            participants: self.participants.read().unwrap().clone(),
+            creator: self.creator(),
+            ended: self.ended_at(),
+        };
+        let mut records = BTreeMap::new();
+        records.insert(SESSION_KEY.to_string(), to_value(&session));
+        if let Some(abort) = self.abort_message() {
+            records.insert(ABORT_KEY.to_string(), to_value(&abort));
+        }
+        for peer in self.peers.read().unwrap().values() {
+            records.insert(
+                format!("{}{:010}", PEER_PREFIX, peer.peer_id),
+                to_value(peer),
+            );
+        }
+        for (peer_id, registration) in self.registrations.read().unwrap().iter() {
+            records.insert(
+                format!("{}{:010}", REGISTRATION_PREFIX, peer_id),
+                to_value(registration),
+            );
+        }
+        for (round, messages) in self.stored_messages.read().unwrap().messages.iter() {
+            records.insert(count_key(*round), to_value(&messages.len()));
+            for (peer_id, msg) in messages {
+                records.insert(
+                    format!("{}{:010}", round_prefix(*round), peer_id),
+                    to_value(msg),
+                );
+            }
+        }
+        records
+            .into_iter()
+            .map(|(key, value)| (key.into_bytes(), value))
+            .collect()
+    }
+
+    /// Restores the state of the session from the stored records.
+    /// A session without a stored state is left empty
+    pub fn restore(&self, records: &[Record]) -> Result<(), StorageError> {
+        for (key, value) in records {
+            let key = String::from_utf8(key.clone())
+                .map_err(|err| StorageError::Corrupt(err.to_string()))?;
+            if key == SESSION_KEY {
+                let session: SessionRecord = from_value(value)?;
+                self.set_protocol(ProtocolDescriptor {
+                    delivery: session.delivery,
+                    rounds: session.rounds,
+                    max_payload: session.max_payload,
+                    timeouts: session.timeouts,
+                    ..ProtocolDescriptor::new(session.protocol_id, session.capacity)
+                });
+                self.set_state(session.state);
+                *self.round.write().unwrap() = session.round;
+                *self.deadline.write().unwrap() = session.deadline;
+                *self.participants.write().unwrap() = session.participants;
+                *self.creator.write().unwrap() = session.creator;
+                *self.ended.write().unwrap() = session.ended;
+            } else if key == ABORT_KEY {
+                *self.abort.write().unwrap() = Some(from_value(value)?);
+            } else if key.starts_with(PEER_PREFIX) {
+                let peer: Peer = from_value(value)?;
+                self.peers.write().unwrap().insert(peer.peer_id, peer);
+            } else if let Some(peer_id) = key.strip_prefix(REGISTRATION_PREFIX) {
+                self.add_registration(parse_number(peer_id)?, from_value(value)?);
+            } else if key.starts_with(COUNT_PREFIX) {
+                // the number of messages of a round is counted from the messages restored
+            } else if let Some(position) = key.strip_prefix(MESSAGE_PREFIX) {
+                let mut position = position.splitn(2, '/');
+                let round = parse_number(position.next().unwrap_or_default())?;
+                let peer_id = parse_number(position.next().unwrap_or_default())?;
+                self.stored_messages
+                    .write()
+                    .unwrap()
+                    .update(round, peer_id, from_value(value)?);
+            } else {
+                return Err(StorageError::Corrupt(format!("Unknown record {}", key)));
+            }
+        }
+        *self.active_peers.write().unwrap() = self.get_number_of_active_peers();
+        Ok(())
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).expect("Unable to serialize the session")
+}
+
+fn from_value<'a, T: Deserialize<'a>>(value: &'a [u8]) -> Result<T, StorageError> {
+    serde_json::from_slice(value).map_err(|err| StorageError::Corrupt(err.to_string()))
+}
+
+fn parse_number(number: &str) -> Result<u32, StorageError> {
+    number
+        .parse()
+        .map_err(|_| StorageError::Corrupt(format!("Invalid number {} in a key", number)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::RelaySession;
     use super::RelaySessionState;
+    use super::Timeouts;
 
-    use mmpc_server_common::protocol::ProtocolDescriptor;
-    use mmpc_server_common::ProtocolIdentifier;
+    use mmpc_server_common::common::RelayError;
+    use mmpc_server_common::identity::Identity;
+    use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
+    use mmpc_server_common::{
+        AbortMessage, AbortReason, ClientMessage, ProtocolIdentifier, RelayMessage,
+    };
 
-    use std::net::SocketAddr;
     use std::sync::Arc;
     use std::thread;
+    use std::time::Duration;
+
+    // A message of the first round of the session
+    fn first_round_message(peer_id: u32, protocol_id: ProtocolIdentifier) -> RelayMessage {
+        let mut msg = RelayMessage::new(peer_id, protocol_id);
+        msg.set_round(0);
+        msg
+    }
 
     #[test]
     fn test_add_peer() {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 1;
         let rs = RelaySession::new(capacity);
-        let client_addr: SocketAddr = format!("127.0.0.1:808{}", 0).parse().unwrap();
 
-        let peer_num = rs.register_new_peer(client_addr, protocol_id, capacity, 0);
-        assert_eq!(peer_num, Some(1));
+        let peer_num = rs.register_new_peer(protocol_id, capacity, 0, Identity::generate().key());
+        assert_eq!(peer_num, Ok(1));
     }
 
     #[test]
@@ -252,10 +862,9 @@ mod tests {
         let rs = RelaySession::new(capacity);
 
         let mut peer_num: u32 = 0;
-        for i in 0..capacity {
-            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
+        for _ in 0..capacity {
             peer_num = rs
-                .register_new_peer(client_addr, protocol_id, capacity, 0)
+                .register_new_peer(protocol_id, capacity, 0, Identity::generate().key())
                 .expect("Unable to register");
             println!("Peer number is {}", peer_num);
         }
@@ -263,6 +872,23 @@ mod tests {
         assert_eq!(peer_num, capacity);
     }
 
+    #[test]
+    fn test_add_peers_with_kg_index() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let rs = RelaySession::new(capacity);
+
+        // peers register in the reverse order of key generation
+        for i in 0..capacity {
+            let kg_index = (capacity - i) as i32;
+            let peer_num = rs
+                .register_new_peer(protocol_id, capacity, kg_index, Identity::generate().key())
+                .expect("Unable to register");
+            assert_eq!(peer_num, i + 1);
+            assert_eq!(rs.peers.read().unwrap()[&peer_num].kg_index, kg_index);
+        }
+    }
+
     #[test]
     fn test_add_multi_peers_in_parallel() {
         let mut children = vec![];
@@ -271,13 +897,11 @@ mod tests {
         let capacity: u32 = 50;
         let rs = Arc::new(RelaySession::new(capacity));
 
-        for i in 0..capacity {
+        for _ in 0..capacity {
             let rs_inner = Arc::clone(&rs);
-
-            let client_addr: SocketAddr = format!("127.0.0.1:80{}", 30 + i).parse().unwrap();
             children.push(thread::spawn(move || {
                 rs_inner
-                    .register_new_peer(client_addr, protocol_id, capacity, -1)
+                    .register_new_peer(protocol_id, capacity, -1, Identity::generate().key())
                     .expect("Unable to register");
             }));
         }
@@ -292,22 +916,46 @@ mod tests {
 
     #[test]
     fn test_can_register_protocol_valid() {
-        let client_addr: SocketAddr = "127.0.0.1:8081".to_string().parse().unwrap();
         let protocol_id: ProtocolIdentifier = 1 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
         let rs = RelaySession::new(capacity);
-        assert!(rs.can_register(&client_addr, protocol_descriptor))
+        assert_eq!(
+            Ok(()),
+            rs.can_register(protocol_descriptor, &Identity::generate().key())
+        )
+    }
+
+    #[test]
+    fn test_can_register_same_identity() {
+        let capacity: u32 = 3;
+        let rs = RelaySession::new(capacity);
+        let identity = Identity::generate();
+        rs.register_new_peer(1, capacity, -1, identity.key())
+            .expect("Unable to register");
+        assert_eq!(
+            Err(RelayError::CantRegister),
+            rs.register_new_peer(1, capacity, -1, identity.key())
+        );
+        assert_eq!(rs.get_number_of_active_peers(), 1);
     }
 
     #[test]
     fn test_can_register_protocol_invalid() {
-        let client_addr: SocketAddr = "127.0.0.1:8081".to_string().parse().unwrap();
         let protocol_id: ProtocolIdentifier = 100 as ProtocolIdentifier;
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
         let rs = RelaySession::new(capacity);
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        assert_eq!(
+            Err(RelayError::UnknownProtocol),
+            rs.can_register(protocol_descriptor, &Identity::generate().key())
+        );
+        // A known protocol with a capacity it does not support
+        let protocol_descriptor = ProtocolDescriptor::new(1, 6);
+        assert_eq!(
+            Err(RelayError::InvalidProtocol),
+            rs.can_register(protocol_descriptor, &Identity::generate().key())
+        )
     }
 
     /////////////////////////// test register ///////////////////////////////////
@@ -319,15 +967,347 @@ mod tests {
 
         // State is empty at first
         assert_eq!(RelaySessionState::Empty, rs.state());
-        for i in 0..capacity - 1 {
-            let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
-            rs.register_new_peer(client_addr, protocol_id, capacity, -1);
+        for _ in 0..capacity - 1 {
+            rs.register_new_peer(protocol_id, capacity, -1, Identity::generate().key())
+                .expect("Unable to register");
             // State is not initialized when not all are connected
             assert_eq!(RelaySessionState::Uninitialized, rs.state());
         }
-        let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity - 1).parse().unwrap();
-        let messages = rs.register_new_peer(client_addr, protocol_id, capacity, -1);
+        let messages = rs.register_new_peer(protocol_id, capacity, -1, Identity::generate().key());
         // Once all are connected, state should initialize
         assert_eq!(RelaySessionState::Initialized, rs.state());
     }
+
+    /////////////////////////// test can_relay ///////////////////////////////////
+    #[test]
+    fn test_can_relay() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let rs = RelaySession::new(capacity);
+        let identities: Vec<Identity> = (0..2).map(|_| Identity::generate()).collect();
+        for identity in &identities {
+            rs.register_new_peer(protocol_id, capacity, -1, identity.key())
+                .expect("Unable to register");
+        }
+
+        let msg = first_round_message(1, protocol_id);
+        assert_eq!(Ok(()), rs.can_relay(&identities[0].key(), &msg));
+        // Another peer can not send under the number of the first peer
+        assert_eq!(
+            Err(RelayError::InvalidSignature),
+            rs.can_relay(&identities[1].key(), &msg)
+        );
+        // An identity that did not register is not a peer, whatever number it claims
+        assert_eq!(
+            Err(RelayError::NotAPeer),
+            rs.can_relay(&Identity::generate().key(), &msg)
+        );
+    }
+
+    #[test]
+    fn test_can_relay_validation() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let mut rs = RelaySession::new(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in &identities {
+            rs.register_new_peer(protocol_id, capacity, -1, identity.key())
+                .expect("Unable to register");
+        }
+
+        // A peer can not send a message under the number of another peer
+        let msg = first_round_message(2, protocol_id);
+        assert_eq!(
+            Err(RelayError::InvalidSignature),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+        let msg = first_round_message(1, 2);
+        assert_eq!(
+            Err(RelayError::InvalidProtocol),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+        // A message must name its round, its signature is bound to it
+        let mut msg = RelayMessage::new(1, protocol_id);
+        assert_eq!(
+            Err(RelayError::MalformedMessage),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+        msg.set_round(1);
+        assert_eq!(
+            Err(RelayError::WrongRound),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+        msg.set_round(0);
+        assert_eq!(Ok(()), rs.can_relay(&identities[0].key(), &msg));
+
+        // Only one message of each peer is relayed in a round
+        let mut client_message = ClientMessage::new();
+        client_message.relay_message = Some(msg.clone());
+        rs.update_stored_messages(0, 1, identities[0].sign(&client_message));
+        assert_eq!(
+            Err(RelayError::DuplicateMessage),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+        let msg = first_round_message(2, protocol_id);
+        assert_eq!(Ok(()), rs.can_relay(&identities[1].key(), &msg));
+    }
+
+    #[test]
+    fn test_set_block_time() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let timeouts = Timeouts {
+            registration: Some(Duration::from_secs(60)),
+            round: Some(Duration::from_secs(30)),
+        };
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let seconds = Duration::from_secs;
+
+        // a session that does not fill up in time is aborted without naming any peer
+        let rs = RelaySession::with_timeouts(capacity, timeouts);
+        rs.set_block_time(seconds(100));
+        rs.register_new_peer(protocol_id, capacity, -1, identities[0].key())
+            .expect("Unable to register");
+        assert!(rs.set_block_time(seconds(159)).is_none());
+        let abort = rs
+            .set_block_time(seconds(160))
+            .expect("Session not aborted");
+        assert!(abort.unresponsive.is_empty());
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        assert_eq!(
+            Err(RelayError::SessionAborted),
+            rs.register_new_peer(protocol_id, capacity, -1, identities[1].key())
+        );
+
+        // a round that is not done in time names the peers that did not send their message
+        let rs = RelaySession::with_timeouts(capacity, timeouts);
+        rs.set_block_time(seconds(100));
+        for identity in &identities {
+            rs.register_new_peer(protocol_id, capacity, -1, identity.key())
+                .expect("Unable to register");
+        }
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(first_round_message(2, protocol_id));
+        let mut session = rs.clone();
+        session.update_stored_messages(0, 2, identities[1].sign(&msg));
+        assert!(rs.set_block_time(seconds(129)).is_none());
+        let abort = rs
+            .set_block_time(seconds(130))
+            .expect("Session not aborted");
+        assert_eq!(abort.unresponsive, vec![1, 3]);
+        let msg = first_round_message(1, protocol_id);
+        assert_eq!(
+            Err(RelayError::SessionAborted),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+    }
+
+    #[test]
+    fn test_protocol_limits() {
+        let path =
+            std::env::temp_dir().join(format!("protocols-limits-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"protocols":[{"id":7,"names":["limited"],"capacities":{"min":2,"max":3},
+                "rounds":1,"delivery":"broadcast","max_payload":8,"timeouts":{"round":5}}]}"#,
+        )
+        .unwrap();
+        let protocols = Arc::new(ProtocolRegistry::load(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let protocol_id: ProtocolIdentifier = 7;
+        let capacity: u32 = 2;
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let seconds = Duration::from_secs;
+
+        let mut rs =
+            RelaySession::with_protocols(capacity, Timeouts::default(), Arc::clone(&protocols));
+        assert_eq!(
+            Err(RelayError::InvalidProtocol),
+            rs.register_new_peer(protocol_id, 4, -1, identities[0].key())
+        );
+        rs.set_block_time(seconds(100));
+        for identity in &identities {
+            rs.register_new_peer(protocol_id, capacity, -1, identity.key())
+                .expect("Unable to register");
+        }
+        let relay_message = |peer_id: u32, to: Vec<u32>, payload: &str| {
+            let mut msg = first_round_message(peer_id, protocol_id);
+            msg.set_message_params(to, payload);
+            msg
+        };
+
+        let msg = relay_message(1, vec![2], "too large");
+        assert_eq!(
+            Err(RelayError::PayloadTooLarge),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+        let msg = relay_message(1, vec![], "first");
+        assert_eq!(
+            Err(RelayError::InvalidRecipients),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+
+        for i in 0..capacity {
+            let msg = relay_message(i + 1, vec![capacity - i], "message");
+            assert_eq!(Ok(()), rs.can_relay(&identities[i as usize].key(), &msg));
+            let mut client_message = ClientMessage::new();
+            client_message.relay_message = Some(msg);
+            rs.update_stored_messages(0, i + 1, identities[i as usize].sign(&client_message));
+        }
+        assert_eq!(rs.ended_at(), None);
+        rs.try_increase_round(capacity);
+        assert_eq!(rs.round(), 1);
+
+        // the session ran all of its rounds, and waits for no one
+        assert!(rs.ended_at().is_some());
+        let mut msg = relay_message(1, vec![2], "third");
+        msg.set_round(1);
+        assert_eq!(
+            Err(RelayError::TooManyRounds),
+            rs.can_relay(&identities[0].key(), &msg)
+        );
+        assert!(rs.set_block_time(seconds(3600)).is_none());
+
+        // the protocol sets a round deadline the relay did not
+        let rs = RelaySession::with_protocols(capacity, Timeouts::default(), protocols);
+        rs.set_block_time(seconds(100));
+        for identity in &identities {
+            rs.register_new_peer(protocol_id, capacity, -1, identity.key())
+                .expect("Unable to register");
+        }
+        assert!(rs.set_block_time(seconds(104)).is_none());
+        assert!(rs.set_block_time(seconds(105)).is_some());
+    }
+
+    #[test]
+    fn test_records() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let timeouts = Timeouts {
+            registration: None,
+            round: Some(Duration::from_secs(60)),
+        };
+        let mut rs = RelaySession::with_timeouts(capacity, timeouts);
+        rs.set_block_time(Duration::from_secs(100));
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        for identity in &identities {
+            let peer_id = rs
+                .register_new_peer(protocol_id, capacity, -1, identity.key())
+                .expect("Unable to register");
+            rs.add_registration(peer_id, identity.sign(&ClientMessage::new()));
+        }
+        let mut client_message = ClientMessage::new();
+        client_message.relay_message = Some(first_round_message(1, protocol_id));
+        rs.update_stored_messages(0, 1, identities[0].sign(&client_message));
+
+        let records = rs.records();
+        let mut keys: Vec<Vec<u8>> = records.iter().map(|(key, _)| key.clone()).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            records
+                .iter()
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>()
+        );
+
+        let restored = RelaySession::with_timeouts(capacity, timeouts);
+        restored.restore(&records).expect("Unable to restore");
+        assert_eq!(restored.records(), records);
+        assert_eq!(restored.state(), RelaySessionState::Initialized);
+        assert_eq!(
+            restored.registrations.read().unwrap().len(),
+            capacity as usize
+        );
+        assert_eq!(restored.stored_messages().get_number_messages(0), 1);
+        // the restored session is full
+        assert_eq!(
+            Err(RelayError::SessionFull),
+            restored.register_new_peer(protocol_id, capacity, -1, identities[0].key())
+        );
+        // and keeps its deadline
+        assert!(restored.set_block_time(Duration::from_secs(159)).is_none());
+        assert!(restored.set_block_time(Duration::from_secs(160)).is_some());
+    }
+
+    #[test]
+    fn test_abort() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let session = || {
+            let rs = RelaySession::new(capacity);
+            for identity in &identities {
+                rs.register_new_peer(protocol_id, capacity, -1, identity.key())
+                    .expect("Unable to register");
+            }
+            rs
+        };
+        // two different messages the second peer signed for the same round
+        let evidence: Vec<_> = ["first", "second"]
+            .iter()
+            .map(|payload| {
+                let mut relay_message = first_round_message(2, protocol_id);
+                relay_message.set_message_params(vec![], *payload);
+                let mut client_message = ClientMessage::new();
+                client_message.relay_message = Some(relay_message);
+                identities[1].sign(&client_message)
+            })
+            .collect();
+
+        // a peer can only abort as itself
+        let rs = session();
+        let abort = AbortMessage::blame(
+            1,
+            protocol_id,
+            AbortReason::Equivocation,
+            2,
+            evidence.clone(),
+        );
+        assert_eq!(
+            Some(RelayError::InvalidSignature),
+            rs.abort(&identities[2].key(), &abort).err()
+        );
+        let recorded = rs.abort(&identities[0].key(), &abort).unwrap();
+        assert_eq!(recorded.culprit, Some(2));
+        assert_eq!(recorded.evidence.len(), 2);
+        assert_eq!(rs.state(), RelaySessionState::Aborted);
+        assert_eq!(rs.abort_message().unwrap().culprit, Some(2));
+        assert_eq!(
+            Some(RelayError::SessionAborted),
+            rs.abort(&identities[2].key(), &AbortMessage::new(3, protocol_id))
+                .err()
+        );
+
+        // the aborted session is restored with its abort
+        let restored = RelaySession::new(capacity);
+        restored.restore(&rs.records()).expect("Unable to restore");
+        assert_eq!(restored.state(), RelaySessionState::Aborted);
+        let abort = restored.abort_message().unwrap();
+        assert_eq!((abort.peer_number, abort.culprit), (1, Some(2)));
+        assert_eq!(abort.evidence.len(), 2);
+
+        // evidence not proving the blame aborts the session without blaming anyone
+        let rs = session();
+        let abort = AbortMessage::blame(
+            1,
+            protocol_id,
+            AbortReason::Equivocation,
+            2,
+            evidence[..1].to_vec(),
+        );
+        let recorded = rs.abort(&identities[0].key(), &abort).unwrap();
+        assert_eq!((recorded.peer_number, recorded.culprit), (1, None));
+        assert!(recorded.evidence.is_empty());
+        let rs = session();
+        let abort = AbortMessage::blame(
+            1,
+            protocol_id,
+            AbortReason::Equivocation,
+            3,
+            evidence.clone(),
+        );
+        let recorded = rs.abort(&identities[0].key(), &abort).unwrap();
+        assert_eq!(recorded.culprit, None);
+    }
 }
@@ -0,0 +1,462 @@
+//! The relay sessions of the application. The application starts with the default session,
+//! and creates a session for every create session transaction it delivers.
+//! Sessions are numbered in the order they are created, so every node numbers them the same way.
+//! Created sessions that ended are pruned once they are kept for the retention of the relay,
+//! which is measured by the time of the blocks, so every node prunes a session at the same block
+use log::info;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use mmpc_server_common::common::RelayError;
+use mmpc_server_common::identity::IdentityKey;
+use mmpc_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry};
+use mmpc_server_common::{
+    AbortMessage, CreateSessionMessage, SessionIdentifier, DEFAULT_SESSION_ID,
+};
+
+use crate::relay_session::{RelaySession, Timeouts};
+use crate::storage::{Record, StorageError};
+
+const SESSION_PREFIX: &str = "sessions/";
+
+// Key of the record of the number the next session created is given,
+// as the last sessions created may have been pruned
+const NEXT_SESSION_KEY: &str = "next_session";
+
+// How long the relay keeps a created session after it ended by default
+const DEFAULT_RETENTION: Duration = Duration::from_secs(600);
+
+// Number of open sessions an identity can create by default
+const DEFAULT_OPEN_SESSIONS: usize = 4;
+
+/// Limits of the sessions the clients create.
+/// All the nodes must run with the same limits, as they decide which transactions are valid
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionLimits {
+    // time a created session is kept for after it was aborted or ran all of its rounds,
+    // it is kept forever if not set
+    pub retention: Option<Duration>,
+
+    // number of sessions an identity can create that did not end yet, any if not set
+    pub open_sessions: Option<usize>,
+}
+
+impl Default for SessionLimits {
+    fn default() -> SessionLimits {
+        SessionLimits {
+            retention: Some(DEFAULT_RETENTION),
+            open_sessions: Some(DEFAULT_OPEN_SESSIONS),
+        }
+    }
+}
+
+/// Prefix of the keys of the records of the session.
+/// The identifier is zero padded, so the records of the sessions sort by session
+pub(crate) fn session_prefix(session_id: SessionIdentifier) -> String {
+    format!("{}{:020}/", SESSION_PREFIX, session_id)
+}
+
+// The session a stored record belongs to, along with the key of the record within the session
+fn parse_session_key(key: &[u8]) -> Result<(SessionIdentifier, Vec<u8>), StorageError> {
+    let key =
+        String::from_utf8(key.to_vec()).map_err(|err| StorageError::Corrupt(err.to_string()))?;
+    let mut position = key
+        .strip_prefix(SESSION_PREFIX)
+        .ok_or_else(|| StorageError::Corrupt(format!("Unknown record {}", key)))?
+        .splitn(2, '/');
+    let session_id = position
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .map_err(|_| StorageError::Corrupt(format!("Invalid session in the key {}", key)))?;
+    Ok((
+        session_id,
+        position.next().unwrap_or_default().as_bytes().to_vec(),
+    ))
+}
+
+/// Holds all the relay sessions of the application, by their identifier
+pub struct SessionManager {
+    sessions: BTreeMap<SessionIdentifier, RelaySession>,
+
+    // the number the next session created is given
+    next_session_id: SessionIdentifier,
+
+    // how long ended sessions are kept, and how many open sessions an identity can create
+    limits: SessionLimits,
+
+    // deadlines of every session created
+    timeouts: Timeouts,
+
+    // the protocols the sessions can run
+    protocols: Arc<ProtocolRegistry>,
+
+    // the time of the current block, the deadlines of the sessions created in it start from then
+    block_time: Duration,
+}
+
+impl SessionManager {
+    /// Creates the manager holding only the default session, which runs with the given number of peers
+    pub fn new(
+        capacity: u32,
+        timeouts: Timeouts,
+        protocols: Arc<ProtocolRegistry>,
+    ) -> SessionManager {
+        let mut sessions = BTreeMap::new();
+        sessions.insert(
+            DEFAULT_SESSION_ID,
+            RelaySession::with_protocols(capacity, timeouts, Arc::clone(&protocols)),
+        );
+        SessionManager {
+            sessions,
+            // the default session is always held, so created sessions are numbered from 1
+            next_session_id: DEFAULT_SESSION_ID + 1,
+            limits: SessionLimits::default(),
+            timeouts,
+            protocols,
+            block_time: Duration::from_secs(0),
+        }
+    }
+
+    /// Sets the limits of the sessions the clients create
+    pub fn set_limits(&mut self, limits: SessionLimits) {
+        self.limits = limits;
+    }
+
+    /// Returns the session with the given identifier.
+    /// The session shares its state with the one held by the manager
+    pub fn get_session(&self, session_id: SessionIdentifier) -> Result<RelaySession, RelayError> {
+        self.sessions
+            .get(&session_id)
+            .cloned()
+            .ok_or(RelayError::UnknownSession)
+    }
+
+    /// Returns the number of sessions held, the default session included
+    pub fn number_of_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Checks the creator can create the session: the protocol must be supported with the capacity,
+    /// the participants, if listed, must be as many distinct identities,
+    /// and the creator must have fewer open sessions than the limit.
+    /// Returns the protocol the session would run
+    pub fn can_create(
+        &self,
+        create: &CreateSessionMessage,
+        creator: &IdentityKey,
+    ) -> Result<ProtocolDescriptor, RelayError> {
+        let protocol = self
+            .protocols
+            .descriptor(create.protocol_id, create.capacity)?;
+        if !create.participants.is_empty() {
+            let distinct: BTreeSet<&IdentityKey> = create.participants.iter().collect();
+            if create.participants.len() != create.capacity as usize
+                || distinct.len() != create.participants.len()
+            {
+                return Err(RelayError::CapacityMismatch);
+            }
+        }
+        if let Some(open_sessions) = self.limits.open_sessions {
+            let created = self
+                .sessions
+                .values()
+                .filter(|session| {
+                    session.creator().as_ref() == Some(creator) && session.ended_at().is_none()
+                })
+                .count();
+            if created >= open_sessions {
+                return Err(RelayError::TooManySessions);
+            }
+        }
+        Ok(protocol)
+    }
+
+    /// Creates a session of the creator open for its participants to register,
+    /// and returns its identifier
+    pub fn create_session(
+        &mut self,
+        create: &CreateSessionMessage,
+        creator: IdentityKey,
+    ) -> Result<SessionIdentifier, RelayError> {
+        let protocol = self.can_create(create, &creator)?;
+        let session_id = self.next_session_id;
+        self.next_session_id += 1;
+        let session = RelaySession::with_protocols(
+            create.capacity,
+            self.timeouts,
+            Arc::clone(&self.protocols),
+        );
+        session.open(
+            protocol,
+            create.participants.clone(),
+            creator,
+            self.block_time,
+        );
+        self.sessions.insert(session_id, session);
+        info!(
+            "Created relay session {} of protocol {} for {} peers",
+            session_id, create.protocol_id, create.capacity
+        );
+        Ok(session_id)
+    }
+
+    /// Sets the time of the block being executed in every session,
+    /// and prunes the created sessions that ended longer than the retention before.
+    /// Returns the aborts of the sessions that missed their deadline by then
+    pub fn set_block_time(&mut self, time: Duration) -> Vec<(SessionIdentifier, AbortMessage)> {
+        self.block_time = time;
+        let aborts = self
+            .sessions
+            .iter()
+            .filter_map(|(session_id, session)| {
+                session
+                    .set_block_time(time)
+                    .map(|abort| (*session_id, abort))
+            })
+            .collect();
+        if let Some(retention) = self.limits.retention {
+            self.sessions.retain(|session_id, session| {
+                let expired = *session_id != DEFAULT_SESSION_ID
+                    && matches!(session.ended_at(), Some(ended) if ended + retention <= time);
+                if expired {
+                    info!("Pruned relay session {}", session_id);
+                }
+                !expired
+            });
+        }
+        aborts
+    }
+
+    /// Returns the records of all the sessions sorted by key, each key prefixed by its session,
+    /// after the record of the number of the next session
+    pub fn records(&self) -> Vec<Record> {
+        let next_session = (
+            NEXT_SESSION_KEY.as_bytes().to_vec(),
+            self.next_session_id.to_string().into_bytes(),
+        );
+        std::iter::once(next_session)
+            .chain(self.sessions.iter().flat_map(|(session_id, session)| {
+                let prefix = session_prefix(*session_id);
+                session
+                    .records()
+                    .into_iter()
+                    .map(move |(key, value)| ([prefix.as_bytes(), &key].concat(), value))
+            }))
+            .collect()
+    }
+
+    /// Restores the sessions from the stored records
+    pub fn restore(&mut self, records: &[Record]) -> Result<(), StorageError> {
+        let mut session_records: BTreeMap<SessionIdentifier, Vec<Record>> = BTreeMap::new();
+        for (key, value) in records {
+            if key.as_slice() == NEXT_SESSION_KEY.as_bytes() {
+                self.next_session_id = String::from_utf8_lossy(value).parse().map_err(|_| {
+                    StorageError::Corrupt(String::from("Invalid number of the next session"))
+                })?;
+                continue;
+            }
+            let (session_id, key) = parse_session_key(key)?;
+            session_records
+                .entry(session_id)
+                .or_default()
+                .push((key, value.clone()));
+        }
+        let timeouts = self.timeouts;
+        let protocols = &self.protocols;
+        for (session_id, records) in session_records {
+            // the protocol and capacity of a created session are restored from its records
+            let session = self.sessions.entry(session_id).or_insert_with(|| {
+                RelaySession::with_protocols(0, timeouts, Arc::clone(protocols))
+            });
+            session.restore(&records)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{session_prefix, SessionLimits, SessionManager};
+    use crate::relay_session::{RelaySessionState, Timeouts};
+
+    use mmpc_server_common::common::RelayError;
+    use mmpc_server_common::identity::Identity;
+    use mmpc_server_common::protocol::ProtocolRegistry;
+    use mmpc_server_common::{CreateSessionMessage, DEFAULT_SESSION_ID};
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn create_message(capacity: u32, participants: usize) -> CreateSessionMessage {
+        CreateSessionMessage {
+            protocol_id: 1,
+            capacity,
+            participants: (0..participants)
+                .map(|_| Identity::generate().key())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_create_session() {
+        let timeouts = Timeouts {
+            registration: Some(Duration::from_secs(10)),
+            round: None,
+        };
+        let mut manager = SessionManager::new(2, timeouts, Arc::new(ProtocolRegistry::default()));
+        let creator = Identity::generate().key();
+        assert_eq!(manager.number_of_sessions(), 1);
+        manager.set_block_time(Duration::from_secs(100));
+
+        assert_eq!(
+            manager.create_session(&create_message(3, 0), creator),
+            Ok(1)
+        );
+        assert_eq!(
+            manager.create_session(&create_message(3, 3), creator),
+            Ok(2)
+        );
+        assert_eq!(manager.number_of_sessions(), 3);
+        let session = manager.get_session(2).unwrap();
+        assert_eq!(session.state(), RelaySessionState::Uninitialized);
+        assert_eq!(session.protocol().capacity, 3);
+        assert_eq!(
+            manager.get_session(DEFAULT_SESSION_ID).unwrap().state(),
+            RelaySessionState::Empty
+        );
+        assert_eq!(
+            manager.get_session(3).err(),
+            Some(RelayError::UnknownSession)
+        );
+
+        // the participants are as many as the peers of the session, and distinct
+        assert_eq!(
+            manager.create_session(&create_message(3, 2), creator),
+            Err(RelayError::CapacityMismatch)
+        );
+        let mut create = create_message(2, 2);
+        create.participants[1] = create.participants[0];
+        assert_eq!(
+            manager.create_session(&create, creator),
+            Err(RelayError::CapacityMismatch)
+        );
+        create.protocol_id = 42;
+        assert!(manager.create_session(&create, creator).is_err());
+        assert_eq!(manager.number_of_sessions(), 3);
+
+        // the registration deadline runs from the block the session was created in,
+        // the time of the block itself is not part of the state of the sessions
+        let records = manager.records();
+        assert!(manager.set_block_time(Duration::from_secs(109)).is_empty());
+        assert_eq!(manager.records(), records);
+        let aborted: Vec<u64> = manager
+            .set_block_time(Duration::from_secs(110))
+            .into_iter()
+            .map(|(session_id, _)| session_id)
+            .collect();
+        assert_eq!(aborted, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_restore_sessions() {
+        let protocols = Arc::new(ProtocolRegistry::default());
+        let mut manager = SessionManager::new(2, Timeouts::default(), Arc::clone(&protocols));
+        let creator = Identity::generate().key();
+        manager
+            .create_session(&create_message(3, 3), creator)
+            .unwrap();
+        manager
+            .create_session(&create_message(4, 0), creator)
+            .unwrap();
+        let records = manager.records();
+        let keys: Vec<&Vec<u8>> = records.iter().map(|(key, _)| key).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        assert!(records
+            .iter()
+            .any(|(key, _)| key.starts_with(session_prefix(2).as_bytes())));
+
+        let mut restored = SessionManager::new(2, Timeouts::default(), protocols);
+        restored.restore(&records).unwrap();
+        assert_eq!(restored.number_of_sessions(), 3);
+        assert_eq!(restored.records(), records);
+        assert_eq!(restored.get_session(1).unwrap().protocol().capacity, 3);
+        // sessions created after the restore are numbered after the restored ones
+        assert_eq!(
+            restored.create_session(&create_message(2, 0), creator),
+            Ok(3)
+        );
+
+        assert!(restored.restore(&[(b"session".to_vec(), vec![])]).is_err());
+    }
+
+    #[test]
+    fn test_session_limits() {
+        let timeouts = Timeouts {
+            registration: Some(Duration::from_secs(10)),
+            round: None,
+        };
+        let protocols = Arc::new(ProtocolRegistry::default());
+        let mut manager = SessionManager::new(2, timeouts, Arc::clone(&protocols));
+        manager.set_limits(SessionLimits {
+            retention: Some(Duration::from_secs(60)),
+            open_sessions: Some(2),
+        });
+        let creator = Identity::generate().key();
+        manager.set_block_time(Duration::from_secs(100));
+
+        // an identity creates up to the limit of open sessions, others are not limited by it
+        assert_eq!(
+            manager.create_session(&create_message(2, 0), creator),
+            Ok(1)
+        );
+        assert_eq!(
+            manager.create_session(&create_message(2, 0), creator),
+            Ok(2)
+        );
+        assert_eq!(
+            manager.create_session(&create_message(2, 0), creator),
+            Err(RelayError::TooManySessions)
+        );
+        let other = Identity::generate().key();
+        assert_eq!(manager.create_session(&create_message(2, 0), other), Ok(3));
+
+        // sessions that ended are not open
+        assert_eq!(manager.set_block_time(Duration::from_secs(110)).len(), 3);
+        assert_eq!(
+            manager.get_session(1).unwrap().ended_at(),
+            Some(Duration::from_secs(110))
+        );
+        assert_eq!(
+            manager.create_session(&create_message(2, 0), creator),
+            Ok(4)
+        );
+
+        // ended sessions are kept for the retention, the default session is never pruned
+        manager.set_block_time(Duration::from_secs(169));
+        assert_eq!(manager.number_of_sessions(), 5);
+        manager.set_block_time(Duration::from_secs(170));
+        assert_eq!(manager.number_of_sessions(), 2);
+        assert_eq!(
+            manager.get_session(1).err(),
+            Some(RelayError::UnknownSession)
+        );
+        assert!(manager.get_session(DEFAULT_SESSION_ID).is_ok());
+
+        // the numbers of pruned sessions are not given again, after a restore as well
+        let mut restored = SessionManager::new(2, timeouts, protocols);
+        restored.restore(&manager.records()).unwrap();
+        assert_eq!(restored.records(), manager.records());
+        assert_eq!(
+            manager.create_session(&create_message(2, 0), creator),
+            Ok(5)
+        );
+        assert_eq!(
+            restored.create_session(&create_message(2, 0), creator),
+            Ok(5)
+        );
+    }
+}
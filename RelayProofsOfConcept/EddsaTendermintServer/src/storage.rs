@@ -0,0 +1,230 @@
+//! Persistent state of the relay application, kept in an embedded key value store.
+//! The state of the relay session is written as records once a block is committed,
+//! so a restarted application resumes from the last committed block.
+//! Only the records that changed since the previous block are written
+use log::debug;
+use std::cmp::Ordering;
+use std::fmt;
+use std::path::Path;
+
+/// A key and the serialized value stored under it
+pub type Record = (Vec<u8>, Vec<u8>);
+
+/// A key and the value it is set to, or none if the record is removed
+pub type Change = (Vec<u8>, Option<Vec<u8>>);
+
+// prefix of the keys of the session records
+const STATE_PREFIX: &[u8] = b"state/";
+
+const HEIGHT_KEY: &[u8] = b"meta/height";
+const APP_HASH_KEY: &[u8] = b"meta/app_hash";
+
+/// Reasons the state could not be stored or loaded
+#[derive(Debug)]
+pub enum StorageError {
+    // The key value store failed
+    Db(sled::Error),
+
+    // A stored record could not be decoded
+    Corrupt(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Db(err) => write!(f, "Storage failed: {}", err),
+            StorageError::Corrupt(err) => write!(f, "Corrupt stored state: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<sled::Error> for StorageError {
+    fn from(err: sled::Error) -> StorageError {
+        StorageError::Db(err)
+    }
+}
+
+/// The last block the application committed, and the hash of its state after the block
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommitInfo {
+    pub height: i64,
+    pub app_hash: Vec<u8>,
+}
+
+/// Returns the changes turning the previous records into the given ones, both sorted by key
+pub fn changes(previous: &[Record], records: &[Record]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    let (mut previous, mut records) = (previous.iter().peekable(), records.iter().peekable());
+    loop {
+        let order = match (previous.peek(), records.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((previous_key, _)), Some((key, _))) => previous_key.cmp(key),
+        };
+        match order {
+            Ordering::Less => {
+                let (key, _) = previous.next().unwrap();
+                changes.push((key.clone(), None));
+            }
+            Ordering::Greater => {
+                let (key, value) = records.next().unwrap();
+                changes.push((key.clone(), Some(value.clone())));
+            }
+            Ordering::Equal => {
+                let (_, previous_value) = previous.next().unwrap();
+                let (key, value) = records.next().unwrap();
+                if previous_value != value {
+                    changes.push((key.clone(), Some(value.clone())));
+                }
+            }
+        }
+    }
+    changes
+}
+
+/// Handle to the store, its clones share the same store
+#[derive(Clone)]
+pub struct Storage {
+    db: sled::Db,
+}
+
+impl Storage {
+    /// Opens the store in the given directory, creating it if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Storage, StorageError> {
+        Ok(Storage {
+            db: sled::open(path)?,
+        })
+    }
+
+    /// Opens a store that is removed once it is dropped
+    pub fn temporary() -> Result<Storage, StorageError> {
+        Ok(Storage {
+            db: sled::Config::new().temporary(true).open()?,
+        })
+    }
+
+    /// Applies the changes of the block of the given height to the stored state,
+    /// along with the hash of the state after the block.
+    /// The changes and the block are written at once, so a crash leaves the state of the previous block
+    pub fn commit(
+        &self,
+        height: i64,
+        app_hash: &[u8],
+        changes: &[Change],
+    ) -> Result<CommitInfo, StorageError> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in changes {
+            let key = [STATE_PREFIX, key].concat();
+            match value {
+                Some(value) => batch.insert(key, value.clone()),
+                None => batch.remove(key),
+            }
+        }
+        batch.insert(HEIGHT_KEY, &height.to_be_bytes()[..]);
+        batch.insert(APP_HASH_KEY, app_hash);
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        debug!("Committed {} changes at height {}", changes.len(), height);
+        Ok(CommitInfo {
+            height,
+            app_hash: app_hash.to_vec(),
+        })
+    }
+
+    /// Returns the records of the state, sorted by key
+    pub fn records(&self) -> Result<Vec<Record>, StorageError> {
+        self.db
+            .scan_prefix(STATE_PREFIX)
+            .map(|record| {
+                let (key, value) = record?;
+                Ok((key[STATE_PREFIX.len()..].to_vec(), value.to_vec()))
+            })
+            .collect()
+    }
+
+    /// Returns the last block committed, or the default before any block was committed
+    pub fn last_commit(&self) -> Result<CommitInfo, StorageError> {
+        let height = match self.db.get(HEIGHT_KEY)? {
+            Some(height) => {
+                let mut bytes = [0u8; 8];
+                if height.len() != bytes.len() {
+                    return Err(StorageError::Corrupt(String::from("Invalid block height")));
+                }
+                bytes.copy_from_slice(&height);
+                i64::from_be_bytes(bytes)
+            }
+            None => return Ok(CommitInfo::default()),
+        };
+        let app_hash = self
+            .db
+            .get(APP_HASH_KEY)?
+            .map(|app_hash| app_hash.to_vec())
+            .unwrap_or_default();
+        Ok(CommitInfo { height, app_hash })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changes, CommitInfo, Record, Storage};
+
+    fn record(key: &str, value: &str) -> Record {
+        (key.as_bytes().to_vec(), value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_commit() {
+        let storage = Storage::temporary().unwrap();
+        assert_eq!(storage.last_commit().unwrap(), CommitInfo::default());
+        assert!(storage.records().unwrap().is_empty());
+
+        let records = vec![record("peer/1", "first"), record("session", "state")];
+        let commit = storage
+            .commit(1, b"first", &changes(&[], &records))
+            .unwrap();
+        assert_eq!(commit.height, 1);
+        assert_eq!(commit.app_hash, b"first".to_vec());
+        assert_eq!(storage.last_commit().unwrap(), commit);
+        assert_eq!(storage.records().unwrap(), records);
+
+        // records missing from a later commit are removed
+        let next_records = vec![record("session", "aborted")];
+        storage
+            .commit(2, b"next", &changes(&records, &next_records))
+            .unwrap();
+        assert_eq!(storage.records().unwrap(), next_records);
+        assert_eq!(storage.last_commit().unwrap().height, 2);
+    }
+
+    #[test]
+    fn test_changes() {
+        let previous = vec![
+            record("a", "1"),
+            record("b", "2"),
+            record("c", "3"),
+            record("e", "5"),
+        ];
+        let records = vec![
+            record("b", "2"),
+            record("c", "4"),
+            record("d", "4"),
+            record("e", "5"),
+            record("f", "6"),
+        ];
+        let value = |value: &str| Some(value.as_bytes().to_vec());
+        assert_eq!(
+            changes(&previous, &records),
+            vec![
+                (b"a".to_vec(), None),
+                (b"c".to_vec(), value("4")),
+                (b"d".to_vec(), value("4")),
+                (b"f".to_vec(), value("6")),
+            ]
+        );
+        // only what changed is written
+        assert!(changes(&records, &records).is_empty());
+    }
+}
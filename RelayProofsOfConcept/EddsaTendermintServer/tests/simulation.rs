@@ -0,0 +1,447 @@
+/// Runs EdDSA key generation and signing through the ABCI application of the Tendermint relay,
+/// in one process and without a Tendermint node. Transactions are delivered to the application
+/// as soon as they pass its check, and the peers query it for the messages they are missing
+use std::collections::BTreeMap;
+
+use abci::{
+    Application, RequestCheckTx, RequestCommit, RequestDeliverTx, RequestInfo, RequestQuery,
+};
+use multi_party_eddsa::protocols::aggsig::{KeyPair, Signature};
+
+use mmpc_client::adversary::{Adversary, Fault};
+use mmpc_client::key_store::{MemoryKeyStore, PeerKeys};
+use mmpc_client::peer::{Peer, ProtocolAbort, ProtocolDataManager};
+use mmpc_client::simulation::{Delivery, NetworkConditions, SimulatedRelay, Simulation};
+use mmpc_client::transport::{RoundMessage, TransportError};
+use mmpc_client::{eddsa_peer_kg, eddsa_peer_sign};
+use mmpc_server::{RelayApp, Storage};
+use mmpc_server_common::common::EddsaMessage;
+use mmpc_server_common::encryption::EncryptionKeyPair;
+use mmpc_server_common::identity::{Identity, PeerIdentities, SignedClientMessage};
+use mmpc_server_common::query::QueryPath;
+use mmpc_server_common::{
+    ClientMessage, MessagePayload, MissingMessagesReply, MissingMessagesRequest, PeerIdentifier,
+    PeersReply, ProtocolIdentifier, RelayMessage, ServerMessage, ServerResponse,
+    DEFAULT_SESSION_ID,
+};
+
+// a peer of the session, as it knows the other peers
+struct SessionPeer {
+    identity: Identity,
+    encryption: EncryptionKeyPair,
+    identities: PeerIdentities,
+}
+
+struct TendermintRelay {
+    app: RelayApp,
+    protocol_id: ProtocolIdentifier,
+    peers: Vec<SessionPeer>,
+    // index of each peer in the simulation, by its identifier in the session
+    indexes: BTreeMap<PeerIdentifier, usize>,
+    // if set, the application stores its state there, and restarts after each transaction
+    storage: Option<Storage>,
+}
+
+impl TendermintRelay {
+    fn new(capacity: u32) -> TendermintRelay {
+        let peers = (0..capacity)
+            .map(|_| SessionPeer {
+                identity: Identity::generate(),
+                encryption: EncryptionKeyPair::generate(),
+                identities: PeerIdentities::new(),
+            })
+            .collect();
+        TendermintRelay {
+            app: RelayApp::new(capacity),
+            protocol_id: 0,
+            peers,
+            indexes: BTreeMap::new(),
+            storage: None,
+        }
+    }
+
+    // The relay restarts the application after each transaction it delivers,
+    // from the state it committed to a temporary storage
+    fn restarting(capacity: u32) -> TendermintRelay {
+        let storage = Storage::temporary().unwrap();
+        let mut relay = TendermintRelay::new(capacity);
+        relay.app = RelayApp::new(capacity)
+            .with_storage(storage.clone())
+            .unwrap();
+        relay.storage = Some(storage);
+        relay
+    }
+
+    // Starts a new application from the storage, after the block of the transaction is committed
+    fn restart(&mut self, capacity: u32, app_hash: Vec<u8>) {
+        let storage = match &self.storage {
+            Some(storage) => storage.clone(),
+            None => return,
+        };
+        self.app = RelayApp::new(capacity).with_storage(storage).unwrap();
+        assert_eq!(
+            self.app.info(&RequestInfo::new()).get_last_block_app_hash(),
+            app_hash.as_slice()
+        );
+    }
+
+    // Checks the signed message, and delivers it if it passes. Returns the log of the delivery
+    fn broadcast(&mut self, envelope: &SignedClientMessage) -> Result<String, TransportError> {
+        let tx = serde_json::to_vec(envelope).unwrap();
+        let mut check = RequestCheckTx::new();
+        check.set_tx(tx.clone());
+        let response = self.app.check_tx(&check);
+        if response.get_code() != 0 {
+            return Err(TransportError::Rejected(response.get_log().to_string()));
+        }
+        let mut deliver = RequestDeliverTx::new();
+        deliver.set_tx(tx);
+        let response = self.app.deliver_tx(&deliver);
+        if response.get_code() != 0 {
+            return Err(TransportError::Rejected(response.get_log().to_string()));
+        }
+        // every transaction is committed in a block of its own
+        let app_hash = self.app.commit(&RequestCommit::new()).get_data().to_vec();
+        self.restart(self.peers.len() as u32, app_hash);
+        Ok(response.get_log().to_string())
+    }
+
+    fn query(&mut self, path: &str, data: String) -> Vec<u8> {
+        let mut request = RequestQuery::new();
+        request.set_path(path.to_string());
+        request.set_data(data.into_bytes());
+        self.app.query(&request).get_value().to_vec()
+    }
+
+    // The peer learns the identities and encryption keys of the peers registered so far
+    fn query_peers(&mut self, index: usize) {
+        let mut request = RequestQuery::new();
+        request.set_path(QueryPath::Peers(DEFAULT_SESSION_ID).to_string());
+        let reply: PeersReply =
+            serde_json::from_slice(self.app.query(&request).get_value()).unwrap();
+        for (peer_id, registration) in reply.registrations.iter() {
+            self.peers[index]
+                .identities
+                .add_peer(*peer_id, registration)
+                .expect("Invalid registration");
+        }
+    }
+
+    // The stored messages of a round the application replied with, as the peer reads them
+    fn deliveries(
+        &mut self,
+        to: PeerIdentifier,
+        round: u32,
+        messages: BTreeMap<PeerIdentifier, SignedClientMessage>,
+    ) -> Vec<Delivery> {
+        let index = self.indexes[&to];
+        if messages.keys().any(|from| {
+            let identities = &self.peers[index].identities;
+            identities.get(from).is_none() || identities.encryption_key(from).is_none()
+        }) {
+            self.query_peers(index);
+        }
+        let peer = &mut self.peers[index];
+        messages
+            .values()
+            .map(|envelope| {
+                let relay_msg = peer
+                    .identities
+                    .verify_round(envelope, DEFAULT_SESSION_ID, round)
+                    .expect("Stored message not signed by its sender");
+                let from = relay_msg.peer_number;
+                let private_payload = relay_msg.ciphertext_for(to).map(|ciphertext| {
+                    let sender_key = peer.identities.encryption_key(&from).unwrap();
+                    peer.encryption
+                        .decrypt(sender_key, from, to, ciphertext)
+                        .expect("Unable to decrypt private payload")
+                });
+                Delivery {
+                    to,
+                    round,
+                    message: RoundMessage {
+                        from,
+                        payload: relay_msg.message,
+                        private_payload,
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+impl SimulatedRelay for TendermintRelay {
+    fn register(
+        &mut self,
+        index: usize,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> Result<PeerIdentifier, TransportError> {
+        self.protocol_id = protocol_id;
+        let peer = &self.peers[index];
+        let mut msg = ClientMessage::new();
+        msg.set_register(
+            protocol_id,
+            capacity,
+            -1,
+            Some(peer.encryption.public_key()),
+        );
+        let envelope = peer.identity.sign(&msg);
+        let log = self.broadcast(&envelope)?;
+        let server_response: ServerMessage = serde_json::from_str(&log).unwrap();
+        match server_response.response {
+            Some(ServerResponse::Register(peer_id)) => {
+                self.indexes.insert(peer_id, index);
+                Ok(peer_id)
+            }
+            other => Err(TransportError::Rejected(format!("{:?}", other))),
+        }
+    }
+
+    fn submit(
+        &mut self,
+        from: PeerIdentifier,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> Result<Option<Vec<Delivery>>, TransportError> {
+        let index = self.indexes[&from];
+        if private_items
+            .keys()
+            .any(|to| self.peers[index].identities.encryption_key(to).is_none())
+        {
+            self.query_peers(index);
+        }
+        let peer = &self.peers[index];
+        let mut relay_message = RelayMessage::new(from, self.protocol_id);
+        relay_message.set_message_params(vec![0], payload);
+        relay_message.set_round(round);
+        for (recipient, private_payload) in private_items.iter() {
+            let recipient_key = peer.identities.encryption_key(recipient).unwrap();
+            let ciphertext =
+                peer.encryption
+                    .encrypt(recipient_key, from, *recipient, private_payload);
+            relay_message.ciphertexts.insert(*recipient, ciphertext);
+        }
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(relay_message);
+        let envelope = peer.identity.sign(&msg);
+        let log = self.broadcast(&envelope)?;
+        let messages = serde_json::from_str(&log).unwrap_or_default();
+        Ok(Some(self.deliveries(from, round, messages)))
+    }
+
+    fn query(
+        &mut self,
+        to: PeerIdentifier,
+        round: u32,
+        missing: Vec<PeerIdentifier>,
+    ) -> Result<Vec<Delivery>, TransportError> {
+        let mut request = MissingMessagesRequest {
+            round,
+            missing_clients: missing,
+            ..Default::default()
+        };
+        let mut deliveries = Vec::new();
+        loop {
+            let value = self.query("", serde_json::to_string(&request).unwrap());
+            let reply: MissingMessagesReply = serde_json::from_slice(&value).unwrap_or_default();
+            deliveries.extend(self.deliveries(to, round, reply.missing_messages));
+            match reply.next_cursor {
+                Some(cursor) => request.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        Ok(deliveries)
+    }
+}
+
+fn run_keygen(capacity: u32, conditions: NetworkConditions, seed: u64) -> Vec<PeerKeys> {
+    let data_managers = (0..capacity)
+        .map(|_| {
+            let keys = PeerKeys::new(Box::new(MemoryKeyStore::new()), None);
+            ProtocolDataManager::new(capacity, Vec::new(), keys)
+        })
+        .collect();
+    let mut sim: Simulation<eddsa_peer_kg::EddsaPeer, _> = Simulation::new(
+        TendermintRelay::new(capacity),
+        conditions,
+        seed,
+        data_managers,
+    );
+    let report = sim.run(1).expect("Key generation failed");
+    assert_eq!(report.rounds, 1);
+    sim.into_peers().into_iter().map(|peer| peer.keys).collect()
+}
+
+fn run_sign(keys: Vec<PeerKeys>, message: &[u8], conditions: NetworkConditions, seed: u64) {
+    let relay = TendermintRelay::new(keys.len() as u32);
+    run_sign_through(relay, keys, message, conditions, seed);
+}
+
+fn run_sign_through(
+    relay: TendermintRelay,
+    keys: Vec<PeerKeys>,
+    message: &[u8],
+    conditions: NetworkConditions,
+    seed: u64,
+) {
+    let capacity = keys.len() as u32;
+    let data_managers = keys
+        .into_iter()
+        .map(|keys| ProtocolDataManager::new(capacity, message.to_vec(), keys))
+        .collect();
+    let mut sim: Simulation<eddsa_peer_sign::EddsaPeer, _> =
+        Simulation::new(relay, conditions, seed, data_managers);
+    let report = sim.run(1).expect("Signing failed");
+    assert_eq!(report.rounds, 4);
+    for mut peer in sim.into_peers() {
+        assert!(peer.is_done());
+        assert_eq!(peer.finalize(), Ok(()));
+    }
+}
+
+#[test]
+fn test_keygen_and_sign() {
+    let keys = run_keygen(3, NetworkConditions::reliable(), 1);
+    run_sign(keys, b"simulated", NetworkConditions::reliable(), 2);
+}
+
+#[test]
+fn test_sign_survives_restarts() {
+    // the application resumes each time from the state it committed
+    let keys = run_keygen(3, NetworkConditions::reliable(), 1);
+    let relay = TendermintRelay::restarting(3);
+    run_sign_through(relay, keys, b"restarted", NetworkConditions::reliable(), 2);
+}
+
+#[test]
+fn test_keygen_and_sign_unreliable_network() {
+    // the peers query for the messages they are missing, so lost messages are recovered
+    let conditions = NetworkConditions {
+        reorder: true,
+        max_delay: 10,
+        drop_rate: 0.3,
+    };
+    for seed in 0..3 {
+        let keys = run_keygen(4, conditions.clone(), seed);
+        run_sign(keys, b"unreliable", conditions.clone(), seed);
+    }
+}
+
+// Signs with the first of the peers misbehaving as the fault says in the given round.
+// Returns the identifier of the adversary, and the aborts of the honest peers
+// Returns the adversary, the aborts of the honest peers,
+// and whether the evidence of each abort proves it to all the other honest peers
+fn run_sign_with_fault(
+    keys: Vec<PeerKeys>,
+    round: u32,
+    fault: Fault,
+) -> (
+    PeerIdentifier,
+    BTreeMap<PeerIdentifier, ProtocolAbort>,
+    bool,
+) {
+    let capacity = keys.len() as u32;
+    let mut data_managers: Vec<ProtocolDataManager<Adversary<eddsa_peer_sign::EddsaPeer>>> = keys
+        .into_iter()
+        .map(|keys| ProtocolDataManager::new(capacity, b"byzantine".to_vec(), keys))
+        .collect();
+    data_managers[0].data_holder.set_fault(round, fault);
+    let mut sim = Simulation::new(
+        TendermintRelay::new(capacity),
+        NetworkConditions::reliable(),
+        3,
+        data_managers,
+    );
+    let mut report = sim.run(1).expect("Signing failed");
+    let peers = sim.into_peers();
+    let adversary = peers[0].peer_id();
+    report.aborts.remove(&adversary);
+    let proven = peers[1..].iter().all(|accuser| match accuser.abort() {
+        Some(abort) => {
+            let evidence = accuser.abort_evidence();
+            peers[1..]
+                .iter()
+                .all(|peer| peer.verify_abort(&abort, &evidence))
+        }
+        None => true,
+    });
+    (adversary, report.aborts, proven)
+}
+
+// The commitment and R of another ephemeral key than the one of the peer
+fn other_commitment(_payload: &MessagePayload) -> MessagePayload {
+    let (_, commitment, _) = Signature::create_ephemeral_key_and_commit(&KeyPair::create(), b"");
+    EddsaMessage::Commitment(serde_json::to_string(&commitment).unwrap()).to_payload()
+}
+
+fn other_r(_payload: &MessagePayload) -> MessagePayload {
+    let (_, _, r) = Signature::create_ephemeral_key_and_commit(&KeyPair::create(), b"");
+    EddsaMessage::RMessage(serde_json::to_string(&r).unwrap()).to_payload()
+}
+
+#[test]
+fn test_sign_blames_r_not_matching_commitment() {
+    let keys = run_keygen(3, NetworkConditions::reliable(), 1);
+    let (adversary, aborts, proven) = run_sign_with_fault(keys, 2, Fault::Mutate(other_r));
+    assert_eq!(aborts.len(), 2);
+    for abort in aborts.values() {
+        assert_eq!(*abort, ProtocolAbort::InvalidCommitment(adversary));
+    }
+    // the commitment and R of the adversary prove it
+    assert!(proven);
+}
+
+#[test]
+fn test_sign_blames_equivocation() {
+    // the adversary sends another commitment along with its R
+    let keys = run_keygen(3, NetworkConditions::reliable(), 1);
+    let (adversary, aborts, proven) =
+        run_sign_with_fault(keys, 2, Fault::Equivocate(other_commitment));
+    assert_eq!(aborts.len(), 2);
+    for abort in aborts.values() {
+        assert_eq!(*abort, ProtocolAbort::Equivocation(adversary));
+    }
+    assert!(proven);
+}
+
+#[test]
+fn test_sign_blames_unexpected_and_malformed_messages() {
+    let keys = run_keygen(3, NetworkConditions::reliable(), 1);
+    let signature = Fault::Mutate(|_| EddsaMessage::Signature(String::new()).to_payload());
+    let (adversary, aborts, proven) = run_sign_with_fault(keys, 1, signature);
+    assert_eq!(aborts.len(), 2);
+    for abort in aborts.values() {
+        assert_eq!(*abort, ProtocolAbort::UnexpectedMessage(adversary));
+    }
+    // a signature is a valid message, it is only sent in the wrong step
+    assert!(!proven);
+
+    let keys = run_keygen(3, NetworkConditions::reliable(), 2);
+    let garbage = Fault::Mutate(|_| String::from("garbage"));
+    let (adversary, aborts, proven) = run_sign_with_fault(keys, 1, garbage);
+    assert_eq!(aborts.len(), 2);
+    for abort in aborts.values() {
+        assert_eq!(*abort, ProtocolAbort::MalformedMessage(adversary));
+    }
+    assert!(proven);
+}
+
+#[test]
+fn test_sign_blames_withheld_message() {
+    let keys = run_keygen(3, NetworkConditions::reliable(), 1);
+    let (adversary, aborts, _) = run_sign_with_fault(keys, 3, Fault::Withhold);
+    assert_eq!(aborts.len(), 2);
+    for abort in aborts.values() {
+        assert_eq!(*abort, ProtocolAbort::Unresponsive(adversary));
+    }
+}
+
+#[test]
+fn test_sign_tolerates_replayed_message() {
+    // a message sent again is taken as a resend, the adversary is only late
+    let keys = run_keygen(3, NetworkConditions::reliable(), 1);
+    let (_, aborts, _) = run_sign_with_fault(keys, 2, Fault::Replay);
+    assert!(aborts.is_empty());
+}
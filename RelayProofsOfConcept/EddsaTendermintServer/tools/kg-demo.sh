@@ -1,7 +1,9 @@
 echo "$0: MP-EDDSA"
+# Passphrase the key stores of the clients are encrypted with
+export MMPC_KEY_PASSPHRASE=${MMPC_KEY_PASSPHRASE:-demo}
 #clean
 
-rm keys*
+rm -r key-store*
 rm log-kg*.log
 rm log-error*.log
 
@@ -4,7 +4,8 @@
 use std::env;
 use std::net::SocketAddr;
 
-use std::sync::Arc;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
 use tokio;
 use tokio::codec::Framed;
 use tokio::net::TcpStream;
@@ -12,9 +13,11 @@ use tokio::net::TcpStream;
 use futures::sync::mpsc;
 use futures::{Future, Sink, Stream};
 
+use relay_server_common::encryption::EncryptionKeyPair;
+use relay_server_common::identity::{Identity, PeerIdentities, SignedClientMessage};
 use relay_server_common::{
-    ClientMessage, ClientToServerCodec, PeerIdentifier, ProtocolIdentifier, RelayMessage,
-    ServerMessage, ServerMessageType, ServerResponse,
+    Challenge, ClientMessage, ClientToServerCodec, PeerIdentifier, ProtocolIdentifier,
+    RelayMessage, ServerMessage, ServerMessageType, ServerResponse, SessionIdentifier,
 };
 
 // ClientSession holds session data
@@ -23,6 +26,7 @@ struct ProtocolSession {
     pub registered: bool,
     pub peer_id: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
+    pub session_id: SessionIdentifier,
     pub next_message: Option<ClientMessage>,
 }
 
@@ -32,20 +36,29 @@ impl ProtocolSession {
             registered: false,
             peer_id: 0,
             protocol_id: 0,
+            session_id: 0,
             next_message: None,
         }
     }
 }
 
-#[derive(Default, Debug, Clone)]
 struct Client {
-    pub session: ProtocolSession,
+    pub session: Mutex<ProtocolSession>,
+    // identity every message to the server is signed with
+    pub identity: Identity,
+    // key pair the other peer encrypts its message to this peer with
+    pub encryption: EncryptionKeyPair,
+    // identities and encryption keys of the peers, received once the session is full
+    pub identities: Mutex<PeerIdentities>,
 }
 
 impl Client {
     pub fn new() -> Client {
         Client {
-            session: ProtocolSession::new(),
+            session: Mutex::new(ProtocolSession::new()),
+            identity: Identity::generate(),
+            encryption: EncryptionKeyPair::generate(),
+            identities: Mutex::new(PeerIdentities::new()),
         }
     }
 }
@@ -61,14 +74,17 @@ impl Client {
         &self,
         msg: ServerMessage,
         // A sender to pass messages to be written back to the server
-        tx: mpsc::Sender<ClientMessage>,
+        tx: mpsc::Sender<SignedClientMessage>,
     ) -> Box<dyn Future<Item = (), Error = E> + Send> {
         let response = self.handle_server_response(&msg).unwrap();
         println!("Returning {:?}", response);
         if response.is_empty() {
             Box::new(futures::future::ok(()))
         } else {
-            Box::new(tx.clone().send(response.clone()).then(|_| Ok(())))
+            // each peer sends a single greeting, in the first round
+            let session_id = self.session.lock().unwrap().session_id;
+            let envelope = self.identity.sign(&response, session_id, 0);
+            Box::new(tx.clone().send(envelope).then(|_| Ok(())))
         }
     }
 
@@ -83,22 +99,33 @@ impl Client {
                 // we expect to receive a register response here
                 let server_response = msg.response.clone().unwrap();
                 match server_response {
-                    ServerResponse::Register(peer_id) => {
+                    ServerResponse::Challenge(challenge) => {
+                        return Ok(self.generate_register_message(challenge));
+                    }
+                    ServerResponse::Register(peer_id, _token) => {
                         println!("Peer identifier: {}", peer_id);
-                        // create a mock relay message
-                        let mut client_message = ClientMessage::new();
-                        let mut relay_message =
-                            RelayMessage::new(peer_id, self.session.protocol_id);
-                        let mut to: Vec<u32> = Vec::new();
-                        if peer_id == 2 {
-                            to.push(1);
-                        } else {
-                            to.push(2);
+                        let mut session = self.session.lock().unwrap();
+                        session.registered = true;
+                        session.peer_id = peer_id;
+                        return Ok(ClientMessage::new());
+                    }
+                    ServerResponse::Peers(registrations) => {
+                        let mut identities = self.identities.lock().unwrap();
+                        for (peer_id, registration) in registrations.iter() {
+                            identities
+                                .add_peer(*peer_id, registration)
+                                .expect("Invalid peer registration");
                         }
-
-                        relay_message.set_message_params(to, format!("Hi from {}", peer_id));
-                        client_message.relay_message = Some(relay_message.clone());
-                        return Ok(client_message);
+                        // the first peer starts, the second replies once it gets the message
+                        if self.session.lock().unwrap().peer_id == 1 {
+                            return Ok(self.generate_greeting(&identities));
+                        }
+                        return Ok(ClientMessage::new());
+                    }
+                    ServerResponse::Session(session_id) => {
+                        println!("Session identifier: {}", session_id);
+                        self.session.lock().unwrap().session_id = session_id;
+                        return Ok(ClientMessage::new());
                     }
                     _ => panic!("failed to register"),
                 }
@@ -106,6 +133,29 @@ impl Client {
             ServerMessageType::RelayMessage => {
                 println!("Got new relay message");
                 println!("{:?}", msg.relay_message.clone().unwrap());
+                let envelope = msg
+                    .signed_message
+                    .as_ref()
+                    .ok_or("Unsigned relay message")?;
+                let identities = self.identities.lock().unwrap();
+                let session_id = self.session.lock().unwrap().session_id;
+                let relay_message = identities
+                    .verify(envelope, session_id)
+                    .map_err(|_| "Invalid relay message signature")?;
+                let peer_id = self.session.lock().unwrap().peer_id;
+                let from = relay_message.peer_number;
+                if let (Some(payload), Some(sender_key)) = (
+                    relay_message.ciphertext_for(peer_id),
+                    identities.encryption_key(&from),
+                ) {
+                    match self.encryption.decrypt(sender_key, from, peer_id, payload) {
+                        Ok(message) => println!("Decrypted message: {}", message),
+                        Err(err) => println!("{}", err),
+                    }
+                }
+                if peer_id == 2 {
+                    return Ok(self.generate_greeting(&identities));
+                }
                 //Ok(MessageProcessResult::NoMessage)
                 Ok(ClientMessage::new())
             }
@@ -118,9 +168,36 @@ impl Client {
         }
     }
 
-    pub fn generate_register_message(&self) -> ClientMessage {
+    // create a mock relay message, encrypted to the other peer
+    fn generate_greeting(&self, identities: &PeerIdentities) -> ClientMessage {
+        let session = self.session.lock().unwrap();
+        let peer_id = session.peer_id;
+        let to = if peer_id == 2 { 1 } else { 2 };
+        let encryption_key = identities
+            .encryption_key(&to)
+            .expect("Peer registered without an encryption key");
+        let mut ciphertexts = BTreeMap::new();
+        ciphertexts.insert(
+            to,
+            self.encryption
+                .encrypt(encryption_key, peer_id, to, &format!("Hi from {}", peer_id)),
+        );
+        let mut client_message = ClientMessage::new();
+        let mut relay_message = RelayMessage::new(peer_id, session.protocol_id);
+        relay_message.set_encrypted_message_params(ciphertexts);
+        client_message.relay_message = Some(relay_message);
+        client_message
+    }
+
+    pub fn generate_register_message(&self, challenge: Challenge) -> ClientMessage {
         let mut msg = ClientMessage::new();
-        msg.register(self.session.protocol_id.clone(), 2);
+        msg.register(
+            self.session.lock().unwrap().protocol_id.clone(),
+            2,
+            None,
+            challenge,
+            Some(self.encryption.public_key()),
+        );
         msg
     }
 }
@@ -139,20 +216,10 @@ fn main() {
 
     let session: Arc<Client> = Arc::new(Client::new());
 
-    let client = Arc::clone(&session);
-    let handshake = tcp.and_then(move |stream| {
-        let handshake_io = Framed::new(stream, ClientToServerCodec::new(false));
-        let msg = client.generate_register_message();
-        handshake_io
-            .send(msg)
-            .map(|handshake_io| handshake_io.into_inner())
-            .map_err(|e| e.into())
-    });
-
-    let run_client = handshake
+    // the client registers once the server sends it the challenge of the connection
+    let run_client = tcp
         .and_then(move |socket| {
             let client = Arc::clone(&session);
-            let _msg = client.generate_register_message();
 
             let (to_server, from_server) =
                 Framed::new(socket, ClientToServerCodec::new(false)).split();
@@ -1,16 +1,17 @@
 
 echo "$0: MP-EDDSA"
 #clean
+export MMPC_KEY_PASSPHRASE=${MMPC_KEY_PASSPHRASE:-demo}
 
 
-rm keys??
+rm -r keys?
 
 kill -9 $(lsof -t -i:8080)
 
 n=2
 
 echo "keygen part"
-cargo run --package relay-server --bin server -- -P $n &
+cargo run --package relay-server --bin server &
 sleep 2
 for i in $(seq 1 $n);
 do
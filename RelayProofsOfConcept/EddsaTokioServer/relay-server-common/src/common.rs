@@ -1,55 +1,260 @@
 /// common constants and structures for relay communication
 use super::MessagePayload;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 // Error responses
-pub static CANT_REGISTER_RESPONSE: &str = "Can't register peer";
-pub static RELAY_ERROR_RESPONSE: &str = "Can't relay message";
-pub static STATE_NOT_INITIALIZED: &str = "Relay sessions state is not initialized";
-pub static RELAY_MESSAGE_DELIMITER: &str = ":::";
-pub static NOT_YOUR_TURN: &str = "Not this peers turn";
-pub static NOT_A_PEER: &str = "Not a peer";
-
-/// eddsa constants
-pub static PK_MESSAGE_PREFIX: &str = "PUBLIC_KEY";
-pub static COMMITMENT_MESSAGE_PREFIX: &str = "COMMITMENT";
-pub static R_KEY_MESSAGE_PREFIX: &str = "R_KEY";
-pub static R_KEY_MESSAGE_DELIMITER: &str = "@";
-pub static SIGNATURE_MESSAGE_PREFIX: &str = "SIGNATURE";
-
-pub static EMPTY_MESSAGE_PAYLOAD: &str = "";
-
-pub fn generate_pk_message_payload(pk: &String) -> MessagePayload {
-    return format!(
-        "{}{}{}",
-        PK_MESSAGE_PREFIX,
-        RELAY_MESSAGE_DELIMITER,
-        pk.clone()
-    );
-}
-
-pub fn generate_commitment_message_payload(cmtnmt: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = COMMITMENT_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = cmtnmt.clone()
-    );
-}
-
-#[allow(non_snake_case)]
-pub fn generate_R_message_payload(r: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = R_KEY_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = r.clone()
-    );
-}
-
-pub fn generate_signature_message_payload(sig: &String) -> MessagePayload {
-    return format!(
-        "{prefix}{delimiter}{message}",
-        prefix = SIGNATURE_MESSAGE_PREFIX,
-        delimiter = RELAY_MESSAGE_DELIMITER,
-        message = sig.clone()
-    );
+/// Errors returned by the relay server to a client.
+/// Each error has a stable numeric code, which is also used as the
+/// response code of rejected transactions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelayError {
+    // The peer could not be registered
+    CantRegister,
+
+    // The sender is not a registered peer of the session
+    NotAPeer,
+
+    // The sender tried to relay a message out of its turn
+    NotYourTurn,
+
+    // Not all peers registered to the session yet
+    StateNotInitialized,
+
+    // The requested protocol is not supported
+    InvalidProtocol,
+
+    // The requested capacity differs from the capacity of the session
+    CapacityMismatch,
+
+    // All the peers of the session already registered
+    SessionFull,
+
+    // The session was aborted by one of its peers
+    SessionAborted,
+
+    // The message could not be parsed
+    MalformedMessage,
+
+    // The requested session does not exist
+    UnknownSession,
+
+    // The peer can not resume the session with the given token
+    InvalidToken,
+
+    // The message is not signed by the identity of the peer that sent it
+    InvalidSignature,
+
+    // The identity was banned from the relay after it was blamed for misbehaving
+    Banned,
+
+    // The peer already sent a different message in the current round
+    AlreadySent,
+
+    // The requested protocol is not declared in the protocols file
+    UnknownProtocol,
+
+    // The payload is larger than the protocol allows
+    PayloadTooLarge,
+
+    // The message is not sent to the peers the protocol requires
+    InvalidRecipients,
+
+    // The protocol already ran all of its rounds
+    TooManyRounds,
+
+    // The message is signed for another round than the current round of the session
+    WrongRound,
+}
+
+impl RelayError {
+    /// Numeric code of the error, never 0 which stands for success
+    pub fn code(&self) -> u32 {
+        match self {
+            RelayError::CantRegister => 1,
+            RelayError::NotAPeer => 2,
+            RelayError::NotYourTurn => 3,
+            RelayError::StateNotInitialized => 4,
+            RelayError::InvalidProtocol => 5,
+            RelayError::CapacityMismatch => 6,
+            RelayError::SessionFull => 7,
+            RelayError::SessionAborted => 8,
+            RelayError::MalformedMessage => 9,
+            RelayError::UnknownSession => 10,
+            RelayError::InvalidToken => 11,
+            RelayError::InvalidSignature => 12,
+            RelayError::Banned => 13,
+            RelayError::AlreadySent => 14,
+            RelayError::UnknownProtocol => 15,
+            RelayError::PayloadTooLarge => 16,
+            RelayError::InvalidRecipients => 17,
+            RelayError::TooManyRounds => 18,
+            RelayError::WrongRound => 19,
+        }
+    }
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            RelayError::CantRegister => "Can't register peer",
+            RelayError::NotAPeer => "Not a peer",
+            RelayError::NotYourTurn => "Not this peers turn",
+            RelayError::StateNotInitialized => "Relay sessions state is not initialized",
+            RelayError::InvalidProtocol => "Invalid protocol",
+            RelayError::CapacityMismatch => "Capacity does not match the relay session",
+            RelayError::SessionFull => "Relay session is full",
+            RelayError::SessionAborted => "Relay session was aborted",
+            RelayError::MalformedMessage => "Malformed message",
+            RelayError::UnknownSession => "Unknown relay session",
+            RelayError::InvalidToken => "Invalid resumption token",
+            RelayError::InvalidSignature => "Invalid message signature",
+            RelayError::Banned => "Identity is banned from the relay",
+            RelayError::AlreadySent => "Peer already sent its message of the round",
+            RelayError::UnknownProtocol => "Unknown protocol",
+            RelayError::PayloadTooLarge => "Payload is too large for the protocol",
+            RelayError::InvalidRecipients => "Message must be sent to all the other peers",
+            RelayError::TooManyRounds => "Protocol already ran all of its rounds",
+            RelayError::WrongRound => "Message is not for the current round",
+        };
+        write!(f, "{} (code {})", description, self.code())
+    }
+}
+
+/// eddsa messages
+/// Version of the eddsa messages format, bumped on every incompatible change
+pub const EDDSA_MESSAGE_VERSION: u32 = 1;
+
+/// Messages sent by peers of the multi party eddsa protocols.
+/// The message is carried as the payload of a RelayMessage
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "eddsa", content = "value")]
+pub enum EddsaMessage {
+    PublicKey(String),
+    Commitment(String),
+    RMessage(String),
+    Signature(String),
+}
+
+/// A protocol message along with the version of the format it was written in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionedMessage<T> {
+    pub version: u32,
+    pub message: T,
+}
+
+/// Reasons a relayed payload could not be decoded into a protocol message
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageDecodeError {
+    // The payload is not a message of the expected protocol
+    Malformed(String),
+
+    // The message was written in a version this peer does not support
+    UnsupportedVersion(u32),
+
+    // The payload could not be decrypted by this peer
+    DecryptionFailed,
+}
+
+impl fmt::Display for MessageDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageDecodeError::Malformed(err) => write!(f, "Malformed message: {}", err),
+            MessageDecodeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported message version {}", version)
+            }
+            MessageDecodeError::DecryptionFailed => write!(f, "Failed to decrypt message"),
+        }
+    }
+}
+
+impl EddsaMessage {
+    /// Encode the message as a relay message payload
+    pub fn to_payload(&self) -> MessagePayload {
+        let versioned = VersionedMessage {
+            version: EDDSA_MESSAGE_VERSION,
+            message: self.clone(),
+        };
+        serde_json::to_string(&versioned).expect("Failed to serialize eddsa message")
+    }
+
+    /// Decode a relay message payload into an eddsa message
+    pub fn from_payload(payload: &str) -> Result<EddsaMessage, MessageDecodeError> {
+        let versioned: VersionedMessage<serde_json::Value> = serde_json::from_str(payload)
+            .map_err(|err| MessageDecodeError::Malformed(err.to_string()))?;
+        if versioned.version != EDDSA_MESSAGE_VERSION {
+            return Err(MessageDecodeError::UnsupportedVersion(versioned.version));
+        }
+        serde_json::from_value(versioned.message)
+            .map_err(|err| MessageDecodeError::Malformed(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EddsaMessage, MessageDecodeError, RelayError};
+
+    #[test]
+    fn test_eddsa_message_round_trip() {
+        let msg = EddsaMessage::Commitment(String::from("{\"commitment\":\"1234\"}"));
+        let payload = msg.to_payload();
+        assert_eq!(EddsaMessage::from_payload(&payload), Ok(msg));
+    }
+
+    #[test]
+    fn test_eddsa_message_malformed() {
+        match EddsaMessage::from_payload("PUBLIC_KEY:::1234") {
+            Err(MessageDecodeError::Malformed(_)) => {}
+            res => panic!("Expected malformed message, got {:?}", res),
+        }
+        // A message of a different protocol
+        let payload = r#"{"version":1,"message":{"ecdsa":"PublicKey","value":"1234"}}"#;
+        match EddsaMessage::from_payload(payload) {
+            Err(MessageDecodeError::Malformed(_)) => {}
+            res => panic!("Expected malformed message, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_eddsa_message_unsupported_version() {
+        let payload = r#"{"version":0,"message":{"eddsa":"PublicKey","value":"1234"}}"#;
+        assert_eq!(
+            EddsaMessage::from_payload(payload),
+            Err(MessageDecodeError::UnsupportedVersion(0))
+        );
+    }
+
+    #[test]
+    fn test_relay_error_codes() {
+        let errors = vec![
+            RelayError::CantRegister,
+            RelayError::NotAPeer,
+            RelayError::NotYourTurn,
+            RelayError::StateNotInitialized,
+            RelayError::InvalidProtocol,
+            RelayError::CapacityMismatch,
+            RelayError::SessionFull,
+            RelayError::SessionAborted,
+            RelayError::MalformedMessage,
+            RelayError::UnknownSession,
+            RelayError::InvalidToken,
+            RelayError::InvalidSignature,
+            RelayError::Banned,
+            RelayError::AlreadySent,
+            RelayError::UnknownProtocol,
+            RelayError::PayloadTooLarge,
+            RelayError::InvalidRecipients,
+            RelayError::TooManyRounds,
+            RelayError::WrongRound,
+        ];
+        let mut codes: Vec<u32> = errors.iter().map(|err| err.code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0));
+
+        for err in errors {
+            let json = serde_json::to_string(&err).unwrap();
+            assert_eq!(serde_json::from_str::<RelayError>(&json).unwrap(), err);
+        }
+    }
 }
@@ -0,0 +1,372 @@
+/// Long term identities of peers, and the signed envelopes client messages are sent in
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::common::RelayError;
+use crate::encryption::EncryptionKey;
+use crate::{ClientMessage, PeerIdentifier, RelayMessage, SessionIdentifier};
+
+/// Public key identifying a peer, bound to its peer number on registration
+pub type IdentityKey = [u8; 32];
+
+/// The long term identity key pair of a peer, used to sign every message it sends
+pub struct Identity {
+    keypair: Keypair,
+}
+
+impl Identity {
+    /// Generates a new random identity
+    pub fn generate() -> Identity {
+        Identity {
+            keypair: Keypair::generate(&mut OsRng),
+        }
+    }
+
+    /// Restores an identity from the bytes of its key pair
+    pub fn from_bytes(bytes: &[u8]) -> Option<Identity> {
+        Keypair::from_bytes(bytes)
+            .ok()
+            .map(|keypair| Identity { keypair })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.keypair.to_bytes().to_vec()
+    }
+
+    /// Loads the identity stored in the given file,
+    /// or generates a new identity and stores it there if the file does not exist.
+    /// The file holds the secret key, so only its owner can read it
+    pub fn load_or_generate(path: &Path) -> io::Result<Identity> {
+        if path.exists() {
+            let bytes = fs::read(path)?;
+            Identity::from_bytes(&bytes)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid identity file"))
+        } else {
+            let identity = Identity::generate();
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options.open(path)?.write_all(&identity.to_bytes())?;
+            Ok(identity)
+        }
+    }
+
+    pub fn key(&self) -> IdentityKey {
+        self.keypair.public.to_bytes()
+    }
+
+    /// Wraps the message in an envelope signed by this identity, for the session it is sent in
+    /// and the round of the sender, the number of relay messages it sent before in the session.
+    /// A register message without a session is signed for session 0
+    pub fn sign(
+        &self,
+        msg: &ClientMessage,
+        session_id: SessionIdentifier,
+        round: u32,
+    ) -> SignedClientMessage {
+        let message = serde_json::to_string(msg).expect("Failed to serialize client message");
+        let signature = self
+            .keypair
+            .sign(&signed_bytes(session_id, round, &message));
+        SignedClientMessage {
+            identity: self.key(),
+            session_id,
+            round,
+            message,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+}
+
+// The session a register or resume message names
+fn message_session(msg: &ClientMessage) -> Option<SessionIdentifier> {
+    match (&msg.register, &msg.resume) {
+        (_, Some(resume)) => Some(resume.session_id),
+        (Some(register), None) => register.session_id,
+        (None, None) => None,
+    }
+}
+
+// The signature covers the session and the round along with the message,
+// so a signed message can not be replayed in another session or round
+fn signed_bytes(session_id: SessionIdentifier, round: u32, message: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(12 + message.len());
+    bytes.extend_from_slice(&session_id.to_be_bytes());
+    bytes.extend_from_slice(&round.to_be_bytes());
+    bytes.extend_from_slice(message.as_bytes());
+    bytes
+}
+
+/// A client message signed by the identity of the peer that sent it, for a session and round.
+/// The message is kept serialized, so the signature can be verified on the exact signed bytes
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct SignedClientMessage {
+    pub identity: IdentityKey,
+
+    pub session_id: SessionIdentifier,
+
+    pub round: u32,
+
+    pub message: String,
+
+    pub signature: Vec<u8>,
+}
+
+impl SignedClientMessage {
+    /// Verifies the signature of the envelope and returns the signed message.
+    /// A message naming a session must be signed for that session
+    pub fn open(&self) -> Result<ClientMessage, RelayError> {
+        let key =
+            PublicKey::from_bytes(&self.identity).map_err(|_| RelayError::InvalidSignature)?;
+        let signature =
+            Signature::try_from(&self.signature[..]).map_err(|_| RelayError::InvalidSignature)?;
+        key.verify(
+            &signed_bytes(self.session_id, self.round, &self.message),
+            &signature,
+        )
+        .map_err(|_| RelayError::InvalidSignature)?;
+        let msg: ClientMessage =
+            serde_json::from_str(&self.message).map_err(|_| RelayError::MalformedMessage)?;
+        match message_session(&msg) {
+            Some(session_id) if session_id != self.session_id => Err(RelayError::InvalidSignature),
+            _ => Ok(msg),
+        }
+    }
+}
+
+/// The identity keys of the other peers of a session, as known to a peer.
+/// Only messages of peers whose identity is pinned, from their registration or set beforehand,
+/// are accepted, so a relay can not forge messages of a peer
+#[derive(Default, Debug, Clone)]
+pub struct PeerIdentities {
+    keys: HashMap<PeerIdentifier, IdentityKey>,
+
+    encryption_keys: HashMap<PeerIdentifier, EncryptionKey>,
+}
+
+impl PeerIdentities {
+    pub fn new() -> PeerIdentities {
+        PeerIdentities {
+            keys: HashMap::new(),
+            encryption_keys: HashMap::new(),
+        }
+    }
+
+    /// Adds a peer from the register message it signed,
+    /// pinning its identity and the encryption key it registered with
+    pub fn add_peer(
+        &mut self,
+        peer_id: PeerIdentifier,
+        registration: &SignedClientMessage,
+    ) -> Result<(), RelayError> {
+        let register = registration
+            .open()?
+            .register
+            .ok_or(RelayError::MalformedMessage)?;
+        match self.keys.get(&peer_id) {
+            Some(key) if *key != registration.identity => return Err(RelayError::InvalidSignature),
+            _ => {}
+        }
+        self.keys.insert(peer_id, registration.identity);
+        if let Some(encryption_key) = register.encryption_key {
+            self.encryption_keys.insert(peer_id, encryption_key);
+        }
+        Ok(())
+    }
+
+    pub fn encryption_key(&self, peer_id: &PeerIdentifier) -> Option<&EncryptionKey> {
+        self.encryption_keys.get(peer_id)
+    }
+
+    /// Sets the identity key expected for the given peer
+    pub fn trust(&mut self, peer_id: PeerIdentifier, key: IdentityKey) {
+        self.keys.insert(peer_id, key);
+    }
+
+    pub fn get(&self, peer_id: &PeerIdentifier) -> Option<&IdentityKey> {
+        self.keys.get(peer_id)
+    }
+
+    /// Verifies a relayed envelope is signed for the session by the pinned identity
+    /// of the peer that sent it, and returns the relay message it holds
+    pub fn verify(
+        &self,
+        envelope: &SignedClientMessage,
+        session_id: SessionIdentifier,
+    ) -> Result<RelayMessage, RelayError> {
+        if envelope.session_id != session_id {
+            return Err(RelayError::InvalidSignature);
+        }
+        let relay_message = envelope
+            .open()?
+            .relay_message
+            .ok_or(RelayError::MalformedMessage)?;
+        match self.keys.get(&relay_message.peer_number) {
+            Some(key) if *key == envelope.identity => Ok(relay_message),
+            Some(_) => Err(RelayError::InvalidSignature),
+            None => Err(RelayError::NotAPeer),
+        }
+    }
+
+    /// Verifies a relayed envelope as with `verify`, and that it is signed for the given round
+    pub fn verify_round(
+        &self,
+        envelope: &SignedClientMessage,
+        session_id: SessionIdentifier,
+        round: u32,
+    ) -> Result<RelayMessage, RelayError> {
+        if envelope.round != round {
+            return Err(RelayError::WrongRound);
+        }
+        self.verify(envelope, session_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Identity, PeerIdentities};
+    use crate::common::RelayError;
+    use crate::encryption::EncryptionKeyPair;
+    use crate::{ClientMessage, RelayMessage};
+
+    fn relay_client_message(peer_id: u32) -> ClientMessage {
+        let mut relay_message = RelayMessage::new(peer_id, 1);
+        relay_message.set_message_params(vec![1, 2], "test");
+        let mut client_message = ClientMessage::new();
+        client_message.relay_message = Some(relay_message);
+        client_message
+    }
+
+    #[test]
+    fn test_signed_message_round_trip() {
+        let identity = Identity::generate();
+        let envelope = identity.sign(&relay_client_message(1), 0, 0);
+        assert_eq!(envelope.identity, identity.key());
+        let msg = envelope.open().expect("Failed to open envelope");
+        assert_eq!(msg.relay_message.unwrap().message, "test");
+
+        let restored = Identity::from_bytes(&identity.to_bytes()).unwrap();
+        assert_eq!(restored.key(), identity.key());
+    }
+
+    #[test]
+    fn test_signed_message_tampered() {
+        let identity = Identity::generate();
+        let mut envelope = identity.sign(&relay_client_message(1), 0, 0);
+        envelope.message = envelope.message.replace("test", "fake");
+        assert_eq!(envelope.open().err(), Some(RelayError::InvalidSignature));
+
+        // an envelope signed by another identity
+        let mut envelope = identity.sign(&relay_client_message(1), 0, 0);
+        envelope.identity = Identity::generate().key();
+        assert_eq!(envelope.open().err(), Some(RelayError::InvalidSignature));
+    }
+
+    #[test]
+    fn test_signed_message_bound_to_session_and_round() {
+        let identity = Identity::generate();
+        let envelope = identity.sign(&relay_client_message(1), 3, 2);
+        assert_eq!((envelope.session_id, envelope.round), (3, 2));
+        assert!(envelope.open().is_ok());
+
+        // the envelope can not be moved to another session or round
+        let mut replayed = envelope.clone();
+        replayed.session_id = 4;
+        assert_eq!(replayed.open().err(), Some(RelayError::InvalidSignature));
+        let mut replayed = envelope;
+        replayed.round = 1;
+        assert_eq!(replayed.open().err(), Some(RelayError::InvalidSignature));
+
+        // a message naming a session must be signed for it
+        let mut resume = ClientMessage::new();
+        resume.resume(3, 1, 7);
+        assert!(identity.sign(&resume, 3, 0).open().is_ok());
+        assert_eq!(
+            identity.sign(&resume, 4, 0).open().err(),
+            Some(RelayError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_peer_identities() {
+        let first = Identity::generate();
+        let second = Identity::generate();
+        let mut identities = PeerIdentities::new();
+
+        // a peer whose identity is not pinned is rejected
+        assert_eq!(
+            identities
+                .verify(&first.sign(&relay_client_message(1), 0, 0), 0)
+                .err(),
+            Some(RelayError::NotAPeer)
+        );
+        assert_eq!(identities.get(&1), None);
+
+        identities.trust(1, first.key());
+        let relay_message = identities
+            .verify(&first.sign(&relay_client_message(1), 0, 0), 0)
+            .unwrap();
+        assert_eq!(relay_message.peer_number, 1);
+
+        // another identity can not send as the first peer
+        assert_eq!(
+            identities
+                .verify(&second.sign(&relay_client_message(1), 0, 0), 0)
+                .err(),
+            Some(RelayError::InvalidSignature)
+        );
+
+        // the message must be signed for the session and round it is read in
+        let envelope = first.sign(&relay_client_message(1), 3, 2);
+        assert!(identities.verify_round(&envelope, 3, 2).is_ok());
+        assert_eq!(
+            identities.verify(&envelope, 0).err(),
+            Some(RelayError::InvalidSignature)
+        );
+        assert_eq!(
+            identities.verify_round(&envelope, 3, 1).err(),
+            Some(RelayError::WrongRound)
+        );
+    }
+
+    #[test]
+    fn test_add_peer() {
+        let identity = Identity::generate();
+        let encryption = EncryptionKeyPair::generate();
+        let mut register = ClientMessage::new();
+        register.register(1, 2, None, 0, Some(encryption.public_key()));
+        let registration = identity.sign(&register, 0, 0);
+
+        let mut identities = PeerIdentities::new();
+        identities.add_peer(1, &registration).unwrap();
+        assert_eq!(identities.get(&1), Some(&identity.key()));
+        assert_eq!(
+            identities.encryption_key(&1),
+            Some(&encryption.public_key())
+        );
+
+        // a registration of another identity can not replace a pinned peer
+        let other = Identity::generate().sign(&register, 0, 0);
+        assert_eq!(
+            identities.add_peer(1, &other).err(),
+            Some(RelayError::InvalidSignature)
+        );
+        // only register messages carry encryption keys
+        assert_eq!(
+            identities
+                .add_peer(2, &identity.sign(&relay_client_message(2), 0, 0))
+                .err(),
+            Some(RelayError::MalformedMessage)
+        );
+    }
+}
@@ -1,12 +1,22 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::vec::Vec;
 use tokio_jsoncodec::Codec as JsonCodec;
 
 pub mod common;
+pub mod encryption;
+pub mod identity;
 pub mod protocol;
 
+use crate::common::RelayError;
+use crate::encryption::{EncryptedPayload, EncryptionKey};
+use crate::identity::SignedClientMessage;
+
 pub type ProtocolIdentifier = u32;
 pub type PeerIdentifier = u32;
+pub type SessionIdentifier = u64;
+pub type ResumptionToken = u64;
+pub type Challenge = u64;
 pub type MessagePayload = String;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +26,11 @@ pub struct RelayMessage {
     //pub round: u32,
     pub to: Vec<PeerIdentifier>,
     pub message: MessagePayload,
+
+    // Payloads encrypted by the sender to each of the recipients,
+    // only the recipient of a payload can decrypt it
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ciphertexts: BTreeMap<PeerIdentifier, EncryptedPayload>,
 }
 
 impl RelayMessage {
@@ -25,6 +40,7 @@ impl RelayMessage {
             protocol_id,
             to: Vec::new(),
             message: String::from(""),
+            ciphertexts: BTreeMap::new(),
         }
     }
 
@@ -33,24 +49,120 @@ impl RelayMessage {
         self.to = to;
         self.message = message.into();
     }
+
+    /// Sets a payload encrypted to each of the recipients instead of a plaintext message
+    pub fn set_encrypted_message_params(
+        &mut self,
+        ciphertexts: BTreeMap<PeerIdentifier, EncryptedPayload>,
+    ) {
+        self.to = ciphertexts.keys().cloned().collect();
+        self.message = String::from("");
+        self.ciphertexts = ciphertexts;
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        !self.ciphertexts.is_empty()
+    }
+
+    /// The payload encrypted to the given recipient
+    pub fn ciphertext_for(&self, peer_id: PeerIdentifier) -> Option<&EncryptedPayload> {
+        self.ciphertexts.get(&peer_id)
+    }
+
+    /// Size in bytes of the largest payload a recipient of the message gets
+    pub fn payload_size(&self) -> usize {
+        self.ciphertexts
+            .values()
+            .map(|payload| payload.ciphertext.len())
+            .fold(self.message.len(), usize::max)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ServerResponse {
-    // Register response containing peer number
-    Register(PeerIdentifier),
+    // Challenge sent to a connection once it is accepted,
+    // the register message of the connection must carry it
+    Challenge(Challenge),
+
+    // Register response containing peer number,
+    // and the token the peer can use to resume the session if its connection drops
+    Register(PeerIdentifier, ResumptionToken),
+
+    // Resume response containing peer number,
+    // followed by the messages the peer missed while disconnected
+    Resumed(PeerIdentifier),
 
-    // Error message
-    ErrorResponse(String),
+    // The signed register messages of all the peers of the session by peer number,
+    // sent once the session is full so peers learn each others encryption keys
+    Peers(BTreeMap<PeerIdentifier, SignedClientMessage>),
+
+    // Identifier of the relay session the peer was registered to
+    Session(SessionIdentifier),
+
+    // Error response
+    ErrorResponse(RelayError),
 
     // No response
     NoResponse,
 }
 
+/// Why a peer aborted the protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AbortReason {
+    // The peer aborted without blaming another peer
+    Unspecified,
+
+    // The culprit sent a message that could not be parsed
+    MalformedMessage,
+
+    // The culprit sent a message that does not belong to the round it was sent in
+    UnexpectedMessage,
+
+    // The culprit sent two different messages for the same round
+    Equivocation,
+
+    // The R the culprit revealed does not match the commitment it sent
+    InvalidCommitment,
+
+    // The culprit claimed a key generation index it does not have
+    InvalidKeyIndex,
+
+    // The signature share of the culprit does not verify
+    InvalidSignatureShare,
+
+    // The secret share the culprit sent does not match the scheme it shared its key with
+    InvalidSecretShare,
+
+    // The culprit did not send its message of the round
+    Unresponsive,
+}
+
+impl Default for AbortReason {
+    fn default() -> AbortReason {
+        AbortReason::Unspecified
+    }
+}
+
 #[derive(Default, Clone, Debug, Deserialize, Serialize)]
 pub struct AbortMessage {
     pub peer_number: PeerIdentifier,
     pub protocol_id: ProtocolIdentifier,
+
+    #[serde(default)]
+    pub reason: AbortReason,
+
+    // The peer the abort blames for misbehaving
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub culprit: Option<PeerIdentifier>,
+
+    // Messages the culprit signed that prove it misbehaved as the reason says,
+    // such as the commitment and R that do not match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evidence: Vec<SignedClientMessage>,
+
+    // The peers that missed a deadline of the session, set when the relay aborts it on a timeout
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unresponsive: Vec<PeerIdentifier>,
 }
 
 impl AbortMessage {
@@ -58,6 +170,40 @@ impl AbortMessage {
         AbortMessage {
             peer_number,
             protocol_id,
+            ..Default::default()
+        }
+    }
+
+    /// An abort blaming the culprit for the reason, along with the messages it signed as evidence
+    pub fn blame(
+        peer_number: PeerIdentifier,
+        protocol_id: ProtocolIdentifier,
+        reason: AbortReason,
+        culprit: PeerIdentifier,
+        evidence: Vec<SignedClientMessage>,
+    ) -> AbortMessage {
+        AbortMessage {
+            peer_number,
+            protocol_id,
+            reason,
+            culprit: Some(culprit),
+            evidence,
+            ..Default::default()
+        }
+    }
+
+    /// An abort of the relay itself, naming the peers that missed a deadline of the session.
+    /// The relay is not a peer of the session, so the abort is sent as peer number 0
+    pub fn timeout(
+        protocol_id: ProtocolIdentifier,
+        unresponsive: Vec<PeerIdentifier>,
+    ) -> AbortMessage {
+        AbortMessage {
+            peer_number: 0,
+            protocol_id,
+            reason: AbortReason::Unresponsive,
+            unresponsive,
+            ..Default::default()
         }
     }
 }
@@ -67,6 +213,28 @@ pub struct RegisterMessage {
     pub protocol_id: ProtocolIdentifier,
 
     pub capacity: u32,
+
+    // Session to join, if not set the peer joins an open session
+    // for the same protocol or a new session is created for it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<SessionIdentifier>,
+
+    // Challenge the server sent to the connection the peer registers from,
+    // so the signed message can not be replayed from another connection
+    pub challenge: Challenge,
+
+    // Key the other peers encrypt payloads to this peer with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+#[derive(Default, Clone, Debug, Deserialize, Serialize)]
+pub struct ResumeMessage {
+    pub session_id: SessionIdentifier,
+
+    pub peer_number: PeerIdentifier,
+
+    pub token: ResumptionToken,
 }
 
 #[derive(Debug, PartialEq)]
@@ -87,6 +255,11 @@ pub struct ServerMessage {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_message: Option<RelayMessage>,
+
+    // The envelope the relay message was sent in,
+    // so the recipient can verify the signature of the sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_message: Option<SignedClientMessage>,
 }
 
 impl ServerMessage {
@@ -97,6 +270,8 @@ impl ServerMessage {
             abort: None,
 
             relay_message: None,
+
+            signed_message: None,
         }
     }
 
@@ -119,6 +294,9 @@ pub struct ClientMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub register: Option<RegisterMessage>,
 
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<ResumeMessage>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub abort: Option<AbortMessage>,
 
@@ -131,30 +309,61 @@ impl ClientMessage {
         ClientMessage {
             register: None,
 
+            resume: None,
+
             abort: None,
 
             relay_message: None,
         }
     }
 
-    pub fn register(&mut self, protocol_id: ProtocolIdentifier, capacity: u32) {
+    pub fn register(
+        &mut self,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        session_id: Option<SessionIdentifier>,
+        challenge: Challenge,
+        encryption_key: Option<EncryptionKey>,
+    ) {
         self.register = Some(RegisterMessage {
             protocol_id,
             capacity,
+            session_id,
+            challenge,
+            encryption_key,
+        });
+    }
+
+    pub fn resume(
+        &mut self,
+        session_id: SessionIdentifier,
+        peer_number: PeerIdentifier,
+        token: ResumptionToken,
+    ) {
+        self.resume = Some(ResumeMessage {
+            session_id,
+            peer_number,
+            token,
         });
     }
 
     pub fn is_empty(&self) -> bool {
-        self.relay_message.is_none() && self.abort.is_none() && self.register.is_none()
+        self.relay_message.is_none()
+            && self.abort.is_none()
+            && self.register.is_none()
+            && self.resume.is_none()
     }
 
     pub fn are_equal_payloads(&self, msg: &ClientMessage) -> bool {
         if self.register.is_some() && msg.register.is_some() {
             return true;
+        } else if self.resume.is_some() && msg.resume.is_some() {
+            return true;
         } else if self.relay_message.is_some() && msg.relay_message.is_some() {
-            let self_message = self.relay_message.clone().unwrap().message;
-            let message = msg.relay_message.clone().unwrap().message;
-            return self_message == message;
+            let self_message = self.relay_message.clone().unwrap();
+            let message = msg.relay_message.clone().unwrap();
+            return self_message.message == message.message
+                && self_message.ciphertexts == message.ciphertexts;
         } else if self.abort.is_some() && msg.abort.is_some() {
             return true;
         }
@@ -165,6 +374,9 @@ impl ClientMessage {
         if self.register.is_some() {
             return ClientMessageType::Register;
         }
+        if self.resume.is_some() {
+            return ClientMessageType::Resume;
+        }
         if self.relay_message.is_some() {
             return ClientMessageType::RelayMessage;
         }
@@ -178,6 +390,7 @@ impl ClientMessage {
 #[derive(Debug)]
 pub enum ClientMessageType {
     Register,
+    Resume,
     Abort,
     RelayMessage,
     Undefined,
@@ -189,6 +402,6 @@ struct RegisterResponse {
     peer_number: PeerIdentifier,
 }
 
-// in: clientMessage out:serverMessage
-pub type ServerToClientCodec = JsonCodec<ClientMessage, ServerMessage>;
-pub type ClientToServerCodec = JsonCodec<ServerMessage, ClientMessage>;
+// in: signed clientMessage out:serverMessage
+pub type ServerToClientCodec = JsonCodec<SignedClientMessage, ServerMessage>;
+pub type ClientToServerCodec = JsonCodec<ServerMessage, SignedClientMessage>;
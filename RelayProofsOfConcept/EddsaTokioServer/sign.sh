@@ -1,5 +1,6 @@
 echo "$0: MP-EDDSA"
 #clean
+export MMPC_KEY_PASSPHRASE=${MMPC_KEY_PASSPHRASE:-demo}
 
 rm signature??
 
@@ -8,7 +9,7 @@ kill -9 $(lsof -t -i:8080)
 n=2
 
 echo "signing part"
-cargo run --package relay-server --bin server -- -P $n&
+cargo run --package relay-server --bin server &
 sleep 2
 for i in $(seq 1 $n);
 do
@@ -10,9 +10,10 @@
 //! this will run a client that utilizes the server in some way
 //!
 use clap::{App, Arg, ArgMatches};
-use relay_server::RelayServer;
+use relay_server::{RelayServer, Timeouts};
 use std::io;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 fn arg_matches<'a>() -> ArgMatches<'a> {
     App::new("relay-server")
@@ -22,10 +23,38 @@ fn arg_matches<'a>() -> ArgMatches<'a> {
                 .value_name("<HOST:PORT>"),
         )
         .arg(
-            Arg::with_name("capacity")
-                .default_value("2")
-                .short("P")
-                .long("participants"),
+            Arg::with_name("grace")
+                .short("g")
+                .long("grace")
+                .default_value("10000")
+                .value_name("MILLISECONDS")
+                .help("Time a disconnected peer has to resume its session before it is aborted"),
+        )
+        .arg(
+            Arg::with_name("ban").long("ban-blamed").help(
+                "Refuses identities blamed for misbehaving in a session from registering again",
+            ),
+        )
+        .arg(
+            Arg::with_name("registration-timeout")
+                .long("registration-timeout")
+                .default_value("300")
+                .value_name("SECONDS")
+                .help("Time the peers of a session have to register, 0 to wait forever"),
+        )
+        .arg(
+            Arg::with_name("round-timeout")
+                .long("round-timeout")
+                .default_value("60")
+                .value_name("SECONDS")
+                .help("Time a peer has to send its message on its turn, 0 to wait forever"),
+        )
+        .arg(
+            Arg::with_name("protocols")
+                .long("protocols")
+                .default_value("./protocols.json")
+                .value_name("FILE")
+                .help("File declaring the supported protocols, reloaded when it changes"),
         )
         .arg(
             Arg::with_name("verbose")
@@ -101,16 +130,36 @@ fn main() {
         .parse()
         .expect("Unable to parse socket address");
 
-    let capacity: u32 = matches
-        .value_of("capacity")
+    let grace_period: u64 = matches
+        .value_of("grace")
         .unwrap()
         .parse()
-        .expect("Invalid number of participants");
+        .expect("Unable to parse grace period");
+
+    let ban_blamed = matches.is_present("ban");
+
+    let timeout = |name| -> Option<Duration> {
+        let seconds: u64 = matches
+            .value_of(name)
+            .unwrap()
+            .parse()
+            .expect("Unable to parse timeout");
+        match seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    };
+    let timeouts = Timeouts {
+        registration: timeout("registration-timeout"),
+        round: timeout("round-timeout"),
+    };
 
     let verbosity: u64 = matches.occurrences_of("verbose");
 
     setup_logging(verbosity).expect("failed to initialize logging.");
 
-    let server = RelayServer::new(addr);
-    server.start_server(capacity);
+    let mut server = RelayServer::new(addr, Duration::from_millis(grace_period), ban_blamed);
+    server.set_timeouts(timeouts);
+    server.set_protocols_path(matches.value_of("protocols").unwrap());
+    server.start_server();
 }
@@ -1,4 +1,6 @@
 mod relay_server;
 mod relay_session;
+mod session_manager;
 
 pub use crate::relay_server::RelayServer;
+pub use crate::relay_session::{Client, RelaySession, Timeouts};
@@ -1,41 +1,114 @@
 use futures::stream;
 use futures::sync::mpsc;
-use futures::{Future, Sink, Stream};
+use futures::{future, Future, Sink, Stream};
 use log::{debug, error, info, warn};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tokio::codec::Framed;
 use tokio::net::TcpListener;
+use tokio::timer::{Delay, Interval};
 
-use crate::relay_session::{Client, RelaySession};
+use crate::relay_session::{Client, Timeouts};
+use crate::session_manager::SessionManager;
+use relay_server_common::common::RelayError;
+use relay_server_common::protocol::{ProtocolRegistry, DEFAULT_PROTOCOLS_PATH};
 use relay_server_common::{ClientMessageType, ServerMessage, ServerToClientCodec};
 
 pub struct RelayServer {
-    pub rs: Option<RelaySession>,
     addr: std::net::SocketAddr,
+
+    // time a disconnected peer has to resume its session before the session is aborted
+    grace_period: Duration,
+
+    // whether identities blamed for misbehaving are refused from registering again
+    ban_blamed: bool,
+
+    // deadlines of the sessions, a session that misses one is aborted
+    timeouts: Timeouts,
+
+    // file declaring the protocols the relay supports
+    protocols_path: PathBuf,
 }
 
+// how often the deadlines of the sessions are checked
+const DEADLINE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// how often the protocols file is checked for changes
+const PROTOCOLS_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
 impl RelayServer {
-    pub fn new(addr: SocketAddr) -> RelayServer {
+    pub fn new(addr: SocketAddr, grace_period: Duration, ban_blamed: bool) -> RelayServer {
         RelayServer {
-            rs: None,
             addr: addr,
+            grace_period,
+            ban_blamed,
+            timeouts: Timeouts::default(),
+            protocols_path: PathBuf::from(DEFAULT_PROTOCOLS_PATH),
         }
     }
 
+    /// Sets the file the supported protocols are loaded from.
+    /// The file is reloaded when it changes, and the sessions created from then on
+    /// run with the protocols it declares
+    pub fn set_protocols_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.protocols_path = path.as_ref().to_path_buf();
+    }
+
+    /// Sets the time the peers of a session have to register,
+    /// and the time each peer has to send its message on its turn
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
     /// Starts the relay server
-    pub fn start_server(&self, capacity: u32) {
+    pub fn start_server(&self) {
         // Create the event loop and TCP listener we'll accept connections on.
         // let mut core = Core::new().unwrap();
         // let handle = core.handle();
 
+        let protocols = match ProtocolRegistry::load(&self.protocols_path) {
+            Ok(protocols) => Arc::new(protocols),
+            Err(err) => {
+                error!("{}", err);
+                return;
+            }
+        };
+
         let listener = TcpListener::bind(&self.addr).unwrap();
         info!("Listening on: {}", &self.addr);
 
-        // Create the session fot the relay server
-        // TODO: Relay sessions should start when a new client connects
-        let relay_session = Arc::new(RelaySession::new(capacity));
+        // Create the manager of all relay sessions.
+        // Sessions are created as clients register
+        let mut session_manager = SessionManager::new(Arc::clone(&protocols));
+        session_manager.set_ban_blamed(self.ban_blamed);
+        session_manager.set_timeouts(self.timeouts);
+        let session_manager = Arc::new(session_manager);
+        let grace_period = self.grace_period;
+
+        // abort the sessions that miss their deadlines, naming the peers that did not respond
+        let session_manager_inner = Arc::clone(&session_manager);
+        let deadlines = Interval::new(Instant::now(), DEADLINE_CHECK_INTERVAL)
+            .for_each(move |_| {
+                let messages_to_send = session_manager_inner.expire_deadlines(Instant::now());
+                RelayServer::send_messages(&messages_to_send)
+            })
+            .map_err(|e| error!("Deadline timer failed: {}", e));
+
+        // reload the protocols when the file changes,
+        // a file that can not be loaded keeps the protocols loaded before
+        let reloads = Interval::new(Instant::now(), PROTOCOLS_RELOAD_INTERVAL)
+            .for_each(move |_| {
+                match protocols.reload_if_modified() {
+                    Ok(true) => info!("Reloaded protocols from {}", protocols.path().display()),
+                    Ok(false) => {}
+                    Err(err) => warn!("Keeping the protocols loaded before: {}", err),
+                }
+                Ok(())
+            })
+            .map_err(|e| error!("Protocols reload timer failed: {}", e));
 
         let srv = listener
             .incoming()
@@ -49,21 +122,33 @@ impl RelayServer {
                 //let framed_socket = ServerToClientCodec::new(false).framed(socket);
                 let framed_socket = Framed::new(socket, ServerToClientCodec::new(false));
 
-                // obtain a clone of the RelaySession
-                let relay_session_inner = Arc::clone(&relay_session); //relay_session.clone();
+                // obtain a clone of the SessionManager
+                let session_manager_inner = Arc::clone(&session_manager);
 
                 // create a channel of communication with the (potential) peer
                 let (tx, rx) = mpsc::channel(0);
 
-                // insert this client to the servers active_connections
-                relay_session_inner.insert_new_connection(addr.clone(), Client::new(tx));
+                // insert this client to the servers active_connections,
+                // and send it the challenge it registers with
+                let challenge = session_manager_inner.connect(addr, Client::new(tx));
+                tokio::spawn(RelayServer::send_messages(&challenge));
 
                 // split the socket to reading part (stream) and writing part (sink)
                 let (to_client, from_client) = framed_socket.split();
 
                 // define future for receiving half
-                let relay_session_inner = Arc::clone(&relay_session);
-                let reader = from_client.for_each(move |msg| {
+                let session_manager_inner = Arc::clone(&session_manager);
+                let reader = from_client.for_each(move |envelope| {
+                    // every message is signed by the identity of the peer that sent it
+                    let msg = match envelope.open() {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            warn!("Rejected message from {}: {}", addr, err);
+                            let messages_to_send = session_manager_inner.error_response(&addr, err);
+                            return RelayServer::send_messages(&messages_to_send);
+                        }
+                    };
+                    let identity = envelope.identity;
                     let msg_type = msg.msg_type();
 
                     // this is our main logic for receiving messages from peer
@@ -74,41 +159,88 @@ impl RelayServer {
                                 "Got register message. protocol id requested: {}",
                                 register.protocol_id
                             );
-                            let messages_to_send = relay_session_inner.register(
+                            let messages_to_send = session_manager_inner.register(
                                 addr,
                                 register.protocol_id,
                                 register.capacity,
+                                register.session_id,
+                                register.challenge,
+                                envelope,
+                            );
+                            RelayServer::send_messages(&messages_to_send)
+                        }
+                        ClientMessageType::Resume => {
+                            let resume = msg.resume.unwrap();
+                            info!(
+                                "Got resume message. peer {} of session {}",
+                                resume.peer_number, resume.session_id
+                            );
+                            let messages_to_send = session_manager_inner.resume(
+                                addr,
+                                resume.session_id,
+                                resume.peer_number,
+                                resume.token,
+                                identity,
                             );
                             RelayServer::send_messages(&messages_to_send)
                         }
                         ClientMessageType::RelayMessage => {
-                            let peer = relay_session_inner
-                                .get_peer_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
+                            // a connection that is not registered, or not resumed yet, is answered
+                            // rather than trusted to be a peer
+                            let peer = match session_manager_inner.get_peer_by_address(&addr) {
+                                Some(peer) => peer,
+                                None => {
+                                    let messages_to_send = session_manager_inner
+                                        .error_response(&addr, RelayError::NotAPeer);
+                                    return RelayServer::send_messages(&messages_to_send);
+                                }
+                            };
                             info!("Got relay message from {}", peer.peer_id);
                             let relay_msg = msg.relay_message.unwrap().clone();
                             let messages_to_send =
-                                relay_session_inner.relay_message(&addr, relay_msg);
+                                session_manager_inner.relay_message(&addr, relay_msg, envelope);
                             RelayServer::send_messages(&messages_to_send)
                         }
                         ClientMessageType::Abort => {
-                            let peer = relay_session_inner
-                                .get_peer_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
+                            let peer = match session_manager_inner.get_peer_by_address(&addr) {
+                                Some(peer) => peer,
+                                None => {
+                                    let messages_to_send = session_manager_inner
+                                        .error_response(&addr, RelayError::NotAPeer);
+                                    return RelayServer::send_messages(&messages_to_send);
+                                }
+                            };
                             debug!("Got abort message from {}", peer.peer_id);
-                            let messages_to_send = relay_session_inner.abort(addr);
+                            if peer.identity != Some(identity)
+                                || !session_manager_inner.is_signed_for_session(&addr, &envelope)
+                            {
+                                warn!("Abort message not signed by peer {}", peer.peer_id);
+                                let messages_to_send = session_manager_inner
+                                    .error_response(&addr, RelayError::InvalidSignature);
+                                return RelayServer::send_messages(&messages_to_send);
+                            }
+                            let abort = msg.abort.unwrap();
+                            let messages_to_send = match abort.culprit {
+                                Some(_) => session_manager_inner.abort_blaming(addr, &abort),
+                                None => session_manager_inner.abort(addr),
+                            };
                             RelayServer::send_messages(&messages_to_send)
                         }
                         ClientMessageType::Test => {
-                            let sender = relay_session_inner
-                                .get_sender_by_address(&addr)
-                                .unwrap_or_else(|| panic!("not a peer"));
-                            let msg = ServerMessage::new();
-                            RelayServer::send_single_message(sender, msg)
+                            match session_manager_inner.get_sender_by_address(&addr) {
+                                Some(sender) => {
+                                    RelayServer::send_single_message(sender, ServerMessage::new())
+                                }
+                                None => {
+                                    let messages_to_send = session_manager_inner
+                                        .error_response(&addr, RelayError::NotAPeer);
+                                    RelayServer::send_messages(&messages_to_send)
+                                }
+                            }
                         }
                         ClientMessageType::Undefined => {
                             warn!("Got unknown or empty message");
-                            let messages_to_send = relay_session_inner.abort(addr);
+                            let messages_to_send = session_manager_inner.abort(addr);
                             RelayServer::send_messages(&messages_to_send)
                         }
                     }
@@ -130,7 +262,7 @@ impl RelayServer {
 
                 // map & map_err here are used for the case reading half or writing half is dropped
                 // in which case we will be dropping the other half as well
-                let relay_session_inner = Arc::clone(&relay_session);
+                let session_manager_inner = Arc::clone(&session_manager);
                 tokio::spawn(
                     connection
                         .map(|_| ())
@@ -142,9 +274,23 @@ impl RelayServer {
                             // connection is closed
                             warn!("Disconnected");
 
-                            // this means either a peer disconnected - same as abort,
+                            // this means either a peer disconnected - which may resume its session,
                             // or an active connection closed - which is allowed
-                            let messages_to_send = relay_session_inner.abort(addr);
+                            let messages_to_send = session_manager_inner.disconnect(addr);
+                            debug!(
+                                "Active sessions: {}",
+                                session_manager_inner.number_of_sessions()
+                            );
+                            if session_manager_inner.is_suspended(&addr) {
+                                // abort the session if the peer does not resume in time
+                                let session_manager_inner = Arc::clone(&session_manager_inner);
+                                tokio::spawn(Delay::new(Instant::now() + grace_period).then(
+                                    move |_| {
+                                        let messages_to_send = session_manager_inner.expire(addr);
+                                        RelayServer::send_messages(&messages_to_send)
+                                    },
+                                ));
+                            }
                             RelayServer::send_messages(&messages_to_send)
                         }),
                 );
@@ -154,19 +300,21 @@ impl RelayServer {
             .map_err(|e| debug!("Error occured {}", e));
 
         // execute server
-        tokio::run(srv);
+        tokio::run(future::lazy(move || {
+            tokio::spawn(deadlines);
+            tokio::spawn(reloads);
+            srv
+        }));
     }
 
     // Recieves a vector of tuples, of a message and a Sink,
-    // Sends the message to the the Sink
+    // Sends the message to the the Sink.
+    // Messages are sent one after the other, so a peer receives them in order
     pub fn send_messages<E: 'static + Send>(
         messages_to_send: &Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>,
     ) -> Box<dyn Future<Item = (), Error = E> + Send> {
-        let sends = messages_to_send
-            .iter()
-            .map(|(msg, sink)| sink.clone().send(msg.clone()));
-        let send_stream = stream::futures_unordered(sends).then(|_| Ok(()));
-        Box::new(send_stream.for_each(|()| Ok(())))
+        let sends = stream::iter_ok(messages_to_send.clone());
+        Box::new(sends.for_each(|(msg, sink)| sink.send(msg).then(|_| Ok(()))))
     }
 
     // Send a Server message to a specific Sink
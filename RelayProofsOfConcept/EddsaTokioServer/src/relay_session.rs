@@ -1,16 +1,19 @@
 use futures::sync::mpsc;
 use log::{debug, info, warn};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use relay_server_common::{
-    AbortMessage, PeerIdentifier, ProtocolIdentifier, RelayMessage, ServerMessage, ServerResponse,
+    AbortMessage, AbortReason, MessagePayload, PeerIdentifier, ProtocolIdentifier, RelayMessage,
+    ResumptionToken, ServerMessage, ServerResponse,
 };
 
-use relay_server_common::common::{NOT_A_PEER, NOT_YOUR_TURN, STATE_NOT_INITIALIZED};
+use relay_server_common::common::RelayError;
+use relay_server_common::identity::{IdentityKey, SignedClientMessage};
 
-use relay_server_common::protocol::ProtocolDescriptor;
+use relay_server_common::protocol::{Delivery, ProtocolDescriptor, ProtocolRegistry, RoundMode};
 
 // Represents the communication channel to remote client
 #[derive(Clone, Debug)]
@@ -22,6 +25,10 @@ impl Client {
     pub fn new(tx: mpsc::Sender<ServerMessage>) -> Client {
         Client { tx }
     }
+
+    pub fn sender(&self) -> mpsc::Sender<ServerMessage> {
+        self.tx.clone()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -29,6 +36,16 @@ pub struct Peer {
     pub peer_id: PeerIdentifier,
     client: Client,
     pub registered: bool,
+    // identity key the peer registered with, every message it sends must be signed by it
+    pub identity: Option<IdentityKey>,
+    // the signed register message of the peer, forwarded to the other peers of the session
+    pub registration: Option<SignedClientMessage>,
+    // token the peer presents to resume the session from a new connection
+    token: ResumptionToken,
+    // set while the connection of a registered peer is lost
+    pub disconnected: bool,
+    // messages to replay to the peer once it resumes the session
+    missed: Vec<ServerMessage>,
 }
 
 impl Peer {
@@ -37,6 +54,25 @@ impl Peer {
             peer_id: 0,
             client,
             registered: false,
+            identity: None,
+            registration: None,
+            token: 0,
+            disconnected: false,
+            missed: Vec::new(),
+        }
+    }
+
+    /// Returns the message along with the Sender to send it with,
+    /// or buffers the message for replay if the peer is disconnected
+    fn deliver(
+        &mut self,
+        msg: ServerMessage,
+    ) -> Option<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        if self.disconnected {
+            self.missed.push(msg);
+            None
+        } else {
+            Some((msg, self.client.tx.clone()))
         }
     }
 }
@@ -52,6 +88,39 @@ pub enum RelaySessionState {
     Aborted,
 }
 
+/// A peer of the session blamed by another for misbehaving,
+/// with evidence the relay verified was signed by the blamed peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blame {
+    pub accuser: PeerIdentifier,
+    pub culprit: PeerIdentifier,
+    // the identity the culprit registered with
+    pub identity: IdentityKey,
+    pub reason: AbortReason,
+}
+
+/// Deadlines of a relay session, a session that misses one is aborted.
+/// A deadline that is not set is never missed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+    // time the peers have to register, from the registration of the first peer
+    pub registration: Option<Duration>,
+
+    // time a peer has to send its message once it is its turn
+    pub round: Option<Duration>,
+}
+
+impl Timeouts {
+    // The deadlines of a session of the protocol,
+    // those the protocol declares take the place of these
+    fn of_protocol(&self, protocol: &ProtocolDescriptor) -> Timeouts {
+        Timeouts {
+            registration: protocol.timeouts.registration().or(self.registration),
+            round: protocol.timeouts.round().or(self.round),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RelaySession {
     peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
@@ -61,6 +130,29 @@ pub struct RelaySession {
     protocol: Arc<RwLock<ProtocolDescriptor>>,
 
     state: Arc<RwLock<RelaySessionState>>,
+
+    // the blames of aborts the session received
+    blames: Arc<RwLock<Vec<Blame>>>,
+
+    timeouts: Timeouts,
+
+    // the time the current stage of the session must be done by,
+    // either the registration of all peers or the turn of a peer
+    deadline: Arc<RwLock<Option<Instant>>>,
+
+    // in broadcast mode, the messages of the current round by their sender,
+    // held until every peer sent its message
+    round_messages: Arc<RwLock<BTreeMap<PeerIdentifier, (RelayMessage, SignedClientMessage)>>>,
+
+    // the round and the payload of the last message taken from each peer
+    last_taken: Arc<RwLock<HashMap<PeerIdentifier, (u32, MessagePayload)>>>,
+
+    // the number of rounds the session completed
+    round: Arc<RwLock<u32>>,
+
+    // the protocols a session can run, the session takes the limits of its protocol
+    // from the registry once the first peer registers
+    protocols: Arc<ProtocolRegistry>,
 }
 
 impl RelaySession {
@@ -84,14 +176,23 @@ impl RelaySession {
         addr: SocketAddr,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
-    ) -> Option<u32> {
+        identity: IdentityKey,
+    ) -> Result<PeerIdentifier, RelayError> {
         let _addr = &addr;
         let number_of_active_peers = self.get_number_of_active_peers();
 
-        let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
+        // the first peer sets the protocol of the session along with its limits,
+        // the peers that follow register to the protocol the session runs
+        let protocol_descriptor = match self.state() {
+            RelaySessionState::Empty => self.protocols.descriptor(protocol_id, capacity),
+            _ => Ok(ProtocolDescriptor::new(protocol_id, capacity)),
+        };
         info!("-----------------PEERS: {:?}---------------", self.peers);
-        match self.can_register(_addr, protocol_descriptor) {
-            true => {
+        match protocol_descriptor.and_then(|protocol_descriptor| {
+            self.can_register(_addr, protocol_descriptor.clone(), &identity)
+                .map(|()| protocol_descriptor)
+        }) {
+            Ok(protocol_descriptor) => {
                 let mut peers = self.peers.write().unwrap();
                 let peer = peers
                     .get_mut(_addr)
@@ -100,41 +201,50 @@ impl RelaySession {
                 // activate this connection as a peer
                 peer.registered = true;
                 peer.peer_id = number_of_active_peers + 1;
+                peer.identity = Some(identity);
+                peer.token = rand::random();
                 // if needed, set the ProtocolDescriptor for this sessuib
                 // and change the state
                 let state = self.state();
                 match state {
                     RelaySessionState::Empty => {
-                        self.set_protocol(ProtocolDescriptor::new(protocol_id, capacity));
+                        self.set_protocol(protocol_descriptor);
                         self.set_state(RelaySessionState::Uninitialized);
+                        self.set_deadline(self.timeouts().registration);
                     }
                     _ => {}
                 }
                 //if self.protocol.clone().into_inner().capacity == number_of_active_peers + 1 {
                 if self.protocol().capacity == number_of_active_peers + 1 {
                     self.set_state(RelaySessionState::Initialized);
+                    self.set_deadline(self.timeouts().round);
                 }
-                return Some(number_of_active_peers + 1); //peer_id
+                return Ok(number_of_active_peers + 1); //peer_id
             }
-            false => {
-                warn!("Unable to register {:}", addr); // error
-                None
+            Err(err) => {
+                warn!("Unable to register {:}: {}", addr, err); // error
+                Err(err)
             }
         }
     }
 
     /// Checks if it is possible for this address
     /// to register as a peer in this session
-    fn can_register(&self, addr: &SocketAddr, protocol: ProtocolDescriptor) -> bool {
+    fn can_register(
+        &self,
+        addr: &SocketAddr,
+        protocol: ProtocolDescriptor,
+        identity: &IdentityKey,
+    ) -> Result<(), RelayError> {
         match self.state() {
             // if this is the first peer to register
             // check that the protocol is valid
             RelaySessionState::Empty => {
                 debug!("Checking if protocol description is valid");
-                if !relay_server_common::protocol::is_valid_protocol(&protocol) {
+                if let Err(err) = self.protocols.descriptor(protocol.id, protocol.capacity) {
                     warn!("Protocol is invalid");
 
-                    return false;
+                    return Err(err);
                 }
             }
             // if there is already a set protocol,
@@ -142,26 +252,48 @@ impl RelaySession {
             RelaySessionState::Uninitialized => {
                 debug!("Checking if protocol description is same as at protocol description");
                 let prot = self.protocol();
-                if !(prot.id == protocol.id && prot.capacity == protocol.capacity) {
+                if prot.id != protocol.id {
                     warn!("Protocol description does not fit current configuration");
-                    return false;
+                    return Err(RelayError::InvalidProtocol);
+                }
+                if prot.capacity != protocol.capacity {
+                    warn!("Protocol capacity does not fit current configuration");
+                    return Err(RelayError::CapacityMismatch);
                 }
             }
-            _ => {
-                debug!("Relay session state is neither empty nor uninitialized ");
-                return false;
+            RelaySessionState::Initialized => {
+                debug!("Relay session is already initialized");
+                return Err(RelayError::SessionFull);
             }
+            RelaySessionState::Aborted => {
+                debug!("Relay session was aborted");
+                return Err(RelayError::SessionAborted);
+            }
+        }
+        // register the peer iff it has an active connection and did not register yet,
+        // with an identity no other peer of the session registered with
+        let peers = self.peers.read().unwrap();
+        if peers
+            .values()
+            .any(|peer| peer.identity.as_ref() == Some(identity))
+        {
+            debug!("Identity is already registered to the session");
+            return Err(RelayError::CantRegister);
         }
-        // register the peer iff it has an active connection and did not register yet
-        if let Some(peer) = self.peers.read().unwrap().get(addr) {
-            return !peer.registered;
+        match peers.get(addr) {
+            Some(peer) if !peer.registered => Ok(()),
+            _ => Err(RelayError::CantRegister),
         }
-        false
     }
 
-    /// Check if this relay message sent from the given SocketAddr
-    /// and is valid to send to rest of the peers
-    fn can_relay(&self, from: &SocketAddr, msg: &RelayMessage) -> Result<(), &'static str> {
+    /// Check if this relay message sent from the given SocketAddr,
+    /// in the given signed envelope, is valid to send to rest of the peers
+    fn can_relay(
+        &self,
+        from: &SocketAddr,
+        envelope: &SignedClientMessage,
+        msg: &RelayMessage,
+    ) -> Result<(), RelayError> {
         debug!("Checking if {:} can relay", msg.peer_number);
         debug!("Server state: {:?}", self.state());
         debug!("Turn of peer #: {:}", self.protocol().next());
@@ -170,9 +302,13 @@ impl RelaySession {
             RelaySessionState::Initialized => {
                 debug!("Relay sessions state is initialized");
             }
+            RelaySessionState::Aborted => {
+                debug!("Relay session was aborted");
+                return Err(RelayError::SessionAborted);
+            }
             _ => {
                 debug!("Relay sessions state is not initialized");
-                return Err(STATE_NOT_INITIALIZED);
+                return Err(RelayError::StateNotInitialized);
             }
         }
         // validate the sender in the message (peer_number field) is the peer associated with this address
@@ -182,15 +318,52 @@ impl RelaySession {
         // if peer is present and registered
         if let Some(p) = peer {
             if p.registered && p.peer_id == sender {
+                // check the message is signed by the identity the peer registered with
+                if p.identity.as_ref() != Some(&envelope.identity) {
+                    return Err(RelayError::InvalidSignature);
+                }
                 // check if it is this peers turn
-                if self.protocol().next() == p.peer_id {
-                    return Ok(());
-                } else {
-                    return Err(NOT_YOUR_TURN);
+                match self.protocol().mode {
+                    RoundMode::Turns if self.protocol().next() == p.peer_id => Ok(()),
+                    RoundMode::Turns => Err(RelayError::NotYourTurn),
+                    // in broadcast mode, each peer sends one message in a round
+                    RoundMode::Broadcast
+                        if self.round_messages.read().unwrap().contains_key(&p.peer_id) =>
+                    {
+                        Err(RelayError::AlreadySent)
+                    }
+                    RoundMode::Broadcast => Ok(()),
+                }?;
+                // the message must be signed for the round the session is in
+                if envelope.round != self.round() {
+                    return Err(RelayError::WrongRound);
                 }
+                return self.within_limits(sender, msg);
             }
         }
-        return Err(NOT_A_PEER);
+        return Err(RelayError::NotAPeer);
+    }
+
+    // Checks the message keeps to the limits the protocol of the session declares
+    fn within_limits(&self, sender: PeerIdentifier, msg: &RelayMessage) -> Result<(), RelayError> {
+        let protocol = self.protocol();
+        if let Some(rounds) = protocol.rounds {
+            if self.round() >= rounds {
+                return Err(RelayError::TooManyRounds);
+            }
+        }
+        if let Some(max_payload) = protocol.max_payload {
+            if msg.payload_size() > max_payload {
+                return Err(RelayError::PayloadTooLarge);
+            }
+        }
+        if protocol.delivery == Delivery::Broadcast
+            && (1..=protocol.capacity)
+                .any(|peer_id| peer_id != sender && !msg.to.contains(&peer_id))
+        {
+            return Err(RelayError::InvalidRecipients);
+        }
+        Ok(())
     }
 }
 
@@ -198,6 +371,20 @@ impl RelaySession {
     /// Creates a new Relay Session with default (empty) fields
     /// and an Empty state
     pub fn new(capacity: u32) -> RelaySession {
+        RelaySession::with_timeouts(capacity, Timeouts::default())
+    }
+
+    /// Creates a new empty Relay Session that is aborted if it misses one of the deadlines
+    pub fn with_timeouts(capacity: u32, timeouts: Timeouts) -> RelaySession {
+        RelaySession::with_protocols(capacity, timeouts, Arc::new(ProtocolRegistry::default()))
+    }
+
+    /// Creates a new empty Relay Session running one of the protocols of the registry
+    pub fn with_protocols(
+        capacity: u32,
+        timeouts: Timeouts,
+        protocols: Arc<ProtocolRegistry>,
+    ) -> RelaySession {
         RelaySession {
             peers: Arc::new(RwLock::new(HashMap::new())),
 
@@ -208,6 +395,20 @@ impl RelaySession {
             )),
 
             state: Arc::new(RwLock::new(RelaySessionState::Empty)),
+
+            blames: Arc::new(RwLock::new(Vec::new())),
+
+            timeouts,
+
+            deadline: Arc::new(RwLock::new(None)),
+
+            round_messages: Arc::new(RwLock::new(BTreeMap::new())),
+
+            last_taken: Arc::new(RwLock::new(HashMap::new())),
+
+            round: Arc::new(RwLock::new(0)),
+
+            protocols,
         }
     }
 
@@ -219,10 +420,15 @@ impl RelaySession {
     }
 
     /// Removes a connection from the peers collection
-    fn remove(&self, addr: &SocketAddr) -> Option<Peer> {
+    pub fn remove(&self, addr: &SocketAddr) -> Option<Peer> {
         self.peers.write().unwrap().remove(addr)
     }
 
+    /// Returns the number of connections in this session, registered or not
+    pub fn number_of_connections(&self) -> usize {
+        self.peers.read().unwrap().len()
+    }
+
     /// Try reutrn a Sender of a specific peer by its address.
     /// The Sender can be used to send ServerMessages to the server
     pub fn get_sender_by_address(&self, addr: &SocketAddr) -> Option<mpsc::Sender<ServerMessage>> {
@@ -233,31 +439,39 @@ impl RelaySession {
         }
     }
 
-    /// Receives the sender's address and a message
+    /// Receives the sender's address, a message and the signed envelope it was sent in.
     /// If the message can be relayed, returns a vector of tupltes,
-    /// with the message as the first member, and a Sender to recipient as the second
+    /// with the message as the first member, and a Sender to recipient as the second.
+    /// The envelope is relayed along with the message for the recipients to verify.
+    /// In broadcast mode the message is held until all the peers sent their message of the round,
+    /// and the messages of the round are then all relayed together
     pub fn relay_message(
         &self,
         from: &SocketAddr,
         msg: RelayMessage,
+        envelope: SignedClientMessage,
     ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
         let mut server_msg = ServerMessage::new();
         let sender = self.get_peer_by_address(from).unwrap();
         let sender_id = sender.peer_id;
-        let can_relay = self.can_relay(from, &msg);
+        if self.is_sent_again(sender_id, &msg, &envelope) {
+            debug!("Dropping a message peer {} sent again", sender_id);
+            return vec![];
+        }
+        let can_relay = self.can_relay(from, &envelope, &msg);
         match can_relay {
+            Ok(()) if self.protocol().mode == RoundMode::Broadcast => {
+                self.take(sender_id, &msg, &envelope);
+                self.add_round_message(sender_id, msg, envelope)
+            }
             Ok(()) => {
-                server_msg.relay_message = Some(msg.clone());
-                let peers = self.peers.read().unwrap();
-                let messages_to_send = peers
-                    .values()
-                    .filter(|peer| {
-                        let id = &(peer.peer_id as PeerIdentifier);
-                        msg.to.contains(id) && peer.registered
-                    })
-                    .map(|peer| (server_msg.clone(), peer.client.tx.clone()))
-                    .collect();
-                self.protocol.write().unwrap().advance_turn();
+                self.take(sender_id, &msg, &envelope);
+                let messages_to_send = self.deliver(&msg, envelope);
+                // the round is done once the turn is back at the first peer
+                if self.protocol.write().unwrap().advance_turn() == 1 {
+                    *self.round.write().unwrap() += 1;
+                }
+                self.set_round_deadline();
 
                 debug!(
                     "Sending relay message from peer {:?} to: {:?}",
@@ -265,62 +479,393 @@ impl RelaySession {
                 );
                 messages_to_send
             }
-            Err(err_msg) => {
+            Err(err) => {
                 // send an error response to sender
-                warn!("Peer {:} can not relay", sender_id);
-                server_msg.response = Some(ServerResponse::ErrorResponse(String::from(err_msg)));
+                warn!("Peer {:} can not relay: {}", sender_id, err);
+                server_msg.response = Some(ServerResponse::ErrorResponse(err));
                 vec![(server_msg, sender.client.tx.clone())]
             }
         }
     }
 
-    /// Register a new peer for the relay session.
-    /// Return a vector of register messages to send to all other peers if state is initialized
+    // Returns the message along with the Sender of each of its recipients
+    fn deliver(
+        &self,
+        msg: &RelayMessage,
+        envelope: SignedClientMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let mut server_msg = ServerMessage::new();
+        server_msg.relay_message = Some(msg.clone());
+        server_msg.signed_message = Some(envelope);
+        let mut peers = self.peers.write().unwrap();
+        peers
+            .values_mut()
+            .filter(|peer| {
+                let id = &(peer.peer_id as PeerIdentifier);
+                msg.to.contains(id) && peer.registered
+            })
+            .filter_map(|peer| peer.deliver(server_msg.clone()))
+            .collect()
+    }
+
+    // A peer sends its message again when it is not sure it was relayed.
+    // The message is taken once, so a message signed for a round the peer already sent
+    // its message of is dropped, unless it differs from the message taken in that round.
+    // A peer can send the same payload in two rounds
+    fn is_sent_again(
+        &self,
+        sender_id: PeerIdentifier,
+        msg: &RelayMessage,
+        envelope: &SignedClientMessage,
+    ) -> bool {
+        if msg.peer_number != sender_id {
+            return false;
+        }
+        match self.last_taken.read().unwrap().get(&sender_id) {
+            Some((round, payload)) => {
+                envelope.round < *round || (envelope.round == *round && msg.message == *payload)
+            }
+            None => false,
+        }
+    }
+
+    // Records the message as the last message taken from the peer, with its round
+    fn take(&self, sender_id: PeerIdentifier, msg: &RelayMessage, envelope: &SignedClientMessage) {
+        self.last_taken
+            .write()
+            .unwrap()
+            .insert(sender_id, (envelope.round, msg.message.clone()));
+    }
+
+    // Holds the message of the peer until all the peers sent their message of the round.
+    // Once they did, returns the messages of the round ordered by their sender,
+    // each with the Sender of its recipients
+    fn add_round_message(
+        &self,
+        sender_id: PeerIdentifier,
+        msg: RelayMessage,
+        envelope: SignedClientMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let round_messages = {
+            let mut round_messages = self.round_messages.write().unwrap();
+            round_messages.insert(sender_id, (msg, envelope));
+            if round_messages.len() < self.protocol().capacity as usize {
+                debug!(
+                    "Holding the message of peer {}, {} of {} peers sent theirs",
+                    sender_id,
+                    round_messages.len(),
+                    self.protocol().capacity
+                );
+                return vec![];
+            }
+            std::mem::replace(&mut *round_messages, BTreeMap::new())
+        };
+        debug!("All the peers sent their message, relaying the round");
+        *self.round.write().unwrap() += 1;
+        self.set_round_deadline();
+        round_messages
+            .into_iter()
+            .flat_map(|(_, (msg, envelope))| self.deliver(&msg, envelope))
+            .collect()
+    }
+
+    // The peers the session waits for to send their message.
+    // The peer whose turn it is, or in broadcast mode all the peers that did not send
+    // their message of the round
+    fn awaited_peers(&self) -> Vec<PeerIdentifier> {
+        let protocol = self.protocol();
+        match protocol.mode {
+            RoundMode::Turns => vec![protocol.next()],
+            RoundMode::Broadcast => {
+                let round_messages = self.round_messages.read().unwrap();
+                (1..=protocol.capacity)
+                    .filter(|peer_id| !round_messages.contains_key(peer_id))
+                    .collect()
+            }
+        }
+    }
+
+    /// Register a new peer for the relay session, with the register message it signed.
+    /// Return a vector of register messages to send to all other peers if state is initialized,
+    /// each followed by the signed register messages of all the peers,
+    /// or the reason the peer could not be registered
     pub fn register(
         &self,
         addr: SocketAddr,
         protocol_id: ProtocolIdentifier,
         capacity: u32,
-    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
-        self.register_new_peer(addr, protocol_id, capacity);
+        registration: SignedClientMessage,
+    ) -> Result<Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>, RelayError> {
+        self.register_new_peer(addr, protocol_id, capacity, registration.identity)?;
+        if let Some(peer) = self.peers.write().unwrap().get_mut(&addr) {
+            peer.registration = Some(registration);
+        }
         // Send message to all
-        match self.state() {
+        let messages_to_send = match self.state() {
             RelaySessionState::Initialized => {
-                let peers = self.peers.read().unwrap();
-                let sends = peers
-                    .iter()
-                    .map(|(_addr, peer)| {
-                        let mut server_msg = ServerMessage::new();
-                        server_msg.response = Some(ServerResponse::Register(peer.peer_id));
-                        (server_msg, peer.client.tx.clone())
+                let mut peers = self.peers.write().unwrap();
+                let registrations: BTreeMap<PeerIdentifier, SignedClientMessage> = peers
+                    .values()
+                    .filter_map(|peer| {
+                        peer.registration
+                            .clone()
+                            .map(|registration| (peer.peer_id, registration))
                     })
                     .collect();
+                let mut sends = Vec::new();
+                for peer in peers.values_mut() {
+                    let mut server_msg = ServerMessage::new();
+                    server_msg.response = Some(ServerResponse::Register(peer.peer_id, peer.token));
+                    sends.extend(peer.deliver(server_msg));
+                    let mut server_msg = ServerMessage::new();
+                    server_msg.response = Some(ServerResponse::Peers(registrations.clone()));
+                    sends.extend(peer.deliver(server_msg));
+                }
                 sends
             }
             _ => vec![],
-        }
+        };
+        Ok(messages_to_send)
     }
 
     // Abort the current relay session
     // Return an abort message to all connected peers
     pub fn abort(&self, addr: SocketAddr) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
         warn!("Received abort, sending abort messages to all");
-        let peer = self.get_peer_by_address(&addr);
+        match self.get_peer_by_address(&addr) {
+            Some(p) => self.send_abort(AbortMessage::new(p.peer_id, self.protocol().id)),
+            None => vec![],
+        }
+    }
+
+    /// Abort the current relay session on an abort of the peer at addr that blames another peer.
+    /// If the blame holds, it is recorded and the abort is sent to all connected peers
+    /// along with its evidence, otherwise the session is aborted without blaming any peer.
+    /// Returns the blame if it holds, and the abort messages to send
+    pub fn abort_blaming(
+        &self,
+        addr: SocketAddr,
+        abort: &AbortMessage,
+    ) -> (
+        Option<Blame>,
+        Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>,
+    ) {
+        let blame = match self.verify_blame(&addr, abort) {
+            Ok(blame) => blame,
+            Err(reason) => {
+                warn!("Ignoring blame of peer {:?}: {}", abort.culprit, reason);
+                return (None, self.abort(addr));
+            }
+        };
+        warn!(
+            "Peer {} blamed peer {}: {:?}",
+            blame.accuser, blame.culprit, blame.reason
+        );
+        self.blames.write().unwrap().push(blame.clone());
+        let abort = AbortMessage::blame(
+            blame.accuser,
+            self.protocol().id,
+            blame.reason,
+            blame.culprit,
+            abort.evidence.clone(),
+        );
+        (Some(blame), self.send_abort(abort))
+    }
+
+    /// Aborts the session if it missed its deadline by the given time.
+    /// The abort names the peers the session waits for, while a session that is still registering
+    /// names no peer, as the peers it waits for never registered.
+    /// Returns the abort messages to send to all connected peers
+    pub fn expire_deadline(
+        &self,
+        now: Instant,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let deadline = *self.deadline.read().unwrap();
+        match deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return vec![],
+        }
+        let unresponsive = match self.state() {
+            RelaySessionState::Uninitialized => vec![],
+            RelaySessionState::Initialized => self.awaited_peers(),
+            _ => return vec![],
+        };
+        warn!(
+            "Session missed its deadline, aborting. Unresponsive peers: {:?}",
+            unresponsive
+        );
+        self.send_abort(AbortMessage::timeout(self.protocol().id, unresponsive))
+    }
+
+    // The deadlines of the session, as set for the relay or declared by its protocol
+    fn timeouts(&self) -> Timeouts {
+        self.timeouts.of_protocol(&self.protocol())
+    }
+
+    /// Returns the number of rounds the session completed
+    pub fn round(&self) -> u32 {
+        *self.round.read().unwrap()
+    }
+
+    // Sets the deadline of the next round,
+    // a session that ran all the rounds of its protocol has nothing left to wait for
+    fn set_round_deadline(&self) {
+        match self.protocol().rounds {
+            Some(rounds) if self.round() >= rounds => self.set_deadline(None),
+            _ => self.set_deadline(self.timeouts().round),
+        }
+    }
+
+    // Sets the deadline of the current stage of the session, from now
+    fn set_deadline(&self, timeout: Option<Duration>) {
+        *self.deadline.write().unwrap() = timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    /// Returns the blames of aborts the session received
+    pub fn blames(&self) -> Vec<Blame> {
+        self.blames.read().unwrap().clone()
+    }
+
+    // A blame holds if the culprit is another peer of the session,
+    // and all the evidence are relay messages the culprit signed.
+    // An unresponsive peer has no messages to prove it, so the blame holds if the session
+    // waits for its message
+    fn verify_blame(&self, addr: &SocketAddr, abort: &AbortMessage) -> Result<Blame, &'static str> {
+        let accuser = self.get_peer_by_address(addr).ok_or("Not a peer")?;
+        let culprit = abort.culprit.ok_or("No culprit")?;
+        let identity = self
+            .peers
+            .read()
+            .unwrap()
+            .values()
+            .find(|peer| peer.registered && peer.peer_id == culprit && culprit != accuser.peer_id)
+            .and_then(|peer| peer.identity)
+            .ok_or("The culprit is not another peer of the session")?;
+        let mut payloads = Vec::new();
+        for envelope in &abort.evidence {
+            if envelope.identity != identity {
+                return Err("Evidence not signed by the culprit");
+            }
+            let relay_msg = envelope
+                .open()
+                .ok()
+                .and_then(|msg| msg.relay_message)
+                .filter(|relay_msg| relay_msg.peer_number == culprit)
+                .ok_or("Evidence is not a relay message of the culprit")?;
+            payloads.push(relay_msg.message);
+        }
+        payloads.sort();
+        payloads.dedup();
+        match abort.reason {
+            AbortReason::Unspecified => return Err("No reason"),
+            AbortReason::Unresponsive if !self.awaited_peers().contains(&culprit) => {
+                return Err("Not the turn of the culprit")
+            }
+            AbortReason::Unresponsive => {}
+            AbortReason::Equivocation if payloads.len() < 2 => {
+                return Err("Equivocation needs two different messages")
+            }
+            _ if payloads.is_empty() => return Err("No evidence"),
+            _ => {}
+        }
+        Ok(Blame {
+            accuser: accuser.peer_id,
+            culprit,
+            identity,
+            reason: abort.reason,
+        })
+    }
+
+    // Sets the session as aborted, and returns the abort to send to every connected peer
+    fn send_abort(&self, abort: AbortMessage) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
         let mut server_msg = ServerMessage::new();
-        match peer {
-            Some(p) => {
-                server_msg.abort = Some(AbortMessage::new(p.peer_id, self.protocol().id));
-                self.set_state(RelaySessionState::Aborted);
-                let peers = self.peers.read().unwrap();
-                peers
-                    .iter()
-                    .map(|(_addr, peer)| (server_msg.clone(), peer.client.tx.clone()))
-                    .collect()
+        server_msg.abort = Some(abort);
+        self.set_state(RelaySessionState::Aborted);
+        let peers = self.peers.read().unwrap();
+        peers
+            .iter()
+            .filter(|(_addr, peer)| !peer.disconnected)
+            .map(|(_addr, peer)| (server_msg.clone(), peer.client.tx.clone()))
+            .collect()
+    }
+
+    /// Marks the registered peer at addr as disconnected.
+    /// Messages to the peer are buffered until it resumes the session.
+    /// Returns false if the peer can not resume, as it is not registered
+    /// or the session was aborted
+    pub fn suspend(&self, addr: &SocketAddr) -> bool {
+        if self.state() == RelaySessionState::Aborted {
+            return false;
+        }
+        match self.peers.write().unwrap().get_mut(addr) {
+            Some(peer) if peer.registered => {
+                peer.disconnected = true;
+                true
             }
-            None => vec![],
+            _ => false,
         }
     }
 
+    /// Returns true if the peer at addr is disconnected and did not resume the session
+    pub fn is_suspended(&self, addr: &SocketAddr) -> bool {
+        match self.peers.read().unwrap().get(addr) {
+            Some(peer) => peer.disconnected,
+            None => false,
+        }
+    }
+
+    /// Resume the session for a peer from a new connection.
+    /// The peer proves its identity with the token it received on registration,
+    /// and by signing the resume message with the identity it registered with.
+    /// Returns the address the peer was connected from, and the messages to send to it:
+    /// a Resumed response followed by all the messages it missed
+    pub fn resume(
+        &self,
+        addr: SocketAddr,
+        client: Client,
+        peer_id: PeerIdentifier,
+        token: ResumptionToken,
+        identity: IdentityKey,
+    ) -> Result<
+        (
+            SocketAddr,
+            Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>,
+        ),
+        RelayError,
+    > {
+        if self.state() == RelaySessionState::Aborted {
+            return Err(RelayError::SessionAborted);
+        }
+        let mut peers = self.peers.write().unwrap();
+        // the old connection might not be detected as closed yet,
+        // in which case it is replaced by the new one
+        let old_addr = match peers
+            .iter()
+            .find(|(_addr, peer)| peer.registered && peer.peer_id == peer_id)
+        {
+            Some((_, peer)) if peer.token != token => return Err(RelayError::InvalidToken),
+            Some((_, peer)) if peer.identity != Some(identity) => {
+                return Err(RelayError::InvalidSignature)
+            }
+            Some((old_addr, _)) => *old_addr,
+            None => return Err(RelayError::InvalidToken),
+        };
+        let mut peer = peers.remove(&old_addr).unwrap();
+        peer.client = client;
+        peer.disconnected = false;
+
+        let mut server_msg = ServerMessage::new();
+        server_msg.response = Some(ServerResponse::Resumed(peer_id));
+        let tx = peer.client.tx.clone();
+        let messages_to_send = std::iter::once(server_msg)
+            .chain(peer.missed.drain(..))
+            .map(|msg| (msg, tx.clone()))
+            .collect();
+        info!("Peer {} resumed from {}", peer_id, addr);
+        peers.insert(addr, peer);
+        Ok((old_addr, messages_to_send))
+    }
+
     /// get a copy of Peer that addr represents
     pub fn get_peer_by_address(&self, addr: &SocketAddr) -> Option<Peer> {
         match self.peers.read().unwrap().get(addr) {
@@ -356,16 +901,27 @@ mod tests {
     use super::Client;
     use super::RelaySession;
     use super::RelaySessionState;
+    use super::Timeouts;
 
     use futures::sync::mpsc;
 
-    use relay_server_common::common::{NOT_A_PEER, NOT_YOUR_TURN, STATE_NOT_INITIALIZED};
-    use relay_server_common::protocol::ProtocolDescriptor;
+    use relay_server_common::common::RelayError;
+    use relay_server_common::encryption::EncryptionKeyPair;
+    use relay_server_common::identity::{Identity, PeerIdentities, SignedClientMessage};
+    use relay_server_common::protocol::{ProtocolDescriptor, ProtocolRegistry, RoundMode};
     use relay_server_common::{
-        ClientMessage, PeerIdentifier, ProtocolIdentifier, RelayMessage, ServerMessageType,
+        AbortMessage, AbortReason, ClientMessage, PeerIdentifier, ProtocolIdentifier, RelayMessage,
+        ServerMessage, ServerMessageType, ServerResponse,
     };
 
     use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn registration(identity: &Identity) -> SignedClientMessage {
+        let mut msg = ClientMessage::new();
+        msg.register(1, 4, None, 0, Some(EncryptionKeyPair::generate().public_key()));
+        identity.sign(&msg, 0, 0)
+    }
     use std::sync::Arc;
     use std::thread;
 
@@ -378,8 +934,13 @@ mod tests {
         let (tx, _) = mpsc::channel(0);
         rs.insert_new_connection(client_addr.clone(), Client::new(tx));
 
-        let peer_num = rs.register_new_peer(client_addr, protocol_id, capacity);
-        assert_eq!(peer_num, Some(1));
+        let peer_num = rs.register_new_peer(
+            client_addr,
+            protocol_id,
+            capacity,
+            Identity::generate().key(),
+        );
+        assert_eq!(peer_num, Ok(1));
     }
 
     #[test]
@@ -394,7 +955,12 @@ mod tests {
             let (tx, _) = mpsc::channel(0);
             rs.insert_new_connection(client_addr.clone(), Client::new(tx));
             peer_num = rs
-                .register_new_peer(client_addr, protocol_id, capacity)
+                .register_new_peer(
+                    client_addr,
+                    protocol_id,
+                    capacity,
+                    Identity::generate().key(),
+                )
                 .expect("Unable to register");
         }
 
@@ -417,7 +983,12 @@ mod tests {
             children.push(thread::spawn(move || {
                 rs_inner.insert_new_connection(client_addr.clone(), Client::new(tx));
                 rs_inner
-                    .register_new_peer(client_addr, protocol_id, capacity)
+                    .register_new_peer(
+                        client_addr,
+                        protocol_id,
+                        capacity,
+                        Identity::generate().key(),
+                    )
                     .expect("Unable to register");
             }));
         }
@@ -439,7 +1010,14 @@ mod tests {
         let rs = RelaySession::new(capacity);
         let (tx, _) = mpsc::channel(0);
         rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-        assert!(rs.can_register(&client_addr, protocol_descriptor))
+        assert_eq!(
+            Ok(()),
+            rs.can_register(
+                &client_addr,
+                protocol_descriptor,
+                &Identity::generate().key()
+            )
+        )
     }
 
     #[test]
@@ -451,7 +1029,24 @@ mod tests {
         let rs = RelaySession::new(capacity);
         let (tx, _) = mpsc::channel(0);
         rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        assert_eq!(
+            Err(RelayError::UnknownProtocol),
+            rs.can_register(
+                &client_addr,
+                protocol_descriptor,
+                &Identity::generate().key()
+            )
+        );
+        // A known protocol with a capacity it does not support
+        let protocol_descriptor = ProtocolDescriptor::new(1, 6);
+        assert_eq!(
+            Err(RelayError::InvalidProtocol),
+            rs.can_register(
+                &client_addr,
+                protocol_descriptor,
+                &Identity::generate().key()
+            )
+        )
     }
 
     #[test]
@@ -461,7 +1056,14 @@ mod tests {
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
         let rs = RelaySession::new(capacity);
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        assert_eq!(
+            Err(RelayError::CantRegister),
+            rs.can_register(
+                &client_addr,
+                protocol_descriptor,
+                &Identity::generate().key()
+            )
+        )
     }
 
     #[test]
@@ -471,8 +1073,20 @@ mod tests {
         let capacity: u32 = 5;
         let protocol_descriptor = ProtocolDescriptor::new(protocol_id, capacity);
         let rs = RelaySession::new(capacity);
-        rs.register(client_addr, protocol_id, capacity);
-        assert!(!rs.can_register(&client_addr, protocol_descriptor))
+        let _ = rs.register(
+            client_addr,
+            protocol_id,
+            capacity,
+            registration(&Identity::generate()),
+        );
+        assert_eq!(
+            Err(RelayError::CantRegister),
+            rs.can_register(
+                &client_addr,
+                protocol_descriptor,
+                &Identity::generate().key()
+            )
+        )
     }
 
     /////////////////////////// test register ///////////////////////////////////
@@ -488,20 +1102,52 @@ mod tests {
             let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
             let (tx, _) = mpsc::channel(0);
             rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-            rs.register(client_addr, protocol_id, capacity);
+            rs.register(
+                client_addr,
+                protocol_id,
+                capacity,
+                registration(&Identity::generate()),
+            )
+            .expect("Unable to register");
             // State is not initialized when not all are connected
             assert_eq!(RelaySessionState::Uninitialized, rs.state());
         }
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity - 1).parse().unwrap();
         let (tx, _) = mpsc::channel(0);
         rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-        let messages = rs.register(client_addr, protocol_id, capacity);
+        let messages = rs
+            .register(
+                client_addr,
+                protocol_id,
+                capacity,
+                registration(&Identity::generate()),
+            )
+            .expect("Unable to register");
         // Once all are connected, state should initialize
         assert_eq!(RelaySessionState::Initialized, rs.state());
 
         messages
             .iter()
             .for_each(|(msg, _)| assert_eq!(msg.msg_type(), ServerMessageType::Response));
+
+        // Every peer gets the signed registrations of all peers along with its register response
+        assert_eq!(messages.len(), 2 * capacity as usize);
+        let registrations = messages
+            .iter()
+            .filter_map(|(msg, _)| match msg.response {
+                Some(ServerResponse::Peers(ref registrations)) => Some(registrations.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(registrations.len(), capacity as usize);
+        assert_eq!(registrations[0].len(), capacity as usize);
+        let mut identities = PeerIdentities::new();
+        for (peer_id, registration) in registrations[0].iter() {
+            identities
+                .add_peer(*peer_id, registration)
+                .expect("Invalid registration");
+            assert!(identities.encryption_key(peer_id).is_some());
+        }
     }
 
     /////////////////////////// test abort ///////////////////////////////////
@@ -516,7 +1162,13 @@ mod tests {
             let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
             let (tx, _) = mpsc::channel(0);
             rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-            rs.register(client_addr, protocol_id, capacity);
+            rs.register(
+                client_addr,
+                protocol_id,
+                capacity,
+                registration(&Identity::generate()),
+            )
+            .expect("Unable to register");
             // State is not initialized when not all are connected
             assert_eq!(RelaySessionState::Uninitialized, rs.state());
         }
@@ -531,6 +1183,133 @@ mod tests {
             .for_each(|(msg, _)| assert_eq!(msg.msg_type(), ServerMessageType::Abort));
     }
 
+    #[test]
+    fn test_abort_blaming() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let addrs: Vec<SocketAddr> = (1..=capacity)
+            .map(|i| format!("127.0.0.1:808{}", i).parse().unwrap())
+            .collect();
+        let new_session = || {
+            let rs = RelaySession::new(capacity);
+            for (addr, identity) in addrs.iter().zip(identities.iter()) {
+                let (tx, _) = mpsc::channel(0);
+                rs.insert_new_connection(*addr, Client::new(tx));
+                rs.register(*addr, protocol_id, capacity, registration(identity))
+                    .expect("Unable to register");
+            }
+            rs
+        };
+        // two different messages peer 2 signed
+        let evidence: Vec<SignedClientMessage> = ["first", "second"]
+            .iter()
+            .map(|payload| {
+                let mut msg = prepare_relay_message(2, protocol_id, &vec![1]);
+                msg.relay_message.as_mut().unwrap().message = payload.to_string();
+                identities[1].sign(&msg, 0, 0)
+            })
+            .collect();
+
+        let rs = new_session();
+        let abort = AbortMessage::blame(1, protocol_id, AbortReason::Equivocation, 2, evidence);
+        let (blame, messages) = rs.abort_blaming(addrs[0], &abort);
+        let blame = blame.expect("The blame should hold");
+        assert_eq!((blame.accuser, blame.culprit), (1, 2));
+        assert_eq!(blame.identity, identities[1].key());
+        assert_eq!(rs.blames(), vec![blame]);
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        assert_eq!(messages.len(), capacity as usize);
+        for (msg, _) in messages {
+            let abort = msg.abort.unwrap();
+            assert_eq!(abort.culprit, Some(2));
+            assert_eq!(abort.evidence.len(), 2);
+        }
+
+        // a peer can not be blamed with messages it did not sign
+        let rs = new_session();
+        let msg = prepare_relay_message(2, protocol_id, &vec![1]);
+        let forged = vec![identities[0].sign(&msg, 0, 0)];
+        let abort = AbortMessage::blame(1, protocol_id, AbortReason::MalformedMessage, 2, forged);
+        let (blame, messages) = rs.abort_blaming(addrs[0], &abort);
+        assert_eq!(blame, None);
+        assert!(rs.blames().is_empty());
+        // the session is still aborted, without blaming any peer
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        for (msg, _) in messages {
+            assert_eq!(msg.abort.unwrap().culprit, None);
+        }
+
+        // nor blame itself
+        let rs = new_session();
+        let abort = AbortMessage::blame(1, protocol_id, AbortReason::Unresponsive, 1, vec![]);
+        assert_eq!(rs.abort_blaming(addrs[0], &abort).0, None);
+    }
+
+    #[test]
+    fn test_expire_deadline() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let timeouts = Timeouts {
+            registration: Some(Duration::from_secs(60)),
+            round: Some(Duration::from_secs(30)),
+        };
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let addrs: Vec<SocketAddr> = (1..=capacity)
+            .map(|i| format!("127.0.0.1:808{}", i).parse().unwrap())
+            .collect();
+        let register = |rs: &RelaySession, i: usize| {
+            let (tx, _) = mpsc::channel(0);
+            rs.insert_new_connection(addrs[i], Client::new(tx));
+            rs.register(
+                addrs[i],
+                protocol_id,
+                capacity,
+                registration(&identities[i]),
+            )
+            .expect("Unable to register");
+        };
+
+        // a session that does not fill up in time is aborted without naming any peer
+        let rs = RelaySession::with_timeouts(capacity, timeouts);
+        register(&rs, 0);
+        assert!(rs.expire_deadline(Instant::now()).is_empty());
+        let messages = rs.expire_deadline(Instant::now() + Duration::from_secs(61));
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        assert_eq!(messages.len(), 1);
+        let abort = messages[0].0.abort.clone().unwrap();
+        assert_eq!(abort.reason, AbortReason::Unresponsive);
+        assert!(abort.unresponsive.is_empty());
+
+        // once full, the peer whose turn it is has until the round deadline to send
+        let rs = RelaySession::with_timeouts(capacity, timeouts);
+        for i in 0..capacity as usize {
+            register(&rs, i);
+        }
+        let msg = prepare_relay_message(1, protocol_id, &vec![2, 3]);
+        let envelope = identities[0].sign(&msg, 0, 0);
+        rs.relay_message(&addrs[0], msg.relay_message.unwrap(), envelope);
+        assert!(rs
+            .expire_deadline(Instant::now() + Duration::from_secs(29))
+            .is_empty());
+        assert_eq!(RelaySessionState::Initialized, rs.state());
+        let messages = rs.expire_deadline(Instant::now() + Duration::from_secs(31));
+        assert_eq!(RelaySessionState::Aborted, rs.state());
+        assert_eq!(messages.len(), capacity as usize);
+        for (msg, _) in messages {
+            let abort = msg.abort.unwrap();
+            assert_eq!(abort.peer_number, 0);
+            assert_eq!(abort.unresponsive, vec![2]);
+        }
+
+        // a session without deadlines waits forever
+        let rs = RelaySession::new(capacity);
+        register(&rs, 0);
+        assert!(rs
+            .expire_deadline(Instant::now() + Duration::from_secs(3600))
+            .is_empty());
+    }
+
     fn prepare_relay_message(
         peer_id: PeerIdentifier,
         protocol_id: ProtocolIdentifier,
@@ -551,42 +1330,101 @@ mod tests {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
         let rs = RelaySession::new(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
 
         // Add all but the last peer to the session
         for i in 0..capacity - 1 {
             let client_addr: SocketAddr = format!("127.0.0.1:808{}", i + 1).parse().unwrap();
+            let identity = &identities[i as usize];
             let (tx, _) = mpsc::channel(0);
             rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-            rs.register(client_addr, protocol_id, capacity);
+            rs.register(
+                client_addr,
+                protocol_id,
+                capacity,
+                registration(&identities[i as usize]),
+            )
+            .expect("Unable to register");
             let msg = prepare_relay_message(i, protocol_id, &vec![]);
             assert_eq!(
-                Err(STATE_NOT_INITIALIZED),
-                rs.can_relay(&client_addr, &msg.relay_message.unwrap())
+                Err(RelayError::StateNotInitialized),
+                rs.can_relay(
+                    &client_addr,
+                    &identity.sign(&msg, 0, 0),
+                    &msg.relay_message.unwrap()
+                )
             );
         }
         // Add the last peer to the session
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity).parse().unwrap();
+        let identity = &identities[capacity as usize - 1];
         let (tx, _) = mpsc::channel(0);
         rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-        rs.register(client_addr, protocol_id, capacity);
+        rs.register(
+            client_addr,
+            protocol_id,
+            capacity,
+            registration(&identities[capacity as usize - 1]),
+        )
+        .expect("Unable to register");
         // Try to relay when not your turn
         let msg = prepare_relay_message(capacity, protocol_id, &vec![]);
         //rs.can_relay(&client_addr, &msg.relay_message.unwrap());
         assert_eq!(
-            Err(NOT_YOUR_TURN),
-            rs.can_relay(&client_addr, &msg.relay_message.unwrap())
+            Err(RelayError::NotYourTurn),
+            rs.can_relay(
+                &client_addr,
+                &identity.sign(&msg, 0, 0),
+                &msg.relay_message.unwrap()
+            )
         );
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", capacity + 1).parse().unwrap();
         let msg = prepare_relay_message(capacity, protocol_id, &vec![]);
         assert_eq!(
-            Err(NOT_A_PEER),
-            rs.can_relay(&client_addr, &msg.relay_message.unwrap())
+            Err(RelayError::NotAPeer),
+            rs.can_relay(
+                &client_addr,
+                &identity.sign(&msg, 0, 0),
+                &msg.relay_message.unwrap()
+            )
         );
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", 1).parse().unwrap();
-        let msg = prepare_relay_message(1, protocol_id, &vec![]);
+        let msg = prepare_relay_message(1, protocol_id, &vec![2, 3, 4]);
+        // Try to relay a message signed by another identity
+        assert_eq!(
+            Err(RelayError::InvalidSignature),
+            rs.can_relay(
+                &client_addr,
+                &identity.sign(&msg, 0, 0),
+                &msg.clone().relay_message.unwrap()
+            )
+        );
+        // The protocol requires every message to be sent to all the other peers
+        let partial = prepare_relay_message(1, protocol_id, &vec![2, 3]);
+        assert_eq!(
+            Err(RelayError::InvalidRecipients),
+            rs.can_relay(
+                &client_addr,
+                &identities[0].sign(&partial, 0, 0),
+                &partial.relay_message.unwrap()
+            )
+        );
+        // The message must be signed for the round the session is in
+        assert_eq!(
+            Err(RelayError::WrongRound),
+            rs.can_relay(
+                &client_addr,
+                &identities[0].sign(&msg, 0, 1),
+                &msg.clone().relay_message.unwrap()
+            )
+        );
         assert_eq!(
             Ok(()),
-            rs.can_relay(&client_addr, &msg.relay_message.unwrap())
+            rs.can_relay(
+                &client_addr,
+                &identities[0].sign(&msg, 0, 0),
+                &msg.relay_message.unwrap()
+            )
         );
     }
 
@@ -596,19 +1434,203 @@ mod tests {
         let protocol_id: ProtocolIdentifier = 1;
         let capacity: u32 = 4;
         let rs = RelaySession::new(capacity);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
 
         // Add all peers to the session
         for i in 0..capacity {
             let client_addr: SocketAddr = format!("127.0.0.1:808{}", i).parse().unwrap();
             let (tx, _) = mpsc::channel(0);
             rs.insert_new_connection(client_addr.clone(), Client::new(tx));
-            rs.register(client_addr, protocol_id, capacity);
+            rs.register(
+                client_addr,
+                protocol_id,
+                capacity,
+                registration(&identities[i as usize]),
+            )
+            .expect("Unable to register");
         }
         let client_num = 1;
         let msg = prepare_relay_message(client_num, protocol_id, &vec![2, 3, 4]);
         let client_addr: SocketAddr = format!("127.0.0.1:808{}", client_num - 1).parse().unwrap();
-        let messages_to_send = rs.relay_message(&client_addr, msg.relay_message.unwrap());
+
+        // A message signed by another peer is returned to the sender as an error
+        let envelope = identities[1].sign(&msg, 0, 0);
+        let messages_to_send =
+            rs.relay_message(&client_addr, msg.clone().relay_message.unwrap(), envelope);
+        assert_eq!(messages_to_send.len(), 1);
+        match messages_to_send[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::InvalidSignature)) => {}
+            _ => panic!("Expected an invalid signature error"),
+        }
+
+        // The envelope is relayed along with the message
+        let envelope = identities[0].sign(&msg, 0, 0);
+        let messages_to_send = rs.relay_message(&client_addr, msg.relay_message.unwrap(), envelope);
         assert_eq!(messages_to_send.len(), 3);
+        messages_to_send
+            .iter()
+            .for_each(|(msg, _)| assert!(msg.signed_message.is_some()));
+    }
+
+    #[test]
+    fn test_broadcast_round() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let timeouts = Timeouts {
+            registration: None,
+            round: Some(Duration::from_secs(30)),
+        };
+        let rs = RelaySession::with_timeouts(capacity, timeouts);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let addrs: Vec<SocketAddr> = (1..=capacity)
+            .map(|i| format!("127.0.0.1:808{}", i).parse().unwrap())
+            .collect();
+        for i in 0..capacity as usize {
+            let (tx, _) = mpsc::channel(0);
+            rs.insert_new_connection(addrs[i], Client::new(tx));
+            rs.register(
+                addrs[i],
+                protocol_id,
+                capacity,
+                registration(&identities[i]),
+            )
+            .expect("Unable to register");
+        }
+        let mut protocol = ProtocolDescriptor::new(protocol_id, capacity);
+        protocol.mode = RoundMode::Broadcast;
+        rs.set_protocol(protocol);
+        let send = |peer_id: PeerIdentifier, round: u32, payload: &str| {
+            let mut msg = prepare_relay_message(peer_id, protocol_id, &vec![1, 2, 3]);
+            msg.relay_message.as_mut().unwrap().message = payload.to_string();
+            let envelope = identities[peer_id as usize - 1].sign(&msg, 0, round);
+            rs.relay_message(
+                &addrs[peer_id as usize - 1],
+                msg.relay_message.unwrap(),
+                envelope,
+            )
+        };
+
+        // peers send out of order, and the messages are held until all of them sent
+        assert!(send(2, 0, "second").is_empty());
+        // the same message sent again is dropped, a different one is rejected
+        assert!(send(2, 0, "second").is_empty());
+        let messages = send(2, 0, "other");
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::AlreadySent)) => {}
+            _ => panic!("Expected an already sent error"),
+        }
+        assert!(send(3, 0, "third").is_empty());
+        let messages = send(1, 0, "first");
+        assert_eq!(messages.len(), (capacity * capacity) as usize);
+        let senders: Vec<PeerIdentifier> = messages
+            .iter()
+            .map(|(msg, _)| msg.relay_message.as_ref().unwrap().peer_number)
+            .collect();
+        assert_eq!(senders, vec![1, 1, 1, 2, 2, 2, 3, 3, 3]);
+
+        // a message of the last round sent again is not taken for the next round
+        assert!(send(3, 0, "third").is_empty());
+        // while a peer can send the same payload in the next round
+        assert!(send(1, 1, "first").is_empty());
+        assert!(send(1, 1, "first").is_empty());
+        let messages = rs.expire_deadline(Instant::now() + Duration::from_secs(31));
+        assert_eq!(
+            messages[0].0.abort.as_ref().unwrap().unresponsive,
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn test_protocol_limits() {
+        let path =
+            std::env::temp_dir().join(format!("protocols-limits-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"protocols":[{"id":7,"names":["limited"],"capacities":{"min":2,"max":3},
+                "rounds":1,"max_payload":8,"timeouts":{"registration":2}}]}"#,
+        )
+        .unwrap();
+        let protocols = Arc::new(ProtocolRegistry::load(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let protocol_id: ProtocolIdentifier = 7;
+        let capacity: u32 = 2;
+
+        // the protocol sets a registration deadline the relay did not
+        let rs = RelaySession::with_protocols(3, Timeouts::default(), Arc::clone(&protocols));
+        let addr: SocketAddr = "127.0.0.1:8090".parse().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        rs.insert_new_connection(addr, Client::new(tx));
+        rs.register(addr, protocol_id, 3, registration(&Identity::generate()))
+            .expect("Unable to register");
+        assert!(!rs
+            .expire_deadline(Instant::now() + Duration::from_secs(3))
+            .is_empty());
+
+        let rs = RelaySession::with_protocols(capacity, Timeouts::default(), protocols);
+        let identities: Vec<Identity> = (0..capacity).map(|_| Identity::generate()).collect();
+        let addrs: Vec<SocketAddr> = (1..=capacity)
+            .map(|i| format!("127.0.0.1:808{}", i).parse().unwrap())
+            .collect();
+        for i in 0..capacity as usize {
+            let (tx, _) = mpsc::channel(0);
+            rs.insert_new_connection(addrs[i], Client::new(tx));
+            rs.register(
+                addrs[i],
+                protocol_id,
+                capacity,
+                registration(&identities[i]),
+            )
+            .expect("Unable to register");
+        }
+        let send = |peer_id: PeerIdentifier, payload: &str| {
+            let to = vec![capacity + 1 - peer_id];
+            let mut msg = prepare_relay_message(peer_id, protocol_id, &to);
+            msg.relay_message.as_mut().unwrap().message = payload.to_string();
+            let envelope = identities[peer_id as usize - 1].sign(&msg, 0, rs.round());
+            rs.relay_message(
+                &addrs[peer_id as usize - 1],
+                msg.relay_message.unwrap(),
+                envelope,
+            )
+        };
+        let error = |messages: Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>| match messages[0]
+            .0
+            .response
+        {
+            Some(ServerResponse::ErrorResponse(err)) => err,
+            _ => panic!("Expected an error response"),
+        };
+
+        assert_eq!(error(send(1, "too large")), RelayError::PayloadTooLarge);
+        assert_eq!(send(1, "first").len(), 1);
+        assert_eq!(send(2, "second").len(), 1);
+        assert_eq!(rs.round(), 1);
+        // the session ran all of its rounds, and waits for no one
+        assert_eq!(error(send(1, "third")), RelayError::TooManyRounds);
+        assert!(rs
+            .expire_deadline(Instant::now() + Duration::from_secs(3600))
+            .is_empty());
     }
 
+    #[test]
+    fn test_register_same_identity() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let rs = RelaySession::new(capacity);
+        let identity = Identity::generate();
+
+        let first: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:8082".parse().unwrap();
+        for addr in &[first, second] {
+            let (tx, _) = mpsc::channel(0);
+            rs.insert_new_connection(*addr, Client::new(tx));
+        }
+        rs.register(first, protocol_id, capacity, registration(&identity))
+            .expect("Unable to register");
+        assert_eq!(
+            Err(RelayError::CantRegister),
+            rs.register(second, protocol_id, capacity, registration(&identity))
+                .map(|_| ())
+        );
+    }
 }
@@ -0,0 +1,1005 @@
+use futures::sync::mpsc;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use relay_server_common::{
+    AbortMessage, Challenge, PeerIdentifier, ProtocolIdentifier, RelayMessage, ResumptionToken,
+    ServerMessage, ServerResponse, SessionIdentifier,
+};
+
+use relay_server_common::common::RelayError;
+use relay_server_common::identity::{IdentityKey, SignedClientMessage};
+use relay_server_common::protocol::ProtocolRegistry;
+
+use crate::relay_session::{Client, Peer, RelaySession, RelaySessionState, Timeouts};
+
+/// Holds all the relay sessions served by the server.
+/// A connection is pending until it registers, at which point it is moved
+/// to the relay session it registered to
+#[derive(Debug, Clone)]
+pub struct SessionManager {
+    sessions: Arc<RwLock<HashMap<SessionIdentifier, RelaySession>>>,
+
+    // connections that did not register to any session yet
+    pending: Arc<RwLock<HashMap<SocketAddr, Client>>>,
+
+    // the challenge each pending connection was sent, its register message must carry it
+    challenges: Arc<RwLock<HashMap<SocketAddr, Challenge>>>,
+
+    // the session each registered connection belongs to
+    session_of: Arc<RwLock<HashMap<SocketAddr, SessionIdentifier>>>,
+
+    // whether identities blamed for misbehaving are refused from registering again
+    ban_blamed: bool,
+
+    // identities refused from registering to any session
+    banned: Arc<RwLock<HashSet<IdentityKey>>>,
+
+    // deadlines of every session created
+    timeouts: Timeouts,
+
+    // the protocols the sessions can run
+    protocols: Arc<ProtocolRegistry>,
+}
+
+impl SessionManager {
+    /// Creates a new SessionManager with no sessions, supporting the protocols of the registry
+    pub fn new(protocols: Arc<ProtocolRegistry>) -> SessionManager {
+        SessionManager {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+
+            pending: Arc::new(RwLock::new(HashMap::new())),
+
+            challenges: Arc::new(RwLock::new(HashMap::new())),
+
+            session_of: Arc::new(RwLock::new(HashMap::new())),
+
+            ban_blamed: false,
+
+            banned: Arc::new(RwLock::new(HashSet::new())),
+
+            timeouts: Timeouts::default(),
+
+            protocols,
+        }
+    }
+
+    /// Sets the deadlines of the sessions created from now on
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Sets whether an identity blamed for misbehaving in a session,
+    /// with evidence it signed, is refused from registering to any session again
+    pub fn set_ban_blamed(&mut self, ban_blamed: bool) {
+        self.ban_blamed = ban_blamed;
+    }
+
+    /// Returns true if the identity is refused from registering to any session
+    pub fn is_banned(&self, identity: &IdentityKey) -> bool {
+        self.banned.read().unwrap().contains(identity)
+    }
+
+    /// Accepts a new connection to the server, which is pending until it registers.
+    /// Returns the challenge to send to the connection, which its register message must carry
+    pub fn connect(
+        &self,
+        addr: SocketAddr,
+        client: Client,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let challenge: Challenge = rand::random();
+        self.challenges.write().unwrap().insert(addr, challenge);
+        let sender = client.sender();
+        self.insert_new_connection(addr, client);
+        let mut server_msg = ServerMessage::new();
+        server_msg.response = Some(ServerResponse::Challenge(challenge));
+        vec![(server_msg, sender)]
+    }
+
+    /// Inserts a new connection to the server.
+    /// the connection is not part of any session until it is registered
+    /// by sending a register message
+    pub fn insert_new_connection(&self, addr: SocketAddr, client: Client) {
+        self.pending.write().unwrap().insert(addr, client);
+    }
+
+    /// Returns the number of sessions currently held by the server
+    pub fn number_of_sessions(&self) -> usize {
+        self.sessions.read().unwrap().len()
+    }
+
+    /// Returns a copy of the session with the given identifier
+    pub fn get_session(&self, session_id: &SessionIdentifier) -> Option<RelaySession> {
+        self.sessions.read().unwrap().get(session_id).cloned()
+    }
+
+    /// Returns the session a registered address belongs to
+    pub fn get_session_by_address(&self, addr: &SocketAddr) -> Option<RelaySession> {
+        let session_id = self.session_of.read().unwrap().get(addr).cloned()?;
+        self.get_session(&session_id)
+    }
+
+    /// Returns the identifier of an open session of the sessions for the given protocol,
+    /// meaning a session that still waits for peers to register
+    fn find_open_session(
+        sessions: &HashMap<SessionIdentifier, RelaySession>,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> Option<SessionIdentifier> {
+        sessions
+            .iter()
+            .find(|(_, session)| {
+                let protocol = session.protocol();
+                session.state() == RelaySessionState::Uninitialized
+                    && protocol.id == protocol_id
+                    && protocol.capacity == capacity
+            })
+            .map(|(session_id, _)| *session_id)
+    }
+
+    /// Creates a new empty session among the sessions and returns its identifier
+    fn create_session(
+        &self,
+        sessions: &mut HashMap<SessionIdentifier, RelaySession>,
+        capacity: u32,
+    ) -> SessionIdentifier {
+        let mut session_id: SessionIdentifier = rand::random();
+        while sessions.contains_key(&session_id) {
+            session_id = rand::random();
+        }
+        sessions.insert(
+            session_id,
+            RelaySession::with_protocols(capacity, self.timeouts, Arc::clone(&self.protocols)),
+        );
+        info!("Created relay session {}", session_id);
+        session_id
+    }
+
+    /// Register a connection to a relay session, with the register message it signed.
+    /// The message must carry the challenge the connection was sent, so a registration
+    /// captured from another connection can not take the identity of its peer.
+    /// If a session identifier is given, the connection joins that session,
+    /// otherwise it joins an open session for the same protocol,
+    /// or a new session is created for it.
+    /// Return a vector of messages to send: the session identifier to the registering peer,
+    /// and register messages to all peers of the session if it is now initialized
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        session_id: Option<SessionIdentifier>,
+        challenge: Challenge,
+        registration: SignedClientMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let client = match self.pending.write().unwrap().remove(&addr) {
+            Some(client) => client,
+            None => {
+                warn!("{:} is not connected or already registered", addr);
+                return self.error_response(&addr, RelayError::CantRegister);
+            }
+        };
+        if self.challenges.read().unwrap().get(&addr) != Some(&challenge) {
+            warn!(
+                "{:} registered without the challenge of its connection",
+                addr
+            );
+            let sender = client.sender();
+            self.insert_new_connection(addr, client);
+            return vec![(error_message(RelayError::InvalidSignature), sender)];
+        }
+        if self.is_banned(&registration.identity) {
+            warn!("{:} registered with a banned identity", addr);
+            let sender = client.sender();
+            self.insert_new_connection(addr, client);
+            return vec![(error_message(RelayError::Banned), sender)];
+        }
+
+        // the session is found or created and the peer registered to it under one lock,
+        // so peers registering at once join the same open session rather than each creating one
+        let mut sessions = self.sessions.write().unwrap();
+        let session_id = match session_id {
+            Some(session_id) => session_id,
+            None => match SessionManager::find_open_session(&sessions, protocol_id, capacity) {
+                Some(session_id) => session_id,
+                None => self.create_session(&mut sessions, capacity),
+            },
+        };
+
+        let session = match sessions.get(&session_id) {
+            Some(session) => session.clone(),
+            None => {
+                drop(sessions);
+                warn!("{:} requested unknown session {}", addr, session_id);
+                let sender = client.sender();
+                self.insert_new_connection(addr, client);
+                return vec![(error_message(RelayError::UnknownSession), sender)];
+            }
+        };
+        session.insert_new_connection(addr, client.clone());
+        let registered = session.register(addr, protocol_id, capacity, registration);
+        if registered.is_err() {
+            session.remove(&addr);
+            if session.state() == RelaySessionState::Empty {
+                sessions.remove(&session_id);
+            }
+        }
+        drop(sessions);
+        let mut messages_to_send = match registered {
+            Ok(messages_to_send) => messages_to_send,
+            Err(err) => {
+                // registration failed, the connection returns to be pending
+                let sender = client.sender();
+                self.insert_new_connection(addr, client);
+                return vec![(error_message(err), sender)];
+            }
+        };
+
+        debug!("Registered {:} to session {}", addr, session_id);
+        self.challenges.write().unwrap().remove(&addr);
+        self.session_of.write().unwrap().insert(addr, session_id);
+        let mut server_msg = ServerMessage::new();
+        server_msg.response = Some(ServerResponse::Session(session_id));
+        messages_to_send.insert(0, (server_msg, client.sender()));
+        messages_to_send
+    }
+
+    /// Resume a relay session from a new connection,
+    /// for a registered peer that lost its previous connection.
+    /// Return a vector of messages to send: a resumed response to the peer,
+    /// followed by the messages it missed while disconnected
+    pub fn resume(
+        &self,
+        addr: SocketAddr,
+        session_id: SessionIdentifier,
+        peer_id: PeerIdentifier,
+        token: ResumptionToken,
+        identity: IdentityKey,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let client = match self.pending.write().unwrap().remove(&addr) {
+            Some(client) => client,
+            None => {
+                warn!("{:} is not connected or already registered", addr);
+                return self.error_response(&addr, RelayError::CantRegister);
+            }
+        };
+
+        let result = match self.get_session(&session_id) {
+            Some(session) => session.resume(addr, client.clone(), peer_id, token, identity),
+            None => Err(RelayError::UnknownSession),
+        };
+        match result {
+            Ok((old_addr, messages_to_send)) => {
+                self.challenges.write().unwrap().remove(&addr);
+                let mut session_of = self.session_of.write().unwrap();
+                session_of.remove(&old_addr);
+                session_of.insert(addr, session_id);
+                messages_to_send
+            }
+            Err(err) => {
+                warn!("{:} can not resume session {}: {}", addr, session_id, err);
+                let sender = client.sender();
+                self.insert_new_connection(addr, client);
+                vec![(error_message(err), sender)]
+            }
+        }
+    }
+
+    /// Returns true if the envelope is signed for the session the given address belongs to
+    pub fn is_signed_for_session(&self, addr: &SocketAddr, envelope: &SignedClientMessage) -> bool {
+        self.session_of.read().unwrap().get(addr) == Some(&envelope.session_id)
+    }
+
+    /// Relay a message sent from the given address, in the signed envelope it was sent in,
+    /// in the session it belongs to. The envelope must be signed for that session
+    pub fn relay_message(
+        &self,
+        from: &SocketAddr,
+        msg: RelayMessage,
+        envelope: SignedClientMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        if !self.is_signed_for_session(from, &envelope) {
+            warn!("Message of {:} is not signed for its session", from);
+            return self.error_response(from, RelayError::InvalidSignature);
+        }
+        match self.get_session_by_address(from) {
+            Some(session) => session.relay_message(from, msg, envelope),
+            None => vec![],
+        }
+    }
+
+    /// Abort the session the given address belongs to
+    pub fn abort(&self, addr: SocketAddr) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_session_by_address(&addr) {
+            Some(session) => session.abort(addr),
+            None => vec![],
+        }
+    }
+
+    /// Abort the session the given address belongs to, on an abort that blames another peer.
+    /// The evidence must be signed for that session, or the session is aborted without blame.
+    /// If the blame holds and blamed identities are banned, the culprit is banned
+    pub fn abort_blaming(
+        &self,
+        addr: SocketAddr,
+        abort: &AbortMessage,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let session = match self.get_session_by_address(&addr) {
+            Some(session) => session,
+            None => return vec![],
+        };
+        if !abort
+            .evidence
+            .iter()
+            .all(|envelope| self.is_signed_for_session(&addr, envelope))
+        {
+            warn!("Ignoring blame with evidence of another session");
+            return session.abort(addr);
+        }
+        let (blame, messages_to_send) = session.abort_blaming(addr, abort);
+        if let Some(blame) = blame {
+            if self.ban_blamed {
+                warn!("Banning the identity of peer {}", blame.culprit);
+                self.banned.write().unwrap().insert(blame.identity);
+            }
+        }
+        messages_to_send
+    }
+
+    /// Aborts every session that missed its deadline by the given time.
+    /// Returns the abort messages to send to the peers of those sessions
+    pub fn expire_deadlines(
+        &self,
+        now: Instant,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let sessions: Vec<RelaySession> = self.sessions.read().unwrap().values().cloned().collect();
+        sessions
+            .iter()
+            .flat_map(|session| session.expire_deadline(now))
+            .collect()
+    }
+
+    /// Handles a closed connection.
+    /// A registered peer of an active session is suspended, and may resume the session
+    /// from a new connection. Any other connection is removed
+    pub fn disconnect(
+        &self,
+        addr: SocketAddr,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        self.pending.write().unwrap().remove(&addr);
+        self.challenges.write().unwrap().remove(&addr);
+        if let Some(session) = self.get_session_by_address(&addr) {
+            if session.suspend(&addr) {
+                info!("{:} disconnected, waiting for it to resume", addr);
+                return vec![];
+            }
+        }
+        self.remove_connection(addr)
+    }
+
+    /// Returns true if addr is a disconnected peer that may still resume its session
+    pub fn is_suspended(&self, addr: &SocketAddr) -> bool {
+        match self.get_session_by_address(addr) {
+            Some(session) => session.is_suspended(addr),
+            None => false,
+        }
+    }
+
+    /// Removes a disconnected peer that did not resume its session in time
+    pub fn expire(&self, addr: SocketAddr) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        if !self.is_suspended(&addr) {
+            return vec![];
+        }
+        warn!("{:} did not resume its session", addr);
+        self.remove_connection(addr)
+    }
+
+    // Removes a connection from the session it belongs to.
+    // Aborts the session, and removes the session once all of its connections are closed
+    fn remove_connection(
+        &self,
+        addr: SocketAddr,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let session_id = match self.session_of.write().unwrap().remove(&addr) {
+            Some(session_id) => session_id,
+            None => return vec![],
+        };
+        let session = match self.get_session(&session_id) {
+            Some(session) => session,
+            None => return vec![],
+        };
+        let messages_to_send = session.abort(addr);
+        session.remove(&addr);
+        if session.number_of_connections() == 0 {
+            info!("Removing relay session {}", session_id);
+            self.sessions.write().unwrap().remove(&session_id);
+        }
+        messages_to_send
+    }
+
+    /// get a copy of the registered Peer that addr represents
+    pub fn get_peer_by_address(&self, addr: &SocketAddr) -> Option<Peer> {
+        self.get_session_by_address(addr)?.get_peer_by_address(addr)
+    }
+
+    /// Try return a Sender of a specific connection by its address,
+    /// whether it is registered to a session or not
+    pub fn get_sender_by_address(&self, addr: &SocketAddr) -> Option<mpsc::Sender<ServerMessage>> {
+        if let Some(client) = self.pending.read().unwrap().get(addr) {
+            return Some(client.sender());
+        }
+        self.get_session_by_address(addr)?
+            .get_sender_by_address(addr)
+    }
+
+    /// Return an error response to the connection at addr, if it is still connected
+    pub fn error_response(
+        &self,
+        addr: &SocketAddr,
+        err: RelayError,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        match self.get_sender_by_address(addr) {
+            Some(sender) => vec![(error_message(err), sender)],
+            None => vec![],
+        }
+    }
+}
+
+fn error_message(err: RelayError) -> ServerMessage {
+    let mut server_msg = ServerMessage::new();
+    server_msg.response = Some(ServerResponse::ErrorResponse(err));
+    server_msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionManager;
+    use crate::relay_session::{Client, RelaySessionState, Timeouts};
+
+    use futures::sync::mpsc;
+
+    use relay_server_common::common::RelayError;
+    use relay_server_common::identity::{Identity, SignedClientMessage};
+    use relay_server_common::protocol::ProtocolRegistry;
+    use relay_server_common::{
+        AbortMessage, AbortReason, Challenge, ClientMessage, PeerIdentifier, ProtocolIdentifier,
+        RelayMessage, ResumptionToken, ServerMessage, ServerResponse, SessionIdentifier,
+    };
+
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // A manager of sessions running the protocols of the default protocols file
+    fn session_manager() -> SessionManager {
+        SessionManager::new(Arc::new(ProtocolRegistry::default()))
+    }
+
+    fn connect(sm: &SessionManager, port: u32) -> SocketAddr {
+        let client_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        sm.connect(client_addr, Client::new(tx));
+        client_addr
+    }
+
+    // The register message of the identity, signed with the challenge of the connection
+    fn registration(
+        sm: &SessionManager,
+        addr: &SocketAddr,
+        session_id: Option<SessionIdentifier>,
+        identity: &Identity,
+    ) -> (Challenge, SignedClientMessage) {
+        let challenge = sm.challenges.read().unwrap()[addr];
+        let mut msg = ClientMessage::new();
+        msg.register(1, 2, session_id, challenge, None);
+        (challenge, identity.sign(&msg, session_id.unwrap_or(0), 0))
+    }
+
+    fn register(
+        sm: &SessionManager,
+        addr: SocketAddr,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+        session_id: Option<SessionIdentifier>,
+        identity: &Identity,
+    ) -> Vec<(ServerMessage, mpsc::Sender<ServerMessage>)> {
+        let (challenge, signed) = registration(sm, &addr, session_id, identity);
+        sm.register(addr, protocol_id, capacity, session_id, challenge, signed)
+    }
+
+    fn registered_session(sm: &SessionManager, addr: &SocketAddr) -> SessionIdentifier {
+        *sm.session_of
+            .read()
+            .unwrap()
+            .get(addr)
+            .expect("Not registered")
+    }
+
+    #[test]
+    fn test_register_creates_session() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let sm = session_manager();
+        let client_addr = connect(&sm, 8081);
+
+        let messages = register(
+            &sm,
+            client_addr,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        assert_eq!(sm.number_of_sessions(), 1);
+        let session_id = registered_session(&sm, &client_addr);
+        match messages[0].0.response {
+            Some(ServerResponse::Session(id)) => assert_eq!(id, session_id),
+            _ => panic!("Expected a session response"),
+        }
+    }
+
+    #[test]
+    fn test_register_joins_open_session() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let sm = session_manager();
+        let first = connect(&sm, 8081);
+        let second = connect(&sm, 8082);
+
+        register(
+            &sm,
+            first,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        register(
+            &sm,
+            second,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        assert_eq!(sm.number_of_sessions(), 1);
+        assert_eq!(
+            registered_session(&sm, &first),
+            registered_session(&sm, &second)
+        );
+        let session = sm.get_session_by_address(&first).unwrap();
+        assert_eq!(session.state(), RelaySessionState::Initialized);
+
+        // the session is full, a new peer opens a new session
+        let third = connect(&sm, 8083);
+        register(
+            &sm,
+            third,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        assert_eq!(sm.number_of_sessions(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_registrations_join_one_session() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 4;
+        let sm = Arc::new(session_manager());
+        let addrs: Vec<SocketAddr> = (0..capacity).map(|i| connect(&sm, 8081 + i)).collect();
+
+        let handles: Vec<_> = addrs
+            .iter()
+            .map(|addr| {
+                let sm = sm.clone();
+                let addr = *addr;
+                thread::spawn(move || {
+                    register(
+                        &sm,
+                        addr,
+                        protocol_id,
+                        capacity,
+                        None,
+                        &Identity::generate(),
+                    );
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(sm.number_of_sessions(), 1);
+        let session_id = registered_session(&sm, &addrs[0]);
+        for addr in &addrs {
+            assert_eq!(registered_session(&sm, addr), session_id);
+        }
+        let session = sm.get_session_by_address(&addrs[0]).unwrap();
+        assert_eq!(session.state(), RelaySessionState::Initialized);
+    }
+
+    #[test]
+    fn test_register_to_session_id() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 3;
+        let sm = session_manager();
+        let first = connect(&sm, 8081);
+        register(
+            &sm,
+            first,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        let session_id = registered_session(&sm, &first);
+
+        // a peer of another protocol capacity opens its own session
+        let other = connect(&sm, 8082);
+        register(&sm, other, protocol_id, 2, None, &Identity::generate());
+        assert_eq!(sm.number_of_sessions(), 2);
+
+        let second = connect(&sm, 8083);
+        register(
+            &sm,
+            second,
+            protocol_id,
+            capacity,
+            Some(session_id),
+            &Identity::generate(),
+        );
+        assert_eq!(registered_session(&sm, &second), session_id);
+        assert_eq!(sm.get_peer_by_address(&second).unwrap().peer_id, 2);
+    }
+
+    #[test]
+    fn test_register_unknown_session() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let sm = session_manager();
+        let client_addr = connect(&sm, 8081);
+
+        let messages = register(
+            &sm,
+            client_addr,
+            protocol_id,
+            capacity,
+            Some(42),
+            &Identity::generate(),
+        );
+        assert_eq!(sm.number_of_sessions(), 0);
+        assert!(sm.get_peer_by_address(&client_addr).is_none());
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::UnknownSession)) => {}
+            _ => panic!("Expected an unknown session error"),
+        }
+        // The connection can still register
+        register(
+            &sm,
+            client_addr,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        assert!(sm.get_peer_by_address(&client_addr).is_some());
+    }
+
+    #[test]
+    fn test_register_invalid_protocol() {
+        let protocol_id: ProtocolIdentifier = 100;
+        let capacity: u32 = 2;
+        let sm = session_manager();
+        let client_addr = connect(&sm, 8081);
+
+        let messages = register(
+            &sm,
+            client_addr,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        assert_eq!(sm.number_of_sessions(), 0);
+        assert!(sm.get_peer_by_address(&client_addr).is_none());
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::UnknownProtocol)) => {}
+            _ => panic!("Expected an unknown protocol error"),
+        }
+    }
+
+    #[test]
+    fn test_register_full_session() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let sm = session_manager();
+        let first = connect(&sm, 8081);
+        let second = connect(&sm, 8082);
+        register(
+            &sm,
+            first,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        register(
+            &sm,
+            second,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        let session_id = registered_session(&sm, &first);
+
+        let third = connect(&sm, 8083);
+        let messages = register(
+            &sm,
+            third,
+            protocol_id,
+            capacity,
+            Some(session_id),
+            &Identity::generate(),
+        );
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::SessionFull)) => {}
+            _ => panic!("Expected a session full error"),
+        }
+
+        // a session waiting for peers rejects a different capacity
+        register(&sm, third, protocol_id, 3, None, &Identity::generate());
+        let session_id = registered_session(&sm, &third);
+        let fourth = connect(&sm, 8084);
+        let messages = register(
+            &sm,
+            fourth,
+            protocol_id,
+            capacity,
+            Some(session_id),
+            &Identity::generate(),
+        );
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::CapacityMismatch)) => {}
+            _ => panic!("Expected a capacity mismatch error"),
+        }
+    }
+
+    #[test]
+    fn test_register_replayed_from_another_connection() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let sm = session_manager();
+        let victim = connect(&sm, 8081);
+        let attacker = connect(&sm, 8082);
+        let identity = Identity::generate();
+
+        // each connection is sent a challenge of its own
+        let (tx, _) = mpsc::channel(0);
+        let other: SocketAddr = "127.0.0.1:8083".parse().unwrap();
+        match sm.connect(other, Client::new(tx))[0].0.response {
+            Some(ServerResponse::Challenge(challenge)) => {
+                assert_eq!(sm.challenges.read().unwrap()[&other], challenge)
+            }
+            _ => panic!("Expected a challenge"),
+        }
+
+        // the registration of the victim is captured and replayed from another connection
+        let (challenge, signed) = registration(&sm, &victim, None, &identity);
+        let messages = sm.register(attacker, protocol_id, capacity, None, challenge, signed);
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::InvalidSignature)) => {}
+            _ => panic!("Expected an invalid signature error"),
+        }
+        assert_eq!(sm.number_of_sessions(), 0);
+
+        // the victim still registers with its identity
+        register(&sm, victim, protocol_id, capacity, None, &identity);
+        let peer = sm.get_peer_by_address(&victim).unwrap();
+        assert_eq!(peer.identity, Some(identity.key()));
+        assert!(sm.challenges.read().unwrap().get(&victim).is_none());
+    }
+
+    #[test]
+    fn test_ban_blamed_identity() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let mut sm = session_manager();
+        sm.set_ban_blamed(true);
+        let identities = vec![Identity::generate(), Identity::generate()];
+        let first = connect(&sm, 8081);
+        let second = connect(&sm, 8082);
+        for (addr, identity) in [first, second].iter().zip(identities.iter()) {
+            register(&sm, *addr, protocol_id, capacity, None, identity);
+        }
+
+        // peer 1 blames peer 2 with a message peer 2 signed
+        let mut msg = ClientMessage::new();
+        let mut relay_message = RelayMessage::new(2, protocol_id);
+        relay_message.set_message_params(vec![1], String::from("not a commitment"));
+        msg.relay_message = Some(relay_message);
+        let session_id = registered_session(&sm, &first);
+        let evidence = vec![identities[1].sign(&msg, session_id, 0)];
+        let abort = AbortMessage::blame(1, protocol_id, AbortReason::MalformedMessage, 2, evidence);
+        let messages = sm.abort_blaming(first, &abort);
+        assert_eq!(messages.len(), capacity as usize);
+        assert!(sm.is_banned(&identities[1].key()));
+        assert!(!sm.is_banned(&identities[0].key()));
+
+        // the culprit can not register again, from any connection
+        let third = connect(&sm, 8083);
+        let messages = register(&sm, third, protocol_id, capacity, None, &identities[1]);
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::Banned)) => {}
+            _ => panic!("Expected a banned error"),
+        }
+        assert!(sm.get_peer_by_address(&third).is_none());
+    }
+
+    #[test]
+    fn test_expire_deadlines() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let mut sm = session_manager();
+        sm.set_timeouts(Timeouts {
+            registration: Some(Duration::from_secs(60)),
+            round: None,
+        });
+        let first = connect(&sm, 8081);
+        register(
+            &sm,
+            first,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        assert!(sm.expire_deadlines(Instant::now()).is_empty());
+
+        // the session is aborted once its registration deadline passes
+        let messages = sm.expire_deadlines(Instant::now() + Duration::from_secs(61));
+        assert_eq!(messages.len(), 1);
+        let session = sm.get_session_by_address(&first).unwrap();
+        assert_eq!(session.state(), RelaySessionState::Aborted);
+
+        // and no longer takes new peers
+        let second = connect(&sm, 8082);
+        register(
+            &sm,
+            second,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        assert_eq!(sm.number_of_sessions(), 2);
+        assert!(sm.expire_deadlines(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_disconnect_removes_session() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let sm = session_manager();
+        let first = connect(&sm, 8081);
+        let second = connect(&sm, 8082);
+        register(
+            &sm,
+            first,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+        register(
+            &sm,
+            second,
+            protocol_id,
+            capacity,
+            None,
+            &Identity::generate(),
+        );
+
+        // a disconnected peer is suspended until its grace period expires
+        sm.disconnect(first);
+        assert!(sm.is_suspended(&first));
+        let session = sm.get_session_by_address(&second).unwrap();
+        assert_eq!(session.state(), RelaySessionState::Initialized);
+
+        sm.expire(first);
+        assert_eq!(session.state(), RelaySessionState::Aborted);
+        assert_eq!(sm.number_of_sessions(), 1);
+
+        sm.disconnect(second);
+        assert_eq!(sm.number_of_sessions(), 0);
+    }
+
+    // Returns the resumption token sent to the given peer on registration
+    fn resumption_token(
+        messages: &Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>,
+        peer_id: PeerIdentifier,
+    ) -> ResumptionToken {
+        messages
+            .iter()
+            .filter_map(|(msg, _)| match msg.response {
+                Some(ServerResponse::Register(id, token)) if id == peer_id => Some(token),
+                _ => None,
+            })
+            .next()
+            .expect("Expected a register response")
+    }
+
+    #[test]
+    fn test_resume_replays_missed_messages() {
+        let protocol_id: ProtocolIdentifier = 1;
+        let capacity: u32 = 2;
+        let sm = session_manager();
+        let first_identity = Identity::generate();
+        let second_identity = Identity::generate();
+        let first = connect(&sm, 8081);
+        let second = connect(&sm, 8082);
+        register(&sm, first, protocol_id, capacity, None, &first_identity);
+        let messages = register(&sm, second, protocol_id, capacity, None, &second_identity);
+        let session_id = registered_session(&sm, &second);
+        let token = resumption_token(&messages, 2);
+
+        // the first peer relays a message while the second is disconnected
+        sm.disconnect(second);
+        let mut relay_message = RelayMessage::new(1, protocol_id);
+        relay_message.set_message_params(vec![1, 2], "test");
+        let mut client_message = ClientMessage::new();
+        client_message.relay_message = Some(relay_message.clone());
+        // a message signed for another session is rejected
+        let envelope = first_identity.sign(&client_message, session_id.wrapping_add(1), 0);
+        let messages = sm.relay_message(&first, relay_message.clone(), envelope);
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::InvalidSignature)) => {}
+            _ => panic!("Expected an invalid signature error"),
+        }
+        let envelope = first_identity.sign(&client_message, session_id, 0);
+        let messages = sm.relay_message(&first, relay_message, envelope);
+        assert_eq!(messages.len(), 1);
+
+        // a wrong token is rejected
+        let reconnected = connect(&sm, 8083);
+        let messages = sm.resume(
+            reconnected,
+            session_id,
+            2,
+            token.wrapping_add(1),
+            second_identity.key(),
+        );
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::InvalidToken)) => {}
+            _ => panic!("Expected an invalid token error"),
+        }
+
+        // as is a resume signed by another identity
+        let messages = sm.resume(reconnected, session_id, 2, token, first_identity.key());
+        match messages[0].0.response {
+            Some(ServerResponse::ErrorResponse(RelayError::InvalidSignature)) => {}
+            _ => panic!("Expected an invalid signature error"),
+        }
+
+        let messages = sm.resume(reconnected, session_id, 2, token, second_identity.key());
+        assert_eq!(registered_session(&sm, &reconnected), session_id);
+        assert_eq!(sm.get_peer_by_address(&reconnected).unwrap().peer_id, 2);
+        assert_eq!(messages.len(), 2);
+        match messages[0].0.response {
+            Some(ServerResponse::Resumed(peer_id)) => assert_eq!(peer_id, 2),
+            _ => panic!("Expected a resumed response"),
+        }
+        assert!(messages[1].0.relay_message.is_some());
+
+        // the grace period of the old connection expires without aborting the session
+        sm.expire(second);
+        let session = sm.get_session(&session_id).unwrap();
+        assert_eq!(session.state(), RelaySessionState::Initialized);
+    }
+}
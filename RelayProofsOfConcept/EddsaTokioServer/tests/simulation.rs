@@ -0,0 +1,284 @@
+/// Runs EdDSA key generation and signing through the relay session of the Tokio server,
+/// in one process and without sockets
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+use futures::sync::mpsc;
+use futures::{future, Async, Future, Stream};
+
+use mmpc_client::key_store::{MemoryKeyStore, PeerKeys};
+use mmpc_client::peer::{Peer, ProtocolAbort, ProtocolDataManager};
+use mmpc_client::simulation::{Delivery, NetworkConditions, SimulatedRelay, Simulation};
+use mmpc_client::transport::{RoundMessage, TransportError};
+use mmpc_client::{eddsa_peer_kg, eddsa_peer_sign};
+use relay_server::{Client, RelaySession};
+use relay_server_common::common::RelayError;
+use relay_server_common::encryption::EncryptionKeyPair;
+use relay_server_common::identity::{Identity, PeerIdentities};
+use relay_server_common::{
+    ClientMessage, MessagePayload, PeerIdentifier, ProtocolIdentifier, RelayMessage, ServerMessage,
+    ServerResponse,
+};
+
+// a peer of the session, as the relay session sees it
+struct SessionPeer {
+    addr: SocketAddr,
+    identity: Identity,
+    encryption: EncryptionKeyPair,
+    identities: PeerIdentities,
+    incoming: mpsc::Receiver<ServerMessage>,
+    // number of messages relayed to the peer from each of the peers, the round of the next one
+    rounds: BTreeMap<PeerIdentifier, u32>,
+}
+
+// The relay session, with the connection of every peer replaced by a channel
+struct TokioRelay {
+    session: RelaySession,
+    protocol_id: ProtocolIdentifier,
+    capacity: u32,
+    peers: Vec<SessionPeer>,
+    // index of each peer in the simulation, by its identifier in the session
+    indexes: BTreeMap<PeerIdentifier, usize>,
+}
+
+impl TokioRelay {
+    fn new(capacity: u32) -> TokioRelay {
+        let session = RelaySession::new(capacity);
+        let peers = (0..capacity)
+            .map(|i| {
+                let addr: SocketAddr = format!("127.0.0.1:{}", 9000 + i).parse().unwrap();
+                let (tx, incoming) = mpsc::channel(256);
+                session.insert_new_connection(addr, Client::new(tx));
+                SessionPeer {
+                    addr,
+                    identity: Identity::generate(),
+                    encryption: EncryptionKeyPair::generate(),
+                    identities: PeerIdentities::new(),
+                    incoming,
+                    rounds: BTreeMap::new(),
+                }
+            })
+            .collect();
+        TokioRelay {
+            session,
+            protocol_id: 0,
+            capacity,
+            peers,
+            indexes: BTreeMap::new(),
+        }
+    }
+
+    fn forward(&mut self, messages: Vec<(ServerMessage, mpsc::Sender<ServerMessage>)>) {
+        for (msg, mut tx) in messages {
+            tx.try_send(msg).expect("Unable to forward server message");
+        }
+    }
+
+    // Reads the server messages sent to each peer, and returns the relayed messages in them
+    fn deliveries(&mut self) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+        for peer in self.peers.iter_mut() {
+            let incoming = &mut peer.incoming;
+            let messages = future::lazy(|| {
+                let mut messages = Vec::new();
+                while let Ok(Async::Ready(Some(msg))) = incoming.poll() {
+                    messages.push(msg);
+                }
+                Ok::<_, ()>(messages)
+            })
+            .wait()
+            .unwrap();
+            for msg in messages {
+                if let Some(ServerResponse::Peers(registrations)) = msg.response {
+                    for (peer_id, registration) in registrations.iter() {
+                        peer.identities
+                            .add_peer(*peer_id, registration)
+                            .expect("Invalid registration");
+                    }
+                    continue;
+                }
+                let envelope = match msg.signed_message {
+                    Some(envelope) => envelope,
+                    None => continue,
+                };
+                let to = match self.session.get_peer_by_address(&peer.addr) {
+                    Some(p) => p.peer_id,
+                    None => continue,
+                };
+                let relay_msg = peer
+                    .identities
+                    .verify(&envelope, 0)
+                    .expect("Relayed message not signed by its sender");
+                let from = relay_msg.peer_number;
+                let round = peer.rounds.get(&from).cloned().unwrap_or(0);
+                assert_eq!(envelope.round, round, "Relayed message of another round");
+                let private_payload = relay_msg.ciphertext_for(to).map(|ciphertext| {
+                    let sender_key = peer.identities.encryption_key(&from).unwrap();
+                    peer.encryption
+                        .decrypt(sender_key, from, to, ciphertext)
+                        .expect("Unable to decrypt private payload")
+                });
+                deliveries.push(Delivery {
+                    to,
+                    round,
+                    message: RoundMessage {
+                        from,
+                        payload: relay_msg.message,
+                        private_payload,
+                    },
+                });
+                peer.rounds.insert(from, round + 1);
+            }
+        }
+        deliveries
+    }
+}
+
+impl SimulatedRelay for TokioRelay {
+    fn register(
+        &mut self,
+        index: usize,
+        protocol_id: ProtocolIdentifier,
+        capacity: u32,
+    ) -> Result<PeerIdentifier, TransportError> {
+        self.protocol_id = protocol_id;
+        let peer = &self.peers[index];
+        let mut msg = ClientMessage::new();
+        msg.register(
+            protocol_id,
+            capacity,
+            None,
+            // the session is driven without connections, so there is no challenge to carry
+            0,
+            Some(peer.encryption.public_key()),
+        );
+        let registration = peer.identity.sign(&msg, 0, 0);
+        let addr = peer.addr;
+        let messages = self
+            .session
+            .register(addr, protocol_id, capacity, registration)
+            .map_err(|err| TransportError::Rejected(err.to_string()))?;
+        self.forward(messages);
+        let peer_id = self.session.get_peer_by_address(&addr).unwrap().peer_id;
+        self.indexes.insert(peer_id, index);
+        Ok(peer_id)
+    }
+
+    fn submit(
+        &mut self,
+        from: PeerIdentifier,
+        round: u32,
+        payload: MessagePayload,
+        private_items: BTreeMap<PeerIdentifier, MessagePayload>,
+    ) -> Result<Option<Vec<Delivery>>, TransportError> {
+        // the encryption keys of the peers arrive once all are registered
+        let mut pending = self.deliveries();
+        let peer = &self.peers[self.indexes[&from]];
+        let mut relay_message = RelayMessage::new(from, self.protocol_id);
+        relay_message.set_message_params((1..=self.capacity).collect(), payload);
+        for (recipient, private_payload) in private_items.iter() {
+            let recipient_key = peer.identities.encryption_key(recipient).unwrap();
+            let ciphertext =
+                peer.encryption
+                    .encrypt(recipient_key, from, *recipient, private_payload);
+            relay_message.ciphertexts.insert(*recipient, ciphertext);
+        }
+        let mut msg = ClientMessage::new();
+        msg.relay_message = Some(relay_message.clone());
+        let envelope = peer.identity.sign(&msg, 0, round);
+        let messages = self
+            .session
+            .relay_message(&peer.addr, relay_message, envelope);
+        // a message the session can not relay is answered with an error to its sender only
+        if let [(
+            ServerMessage {
+                response: Some(ServerResponse::ErrorResponse(err)),
+                ..
+            },
+            _,
+        )] = &messages[..]
+        {
+            return match err {
+                RelayError::NotYourTurn => Ok(None),
+                err => Err(TransportError::Rejected(err.to_string())),
+            };
+        }
+        self.forward(messages);
+        pending.extend(self.deliveries());
+        Ok(Some(pending))
+    }
+}
+
+fn run_keygen(capacity: u32, conditions: NetworkConditions, seed: u64) -> Vec<PeerKeys> {
+    let data_managers = (0..capacity)
+        .map(|_| {
+            let keys = PeerKeys::new(Box::new(MemoryKeyStore::new()), None);
+            ProtocolDataManager::new(capacity, Vec::new(), keys)
+        })
+        .collect();
+    let mut sim: Simulation<eddsa_peer_kg::EddsaPeer, _> =
+        Simulation::new(TokioRelay::new(capacity), conditions, seed, data_managers);
+    let report = sim.run(1).expect("Key generation failed");
+    assert_eq!(report.rounds, 1);
+    sim.into_peers().into_iter().map(|peer| peer.keys).collect()
+}
+
+fn run_sign(keys: Vec<PeerKeys>, message: &[u8], conditions: NetworkConditions, seed: u64) {
+    let capacity = keys.len() as u32;
+    let data_managers = keys
+        .into_iter()
+        .map(|keys| ProtocolDataManager::new(capacity, message.to_vec(), keys))
+        .collect();
+    let mut sim: Simulation<eddsa_peer_sign::EddsaPeer, _> =
+        Simulation::new(TokioRelay::new(capacity), conditions, seed, data_managers);
+    let report = sim.run(1).expect("Signing failed");
+    assert_eq!(report.rounds, 4);
+    for mut peer in sim.into_peers() {
+        assert!(peer.is_done());
+        assert_eq!(peer.finalize(), Ok(()));
+    }
+}
+
+#[test]
+fn test_keygen_and_sign() {
+    let keys = run_keygen(3, NetworkConditions::reliable(), 1);
+    run_sign(keys, b"simulated", NetworkConditions::reliable(), 2);
+}
+
+#[test]
+fn test_keygen_and_sign_reordered() {
+    let conditions = NetworkConditions {
+        reorder: true,
+        max_delay: 10,
+        drop_rate: 0.0,
+    };
+    for seed in 0..3 {
+        let keys = run_keygen(3, conditions.clone(), seed);
+        run_sign(keys, b"reordered", conditions.clone(), seed);
+    }
+}
+
+#[test]
+fn test_dropped_messages_are_not_resent() {
+    let conditions = NetworkConditions {
+        reorder: false,
+        max_delay: 0,
+        drop_rate: 0.5,
+    };
+    let data_managers: Vec<ProtocolDataManager<eddsa_peer_kg::EddsaPeer>> = (0..3)
+        .map(|_| {
+            let keys = PeerKeys::new(Box::new(MemoryKeyStore::new()), None);
+            ProtocolDataManager::new(3, Vec::new(), keys)
+        })
+        .collect();
+    let mut sim = Simulation::new(TokioRelay::new(3), conditions, 5, data_managers);
+    // the peers missing a message wait for it in vain, and blame its sender
+    let report = sim.run(1).unwrap();
+    assert!(!report.aborts.is_empty());
+    for (peer_id, abort) in report.aborts {
+        match abort {
+            ProtocolAbort::Unresponsive(culprit) => assert_ne!(culprit, peer_id),
+            other => panic!("Expected unresponsive peer, got {:?}", other),
+        }
+    }
+}